@@ -0,0 +1,208 @@
+use crate::gorilla;
+use gluesql::sled::{self, Tree};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use uuid::Uuid;
+
+// Compressed blocks
+// Sealed shards are compressed into blocks of up to BLOCK_POINTS points of a series, saved in the
+// blocks tree of the shard and keyed as their first point. Times and write times are written as
+// deltas of deltas and values XORed with the previous one (see gorilla.rs), the point ids as they
+// are. The field and tags aren't repeated: they are those of the series, saved once.
+
+pub const BLOCK_POINTS: usize = 1024;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Block {
+    count: u32,
+    times: Vec<u8>,
+    created: Vec<u8>,
+    values: Vec<u8>,
+    ids: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Entry {
+    pub time: i64,
+    pub id: Uuid,
+    pub value: f64,
+    pub created_at: i64,
+}
+
+// Entries in time then id order
+pub fn encode(entries: &[Entry]) -> Result<Vec<u8>, String> {
+    let times: Vec<i64> = entries.iter().map(|e| e.time).collect();
+    let created: Vec<i64> = entries.iter().map(|e| e.created_at).collect();
+    let values: Vec<f64> = entries.iter().map(|e| e.value).collect();
+    let block = Block {
+        count: entries.len() as u32,
+        times: gorilla::encode_times(&times),
+        created: gorilla::encode_times(&created),
+        values: gorilla::encode_values(&values),
+        ids: entries.iter().flat_map(|e| *e.id.as_bytes()).collect(),
+    };
+    match bincode::serialize(&block) {
+        Ok(b) => Ok(b),
+        Err(e) => Err(format!("Error encoding block: {}", e)),
+    }
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Vec<Entry>, String> {
+    let block: Block = match bincode::deserialize(bytes) {
+        Ok(b) => b,
+        Err(e) => return Err(format!("Error decoding block: {}", e)),
+    };
+    let count = block.count as usize;
+    if block.ids.len() != count * 16 {
+        return Err(format!(
+            "Invalid block of {} points with {} bytes of ids",
+            count,
+            block.ids.len()
+        ));
+    }
+    let times = gorilla::decode_times(&block.times, count)?;
+    let created = gorilla::decode_times(&block.created, count)?;
+    let values = gorilla::decode_values(&block.values, count)?;
+    let mut entries = Vec::with_capacity(count);
+    for (i, id) in block.ids.chunks_exact(16).enumerate() {
+        let mut bytes = [0; 16];
+        bytes.copy_from_slice(id);
+        entries.push(Entry {
+            time: times[i],
+            id: Uuid::from_bytes(bytes),
+            value: values[i],
+            created_at: created[i],
+        });
+    }
+    Ok(entries)
+}
+
+// Entries of a series from start (included) to end (excluded), decoding a block at a time
+pub struct Reader {
+    blocks: sled::Iter,
+    entries: VecDeque<Entry>,
+    start: Option<i64>,
+    end: Option<i64>,
+    descending: bool,
+}
+
+impl Reader {
+    // from and to are the keys of the range of points read, of a single series
+    pub fn new(
+        tree: &Tree,
+        from: Vec<u8>,
+        to: Vec<u8>,
+        range: (Option<i64>, Option<i64>),
+        descending: bool,
+    ) -> Result<Reader, String> {
+        // the block holding the start may begin before it
+        let first = match tree.range(from[..8].to_vec()..from.clone()).next_back() {
+            Some(Ok((key, _))) => key.to_vec(),
+            Some(Err(e)) => return Err(format!("Error reading blocks: {}", e)),
+            None => from,
+        };
+        Ok(Reader {
+            blocks: tree.range(first..to),
+            entries: VecDeque::new(),
+            start: range.0,
+            end: range.1,
+            descending,
+        })
+    }
+}
+
+impl Iterator for Reader {
+    type Item = Result<Entry, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entry = match self.descending {
+                true => self.entries.pop_back(),
+                false => self.entries.pop_front(),
+            };
+            if let Some(e) = entry {
+                return Some(Ok(e));
+            }
+            let block = match self.descending {
+                true => self.blocks.next_back()?,
+                false => self.blocks.next()?,
+            };
+            let entries = match block {
+                Ok((_, value)) => decode(&value),
+                Err(e) => Err(format!("Error reading blocks: {}", e)),
+            };
+            let (start, end) = (self.start, self.end);
+            match entries {
+                Ok(entries) => self.entries.extend(entries.into_iter().filter(|e| {
+                    start.is_none_or(|s| e.time >= s) && end.is_none_or(|t| e.time < t)
+                })),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine;
+    use crate::persistence::Measurement;
+    use crate::shards;
+    use gluesql::ast::IndexOperator;
+    use gluesql::data::Value;
+    use gluesql::storages::SledStorage;
+    use std::collections::HashMap;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn compressed_shards() {
+        let storage = SledStorage::try_from(sled::Config::default().temporary(true)).unwrap();
+        let config = shards::Config {
+            grace: 0,
+            ..shards::Config::default()
+        };
+        for i in 0..2500 {
+            let m = Measurement {
+                key: i * 10_000,
+                id: Uuid::new_v4(),
+                name: "usage".to_string(),
+                value: (i % 7) as f64,
+                tags: HashMap::from([("host".to_string(), (i % 5 / 4).to_string())]),
+            };
+            engine::insert(&storage, &config, &m, i * 10_000 + 3).unwrap();
+        }
+        let times = |descending, condition| -> Vec<i64> {
+            engine::scan(&storage, None, descending, condition)
+                .unwrap()
+                .map(|r| match &r.unwrap().1 .0[1] {
+                    Value::Timestamp(t) => t.and_utc().timestamp_millis(),
+                    v => panic!("unexpected time {:?}", v),
+                })
+                .collect()
+        };
+        let from = Some((
+            IndexOperator::GtEq,
+            Value::Str("1970-01-01T01:00:00Z".to_string()),
+        ));
+        let before = (times(false, None), times(true, from.clone()));
+        let raw = shards::list(&storage).unwrap()[0].open(&storage).unwrap();
+        let size: usize = raw.iter().map(|e| e.unwrap().1.len() + 32).sum();
+
+        assert_eq!(
+            shards::seal(&storage, &config, 86_400_000).unwrap().len(),
+            1
+        );
+        let shard = &shards::list(&storage).unwrap()[0];
+        let blocks = shard.blocks(&storage).unwrap();
+        // 2000 points of a host in 2 blocks, 500 of the other in 1
+        assert_eq!(blocks.len(), 3);
+        let compressed: usize = blocks.iter().map(|e| e.unwrap().1.len() + 32).sum();
+        assert!(compressed < size * 2 / 3); // the point ids take most of it
+        assert_eq!((times(false, None), times(true, from)), before);
+
+        // expiry rewrites the blocks it goes through
+        assert_eq!(engine::expire(&storage, 15_000_000).unwrap(), 1500);
+        assert_eq!(times(false, None), before.0[1500..].to_vec());
+        assert_eq!(shards::list(&storage).unwrap()[0].points, 1000);
+    }
+}
@@ -0,0 +1,336 @@
+use crate::explain::Scan;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+// Query result cache
+// Dashboards run the same queries every few seconds. Results are kept in an LRU cache keyed by
+// the normalized query text and its parameters, time range included. A cached query is run
+// traced, so its entry knows the timeseries and time ranges it read: a write into one of them
+// invalidates it. Queries relative to now() aren't cached, their range moves with the clock.
+// The number of entries is read from the environment, 0 disables the cache:
+//   REFLUXDB_RESULT_CACHE=1000
+
+pub const DEFAULT_CAPACITY: usize = 1000;
+
+// Writes remembered to invalidate the results of the queries running while they happen
+const WRITE_LOG: usize = 1024;
+
+// A timeseries and the time range read from it, unbounded when none
+#[derive(Debug, Clone, PartialEq)]
+pub struct Read {
+    pub timeseries: String,
+    pub start: Option<i64>,
+    pub end: Option<i64>,
+}
+
+impl Read {
+    fn overlaps(&self, other: &Read) -> bool {
+        self.timeseries == other.timeseries
+            && self.start.is_none_or(|s| other.end.is_none_or(|e| e >= s))
+            && self.end.is_none_or(|e| other.start.is_none_or(|s| s <= e))
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct CacheStats {
+    pub capacity: usize,
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub invalidations: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    value: serde_json::Value,
+    reads: Vec<Read>,
+    used: u64,
+}
+
+// The range of a timeseries written or deleted, or a clear of every entry when none
+#[derive(Debug)]
+struct Write {
+    generation: u64,
+    range: Option<Read>,
+}
+
+impl Write {
+    fn hits(&self, reads: &[Read]) -> bool {
+        match &self.range {
+            Some(range) => reads.iter().any(|r| r.overlaps(range)),
+            None => true,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    entries: HashMap<String, Entry>,
+    recency: BTreeMap<u64, String>, // last use -> key, least recently used first
+    tick: u64,
+    generation: u64, // writes seen
+    writes: VecDeque<Write>,
+}
+
+#[derive(Debug)]
+pub struct ResultCache {
+    capacity: usize,
+    inner: Mutex<Entries>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    invalidations: AtomicU64,
+}
+
+impl Default for ResultCache {
+    fn default() -> Self {
+        ResultCache::new(DEFAULT_CAPACITY)
+    }
+}
+
+pub fn capacity_from_env() -> Result<usize, String> {
+    match std::env::var("REFLUXDB_RESULT_CACHE") {
+        Ok(v) => match v.trim().parse::<usize>() {
+            Ok(n) => Ok(n),
+            Err(_) => Err(format!("Invalid result cache size: {}", v)),
+        },
+        Err(_) => Ok(DEFAULT_CAPACITY),
+    }
+}
+
+// Cache key of a query: its kind and parameters and the query text, normalized by the SQL
+// parser or by collapsing whitespace. None when the result depends on the clock.
+pub fn key(params: &str, query: &str) -> Option<String> {
+    if query.to_lowercase().replace(' ', "").contains("now()") {
+        return None;
+    }
+    let normalized = match gluesql::parse_sql::parse(query) {
+        Ok(statements) => statements
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<String>>()
+            .join("; "),
+        Err(_) => query.split_whitespace().collect::<Vec<&str>>().join(" "),
+    };
+    Some(format!("{}\n{}", params, normalized))
+}
+
+// What the traced scans of a query read. Writes happen at the current time, so a range
+// ending at or after the execution time stays open: later writes land in it.
+pub fn reads(scans: &[Scan], executed_at: i64) -> Vec<Read> {
+    scans
+        .iter()
+        .filter(|s| s.timeseries != "(temporary)")
+        .map(|s| {
+            let (start, end) = match s.query == crate::explain::LAST_VALUES {
+                true => (None, None),
+                false => crate::explain::time_bounds(&s.query),
+            };
+            Read {
+                timeseries: s.timeseries.clone(),
+                start,
+                end: end.filter(|e| *e < executed_at),
+            }
+        })
+        .collect()
+}
+
+impl ResultCache {
+    pub fn new(capacity: usize) -> Self {
+        ResultCache {
+            capacity,
+            inner: Mutex::new(Entries::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            invalidations: AtomicU64::new(0),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn get(&self, key: &str) -> Option<serde_json::Value> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+        let used = match inner.entries.get_mut(key) {
+            Some(entry) => std::mem::replace(&mut entry.used, tick),
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+        };
+        inner.recency.remove(&used);
+        inner.recency.insert(tick, key.to_string());
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        inner.entries.get(key).map(|e| e.value.clone())
+    }
+
+    // Writes seen so far, taken before running a query to insert its result
+    pub fn generation(&self) -> u64 {
+        self.inner.lock().unwrap().generation
+    }
+
+    // Keeps the result of a query started at the generation, unless a write it read
+    // happened meanwhile
+    pub fn insert(&self, key: &str, value: serde_json::Value, reads: Vec<Read>, since: u64) {
+        if !self.enabled() {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let forgotten = inner
+            .writes
+            .front()
+            .map_or(inner.generation > since, |w| w.generation > since + 1);
+        let written = inner
+            .writes
+            .iter()
+            .any(|w| w.generation > since && w.hits(&reads));
+        if forgotten || written {
+            return;
+        }
+        inner.tick += 1;
+        let tick = inner.tick;
+        let entry = Entry {
+            value,
+            reads,
+            used: tick,
+        };
+        if let Some(old) = inner.entries.insert(key.to_string(), entry) {
+            inner.recency.remove(&old.used);
+        }
+        inner.recency.insert(tick, key.to_string());
+        while inner.entries.len() > self.capacity {
+            let oldest = match inner.recency.pop_first() {
+                Some((_, key)) => key,
+                None => break,
+            };
+            inner.entries.remove(&oldest);
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Drops the results that read the timeseries at the time written
+    pub fn invalidate(&self, timeseries: &str, time: i64) {
+        self.invalidate_range(timeseries, Some(time), Some(time));
+    }
+
+    // Drops the results that read the timeseries within the range, when rows are deleted
+    pub fn invalidate_range(&self, timeseries: &str, start: Option<i64>, end: Option<i64>) {
+        self.forget(Write {
+            generation: 0,
+            range: Some(Read {
+                timeseries: timeseries.to_string(),
+                start,
+                end,
+            }),
+        });
+    }
+
+    // Drops every result, when a timeseries is created
+    pub fn clear(&self) {
+        self.forget(Write {
+            generation: 0,
+            range: None,
+        });
+    }
+
+    fn forget(&self, mut write: Write) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.generation += 1;
+        write.generation = inner.generation;
+        let stale: Vec<(String, u64)> = inner
+            .entries
+            .iter()
+            .filter(|(_, e)| write.hits(&e.reads))
+            .map(|(k, e)| (k.clone(), e.used))
+            .collect();
+        for (key, used) in stale {
+            inner.entries.remove(&key);
+            inner.recency.remove(&used);
+            self.invalidations.fetch_add(1, Ordering::Relaxed);
+        }
+        inner.writes.push_back(write);
+        if inner.writes.len() > WRITE_LOG {
+            inner.writes.pop_front();
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            capacity: self.capacity,
+            entries: self.inner.lock().unwrap().entries.len(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            invalidations: self.invalidations.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(timeseries: &str, start: Option<i64>, end: Option<i64>) -> Vec<Read> {
+        vec![Read {
+            timeseries: timeseries.to_string(),
+            start,
+            end,
+        }]
+    }
+
+    #[test]
+    fn cache_results() {
+        assert_eq!(
+            key("sql", "select *  from cpu where  time > '2021-11-30'"),
+            key("sql", "SELECT * FROM cpu WHERE time > '2021-11-30'")
+        );
+        assert_eq!(
+            key("influxql", "SELECT * FROM cpu WHERE time > now() - 1h"),
+            None
+        );
+
+        let cache = ResultCache::new(2);
+        let since = cache.generation();
+        cache.insert(
+            "a",
+            serde_json::json!(1),
+            read("cpu", Some(0), Some(10)),
+            since,
+        );
+        cache.insert(
+            "b",
+            serde_json::json!(2),
+            read("cpu", Some(20), None),
+            since,
+        );
+        assert_eq!(cache.get("a"), Some(serde_json::json!(1)));
+        // b is the least recently used
+        cache.insert("c", serde_json::json!(3), read("mem", None, None), since);
+        assert_eq!(cache.get("b"), None);
+
+        cache.invalidate("cpu", 15);
+        assert_eq!(cache.get("a"), Some(serde_json::json!(1)));
+        cache.invalidate("cpu", 5);
+        assert_eq!(cache.get("a"), None);
+        assert_eq!(cache.get("c"), Some(serde_json::json!(3)));
+
+        // a write during the query drops its result
+        cache.insert(
+            "a",
+            serde_json::json!(1),
+            read("cpu", Some(0), Some(10)),
+            since,
+        );
+        assert_eq!(cache.get("a"), None);
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (3, 3));
+        assert_eq!((stats.evictions, stats.invalidations), (1, 1));
+    }
+}
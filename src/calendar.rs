@@ -0,0 +1,209 @@
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Offset, TimeZone, Utc};
+use chrono_tz::Tz;
+
+// Calendar windows
+// GROUP BY time() windows are fixed durations aligned to the epoch, or calendar periods
+// (months '1mo', years '1y') aligned to their first day. tz('Europe/Paris') aligns them to
+// the local time of a zone: whole days become local days starting at midnight and whole
+// weeks ISO weeks starting on Monday, so a window lasts 23 or 25 hours across DST changes.
+//   SELECT sum(value) FROM billing GROUP BY time('1mo'), tz('America/Chicago')
+
+const DAY: i64 = 86_400_000;
+const WEEK: i64 = 7 * DAY;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Period {
+    Fixed(i64), // milliseconds
+    Days(i64),
+    Weeks(i64),
+    Months(i64),
+    Years(i64),
+}
+
+impl Period {
+    // A duration such as 5m or 1d, or a number of months (3mo) or years (1y)
+    pub fn parse(period: &str) -> Result<Self, String> {
+        let period = period.trim();
+        let split = period
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(period.len());
+        let (amount, unit) = period.split_at(split);
+        let calendar = match unit {
+            "mo" => Period::Months,
+            "y" => Period::Years,
+            _ => return Ok(Period::Fixed(crate::functions::parse_duration(period)?)),
+        };
+        match amount.parse::<i64>() {
+            Ok(a) if a > 0 => Ok(calendar(a)),
+            _ => Err(format!("Invalid period: {}", period)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Window {
+    pub period: Period,
+    pub tz: Option<Tz>,
+}
+
+pub fn timezone(name: &str) -> Result<Tz, String> {
+    match name.parse::<Tz>() {
+        Ok(tz) => Ok(tz),
+        Err(_) => Err(format!("Unknown time zone: {}", name)),
+    }
+}
+
+fn epoch_date() -> NaiveDate {
+    NaiveDate::from_ymd_opt(1970, 1, 1).unwrap()
+}
+
+fn utc(time: i64) -> DateTime<Utc> {
+    DateTime::<Utc>::from_timestamp_millis(time).unwrap_or_default()
+}
+
+fn local_date(time: i64, tz: Option<Tz>) -> NaiveDate {
+    match tz {
+        Some(tz) => utc(time).with_timezone(&tz).date_naive(),
+        None => utc(time).date_naive(),
+    }
+}
+
+// First instant of a local day: midnight, or the end of a DST gap skipping it
+fn day_start(date: NaiveDate, tz: Option<Tz>) -> i64 {
+    let midnight = date.and_hms_opt(0, 0, 0).unwrap();
+    let tz = match tz {
+        Some(tz) => tz,
+        None => return midnight.and_utc().timestamp_millis(),
+    };
+    let mut local = midnight;
+    while local < midnight + Duration::days(1) {
+        if let Some(t) = tz.from_local_datetime(&local).earliest() {
+            return t.timestamp_millis();
+        }
+        local += Duration::minutes(15);
+    }
+    midnight.and_utc().timestamp_millis()
+}
+
+fn month_date(months: i64) -> NaiveDate {
+    NaiveDate::from_ymd_opt(
+        months.div_euclid(12) as i32,
+        months.rem_euclid(12) as u32 + 1,
+        1,
+    )
+    .unwrap_or_else(epoch_date)
+}
+
+impl Window {
+    pub fn fixed(interval: i64) -> Self {
+        Window {
+            period: Period::Fixed(interval),
+            tz: None,
+        }
+    }
+
+    // Whole days and weeks follow the local calendar of a time zone
+    pub fn new(period: Period, tz: Option<Tz>) -> Self {
+        let period = match (period, tz) {
+            (Period::Fixed(ms), Some(_)) if ms % WEEK == 0 => Period::Weeks(ms / WEEK),
+            (Period::Fixed(ms), Some(_)) if ms % DAY == 0 => Period::Days(ms / DAY),
+            (period, _) => period,
+        };
+        Window { period, tz }
+    }
+
+    // Start of the window holding the time
+    pub fn start(&self, time: i64) -> i64 {
+        let date = local_date(time, self.tz);
+        let days = (date - epoch_date()).num_days();
+        let start = match self.period {
+            Period::Fixed(ms) => {
+                let offset = match self.tz {
+                    Some(tz) => {
+                        let offset = tz.offset_from_utc_datetime(&utc(time).naive_utc());
+                        offset.fix().local_minus_utc() as i64 * 1000
+                    }
+                    None => 0,
+                };
+                return time - (time + offset).rem_euclid(ms);
+            }
+            Period::Days(n) => epoch_date() + Duration::days(days - days.rem_euclid(n)),
+            Period::Weeks(n) => {
+                // 1969-12-29 is a Monday
+                let days = days + 3;
+                epoch_date() + Duration::days(days - days.rem_euclid(7 * n) - 3)
+            }
+            Period::Months(n) => {
+                let months = date.year() as i64 * 12 + date.month0() as i64;
+                month_date(months - months.rem_euclid(n))
+            }
+            Period::Years(n) => {
+                let year = date.year() as i64;
+                month_date((year - year.rem_euclid(n)) * 12)
+            }
+        };
+        day_start(start, self.tz)
+    }
+
+    // Start of the window following the one starting at the time
+    pub fn next(&self, start: i64) -> i64 {
+        let date = local_date(start, self.tz);
+        let months = date.year() as i64 * 12 + date.month0() as i64;
+        let next = match self.period {
+            Period::Fixed(ms) => return start + ms,
+            Period::Days(n) => date + Duration::days(n),
+            Period::Weeks(n) => date + Duration::days(7 * n),
+            Period::Months(n) => month_date(months + n),
+            Period::Years(n) => month_date(months + 12 * n),
+        };
+        day_start(next, self.tz)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(rfc3339: &str) -> i64 {
+        rfc3339.parse::<DateTime<Utc>>().unwrap().timestamp_millis()
+    }
+
+    #[test]
+    fn calendar_windows() {
+        assert_eq!(Period::parse("3mo"), Ok(Period::Months(3)));
+        assert_eq!(Period::parse("1d"), Ok(Period::Fixed(DAY)));
+        assert!(Period::parse("0y").is_err());
+        assert!(timezone("Mars/Olympus").is_err());
+
+        let paris = Some(timezone("Europe/Paris").unwrap());
+        // the day of the switch to summer time lasts 23 hours
+        let day = Window::new(Period::parse("1d").unwrap(), paris);
+        assert_eq!(day.period, Period::Days(1));
+        let start = day.start(time("2021-03-28T12:00:00Z"));
+        assert_eq!(start, time("2021-03-27T23:00:00Z"));
+        assert_eq!(day.next(start), time("2021-03-28T22:00:00Z"));
+
+        let week = Window::new(Period::Fixed(WEEK), paris);
+        assert_eq!(
+            week.start(time("2021-12-01T12:00:00Z")),
+            time("2021-11-28T23:00:00Z")
+        );
+
+        let quarter = Window::new(Period::Months(3), None);
+        let start = quarter.start(time("2021-12-01T12:00:00Z"));
+        assert_eq!(start, time("2021-10-01T00:00:00Z"));
+        assert_eq!(quarter.next(start), time("2022-01-01T00:00:00Z"));
+
+        let year = Window::new(Period::Years(1), paris);
+        assert_eq!(
+            year.start(time("2021-12-31T23:30:00Z")),
+            time("2021-12-31T23:00:00Z")
+        );
+        // fixed durations stay aligned to the epoch without a time zone
+        let hours = Window::fixed(3_600_000);
+        assert_eq!(
+            hours.start(time("2021-12-01T12:30:00Z")),
+            time("2021-12-01T12:00:00Z")
+        );
+    }
+}
@@ -0,0 +1,230 @@
+use crate::persistence::Measurement;
+use gluesql::sqlparser::ast::{BinaryOperator, Expr, SetExpr, Statement};
+use serde::Serialize;
+use uuid::Uuid;
+
+// Cursor pagination
+// Pages are read in (time, id) order and end with an opaque continuation token encoding the
+// time and id of their last row. The next page reads the rows after it: the time is pushed
+// down as a condition so earlier rows aren't read again, and the scan stops once the page is
+// full. Rows written while a client walks the pages are neither skipped nor repeated, as ids
+// break the ties between rows of the same time.
+//   GET /range/cpu?start=...&end=...&limit=1000&cursor=<token>
+
+pub const DEFAULT_PAGE_SIZE: usize = 1000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cursor {
+    pub time: i64,
+    pub id: Uuid,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Page {
+    pub data: Vec<Measurement>,
+    pub next: Option<String>, // continuation token, none on the last page
+}
+
+impl Cursor {
+    // 8 bytes of time and 16 of id, hex encoded
+    pub fn encode(&self) -> String {
+        let mut bytes = self.time.to_be_bytes().to_vec();
+        bytes.extend_from_slice(self.id.as_bytes());
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    pub fn decode(token: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid cursor: {}", token);
+        if token.len() != 48 || !token.is_ascii() {
+            return Err(invalid());
+        }
+        let mut bytes: Vec<u8> = Vec::new();
+        for i in (0..token.len()).step_by(2) {
+            match u8::from_str_radix(&token[i..i + 2], 16) {
+                Ok(b) => bytes.push(b),
+                Err(_) => return Err(invalid()),
+            }
+        }
+        let mut time = [0u8; 8];
+        time.copy_from_slice(&bytes[..8]);
+        let time = crate::query::valid_time(i64::from_be_bytes(time)).map_err(|_| invalid())?;
+        Ok(Cursor {
+            time,
+            id: Uuid::from_slice(&bytes[8..]).map_err(|_| invalid())?,
+        })
+    }
+
+    fn is_before(&self, m: &Measurement) -> bool {
+        (self.time, self.id) < (m.key, m.id)
+    }
+}
+
+// The page asked for by the limit and cursor parameters, none when the result isn't paginated
+pub fn page_request(
+    limit: Option<usize>,
+    cursor: Option<&str>,
+) -> Result<Option<(Option<Cursor>, usize)>, String> {
+    if limit.is_none() && cursor.is_none() {
+        return Ok(None);
+    }
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if limit == 0 {
+        return Err("The page limit must be positive".to_string());
+    }
+    match cursor {
+        Some(token) => Ok(Some((Some(Cursor::decode(token)?), limit))),
+        None => Ok(Some((None, limit))),
+    }
+}
+
+// Sorts the rows read by the page queries and keeps the limit first ones after the cursor
+pub fn paginate(mut rows: Vec<Measurement>, cursor: Option<&Cursor>, limit: usize) -> Page {
+    rows.retain(|m| cursor.is_none_or(|c| c.is_before(m)));
+    rows.sort_by_key(|m| (m.key, m.id));
+    let next = match rows.len() > limit {
+        true => {
+            rows.truncate(limit);
+            rows.last().map(|m| {
+                Cursor {
+                    time: m.key,
+                    id: m.id,
+                }
+                .encode()
+            })
+        }
+        false => None,
+    };
+    Page { data: rows, next }
+}
+
+/*
+Queries reading a page of a SELECT: after a cursor, the rows of its time (ties broken by their
+ids) then at most limit + 1 rows after it, the one past the limit telling there's a next page.
+The cursor time comes first in the conditions, GlueSQL scans the time index from it and the rows
+come in (time, id) order, so the limit stops the scan instead of every later row being read.
+ */
+pub fn page_queries(
+    query: &str,
+    cursor: Option<&Cursor>,
+    limit: usize,
+) -> Result<Vec<String>, String> {
+    let limited = |query: String| format!("{} LIMIT {}", query, limit + 1);
+    match cursor {
+        Some(c) => Ok(vec![
+            resume_query(query, "=", c.time)?,
+            limited(resume_query(query, ">", c.time)?),
+        ]),
+        None => Ok(vec![limited(resume_query(query, "", 0)?)]),
+    }
+}
+
+// Checks the SELECT can be paginated, with a condition on the time put before its own
+fn resume_query(query: &str, op: &str, time: i64) -> Result<String, String> {
+    let mut statements = match gluesql::parse_sql::parse(query) {
+        Ok(s) => s,
+        Err(e) => return Err(format!("Error parsing query: {}", e)),
+    };
+    let q = match statements.first_mut() {
+        Some(Statement::Query(q)) => q,
+        _ => return Err("Pagination applies to a single SELECT".to_string()),
+    };
+    if !q.order_by.is_empty() || q.limit.is_some() || q.offset.is_some() {
+        return Err("Pages are in time order, without ORDER BY, LIMIT or OFFSET".to_string());
+    }
+    let select = match &mut q.body {
+        SetExpr::Select(select) => select,
+        _ => return Err("Pagination applies to a single SELECT".to_string()),
+    };
+    if !op.is_empty() {
+        let condition = time_condition(op, time)?;
+        select.selection = Some(match select.selection.take() {
+            Some(e) => Expr::BinaryOp {
+                left: Box::new(condition),
+                op: BinaryOperator::And,
+                right: Box::new(Expr::Nested(Box::new(e))),
+            },
+            None => condition,
+        });
+    }
+    Ok(statements[0].to_string())
+}
+
+fn time_condition(op: &str, time: i64) -> Result<Expr, String> {
+    let query = format!(
+        "SELECT * FROM t WHERE time {} '{}'",
+        op,
+        crate::query::timestamp_literal(time)
+    );
+    match gluesql::parse_sql::parse(&query) {
+        Ok(mut statements) => match statements.pop() {
+            Some(Statement::Query(q)) => match q.body {
+                SetExpr::Select(select) => Ok(select.selection.unwrap()),
+                _ => Err("Error building cursor condition".to_string()),
+            },
+            _ => Err("Error building cursor condition".to_string()),
+        },
+        Err(e) => Err(format!("Error building cursor condition: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn measurement(key: i64, id: u128) -> Measurement {
+        Measurement {
+            key,
+            id: Uuid::from_u128(id),
+            name: "value".to_string(),
+            value: 1.0,
+            tags: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn cursor_pages() {
+        let cursor = Cursor {
+            time: 1638316800000,
+            id: Uuid::from_u128(42),
+        };
+        assert_eq!(Cursor::decode(&cursor.encode()), Ok(cursor));
+        assert!(Cursor::decode("not a cursor").is_err());
+        // a time chrono can't represent
+        assert!(Cursor::decode(&"7f".repeat(24)).is_err());
+
+        let rows = vec![
+            measurement(2, 1),
+            measurement(1, 2),
+            measurement(2, 0),
+            measurement(3, 0),
+        ];
+        let page = paginate(rows.clone(), None, 2);
+        assert_eq!(page.data, vec![measurement(1, 2), measurement(2, 0)]);
+        let cursor = Cursor::decode(&page.next.unwrap()).unwrap();
+        // the next page starts after (2, 0), its row of the same time included
+        let page = paginate(rows.clone(), Some(&cursor), 2);
+        assert_eq!(page.data, vec![measurement(2, 1), measurement(3, 0)]);
+        assert_eq!(page.next, None);
+    }
+
+    #[test]
+    fn resume_queries() {
+        let cursor = Cursor {
+            time: 0,
+            id: Uuid::nil(),
+        };
+        assert_eq!(
+            page_queries("SELECT * FROM cpu WHERE name = 'a' OR value > 1", Some(&cursor), 2).unwrap(),
+            vec![
+                "SELECT * FROM cpu WHERE time = '1970-01-01T00:00:00.000Z' AND (name = 'a' OR value > 1)",
+                "SELECT * FROM cpu WHERE time > '1970-01-01T00:00:00.000Z' AND (name = 'a' OR value > 1) LIMIT 3"
+            ]
+        );
+        assert_eq!(
+            page_queries("SELECT * FROM cpu", None, 2).unwrap(),
+            vec!["SELECT * FROM cpu LIMIT 3"]
+        );
+        assert!(page_queries("SELECT * FROM cpu ORDER BY value", None, 2).is_err());
+    }
+}
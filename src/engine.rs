@@ -0,0 +1,1012 @@
+use crate::blocks::{self, Entry, BLOCK_POINTS};
+use crate::head::TimeseriesHead;
+use crate::lastvalue::series_key;
+use crate::limits::Budget;
+use crate::persistence::Measurement;
+use crate::shards;
+use chrono::{DateTime, NaiveDateTime};
+use gluesql::ast::{DataType, IndexOperator};
+use gluesql::data::{Literal, Row, Value};
+use gluesql::prelude::{Glue, Payload};
+use gluesql::sled::{self, Batch, IVec, Tree};
+use gluesql::storages::SledStorage;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::sync::Arc;
+use uuid::Uuid;
+
+// Native storage engine
+// Points are saved in the time shards of the timeseries' sled database (see shards.rs), keyed by
+// series id, time and point id: the points of a series within a time range are a range of keys.
+// Values only hold the value and the write time, the field and tags are saved once per series.
+// GlueSQL reads the points as the rows of the timeseries table (see utils/readonly.rs), in time
+// order, through a time index: a condition on the time is a range of keys of every series.
+// Sealed shards have their points compressed in blocks of each series, keyed the same way.
+// Recent points are in the head (see head.rs) until flushed, reads merge them with the shards.
+
+const UNSHARDED: &str = "points"; // points saved before the shards
+const SERIES: &str = "series"; // series key -> series id
+const SERIES_META: &str = "series_meta"; // series id -> field and tags
+
+// Index of the time column declared on the timeseries tables GlueSQL reads
+pub const TIME_INDEX: &str = "time";
+
+const KEY_LEN: usize = 32;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Series {
+    name: String,
+    tags: HashMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Point {
+    pub value: f64,
+    pub created_at: i64,
+}
+
+fn open(storage: &SledStorage, tree: &str) -> Result<Tree, String> {
+    match storage.tree.open_tree(tree) {
+        Ok(t) => Ok(t),
+        Err(e) => Err(format!("Error opening {}: {}", tree, e)),
+    }
+}
+
+// Big endian with the sign flipped: keys sort as the times do, before 1970 included
+fn time_bytes(time: i64) -> [u8; 8] {
+    ((time as u64) ^ (1 << 63)).to_be_bytes()
+}
+
+pub fn point_key(series: u64, time: i64, id: &Uuid) -> Vec<u8> {
+    let mut key = Vec::with_capacity(KEY_LEN);
+    key.extend_from_slice(&series.to_be_bytes());
+    key.extend_from_slice(&time_bytes(time));
+    key.extend_from_slice(id.as_bytes());
+    key
+}
+
+// Keys of the points of a series from start (included) to end (excluded)
+pub fn series_range(series: u64, start: Option<i64>, end: Option<i64>) -> (Vec<u8>, Vec<u8>) {
+    let bound = |time: Option<i64>| {
+        let mut key = series.to_be_bytes().to_vec();
+        if let Some(t) = time {
+            key.extend_from_slice(&time_bytes(t));
+        }
+        key
+    };
+    let end = match end {
+        Some(_) => bound(end),
+        None => (series + 1).to_be_bytes().to_vec(),
+    };
+    (bound(start), end)
+}
+
+fn series_of(key: &[u8]) -> u64 {
+    let mut series = [0; 8];
+    series.copy_from_slice(&key[..8]);
+    u64::from_be_bytes(series)
+}
+
+pub fn decode_key(key: &[u8]) -> Result<(i64, Uuid), String> {
+    if key.len() != KEY_LEN {
+        return Err(format!("Invalid point key of {} bytes", key.len()));
+    }
+    let mut time = [0; 8];
+    time.copy_from_slice(&key[8..16]);
+    let mut id = [0; 16];
+    id.copy_from_slice(&key[16..]);
+    Ok((
+        (u64::from_be_bytes(time) ^ (1 << 63)) as i64,
+        Uuid::from_bytes(id),
+    ))
+}
+
+fn naive(time: i64) -> Result<NaiveDateTime, String> {
+    match DateTime::from_timestamp_millis(time) {
+        Some(t) => Ok(t.naive_utc()),
+        None => Err(format!("Invalid timestamp {}", time)),
+    }
+}
+
+// The id of the series of a point, registered on its first point
+fn series_id(storage: &SledStorage, m: &Measurement) -> Result<u64, String> {
+    let key = series_key(m);
+    let series = open(storage, SERIES)?;
+    let decode = |id: &IVec| {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&id[..8]);
+        u64::from_be_bytes(bytes)
+    };
+    match series.get(key.as_bytes()) {
+        Ok(Some(id)) => return Ok(decode(&id)),
+        Ok(None) => {}
+        Err(e) => return Err(format!("Error reading series {}: {}", key, e)),
+    }
+    let id = match storage.tree.generate_id() {
+        Ok(id) => id,
+        Err(e) => return Err(format!("Error creating series {}: {}", key, e)),
+    };
+    let meta = Series {
+        name: m.name.clone(),
+        tags: m.tags.clone(),
+    };
+    let encoded = match bincode::serialize(&meta) {
+        Ok(e) => e,
+        Err(e) => return Err(format!("Error encoding series {}: {}", key, e)),
+    };
+    let metas = open(storage, SERIES_META)?;
+    if let Err(e) = metas.insert(id.to_be_bytes(), encoded) {
+        return Err(format!("Error saving series {}: {}", key, e));
+    }
+    // a concurrent write of the same series may have registered it first
+    let registered = series.compare_and_swap(
+        key.as_bytes(),
+        None as Option<&[u8]>,
+        Some(&id.to_be_bytes()[..]),
+    );
+    match registered {
+        Ok(Ok(())) => Ok(id),
+        Ok(Err(current)) => {
+            let _ = metas.remove(id.to_be_bytes());
+            match current.current {
+                Some(existing) => Ok(decode(&existing)),
+                None => Err(format!("Error registering series {}", key)),
+            }
+        }
+        Err(e) => Err(format!("Error registering series {}: {}", key, e)),
+    }
+}
+
+fn all_series(storage: &SledStorage) -> Result<Vec<(u64, Series)>, String> {
+    let mut series = Vec::new();
+    for entry in open(storage, SERIES_META)?.iter() {
+        let (key, value) = match entry {
+            Ok(e) => e,
+            Err(e) => return Err(format!("Error reading series: {}", e)),
+        };
+        let mut id = [0; 8];
+        id.copy_from_slice(&key[..8]);
+        match bincode::deserialize::<Series>(&value) {
+            Ok(s) => series.push((u64::from_be_bytes(id), s)),
+            Err(e) => return Err(format!("Error decoding series: {}", e)),
+        }
+    }
+    Ok(series)
+}
+
+// Shard of the time, sealed shards take no more points
+fn writable(
+    storage: &SledStorage,
+    config: &shards::Config,
+    time: i64,
+) -> Result<shards::Shard, String> {
+    let shard = shards::for_time(storage, config, time)?;
+    if shard.sealed {
+        return Err(format!(
+            "Shard from {} to {} is sealed",
+            shard.info().start,
+            shard.info().end
+        ));
+    }
+    Ok(shard)
+}
+
+// Key and value of a point, its series registered and its shard created and checked
+pub fn entry(
+    storage: &SledStorage,
+    config: &shards::Config,
+    m: &Measurement,
+    created_at: i64,
+) -> Result<(Vec<u8>, Point), String> {
+    let series = series_id(storage, m)?;
+    writable(storage, config, m.key)?;
+    let point = Point {
+        value: m.value,
+        created_at,
+    };
+    Ok((point_key(series, m.key, &m.id), point))
+}
+
+// Points added to each shard by a batch, the count of a shard written once
+#[derive(Default)]
+struct Added(HashMap<i64, (shards::Shard, i64)>);
+
+impl Added {
+    fn add(&mut self, shard: &shards::Shard) {
+        self.0.entry(shard.start).or_insert((shard.clone(), 0)).1 += 1;
+    }
+
+    // Counted even when the batch failed halfway, for the points it saved
+    fn count<T>(self, storage: &SledStorage, saved: Result<T, String>) -> Result<T, String> {
+        for (shard, points) in self.0.values() {
+            shards::count(storage, shard, *points)?;
+        }
+        saved
+    }
+}
+
+// Saves the points in the shards of their times
+pub fn save<'a>(
+    storage: &SledStorage,
+    config: &shards::Config,
+    points: impl IntoIterator<Item = (&'a Vec<u8>, &'a Point)>,
+) -> Result<(), String> {
+    let mut added = Added::default();
+    let saved = points.into_iter().try_for_each(|(key, point)| {
+        let shard = writable(storage, config, decode_key(key)?.0)?;
+        put(storage, &shard, key, point, &mut added)
+    });
+    added.count(storage, saved)
+}
+
+/*
+Saves points logged by the head in the shards of their times. The points were acknowledged: if
+the shard of one was sealed since, the shard is reopened for it and compressed again by the next
+seal.
+ */
+pub fn save_logged<'a>(
+    storage: &SledStorage,
+    config: &shards::Config,
+    points: impl IntoIterator<Item = (&'a Vec<u8>, &'a Point)>,
+) -> Result<(), String> {
+    let mut added = Added::default();
+    let saved = points.into_iter().try_for_each(|(key, point)| {
+        let mut shard = shards::for_time(storage, config, decode_key(key)?.0)?;
+        if shard.sealed {
+            shard = reopen(storage, &shard)?;
+        }
+        put(storage, &shard, key, point, &mut added)
+    });
+    added.count(storage, saved)
+}
+
+fn put(
+    storage: &SledStorage,
+    shard: &shards::Shard,
+    key: &[u8],
+    point: &Point,
+    added: &mut Added,
+) -> Result<(), String> {
+    let encoded = match bincode::serialize(point) {
+        Ok(e) => e,
+        Err(e) => return Err(format!("Error encoding point: {}", e)),
+    };
+    match shard.open(storage)?.insert(key, encoded) {
+        Ok(None) => added.add(shard),
+        Ok(Some(_)) => {} // the same point saved again
+        Err(e) => return Err(format!("Error saving point: {}", e)),
+    }
+    Ok(())
+}
+
+pub fn insert(
+    storage: &SledStorage,
+    config: &shards::Config,
+    m: &Measurement,
+    created_at: i64,
+) -> Result<(), String> {
+    insert_all(storage, config, std::slice::from_ref(m), created_at)
+}
+
+pub fn insert_all(
+    storage: &SledStorage,
+    config: &shards::Config,
+    batch: &[Measurement],
+    created_at: i64,
+) -> Result<(), String> {
+    let entries = batch
+        .iter()
+        .map(|m| entry(storage, config, m, created_at))
+        .collect::<Result<Vec<(Vec<u8>, Point)>, String>>()?;
+    save(storage, config, entries.iter().map(|(k, p)| (k, p)))
+}
+
+// Deletes the points from start (included) to end (excluded), sealed shards can't be changed
+pub fn delete(storage: &SledStorage, start: Option<i64>, end: i64) -> Result<usize, String> {
+    let overlapping = shards::overlapping(storage, start, Some(end))?;
+    if let Some(shard) = overlapping.iter().find(|s| s.sealed) {
+        return Err(format!(
+            "Shard from {} to {} is sealed",
+            shard.info().start,
+            shard.info().end
+        ));
+    }
+    remove(storage, overlapping, start, end)
+}
+
+// Deletes the points before the cutoff, sealed shards included
+pub fn expire(storage: &SledStorage, cutoff: i64) -> Result<usize, String> {
+    let overlapping = shards::overlapping(storage, None, Some(cutoff))?;
+    remove(storage, overlapping, None, cutoff)
+}
+
+// The shards within the range are dropped whole, the others have the points in range deleted
+fn remove(
+    storage: &SledStorage,
+    overlapping: Vec<shards::Shard>,
+    start: Option<i64>,
+    end: i64,
+) -> Result<usize, String> {
+    let series = all_series(storage)?;
+    let mut deleted = 0;
+    for shard in overlapping {
+        if start.is_none_or(|s| s <= shard.start) && shard.end <= end {
+            shards::drop(storage, &shard)?;
+            deleted += shard.points as usize;
+            continue;
+        }
+        if shard.compressed {
+            let removed = rewrite(&shard.blocks(storage)?, &series, start, end)?;
+            shards::count(storage, &shard, -removed)?;
+            deleted += removed as usize;
+            continue;
+        }
+        let points = shard.open(storage)?;
+        let mut batch = Batch::default();
+        let mut removed: i64 = 0;
+        for (id, _) in series.iter() {
+            let (from, to) = series_range(*id, start, Some(end));
+            for entry in points.range(from..to) {
+                match entry {
+                    Ok((key, _)) => batch.remove(key),
+                    Err(e) => return Err(format!("Error reading points: {}", e)),
+                }
+                removed += 1;
+            }
+        }
+        if let Err(e) = points.apply_batch(batch) {
+            return Err(format!("Error deleting points: {}", e));
+        }
+        shards::count(storage, &shard, -removed)?;
+        deleted += removed as usize;
+    }
+    Ok(deleted)
+}
+
+// Removes the points in range from the blocks of a compressed shard, decoded and encoded again
+fn rewrite(
+    blocks: &Tree,
+    series: &[(u64, Series)],
+    start: Option<i64>,
+    end: i64,
+) -> Result<i64, String> {
+    let mut batch = Batch::default();
+    let mut removed: i64 = 0;
+    for (id, _) in series.iter() {
+        // the blocks starting before the end
+        let (from, to) = series_range(*id, None, Some(end));
+        for entry in blocks.range(from..to) {
+            let (key, value) = match entry {
+                Ok(e) => e,
+                Err(e) => return Err(format!("Error reading blocks: {}", e)),
+            };
+            let entries = blocks::decode(&value)?;
+            let kept: Vec<Entry> = entries
+                .iter()
+                .filter(|e| !(start.is_none_or(|s| e.time >= s) && e.time < end))
+                .copied()
+                .collect();
+            if kept.len() == entries.len() {
+                continue;
+            }
+            removed += (entries.len() - kept.len()) as i64;
+            batch.remove(key);
+            if let Some(first) = kept.first() {
+                batch.insert(
+                    point_key(*id, first.time, &first.id),
+                    blocks::encode(&kept)?,
+                );
+            }
+        }
+    }
+    if let Err(e) = blocks.apply_batch(batch) {
+        return Err(format!("Error rewriting blocks: {}", e));
+    }
+    Ok(removed)
+}
+
+/*
+Compresses the points of a sealed shard in blocks of each series, then drops them. The blocks
+are saved and flushed before the shard is marked compressed: compressing again what an
+interrupted compression left saves the same blocks.
+ */
+pub fn compress(storage: &SledStorage, shard: &shards::Shard) -> Result<usize, String> {
+    let points = shard.open(storage)?;
+    let blocks = shard.blocks(storage)?;
+    if let Err(e) = blocks.clear() {
+        return Err(format!("Error clearing blocks: {}", e));
+    }
+    let mut batch = Batch::default();
+    let mut entries: Vec<Entry> = Vec::with_capacity(BLOCK_POINTS);
+    let mut series = None;
+    let mut count = 0;
+    for entry in points.iter() {
+        let (key, value) = match entry {
+            Ok(e) => e,
+            Err(e) => return Err(format!("Error reading points: {}", e)),
+        };
+        let (time, id) = decode_key(&key)?;
+        let point: Point = match bincode::deserialize(&value) {
+            Ok(p) => p,
+            Err(e) => return Err(format!("Error decoding point: {}", e)),
+        };
+        if let Some(s) = series.filter(|s| *s != series_of(&key) || entries.len() == BLOCK_POINTS) {
+            batch.insert(
+                point_key(s, entries[0].time, &entries[0].id),
+                blocks::encode(&entries)?,
+            );
+            entries.clear();
+            count += 1;
+        }
+        series = Some(series_of(&key));
+        entries.push(Entry {
+            time,
+            id,
+            value: point.value,
+            created_at: point.created_at,
+        });
+    }
+    if let Some(s) = series {
+        batch.insert(
+            point_key(s, entries[0].time, &entries[0].id),
+            blocks::encode(&entries)?,
+        );
+        count += 1;
+    }
+    if let Err(e) = blocks.apply_batch(batch) {
+        return Err(format!("Error saving blocks: {}", e));
+    }
+    if let Err(e) = storage.tree.flush() {
+        return Err(format!("Error flushing blocks: {}", e));
+    }
+    shards::compressed(storage, shard)?;
+    Ok(count)
+}
+
+/*
+Decodes the blocks of a compressed shard back in points, flushed before the shard is marked
+open again: reopening again what an interrupted reopening left saves the same points.
+ */
+pub fn reopen(storage: &SledStorage, shard: &shards::Shard) -> Result<shards::Shard, String> {
+    if shard.compressed {
+        let points = shard.open(storage)?;
+        let mut batch = Batch::default();
+        for entry in shard.blocks(storage)?.iter() {
+            let (key, value) = match entry {
+                Ok(e) => e,
+                Err(e) => return Err(format!("Error reading blocks: {}", e)),
+            };
+            for e in blocks::decode(&value)? {
+                let point = Point {
+                    value: e.value,
+                    created_at: e.created_at,
+                };
+                let encoded = match bincode::serialize(&point) {
+                    Ok(p) => p,
+                    Err(e) => return Err(format!("Error encoding point: {}", e)),
+                };
+                batch.insert(point_key(series_of(&key), e.time, &e.id), encoded);
+            }
+        }
+        if let Err(e) = points.apply_batch(batch) {
+            return Err(format!("Error saving points: {}", e));
+        }
+        if let Err(e) = storage.tree.flush() {
+            return Err(format!("Error flushing points: {}", e));
+        }
+    }
+    info!(
+        "Reopened shard from {} to {} for points logged before it was sealed",
+        shard.info().start,
+        shard.info().end
+    );
+    shards::reopened(storage, shard)
+}
+
+/*
+Rows of the points ordered by time then point id, the latest first when descending: the shards
+are read one after the other and the keys of each series merged, with the points of the head in
+the shard. A point being flushed from the head may be read twice, it's returned once. With a
+condition on the time from the index, only the shards and the range of keys it covers are read
+and the rows are checked against it, as GlueSQL compares the time.
+ */
+pub fn scan(
+    storage: &SledStorage,
+    head: Option<Arc<TimeseriesHead>>,
+    descending: bool,
+    condition: Option<(IndexOperator, Value)>,
+) -> Result<Rows, String> {
+    let (start, end) = match &condition {
+        Some((op, value)) => bounds(op, value),
+        None => (None, None),
+    };
+    let mut rows = Rows {
+        storage: storage.clone(),
+        head,
+        series: Vec::new(),
+        shards: Vec::new(),
+        sources: Vec::new(),
+        heads: Vec::new(),
+        heap: BinaryHeap::new(),
+        last: None,
+        start,
+        end,
+        descending,
+        condition,
+        budget: None,
+    };
+    if matches!((start, end), (Some(s), Some(e)) if s >= e) {
+        return Ok(rows);
+    }
+    for (id, series) in all_series(storage)? {
+        let tags = series
+            .tags
+            .into_iter()
+            .map(|(k, v)| (k, Value::Str(v)))
+            .collect();
+        rows.series
+            .push((id, Value::Str(series.name), Value::Map(tags)));
+    }
+    rows.shards = shards::overlapping(storage, start, end)?;
+    // taken from the back
+    if !descending {
+        rows.shards.reverse();
+    }
+    Ok(rows)
+}
+
+// Time range holding the rows matching the condition, to the millisecond
+fn bounds(op: &IndexOperator, value: &Value) -> (Option<i64>, Option<i64>) {
+    let time = match value {
+        Value::Timestamp(t) => *t,
+        Value::Date(d) => d.and_hms_opt(0, 0, 0).unwrap_or_default(),
+        Value::Str(s) => {
+            let literal = Literal::Text(Cow::Borrowed(s));
+            match Value::try_from_literal(&DataType::Timestamp, &literal) {
+                Ok(Value::Timestamp(t)) => t,
+                _ => return (None, None),
+            }
+        }
+        // only the null time sorts after a null
+        Value::Null => match op {
+            IndexOperator::Lt | IndexOperator::LtEq => return (None, None),
+            _ => return (Some(0), Some(0)),
+        },
+        _ => return (None, None),
+    };
+    let millis = time.and_utc().timestamp_millis();
+    match op {
+        IndexOperator::Gt | IndexOperator::GtEq => (Some(millis), None),
+        IndexOperator::Lt | IndexOperator::LtEq => (None, millis.checked_add(1)),
+        IndexOperator::Eq => (Some(millis), millis.checked_add(1)),
+    }
+}
+
+fn matches(time: &Value, op: &IndexOperator, value: &Value) -> bool {
+    let ordering = match value {
+        Value::Null => return matches!(op, IndexOperator::Lt | IndexOperator::LtEq),
+        Value::Str(s) => time.partial_cmp(&Literal::Text(Cow::Borrowed(s))),
+        v => time.partial_cmp(v),
+    };
+    matches!(
+        (op, ordering),
+        (IndexOperator::Gt, Some(Ordering::Greater))
+            | (
+                IndexOperator::GtEq,
+                Some(Ordering::Greater | Ordering::Equal)
+            )
+            | (IndexOperator::Eq, Some(Ordering::Equal))
+            | (IndexOperator::LtEq, Some(Ordering::Less | Ordering::Equal))
+            | (IndexOperator::Lt, Some(Ordering::Less))
+    )
+}
+
+enum Source {
+    Keys(sled::Iter),
+    Blocks(blocks::Reader),
+    Head(VecDeque<(Vec<u8>, Point)>),
+}
+
+pub struct Rows {
+    storage: SledStorage,
+    head: Option<Arc<TimeseriesHead>>,
+    series: Vec<(u64, Value, Value)>, // id, name and tags
+    shards: Vec<shards::Shard>,       // shards left to read
+    sources: Vec<(usize, Source)>,    // series and points of each source in the shard read
+    heads: Vec<Option<(IVec, Point)>>,
+    heap: BinaryHeap<(i128, u128, usize)>, // next time and id of each source, largest first
+    last: Option<IVec>,                    // key of the last row
+    start: Option<i64>,
+    end: Option<i64>,
+    descending: bool,
+    condition: Option<(IndexOperator, Value)>,
+    budget: Option<Arc<Budget>>,
+}
+
+impl Rows {
+    // Checks the budget of the query for every key visited, the rows filtered out included
+    pub fn with_budget(mut self, budget: Option<Arc<Budget>>) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    // Opens the next shard once the points of the previous one are read
+    fn next_shard(&mut self) -> Result<bool, String> {
+        let shard = match self.shards.pop() {
+            Some(s) => s,
+            None => return Ok(false),
+        };
+        let tree = match shard.compressed {
+            true => shard.blocks(&self.storage)?,
+            false => shard.open(&self.storage)?,
+        };
+        self.sources.clear();
+        self.heads.clear();
+        for (index, (id, _, _)) in self.series.iter().enumerate() {
+            if let Some(head) = &self.head {
+                let start = self.start.map_or(shard.start, |s| s.max(shard.start));
+                let end = self.end.map_or(shard.end, |e| e.min(shard.end));
+                let (from, to) = series_range(*id, Some(start), Some(end));
+                self.sources
+                    .push((index, Source::Head(head.range(from, to))));
+                self.heads.push(None);
+            }
+            let (from, to) = series_range(*id, self.start, self.end);
+            let source = match shard.compressed {
+                true => Source::Blocks(blocks::Reader::new(
+                    &tree,
+                    from,
+                    to,
+                    (self.start, self.end),
+                    self.descending,
+                )?),
+                false => Source::Keys(tree.range(from..to)),
+            };
+            self.sources.push((index, source));
+            self.heads.push(None);
+        }
+        for source in 0..self.sources.len() {
+            self.pull(source)?;
+        }
+        Ok(true)
+    }
+
+    fn pull(&mut self, source: usize) -> Result<(), String> {
+        let descending = self.descending;
+        let (series, points) = &mut self.sources[source];
+        let (key, point) = match points {
+            Source::Keys(keys) => {
+                let next = match descending {
+                    true => keys.next_back(),
+                    false => keys.next(),
+                };
+                let (key, value) = match next {
+                    Some(Ok(entry)) => entry,
+                    Some(Err(e)) => return Err(format!("Error reading points: {}", e)),
+                    None => return Ok(()),
+                };
+                match bincode::deserialize::<Point>(&value) {
+                    Ok(p) => (key, p),
+                    Err(e) => return Err(format!("Error decoding point: {}", e)),
+                }
+            }
+            Source::Blocks(entries) => match entries.next() {
+                Some(Ok(e)) => {
+                    let key = point_key(self.series[*series].0, e.time, &e.id);
+                    let point = Point {
+                        value: e.value,
+                        created_at: e.created_at,
+                    };
+                    (IVec::from(key), point)
+                }
+                Some(Err(e)) => return Err(e),
+                None => return Ok(()),
+            },
+            Source::Head(points) => {
+                let next = match descending {
+                    true => points.pop_back(),
+                    false => points.pop_front(),
+                };
+                match next {
+                    Some((key, point)) => (IVec::from(key), point),
+                    None => return Ok(()),
+                }
+            }
+        };
+        let (time, id) = decode_key(&key)?;
+        match self.descending {
+            true => self.heap.push((time as i128, id.as_u128(), source)),
+            false => self.heap.push((-(time as i128), !id.as_u128(), source)),
+        }
+        self.heads[source] = Some((key, point));
+        Ok(())
+    }
+
+    fn row(&self, source: usize, key: &IVec, point: &Point) -> Result<Row, String> {
+        let (time, id) = decode_key(key)?;
+        let (_, name, tags) = &self.series[self.sources[source].0];
+        Ok(Row(vec![
+            Value::Uuid(id.as_u128()),
+            Value::Timestamp(naive(time)?),
+            Value::Timestamp(naive(point.created_at)?),
+            name.clone(),
+            Value::F64(point.value),
+            tags.clone(),
+        ]))
+    }
+}
+
+impl Iterator for Rows {
+    type Item = Result<(IVec, Row), String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(Err(e)) = self.budget.as_ref().map(|b| b.check()) {
+                return Some(Err(e));
+            }
+            let source = match self.heap.pop() {
+                Some((_, _, source)) => source,
+                None => match self.next_shard() {
+                    Ok(true) => continue,
+                    Ok(false) => return None,
+                    Err(e) => return Some(Err(e)),
+                },
+            };
+            let (key, point) = self.heads[source].take()?;
+            if let Err(e) = self.pull(source) {
+                return Some(Err(e));
+            }
+            if self.last.as_ref() == Some(&key) {
+                continue;
+            }
+            self.last = Some(key.clone());
+            let row = match self.row(source, &key, &point) {
+                Ok(r) => r,
+                Err(e) => return Some(Err(e)),
+            };
+            if let Some((op, value)) = &self.condition {
+                if !matches(&row.0[1], op, value) {
+                    continue;
+                }
+            }
+            return Some(Ok((key, row)));
+        }
+    }
+}
+
+/*
+Timeseries written before the engine have their rows moved from the GlueSQL table to the
+shards, the table is kept for its schema, and those written before the shards have their points
+tree split. The point ids are kept: moving again what an interrupted migration left saves the
+same points.
+ */
+pub fn migrate(
+    timeseries: &str,
+    storage: &SledStorage,
+    config: &shards::Config,
+) -> Result<usize, String> {
+    let unsharded = storage
+        .tree
+        .tree_names()
+        .iter()
+        .any(|name| name.as_ref() == UNSHARDED.as_bytes());
+    if unsharded {
+        let points = open(storage, UNSHARDED)?;
+        let mut added = Added::default();
+        let split = points.iter().try_for_each(|entry| {
+            let (key, value) = match entry {
+                Ok(e) => e,
+                Err(e) => return Err(format!("Error reading points: {}", e)),
+            };
+            let shard = shards::for_time(storage, config, decode_key(&key)?.0)?;
+            match shard.open(storage)?.insert(key, value) {
+                Ok(None) => added.add(&shard),
+                Ok(Some(_)) => {}
+                Err(e) => return Err(format!("Error saving point: {}", e)),
+            }
+            Ok(())
+        });
+        added.count(storage, split)?;
+        info!("Split {} points of {} in shards", points.len(), timeseries);
+        if let Err(e) = storage.tree.drop_tree(UNSHARDED) {
+            return Err(format!("Error dropping points of {}: {}", timeseries, e));
+        }
+    }
+    let mut db = Glue::new(storage.clone());
+    let query = format!("SELECT * FROM {}", timeseries);
+    let rows = match db.execute(&query) {
+        Ok(Payload::Select { rows, .. }) => rows,
+        Ok(p) => return Err(format!("Unexpected result: {:?}", p)),
+        Err(e) => return Err(format!("Error reading {}: {:?}", timeseries, e)),
+    };
+    if rows.is_empty() {
+        return Ok(0);
+    }
+    for row in rows.iter() {
+        let (m, created_at) = match row.as_slice() {
+            [Value::Uuid(id), Value::Timestamp(time), created_at, Value::Str(name), Value::F64(value), Value::Map(tags)] =>
+            {
+                let created_at = match created_at {
+                    Value::Timestamp(t) => t.and_utc().timestamp_millis(),
+                    _ => time.and_utc().timestamp_millis(),
+                };
+                let tags = tags
+                    .iter()
+                    .filter_map(|(k, v)| match v {
+                        Value::Str(s) => Some((k.clone(), s.clone())),
+                        _ => None,
+                    })
+                    .collect();
+                let m = Measurement {
+                    key: time.and_utc().timestamp_millis(),
+                    id: Uuid::from_u128(*id),
+                    name: name.clone(),
+                    value: *value,
+                    tags,
+                };
+                (m, created_at)
+            }
+            _ => return Err(format!("Unexpected row in {}: {:?}", timeseries, row)),
+        };
+        insert(storage, config, &m, created_at)?;
+    }
+    let delete = format!("DELETE FROM {}", timeseries);
+    if let Err(e) = db.execute(&delete) {
+        return Err(format!("Error clearing {}: {:?}", timeseries, e));
+    }
+    info!("Moved {} rows of {} to the shards", rows.len(), timeseries);
+    Ok(rows.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::ReadOnlyStorage;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn scans_check_the_timeout() {
+        let storage = SledStorage::try_from(sled::Config::default().temporary(true)).unwrap();
+        crate::utils::db::check_or_create_database("cpu".to_string(), storage.clone(), true)
+            .unwrap();
+        for time in 0..100 {
+            let m = Measurement {
+                key: time,
+                id: Uuid::new_v4(),
+                name: "usage".to_string(),
+                value: time as f64,
+                tags: HashMap::new(),
+            };
+            insert(&storage, &shards::Config::default(), &m, 0).unwrap();
+        }
+        let limits = crate::limits::QueryLimits {
+            timeout: Some(std::time::Duration::from_millis(1)),
+            ..crate::limits::QueryLimits::default()
+        };
+        let budget = Arc::new(Budget::new(1, "SELECT * FROM cpu", limits));
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        // every key visited checks the timeout, whether its row is kept or not
+        let mut rows = scan(&storage, None, false, None)
+            .unwrap()
+            .with_budget(Some(budget.clone()));
+        assert!(rows.next().unwrap().unwrap_err().contains("timed out"));
+        let mut db = Glue::new(
+            ReadOnlyStorage::new(storage)
+                .native(None)
+                .with_budget(Some(budget)),
+        );
+        let res = db.execute("SELECT * FROM cpu WHERE value > 1000");
+        assert!(format!("{:?}", res.unwrap_err()).contains("timed out"));
+    }
+
+    #[test]
+    fn range_scans() {
+        let storage = SledStorage::try_from(sled::Config::default().temporary(true)).unwrap();
+        crate::utils::db::check_or_create_database("cpu".to_string(), storage.clone(), true)
+            .unwrap();
+        // written out of order, before 1970 included
+        for (time, host) in [
+            (3000, "a"),
+            (-1000, "b"),
+            (1000, "a"),
+            (2000, "b"),
+            (1000, "b"),
+        ] {
+            let m = Measurement {
+                key: time,
+                id: Uuid::new_v4(),
+                name: "usage".to_string(),
+                value: time as f64,
+                tags: HashMap::from([("host".to_string(), host.to_string())]),
+            };
+            insert(&storage, &shards::Config::default(), &m, 0).unwrap();
+        }
+        let mut db = Glue::new(ReadOnlyStorage::new(storage.clone()).native(None));
+        let times = |db: &mut Glue<IVec, ReadOnlyStorage>, query: &str| -> Vec<f64> {
+            match db.execute(query) {
+                Ok(Payload::Select { rows, .. }) => rows
+                    .iter()
+                    .map(|r| match r[4] {
+                        Value::F64(v) => v,
+                        _ => panic!("unexpected row {:?}", r),
+                    })
+                    .collect(),
+                r => panic!("unexpected result {:?}", r),
+            }
+        };
+        assert_eq!(
+            times(&mut db, "SELECT * FROM cpu"),
+            vec![-1000.0, 1000.0, 1000.0, 2000.0, 3000.0]
+        );
+        assert_eq!(
+            times(
+                &mut db,
+                "SELECT * FROM cpu WHERE time >= '1970-01-01T00:00:01Z' AND time < '1970-01-01T00:00:03Z'"
+            ),
+            vec![1000.0, 1000.0, 2000.0]
+        );
+        assert_eq!(
+            times(
+                &mut db,
+                "SELECT * FROM cpu WHERE time > '1970-01-01T00:00:00.0005Z'"
+            ),
+            vec![1000.0, 1000.0, 2000.0, 3000.0]
+        );
+        assert_eq!(
+            times(&mut db, "SELECT * FROM cpu ORDER BY time DESC LIMIT 2"),
+            vec![3000.0, 2000.0]
+        );
+        assert_eq!(
+            times(
+                &mut db,
+                "SELECT * FROM cpu WHERE time = '1970-01-01T00:00:01Z'"
+            ),
+            vec![1000.0, 1000.0]
+        );
+        assert!(times(&mut db, "SELECT * FROM cpu WHERE time IS NULL").is_empty());
+
+        assert_eq!(delete(&storage, None, 2000).unwrap(), 3);
+        assert_eq!(times(&mut db, "SELECT * FROM cpu"), vec![2000.0, 3000.0]);
+
+        // a sealed shard takes no more points, only expiry changes it
+        let config = shards::Config {
+            grace: 0,
+            ..shards::Config::default()
+        };
+        assert_eq!(
+            shards::seal(&storage, &config, 86_400_000).unwrap().len(),
+            1
+        );
+        let late = Measurement {
+            key: 500,
+            id: Uuid::new_v4(),
+            name: "usage".to_string(),
+            value: 500.0,
+            tags: HashMap::new(),
+        };
+        assert!(insert(&storage, &config, &late, 0).is_err());
+        assert!(delete(&storage, None, 2500).is_err());
+        assert_eq!(expire(&storage, 2500).unwrap(), 1);
+        assert_eq!(times(&mut db, "SELECT * FROM cpu"), vec![3000.0]);
+
+        // a point logged before the shard was sealed reopens it, the next seal compresses it again
+        let logged = Measurement { key: 3500, ..late };
+        let key = point_key(series_id(&storage, &logged).unwrap(), 3500, &logged.id);
+        let point = Point {
+            value: 3500.0,
+            created_at: 0,
+        };
+        save_logged(&storage, &config, [(&key, &point)]).unwrap();
+        assert!(!shards::list(&storage).unwrap()[0].sealed);
+        assert_eq!(times(&mut db, "SELECT * FROM cpu"), vec![3000.0, 3500.0]);
+        assert_eq!(
+            shards::seal(&storage, &config, 86_400_000).unwrap().len(),
+            1
+        );
+        assert!(shards::list(&storage).unwrap()[0].compressed);
+        assert_eq!(times(&mut db, "SELECT * FROM cpu"), vec![3000.0, 3500.0]);
+    }
+}
@@ -0,0 +1,433 @@
+use crate::federation::{self, Federation};
+use crate::influxql;
+use crate::persistence::TimeseriesDiskPersistenceManager;
+use crate::query::{self, Execution, SelectPlan};
+use chrono::{DateTime, Utc};
+use gluesql::sqlparser::ast::{BinaryOperator, Expr, SetExpr, Statement, Value};
+use gluesql::store::Store;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+// EXPLAIN [ANALYZE] <query>
+// EXPLAIN describes how a query runs without running it: how it is planned, the storages it
+// reads, the query run against each one and what prunes the rows read (time ranges, indexes
+// or the last value cache). EXPLAIN ANALYZE runs it and reports the reads that happened, with
+// the rows each one read and matched, the rows returned and the time spent parsing,
+// executing and serializing.
+//   EXPLAIN ANALYZE SELECT mean(usage) FROM cpu WHERE time > '2021-11-30T00:00:00Z' GROUP BY time('1m')
+
+// Query of the scans answered by the last value cache
+pub const LAST_VALUES: &str = "last values";
+
+// A read of a storage: the timeseries, the query run against it and what prunes its rows
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Scan {
+    pub timeseries: String,
+    pub query: String,
+    pub pruning: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rows_read: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rows_matched: Option<usize>,
+}
+
+impl Scan {
+    pub fn new(timeseries: &str, query: &str) -> Self {
+        Scan {
+            timeseries: timeseries.to_string(),
+            query: query.to_string(),
+            pruning: Vec::new(),
+            rows_read: None,
+            rows_matched: None,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Analyze {
+    pub rows_scanned: usize,
+    pub bytes_scanned: usize,
+    pub rows_returned: usize,
+    pub parse_ms: f64,
+    pub execute_ms: f64,
+    pub serialize_ms: f64,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Explain {
+    pub query: String,
+    pub plan: Vec<String>, // how each statement runs
+    pub storages: Vec<String>,
+    pub scans: Vec<Scan>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub analyze: Option<Analyze>,
+}
+
+// Reads made by a query run with EXPLAIN ANALYZE
+#[derive(Debug, Default)]
+pub struct Trace {
+    scans: Mutex<Vec<Scan>>,
+    returned: AtomicUsize,
+}
+
+impl Trace {
+    pub fn record(&self, scan: Scan) {
+        self.scans.lock().unwrap().push(scan);
+    }
+
+    pub fn returned(&self, rows: usize) {
+        self.returned.fetch_add(rows, Ordering::Relaxed);
+    }
+
+    pub fn scans(&self) -> Vec<Scan> {
+        self.scans.lock().unwrap().clone()
+    }
+
+    pub fn rows_returned(&self) -> usize {
+        self.returned.load(Ordering::Relaxed)
+    }
+}
+
+// Splits EXPLAIN [ANALYZE] from the query: whether to analyze and the query explained
+pub fn parse(query: &str) -> Option<(bool, &str)> {
+    let mut words = query.trim_start().splitn(2, char::is_whitespace);
+    if !words.next()?.eq_ignore_ascii_case("EXPLAIN") {
+        return None;
+    }
+    let rest = words.next().unwrap_or("").trim_start();
+    let mut words = rest.splitn(2, char::is_whitespace);
+    match words.next() {
+        Some(w) if w.eq_ignore_ascii_case("ANALYZE") => {
+            Some((true, words.next().unwrap_or("").trim_start()))
+        }
+        _ => Some((false, rest)),
+    }
+}
+
+pub fn new(query: &str, plan: Vec<String>, scans: Vec<Scan>) -> Explain {
+    let storages: BTreeSet<String> = scans.iter().map(|s| s.timeseries.clone()).collect();
+    Explain {
+        query: query.to_string(),
+        plan,
+        storages: storages.into_iter().collect(),
+        scans,
+        analyze: None,
+    }
+}
+
+fn plan_scans(pm: &TimeseriesDiskPersistenceManager, plan: &SelectPlan) -> Vec<Scan> {
+    if let Some(tier) = crate::rollup::plan(pm, plan).filter(|_| !pm.raw) {
+        return std::iter::once(&tier.rollup)
+            .chain(tier.raw.iter())
+            .map(|p| Scan::new(&p.timeseries, &p.base_query()))
+            .collect();
+    }
+    match pm.last_measurements(&plan.timeseries) {
+        Ok(last) if crate::lastvalue::answers(plan, &last) => {
+            vec![Scan::new(&plan.timeseries, LAST_VALUES)]
+        }
+        _ => vec![Scan::new(&plan.timeseries, &plan.base_query())],
+    }
+}
+
+fn raw_scans(pm: &TimeseriesDiskPersistenceManager, query: &str) -> Result<Vec<Scan>, String> {
+    let mut scans: Vec<Scan> = Vec::new();
+    for t in crate::utils::query_statement_tablenames(query)? {
+        if !pm.storages.lock().unwrap().contains_key(&t) {
+            return Err(format!("Timeseries not found: {}", t));
+        }
+        scans.push(Scan::new(&t, query));
+    }
+    Ok(scans)
+}
+
+// How a SQL statement is planned and the reads it makes
+pub fn sql(
+    pm: &TimeseriesDiskPersistenceManager,
+    execution: &Execution,
+) -> Result<(Vec<String>, Vec<Scan>), String> {
+    let (plan, scans) = match execution {
+        Execution::Raw(q) => ("GlueSQL".to_string(), raw_scans(pm, q)?),
+        Execution::Planned(p) => match crate::rollup::plan(pm, p).filter(|_| !pm.raw) {
+            Some(tier) => (
+                format!("timeseries functions over rollup {}", tier.tier),
+                plan_scans(pm, p),
+            ),
+            None => ("timeseries functions".to_string(), plan_scans(pm, p)),
+        },
+        Execution::Federated(Federation::Union { branches, .. }) => {
+            let mut scans: Vec<Scan> = Vec::new();
+            for branch in branches {
+                match query::plan(branch.clone())? {
+                    Some(p) => scans.extend(plan_scans(pm, &p)),
+                    None => scans.extend(raw_scans(pm, branch)?),
+                }
+            }
+            (format!("union of {} branches", branches.len()), scans)
+        }
+        Execution::Federated(Federation::Join { sources, .. }) => (
+            "join in a temporary storage".to_string(),
+            sources
+                .iter()
+                .map(|s| Scan::new(&s.timeseries, &s.query()))
+                .collect(),
+        ),
+        Execution::Federated(Federation::Buckets { sources, .. }) => (
+            "join of time windows".to_string(),
+            sources
+                .iter()
+                .map(|s| Scan::new(&s.timeseries, &s.query()))
+                .collect(),
+        ),
+    };
+    Ok((vec![plan], scans))
+}
+
+// How the statements of an InfluxQL query are planned and the reads they make
+pub fn influxql(
+    pm: &TimeseriesDiskPersistenceManager,
+    statements: &[influxql::Statement],
+) -> Result<(Vec<String>, Vec<Scan>), String> {
+    let mut timeseries = pm.clone().list_timeseries()?;
+    timeseries.sort();
+    let now = chrono::Utc::now().timestamp_millis();
+    let mut plans: Vec<String> = Vec::new();
+    let mut scans: Vec<Scan> = Vec::new();
+    for stmt in statements {
+        match stmt {
+            influxql::Statement::Select(select) => {
+                let names = influxql::resolve_sources(&select.sources, &timeseries)?;
+                plans.push(format!("timeseries functions over {}", names.join(", ")));
+                for plan in influxql::plan_select(select, &names, now)? {
+                    scans.extend(plan_scans(pm, &plan));
+                }
+            }
+            _ => plans.push("schema exploration".to_string()),
+        }
+    }
+    Ok((plans, scans))
+}
+
+// Top level AND conditions of a query on the time column
+fn time_conditions(query: &str) -> Vec<String> {
+    time_exprs(query).iter().map(|c| c.to_string()).collect()
+}
+
+// Top level AND conditions of a query
+fn conjuncts(query: &str) -> Vec<Expr> {
+    let select = match gluesql::parse_sql::parse(query) {
+        Ok(mut statements) => match statements.pop() {
+            Some(Statement::Query(q)) => match q.body {
+                SetExpr::Select(select) => select,
+                _ => return vec![],
+            },
+            _ => return vec![],
+        },
+        Err(_) => return vec![],
+    };
+    let mut conditions: Vec<Expr> = Vec::new();
+    if let Some(selection) = &select.selection {
+        federation::conjuncts(selection, &mut conditions);
+    }
+    conditions
+        .into_iter()
+        .map(|c| match c {
+            Expr::Nested(e) => *e,
+            c => c,
+        })
+        .collect()
+}
+
+fn is_time_condition(condition: &Expr) -> bool {
+    match condition {
+        Expr::BinaryOp { left, op, right } => {
+            matches!(
+                op,
+                BinaryOperator::Gt
+                    | BinaryOperator::GtEq
+                    | BinaryOperator::Lt
+                    | BinaryOperator::LtEq
+                    | BinaryOperator::Eq
+            ) && (left.to_string() == "time" || right.to_string() == "time")
+        }
+        Expr::Between { expr, .. } => expr.to_string() == "time",
+        _ => false,
+    }
+}
+
+fn time_exprs(query: &str) -> Vec<Expr> {
+    conjuncts(query)
+        .into_iter()
+        .filter(is_time_condition)
+        .collect()
+}
+
+// Whether the conditions of a query are all literal time bounds
+pub fn only_time_bounds(query: &str) -> bool {
+    conjuncts(query).iter().all(|c| match c {
+        Expr::BinaryOp { left, right, .. } if is_time_condition(c) => {
+            timestamp(left).or(timestamp(right)).is_some()
+        }
+        Expr::Between {
+            negated: false,
+            low,
+            high,
+            ..
+        } if is_time_condition(c) => timestamp(low).is_some() && timestamp(high).is_some(),
+        _ => false,
+    })
+}
+
+fn timestamp(expr: &Expr) -> Option<i64> {
+    match expr {
+        Expr::Value(Value::SingleQuotedString(s)) => match s.parse::<DateTime<Utc>>() {
+            Ok(t) => Some(t.timestamp_millis()),
+            Err(_) => None,
+        },
+        _ => None,
+    }
+}
+
+// Time range read by a query, from its literal time conditions, unbounded on a side
+// without one
+pub fn time_bounds(query: &str) -> (Option<i64>, Option<i64>) {
+    condition_bounds(time_exprs(query))
+}
+
+fn condition_bounds(conditions: Vec<Expr>) -> (Option<i64>, Option<i64>) {
+    let (mut start, mut end): (Option<i64>, Option<i64>) = (None, None);
+    let mut bound = |op: &BinaryOperator, time: Option<i64>| {
+        let time = match time {
+            Some(t) => t,
+            None => return,
+        };
+        if matches!(
+            op,
+            BinaryOperator::Gt | BinaryOperator::GtEq | BinaryOperator::Eq
+        ) {
+            start = Some(start.map_or(time, |s| s.max(time)));
+        }
+        if matches!(
+            op,
+            BinaryOperator::Lt | BinaryOperator::LtEq | BinaryOperator::Eq
+        ) {
+            end = Some(end.map_or(time, |e| e.min(time)));
+        }
+    };
+    for condition in conditions {
+        match condition {
+            Expr::BinaryOp { left, op, right } if left.to_string() == "time" => {
+                bound(&op, timestamp(&right))
+            }
+            Expr::BinaryOp { left, op, .. } => {
+                let op = match op {
+                    BinaryOperator::Gt => BinaryOperator::Lt,
+                    BinaryOperator::GtEq => BinaryOperator::LtEq,
+                    BinaryOperator::Lt => BinaryOperator::Gt,
+                    BinaryOperator::LtEq => BinaryOperator::GtEq,
+                    op => op,
+                };
+                bound(&op, timestamp(&left))
+            }
+            Expr::Between {
+                negated: false,
+                low,
+                high,
+                ..
+            } => {
+                bound(&BinaryOperator::GtEq, timestamp(&low));
+                bound(&BinaryOperator::LtEq, timestamp(&high));
+            }
+            _ => {}
+        }
+    }
+    (start, end)
+}
+
+// What prunes the rows of a scan: the time range conditions, the indexes GlueSQL can use for
+// them, otherwise every row of the storage is read and filtered
+pub fn pruning(pm: &TimeseriesDiskPersistenceManager, scan: &mut Scan) {
+    if scan.query == LAST_VALUES {
+        scan.pruning = vec![format!("last value cache")];
+        return;
+    }
+    let conditions = time_conditions(&scan.query);
+    let storage = pm.storages.lock().unwrap().get(&scan.timeseries).cloned();
+    let indexes: Vec<String> = match storage {
+        Some(s) => {
+            match futures::executor::block_on(
+                pm.read_only(&scan.timeseries, s)
+                    .fetch_schema(&scan.timeseries),
+            ) {
+                Ok(Some(schema)) => schema.indexes.iter().map(|i| i.name.clone()).collect(),
+                _ => vec![],
+            }
+        }
+        None => vec![],
+    };
+    if !conditions.is_empty() {
+        scan.pruning
+            .push(format!("time range: {}", conditions.join(" AND ")));
+    }
+    match indexes.is_empty() {
+        true => scan.pruning.push("no index: full scan".to_string()),
+        false => scan
+            .pruning
+            .push(format!("indexes: {}", indexes.join(", "))),
+    }
+    // the shards read: the index holds the first condition on the time
+    if let Some(storage) = pm.storages.lock().unwrap().get(&scan.timeseries) {
+        let first = time_exprs(&scan.query)
+            .into_iter()
+            .find(|c| matches!(c, Expr::BinaryOp { .. }));
+        let (start, end) = condition_bounds(first.into_iter().collect());
+        let all = crate::shards::list(storage).map_or(0, |s| s.len());
+        let read = crate::shards::overlapping(storage, start, end.map(|e| e + 1));
+        if let Ok(read) = read {
+            scan.pruning
+                .push(format!("shards: {} of {}", read.len(), all));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_explain() {
+        assert_eq!(
+            parse("explain analyze SELECT * FROM cpu"),
+            Some((true, "SELECT * FROM cpu"))
+        );
+        assert_eq!(
+            parse("EXPLAIN SELECT * FROM cpu"),
+            Some((false, "SELECT * FROM cpu"))
+        );
+        assert_eq!(parse("SELECT * FROM explain"), None);
+        assert_eq!(
+            time_conditions(
+                "SELECT * FROM cpu WHERE name = 'a' AND time >= '2021-11-30T00:00:00Z' AND (time < '2021-12-01T00:00:00Z')"
+            ),
+            vec!["time >= '2021-11-30T00:00:00Z'", "time < '2021-12-01T00:00:00Z'"]
+        );
+        assert_eq!(
+            time_bounds(
+                "SELECT * FROM cpu WHERE time >= '1970-01-01T00:00:01Z' AND '1970-01-01T00:00:02.000Z' > time AND time < '1970-01-01T00:00:03Z'"
+            ),
+            (Some(1000), Some(2000))
+        );
+        assert_eq!(
+            time_bounds("SELECT * FROM cpu WHERE time > '1970-01-01T00:00:01Z' OR value > 1"),
+            (None, None)
+        );
+        assert!(only_time_bounds(
+            "SELECT * FROM cpu WHERE time >= '1970-01-01T00:00:01Z' AND time < '1970-01-02T00:00:00Z'"
+        ));
+        assert!(!only_time_bounds(
+            "SELECT * FROM cpu WHERE time >= '1970-01-01T00:00:01Z' AND name = 'usage'"
+        ));
+    }
+}
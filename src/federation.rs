@@ -0,0 +1,819 @@
+use crate::functions::{self, Point};
+use crate::persistence::TimeseriesDiskPersistenceManager;
+use crate::query::{self, Aggregate, Series, TagGrouping};
+use crate::utils::db;
+use gluesql::sqlparser::ast::{
+    BinaryOperator, Expr, JoinConstraint, JoinOperator, Query, Select, SelectItem, SetExpr,
+    SetOperator, Statement, TableFactor, Value as SqlValue,
+};
+use gluesql::sqlparser::dialect::GenericDialect;
+use gluesql::sqlparser::tokenizer::{Token, Tokenizer};
+use std::collections::{BTreeMap, HashSet};
+
+// Federated queries
+// Every timeseries lives in its own sled/GlueSQL storage, so statements reading several of
+// them are executed here, pulling the rows from each storage and combining them:
+//   SELECT * FROM cpu UNION ALL SELECT * FROM mem ORDER BY time DESC LIMIT 10
+//   SELECT c.time, c.value / m.value AS ratio FROM cpu c JOIN mem m ON c.time = m.time
+//   SELECT mean(c.usage) / mean(m.used) AS ratio FROM cpu c JOIN mem m ON c.host = m.host GROUP BY time('1m')
+// UNION branches run on their own timeseries, through the planner when they use timeseries
+// functions, and are concatenated. Joins copy the rows of each timeseries into a temporary
+// storage where GlueSQL runs the statement, WHERE conditions on a single timeseries are
+// applied while copying. Joins grouped by time('<duration>') are evaluated here: the
+// aggregates of each timeseries are computed per window and per joined tags, and the windows
+// with data in every timeseries are combined.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Source {
+    pub timeseries: String,
+    pub alias: String, // qualifier of its columns, the timeseries name unless aliased
+    pub conditions: Vec<String>, // pushed down WHERE conditions, unqualified
+}
+
+impl Source {
+    fn new(timeseries: &str, alias: &str) -> Self {
+        Source {
+            timeseries: timeseries.to_string(),
+            alias: alias.to_string(),
+            conditions: Vec::new(),
+        }
+    }
+
+    pub fn query(&self) -> String {
+        if self.conditions.is_empty() {
+            return format!("SELECT * FROM {}", self.timeseries);
+        }
+        format!(
+            "SELECT * FROM {} WHERE {}",
+            self.timeseries,
+            self.conditions.join(" AND ")
+        )
+    }
+}
+
+// Arithmetic over the aggregates of the joined timeseries
+#[derive(Debug, Clone, PartialEq)]
+pub enum Term {
+    Aggregate {
+        source: usize,
+        field: String,
+        aggregate: Aggregate,
+    },
+    Number(f64),
+    Binary {
+        op: BinaryOperator,
+        left: Box<Term>,
+        right: Box<Term>,
+    },
+}
+
+impl Term {
+    // window holds the points of each source by field, None without data or on a division by zero
+    fn evaluate(&self, window: &[BTreeMap<String, Vec<Point>>]) -> Option<f64> {
+        match self {
+            Term::Aggregate {
+                source,
+                field,
+                aggregate,
+            } => aggregate.scalar(window.get(*source)?.get(field)?),
+            Term::Number(n) => Some(*n),
+            Term::Binary { op, left, right } => {
+                let (l, r) = (left.evaluate(window)?, right.evaluate(window)?);
+                match op {
+                    BinaryOperator::Plus => Some(l + r),
+                    BinaryOperator::Minus => Some(l - r),
+                    BinaryOperator::Multiply => Some(l * r),
+                    BinaryOperator::Divide if r != 0.0 => Some(l / r),
+                    _ => None,
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Page {
+    pub order: Option<bool>, // ORDER BY time, true when descending
+    pub limit: Option<usize>,
+    pub offset: usize,
+}
+
+impl Page {
+    fn apply(
+        &self,
+        mut values: Vec<Vec<serde_json::Value>>,
+        time: Option<usize>,
+    ) -> Result<Vec<Vec<serde_json::Value>>, String> {
+        if let Some(descending) = self.order {
+            let t = match time {
+                Some(t) => t,
+                None => return Err("ORDER BY time needs a time column".to_string()),
+            };
+            values.sort_by_key(|row| row.get(t).and_then(|v| v.as_i64()));
+            if descending {
+                values.reverse();
+            }
+        }
+        let mut values: Vec<Vec<serde_json::Value>> =
+            values.into_iter().skip(self.offset).collect();
+        if let Some(limit) = self.limit {
+            values.truncate(limit);
+        }
+        Ok(values)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Federation {
+    Union {
+        branches: Vec<String>,
+        all: bool,
+        page: Page,
+    },
+    Join {
+        query: String,
+        sources: Vec<Source>,
+    },
+    Buckets {
+        sources: Vec<Source>,
+        tags: Vec<String>, // tags matched by the ON clause
+        interval: i64,
+        columns: Vec<(String, Term)>,
+        page: Page,
+    },
+}
+
+fn number(expr: &Expr) -> Result<usize, String> {
+    match expr {
+        Expr::Value(SqlValue::Number(n, _)) => match n.to_string().parse::<usize>() {
+            Ok(n) => Ok(n),
+            Err(_) => Err(format!("Invalid number: {}", n)),
+        },
+        e => Err(format!("Invalid number: {}", e)),
+    }
+}
+
+fn parse_page(q: &Query) -> Result<Page, String> {
+    let order = match q.order_by.as_slice() {
+        [] => None,
+        [o] if matches!(&o.expr, Expr::Identifier(i) if i.value == "time") => {
+            Some(o.asc == Some(false))
+        }
+        _ => return Err("Only ORDER BY time is supported across timeseries".to_string()),
+    };
+    let limit = match &q.limit {
+        Some(l) => Some(number(l)?),
+        None => None,
+    };
+    let offset = match &q.offset {
+        Some(o) => number(&o.value)?,
+        None => 0,
+    };
+    Ok(Page {
+        order,
+        limit,
+        offset,
+    })
+}
+
+fn union_branches(
+    body: &SetExpr,
+    all: &mut bool,
+    branches: &mut Vec<String>,
+) -> Result<(), String> {
+    match body {
+        SetExpr::SetOperation {
+            op: SetOperator::Union,
+            all: a,
+            left,
+            right,
+        } => {
+            *all = *all && *a;
+            union_branches(left, all, branches)?;
+            union_branches(right, all, branches)
+        }
+        SetExpr::SetOperation { op, .. } => {
+            Err(format!("{} is not supported across timeseries", op))
+        }
+        SetExpr::Select(s) => {
+            branches.push(s.to_string());
+            Ok(())
+        }
+        SetExpr::Query(q) => {
+            branches.push(q.to_string());
+            Ok(())
+        }
+        e => Err(format!("Unsupported UNION branch: {}", e)),
+    }
+}
+
+// Splits a condition on its top level ANDs
+pub fn conjuncts(expr: &Expr, out: &mut Vec<Expr>) {
+    match expr {
+        Expr::BinaryOp {
+            left,
+            op: BinaryOperator::And,
+            right,
+        } => {
+            conjuncts(left, out);
+            conjuncts(right, out);
+        }
+        Expr::Nested(e)
+            if matches!(
+                **e,
+                Expr::BinaryOp {
+                    op: BinaryOperator::And,
+                    ..
+                }
+            ) =>
+        {
+            conjuncts(e, out)
+        }
+        e => out.push(e.clone()),
+    }
+}
+
+fn tokens(expr: &str) -> Vec<Token> {
+    Tokenizer::new(&GenericDialect {}, expr)
+        .tokenize()
+        .unwrap_or_default()
+}
+
+fn selects(tokens: &[Token]) -> usize {
+    tokens
+        .iter()
+        .filter(|t| matches!(t, Token::Word(w) if w.quote_style.is_none() && w.value.eq_ignore_ascii_case("select")))
+        .count()
+}
+
+// Table qualifiers used by a condition, and whether it holds a subquery
+fn qualifiers(expr: &str) -> (Vec<String>, bool) {
+    let tokens = tokens(expr);
+    let mut names: Vec<String> = Vec::new();
+    let subquery = selects(&tokens) > 0;
+    for (i, token) in tokens.iter().enumerate() {
+        if let Token::Word(w) = token {
+            if tokens.get(i + 1) == Some(&Token::Period) && !names.contains(&w.value) {
+                names.push(w.value.clone());
+            }
+        }
+    }
+    (names, subquery)
+}
+
+// c.value > 1 -> value > 1
+fn unqualify(expr: &str, alias: &str) -> String {
+    let tokens = tokens(expr);
+    let mut result = String::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        match (&tokens[i], tokens.get(i + 1)) {
+            (Token::Word(w), Some(Token::Period)) if w.value == alias => i += 2,
+            (token, _) => {
+                result += &token.to_string();
+                i += 1;
+            }
+        }
+    }
+    result
+}
+
+// alias -> timeseries of the FROM and JOIN relations
+fn relations(select: &Select) -> Result<Vec<(String, String)>, String> {
+    if select.from.len() != 1 {
+        return Err(format!("Timeseries are combined with JOIN: {}", select));
+    }
+    let from = &select.from[0];
+    let mut relations: Vec<(String, String)> = Vec::new();
+    for factor in std::iter::once(&from.relation).chain(from.joins.iter().map(|j| &j.relation)) {
+        match factor {
+            TableFactor::Table { name, alias, .. } => {
+                let timeseries = name.to_string();
+                let alias = match alias {
+                    Some(a) => a.name.value.clone(),
+                    None => timeseries.clone(),
+                };
+                relations.push((alias, timeseries));
+            }
+            f => return Err(format!("Only timeseries can be joined: {}", f)),
+        }
+    }
+    Ok(relations)
+}
+
+fn parse_term(expr: &Expr, relations: &[(String, String)]) -> Result<Term, String> {
+    match expr {
+        Expr::Nested(e) => parse_term(e, relations),
+        Expr::Value(SqlValue::Number(n, _)) => match n.to_string().parse::<f64>() {
+            Ok(n) => Ok(Term::Number(n)),
+            Err(_) => Err(format!("Invalid number: {}", n)),
+        },
+        Expr::BinaryOp { left, op, right }
+            if matches!(
+                op,
+                BinaryOperator::Plus
+                    | BinaryOperator::Minus
+                    | BinaryOperator::Multiply
+                    | BinaryOperator::Divide
+            ) =>
+        {
+            Ok(Term::Binary {
+                op: op.clone(),
+                left: Box::new(parse_term(left, relations)?),
+                right: Box::new(parse_term(right, relations)?),
+            })
+        }
+        Expr::Function(f) => {
+            let aggregate = match query::parse_aggregate(f)? {
+                Some(Aggregate::Histogram(_)) | None => {
+                    return Err(format!(
+                        "Expected an aggregate such as mean(cpu.value): {}",
+                        f
+                    ))
+                }
+                Some(a) => a,
+            };
+            match query::function_args(f).first() {
+                Some(Expr::CompoundIdentifier(idents)) if idents.len() == 2 => {
+                    match relations.iter().position(|(a, _)| *a == idents[0].value) {
+                        Some(source) => Ok(Term::Aggregate {
+                            source,
+                            field: idents[1].value.clone(),
+                            aggregate,
+                        }),
+                        None => Err(format!("Unknown timeseries: {}", idents[0].value)),
+                    }
+                }
+                _ => Err(format!(
+                    "Expected a qualified field such as mean(cpu.value): {}",
+                    f
+                )),
+            }
+        }
+        e => Err(format!("Unsupported expression with GROUP BY time: {}", e)),
+    }
+}
+
+// ON c.host = m.host AND c.time = m.time: the tags both sides must share, time is implied
+fn join_tags(select: &Select) -> Result<Vec<String>, String> {
+    let mut tags: Vec<String> = Vec::new();
+    for join in select.from[0].joins.iter() {
+        let constraint = match &join.join_operator {
+            JoinOperator::Inner(c) => c,
+            JoinOperator::CrossJoin => continue,
+            o => return Err(format!("Only inner joins can be grouped by time: {:?}", o)),
+        };
+        let on = match constraint {
+            JoinConstraint::On(e) => e,
+            JoinConstraint::None => continue,
+            c => return Err(format!("Unsupported join constraint: {:?}", c)),
+        };
+        let mut equalities: Vec<Expr> = Vec::new();
+        conjuncts(on, &mut equalities);
+        for e in equalities {
+            let (left, right) = match &e {
+                Expr::BinaryOp {
+                    left,
+                    op: BinaryOperator::Eq,
+                    right,
+                } => (left, right),
+                _ => return Err(format!("Unsupported join condition: {}", e)),
+            };
+            match (&**left, &**right) {
+                (Expr::CompoundIdentifier(l), Expr::CompoundIdentifier(r))
+                    if l.len() == 2 && r.len() == 2 && l[1].value == r[1].value =>
+                {
+                    let tag = l[1].value.clone();
+                    if tag != "time" && !tags.contains(&tag) {
+                        tags.push(tag);
+                    }
+                }
+                _ => {
+                    return Err(format!(
+                        "Joined timeseries are matched on tags with the same name: {}",
+                        e
+                    ))
+                }
+            }
+        }
+    }
+    Ok(tags)
+}
+
+fn plan_buckets(
+    q: &Query,
+    select: &Select,
+    tables: &[String],
+    interval: i64,
+) -> Result<Federation, String> {
+    let relations = relations(select)?;
+    let mut sources: Vec<Source> = relations.iter().map(|(a, t)| Source::new(t, a)).collect();
+    if tables
+        .iter()
+        .any(|t| !relations.iter().any(|(_, ts)| ts == t))
+    {
+        return Err("Subqueries aren't supported with GROUP BY time".to_string());
+    }
+    let mut conditions: Vec<Expr> = Vec::new();
+    if let Some(selection) = &select.selection {
+        conjuncts(selection, &mut conditions);
+    }
+    for condition in conditions {
+        let condition = condition.to_string();
+        match qualifiers(&condition) {
+            (_, true) => return Err("Subqueries aren't supported with GROUP BY time".to_string()),
+            (names, _) if names.is_empty() => {
+                for source in sources.iter_mut() {
+                    source.conditions.push(condition.clone());
+                }
+            }
+            (names, _) if names.len() == 1 => {
+                match sources.iter_mut().find(|s| s.alias == names[0]) {
+                    Some(source) => source.conditions.push(unqualify(&condition, &names[0])),
+                    None => return Err(format!("Unknown timeseries: {}", names[0])),
+                }
+            }
+            _ => {
+                return Err(format!(
+                    "Conditions across timeseries aren't supported with GROUP BY time: {}",
+                    condition
+                ))
+            }
+        }
+    }
+
+    let mut columns: Vec<(String, Term)> = Vec::new();
+    for item in select.projection.iter() {
+        let (expr, label) = match item {
+            SelectItem::UnnamedExpr(Expr::Identifier(i)) if i.value == "time" => continue,
+            SelectItem::UnnamedExpr(e) => (e, e.to_string()),
+            SelectItem::ExprWithAlias { expr, alias } => (expr, alias.value.clone()),
+            i => return Err(format!("Unsupported projection with GROUP BY time: {}", i)),
+        };
+        columns.push((label, parse_term(expr, &relations)?));
+    }
+    if columns.is_empty() {
+        return Err(format!("No aggregate to compute: {}", select));
+    }
+    Ok(Federation::Buckets {
+        sources,
+        tags: join_tags(select)?,
+        interval,
+        columns,
+        page: parse_page(q)?,
+    })
+}
+
+fn plan_join(q: &Query, select: &Select, tables: &[String]) -> Result<Federation, String> {
+    let interval = match select.group_by.as_slice() {
+        [Expr::Function(f)] if query::function_name(f) == "time" => {
+            match query::function_args(f).first() {
+                Some(Expr::Value(SqlValue::SingleQuotedString(d))) => {
+                    Some(functions::parse_duration(d)?)
+                }
+                _ => return Err(format!("Expected a duration such as time('1m'): {}", f)),
+            }
+        }
+        _ => None,
+    };
+    if let Some(interval) = interval {
+        return plan_buckets(q, select, tables, interval);
+    }
+
+    // a condition can only be applied while copying when its timeseries is read once
+    let relations = relations(select)?;
+    let subqueries = selects(&tokens(&q.to_string())) > 1;
+    let mut conditions: Vec<Expr> = Vec::new();
+    if let Some(selection) = &select.selection {
+        conjuncts(selection, &mut conditions);
+    }
+    let mut sources: Vec<Source> = Vec::new();
+    for table in tables {
+        let aliases: Vec<&String> = relations
+            .iter()
+            .filter(|(_, t)| t == table)
+            .map(|(a, _)| a)
+            .collect();
+        let mut source = Source::new(table, aliases.first().map_or(table, |a| a));
+        if aliases.len() == 1 && !subqueries {
+            for condition in conditions.iter() {
+                let condition = condition.to_string();
+                if qualifiers(&condition).0 == vec![source.alias.clone()] {
+                    source.conditions.push(unqualify(&condition, &source.alias));
+                }
+            }
+        }
+        sources.push(source);
+    }
+    Ok(Federation::Join {
+        query: q.to_string(),
+        sources,
+    })
+}
+
+// Plans statements that read several timeseries or use UNION, the others return Ok(None)
+pub fn plan(query: &str) -> Result<Option<Federation>, String> {
+    let query = query::quote_selectors(query);
+    // malformed and writing statements are reported by the validator
+    let statements = match gluesql::parse_sql::parse(&query) {
+        Ok(s) => s,
+        Err(_) => return Ok(None),
+    };
+    let q = match statements.as_slice() {
+        [Statement::Query(q)] => q,
+        _ => return Ok(None),
+    };
+    let tables = match db::query_statement_tablenames(&query) {
+        Ok(t) => t,
+        Err(_) => return Ok(None),
+    };
+    match &q.body {
+        SetExpr::SetOperation { .. } => {
+            let mut branches: Vec<String> = Vec::new();
+            let mut all = true;
+            union_branches(&q.body, &mut all, &mut branches)?;
+            Ok(Some(Federation::Union {
+                branches,
+                all,
+                page: parse_page(q)?,
+            }))
+        }
+        SetExpr::Select(select) if tables.len() > 1 => plan_join(q, select, &tables).map(Some),
+        _ => Ok(None),
+    }
+}
+
+fn series_name(sources: &[Source]) -> String {
+    let mut names: Vec<&str> = Vec::new();
+    for s in sources {
+        if !names.contains(&s.timeseries.as_str()) {
+            names.push(&s.timeseries);
+        }
+    }
+    names.join(",")
+}
+
+fn execute_union(
+    pm: &mut TimeseriesDiskPersistenceManager,
+    branches: &[String],
+    all: bool,
+    page: &Page,
+) -> Result<Vec<Series>, String> {
+    let mut series: Vec<Series> = Vec::new();
+    let mut sources: Vec<Source> = Vec::new();
+    let mut labels: Option<Vec<String>> = None;
+    let mut rows: Vec<Vec<serde_json::Value>> = Vec::new();
+    for branch in branches {
+        if let Some(plan) = query::plan(branch.clone())? {
+            series.extend(query::execute(pm, &plan)?);
+            continue;
+        }
+        let tables = db::query_statement_tablenames(branch)?;
+        if tables.len() != 1 {
+            return Err(format!(
+                "Each UNION branch must read a single timeseries: {}",
+                branch
+            ));
+        }
+        let (l, r) = pm.select_rows(tables[0].clone(), branch.clone())?;
+        match &labels {
+            Some(existing) if existing.len() != l.len() => {
+                return Err(format!(
+                    "UNION branches must have the same number of columns: {}",
+                    branch
+                ))
+            }
+            Some(_) => (),
+            None => labels = Some(l),
+        }
+        rows.extend(r);
+        sources.push(Source::new(&tables[0], &tables[0]));
+    }
+    if !series.is_empty() {
+        if labels.is_some() {
+            return Err(
+                "UNION branches can't mix timeseries functions with plain columns".to_string(),
+            );
+        }
+        if *page != Page::default() {
+            return Err(
+                "ORDER BY, LIMIT and OFFSET apply to each branch when using timeseries functions"
+                    .to_string(),
+            );
+        }
+        return Ok(series);
+    }
+    if !all {
+        let mut seen: HashSet<String> = HashSet::new();
+        rows.retain(|row| seen.insert(serde_json::Value::from(row.clone()).to_string()));
+    }
+    let columns = labels.unwrap_or_default();
+    let time = columns.iter().position(|c| c == "time");
+    Ok(vec![Series {
+        name: series_name(&sources),
+        tags: BTreeMap::new(),
+        values: page.apply(rows, time)?,
+        columns,
+        tier: None,
+    }])
+}
+
+// window start -> points of each source by field
+type Windows = BTreeMap<i64, Vec<BTreeMap<String, Vec<Point>>>>;
+
+fn execute_buckets(
+    pm: &mut TimeseriesDiskPersistenceManager,
+    sources: &[Source],
+    tags: &[String],
+    interval: i64,
+    columns: &[(String, Term)],
+    page: &Page,
+) -> Result<Vec<Series>, String> {
+    let grouping = TagGrouping::Tags(tags.to_vec());
+    let mut groups: BTreeMap<BTreeMap<String, String>, Windows> = BTreeMap::new();
+    for (i, source) in sources.iter().enumerate() {
+        for m in pm.scan_measurements(source.timeseries.clone(), source.query())? {
+            let window = groups
+                .entry(grouping.key(&query::tag_key(&m.tags)))
+                .or_default()
+                .entry(query::window_start(m.key, interval))
+                .or_insert_with(|| vec![BTreeMap::new(); sources.len()]);
+            window[i]
+                .entry(m.name.clone())
+                .or_default()
+                .push(Point::new(m.key, m.value));
+        }
+    }
+
+    let mut labels: Vec<String> = vec!["time".to_string()];
+    labels.extend(columns.iter().map(|(label, _)| label.clone()));
+    let mut result: Vec<Series> = Vec::new();
+    for (group, windows) in groups {
+        let mut values: Vec<Vec<serde_json::Value>> = Vec::new();
+        for (start, mut window) in windows {
+            // inner join: every timeseries has data within the window
+            if window.iter().any(|fields| fields.is_empty()) {
+                continue;
+            }
+            for points in window.iter_mut().flat_map(|fields| fields.values_mut()) {
+                points.sort_by_key(|p| p.time);
+            }
+            let mut row = vec![serde_json::json!(start)];
+            for (_, term) in columns {
+                row.push(match term.evaluate(&window) {
+                    Some(v) => serde_json::json!(v),
+                    None => serde_json::Value::Null,
+                });
+            }
+            values.push(row);
+        }
+        if values.is_empty() {
+            continue;
+        }
+        result.push(Series {
+            name: series_name(sources),
+            tags: group,
+            columns: labels.clone(),
+            values: page.apply(values, Some(0))?,
+            tier: None,
+        });
+    }
+    Ok(result)
+}
+
+pub fn execute(
+    pm: &mut TimeseriesDiskPersistenceManager,
+    federation: &Federation,
+) -> Result<Vec<Series>, String> {
+    match federation {
+        Federation::Union {
+            branches,
+            all,
+            page,
+        } => execute_union(pm, branches, *all, page),
+        Federation::Join { query, sources } => {
+            let copies = sources
+                .iter()
+                .map(|s| (s.timeseries.clone(), s.query()))
+                .collect();
+            let (columns, values) = pm.query_federated(query.clone(), copies)?;
+            Ok(vec![Series {
+                name: series_name(sources),
+                tags: BTreeMap::new(),
+                columns,
+                values,
+                tier: None,
+            }])
+        }
+        Federation::Buckets {
+            sources,
+            tags,
+            interval,
+            columns,
+            page,
+        } => execute_buckets(pm, sources, tags, *interval, columns, page),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plan_unions() {
+        assert_eq!(plan("SELECT * FROM cpu WHERE value > 1"), Ok(None));
+        match plan("SELECT * FROM cpu UNION ALL SELECT * FROM mem ORDER BY time DESC LIMIT 2") {
+            Ok(Some(Federation::Union {
+                branches,
+                all,
+                page,
+            })) => {
+                assert_eq!(branches, vec!["SELECT * FROM cpu", "SELECT * FROM mem"]);
+                assert!(all);
+                assert_eq!(
+                    page,
+                    Page {
+                        order: Some(true),
+                        limit: Some(2),
+                        offset: 0
+                    }
+                );
+            }
+            p => panic!("unexpected plan {:?}", p),
+        }
+        assert!(matches!(
+            plan("SELECT * FROM cpu UNION SELECT * FROM cpu UNION ALL SELECT * FROM mem"),
+            Ok(Some(Federation::Union { all: false, .. }))
+        ));
+        assert!(plan("SELECT * FROM cpu EXCEPT SELECT * FROM mem").is_err());
+        assert!(plan("SELECT * FROM cpu UNION SELECT * FROM mem ORDER BY value").is_err());
+    }
+
+    #[test]
+    fn plan_joins() {
+        match plan(
+            "SELECT c.value / m.value AS ratio FROM cpu c JOIN mem m ON c.time = m.time WHERE c.name = 'usage' AND m.value > 0 AND c.value > m.value",
+        ) {
+            Ok(Some(Federation::Join { sources, .. })) => {
+                assert_eq!(sources[0].query(), "SELECT * FROM cpu WHERE name = 'usage'");
+                assert_eq!(sources[1].query(), "SELECT * FROM mem WHERE value > 0");
+            }
+            p => panic!("unexpected plan {:?}", p),
+        }
+        // the subquery reads cpu too, nothing is pushed down
+        match plan(
+            "SELECT * FROM cpu JOIN mem ON cpu.time = mem.time WHERE cpu.value > 1 AND mem.value IN (SELECT value FROM cpu)",
+        ) {
+            Ok(Some(Federation::Join { sources, .. })) => {
+                assert!(sources.iter().all(|s| s.conditions.is_empty()))
+            }
+            p => panic!("unexpected plan {:?}", p),
+        }
+
+        match plan(
+            "SELECT time, (mean(c.usage) + 1) / max(m.used) AS ratio FROM cpu c JOIN mem m ON c.host = m.host WHERE time > '2021-11-30T00:00:00Z' AND m.name = 'used' GROUP BY time('1m')",
+        ) {
+            Ok(Some(Federation::Buckets {
+                sources,
+                tags,
+                interval,
+                columns,
+                ..
+            })) => {
+                assert_eq!(
+                    sources[0].query(),
+                    "SELECT * FROM cpu WHERE time > '2021-11-30T00:00:00Z'"
+                );
+                assert_eq!(
+                    sources[1].query(),
+                    "SELECT * FROM mem WHERE time > '2021-11-30T00:00:00Z' AND name = 'used'"
+                );
+                assert_eq!(tags, vec!["host".to_string()]);
+                assert_eq!(interval, 60_000);
+                assert_eq!(columns.len(), 1);
+                assert_eq!(columns[0].0, "ratio");
+
+                let mut cpu: BTreeMap<String, Vec<Point>> = BTreeMap::new();
+                cpu.insert(
+                    "usage".to_string(),
+                    vec![Point::new(0, 2.0), Point::new(1, 4.0)],
+                );
+                let mut mem: BTreeMap<String, Vec<Point>> = BTreeMap::new();
+                mem.insert("used".to_string(), vec![Point::new(0, 8.0)]);
+                assert_eq!(columns[0].1.evaluate(&[cpu.clone(), mem]), Some(0.5));
+                let mut empty: BTreeMap<String, Vec<Point>> = BTreeMap::new();
+                empty.insert("used".to_string(), vec![Point::new(0, 0.0)]);
+                assert_eq!(columns[0].1.evaluate(&[cpu, empty]), None);
+            }
+            p => panic!("unexpected plan {:?}", p),
+        }
+        assert!(plan(
+            "SELECT mean(c.value) FROM cpu c JOIN mem m ON c.host = m.hostname GROUP BY time('1m')"
+        )
+        .is_err());
+        assert!(plan(
+            "SELECT mean(value) FROM cpu c JOIN mem m ON c.time = m.time GROUP BY time('1m')"
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,453 @@
+use serde::Serialize;
+
+// Time-series analytic functions
+// All functions take the points of a single series, ordered by time, and return a new series.
+// Timestamps are unix milliseconds, same as Measurement.key
+// Counter functions (rate, non_negative_derivative) treat a decreasing value as a counter reset:
+// the increase since the reset is the current value itself.
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct Point {
+    pub time: i64,
+    pub value: f64,
+}
+
+impl Point {
+    pub fn new(time: i64, value: f64) -> Self {
+        Point { time, value }
+    }
+}
+
+// Parses durations such as 500ms, 10s, 5m, 1h, 1d or 1w into milliseconds
+pub fn parse_duration(duration: &str) -> Result<i64, String> {
+    let duration = duration.trim();
+    let split = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(duration.len());
+    let (amount, unit) = duration.split_at(split);
+    let amount = match amount.parse::<i64>() {
+        Ok(a) => a,
+        Err(_) => return Err(format!("Invalid duration: {}", duration)),
+    };
+    let multiplier = match unit {
+        "ms" => 1,
+        "s" => 1_000,
+        "m" => 60_000,
+        "h" => 3_600_000,
+        "d" => 86_400_000,
+        "w" => 604_800_000,
+        _ => return Err(format!("Invalid duration unit: {}", duration)),
+    };
+    if amount <= 0 {
+        return Err(format!("Duration must be positive: {}", duration));
+    }
+    match amount.checked_mul(multiplier) {
+        Some(ms) => Ok(ms),
+        None => Err(format!("Duration out of range: {}", duration)),
+    }
+}
+
+fn counter_increase(previous: f64, current: f64) -> f64 {
+    if current >= previous {
+        current - previous
+    } else {
+        current // counter reset
+    }
+}
+
+// Per second increase of a counter between consecutive points
+pub fn rate(points: &[Point]) -> Vec<Point> {
+    non_negative_derivative(points, 1_000)
+}
+
+// Total increase of a counter over the points, accounting for resets
+pub fn increase(points: &[Point]) -> Option<f64> {
+    if points.len() < 2 {
+        return None;
+    }
+    Some(
+        points
+            .windows(2)
+            .map(|w| counter_increase(w[0].value, w[1].value))
+            .sum(),
+    )
+}
+
+// Change of value per unit (milliseconds) between consecutive points
+pub fn derivative(points: &[Point], unit: i64) -> Vec<Point> {
+    points
+        .windows(2)
+        .filter(|w| w[1].time > w[0].time)
+        .map(|w| {
+            let elapsed = (w[1].time - w[0].time) as f64;
+            Point::new(w[1].time, (w[1].value - w[0].value) * unit as f64 / elapsed)
+        })
+        .collect()
+}
+
+// Like derivative, but counter resets are accounted as an increase from zero
+pub fn non_negative_derivative(points: &[Point], unit: i64) -> Vec<Point> {
+    points
+        .windows(2)
+        .filter(|w| w[1].time > w[0].time)
+        .map(|w| {
+            let elapsed = (w[1].time - w[0].time) as f64;
+            let increase = counter_increase(w[0].value, w[1].value);
+            Point::new(w[1].time, increase * unit as f64 / elapsed)
+        })
+        .collect()
+}
+
+pub fn difference(points: &[Point]) -> Vec<Point> {
+    points
+        .windows(2)
+        .map(|w| Point::new(w[1].time, w[1].value - w[0].value))
+        .collect()
+}
+
+pub fn non_negative_difference(points: &[Point]) -> Vec<Point> {
+    difference(points)
+        .into_iter()
+        .filter(|p| p.value >= 0.0)
+        .collect()
+}
+
+// Average of the last n points, emitted once the window is full
+pub fn moving_average(points: &[Point], n: usize) -> Vec<Point> {
+    if n == 0 {
+        return vec![];
+    }
+    points
+        .windows(n)
+        .map(|w| {
+            let sum: f64 = w.iter().map(|p| p.value).sum();
+            Point::new(w[n - 1].time, sum / n as f64)
+        })
+        .collect()
+}
+
+pub fn cumulative_sum(points: &[Point]) -> Vec<Point> {
+    let mut sum = 0.0;
+    points
+        .iter()
+        .map(|p| {
+            sum += p.value;
+            Point::new(p.time, sum)
+        })
+        .collect()
+}
+
+// Time between consecutive points, expressed in unit (milliseconds)
+pub fn elapsed(points: &[Point], unit: i64) -> Vec<Point> {
+    points
+        .windows(2)
+        .map(|w| Point::new(w[1].time, (w[1].time - w[0].time) as f64 / unit as f64))
+        .collect()
+}
+
+// Aggregates: reduce the points of a window to a single value
+
+pub fn count(points: &[Point]) -> Option<f64> {
+    Some(points.len() as f64)
+}
+
+pub fn sum(points: &[Point]) -> Option<f64> {
+    if points.is_empty() {
+        return None;
+    }
+    Some(points.iter().map(|p| p.value).sum())
+}
+
+pub fn mean(points: &[Point]) -> Option<f64> {
+    sum(points).map(|s| s / points.len() as f64)
+}
+
+pub fn min(points: &[Point]) -> Option<f64> {
+    points.iter().map(|p| p.value).fold(None, |m, v| match m {
+        Some(m) if m <= v => Some(m),
+        _ => Some(v),
+    })
+}
+
+pub fn max(points: &[Point]) -> Option<f64> {
+    points.iter().map(|p| p.value).fold(None, |m, v| match m {
+        Some(m) if m >= v => Some(m),
+        _ => Some(v),
+    })
+}
+
+pub fn first(points: &[Point]) -> Option<f64> {
+    points.first().map(|p| p.value)
+}
+
+pub fn last(points: &[Point]) -> Option<f64> {
+    points.last().map(|p| p.value)
+}
+
+fn sorted_values(points: &[Point]) -> Vec<f64> {
+    let mut values: Vec<f64> = points.iter().map(|p| p.value).collect();
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    values
+}
+
+// Nearest rank percentile, p in 0..=100
+pub fn percentile(points: &[Point], p: f64) -> Option<f64> {
+    let values = sorted_values(points);
+    let rank = (values.len() as f64 * p / 100.0 + 0.5).floor() as i64 - 1;
+    if values.is_empty() || rank < 0 {
+        return None;
+    }
+    values.get((rank as usize).min(values.len() - 1)).cloned()
+}
+
+pub fn median(points: &[Point]) -> Option<f64> {
+    let values = sorted_values(points);
+    let n = values.len();
+    if n == 0 {
+        return None;
+    }
+    if n % 2 == 1 {
+        Some(values[n / 2])
+    } else {
+        Some((values[n / 2 - 1] + values[n / 2]) / 2.0)
+    }
+}
+
+// Sample standard deviation
+pub fn stddev(points: &[Point]) -> Option<f64> {
+    if points.len() < 2 {
+        return None;
+    }
+    let m = mean(points)?;
+    let variance = points
+        .iter()
+        .map(|p| (p.value - m) * (p.value - m))
+        .sum::<f64>()
+        / (points.len() - 1) as f64;
+    Some(variance.sqrt())
+}
+
+pub fn spread(points: &[Point]) -> Option<f64> {
+    Some(max(points)? - min(points)?)
+}
+
+// Most frequent value, ties resolved to the smallest value
+pub fn mode(points: &[Point]) -> Option<f64> {
+    let values = sorted_values(points);
+    let mut best: Option<(f64, usize)> = None;
+    let mut i = 0;
+    while i < values.len() {
+        let mut j = i;
+        while j < values.len() && values[j] == values[i] {
+            j += 1;
+        }
+        match best {
+            Some((_, c)) if c >= j - i => (),
+            _ => best = Some((values[i], j - i)),
+        }
+        i = j;
+    }
+    best.map(|(v, _)| v)
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Bucket {
+    pub lower: f64,
+    pub upper: f64,
+    pub count: u64,
+}
+
+// Equal width buckets between the smallest and largest value
+pub fn histogram(points: &[Point], buckets: usize) -> Vec<Bucket> {
+    let (lo, hi) = match (min(points), max(points)) {
+        (Some(lo), Some(hi)) if buckets > 0 => (lo, hi),
+        _ => return vec![],
+    };
+    let width = if hi > lo {
+        (hi - lo) / buckets as f64
+    } else {
+        1.0
+    };
+    let mut result: Vec<Bucket> = (0..buckets)
+        .map(|i| Bucket {
+            lower: lo + width * i as f64,
+            upper: lo + width * (i + 1) as f64,
+            count: 0,
+        })
+        .collect();
+    for p in points {
+        let i = (((p.value - lo) / width) as usize).min(buckets - 1);
+        result[i].count += 1;
+    }
+    result
+}
+
+// Quantile q (0..=1) from cumulative histogram buckets given as (upper bound, count),
+// interpolating linearly within the bucket, as Prometheus histogram_quantile does
+pub fn bucket_quantile(q: f64, buckets: &[(f64, f64)]) -> f64 {
+    if q.is_nan() {
+        return f64::NAN;
+    }
+    if q < 0.0 {
+        return f64::NEG_INFINITY;
+    }
+    if q > 1.0 {
+        return f64::INFINITY;
+    }
+    let mut buckets = buckets.to_vec();
+    buckets.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+    match buckets.last() {
+        Some((upper, _)) if *upper == f64::INFINITY && buckets.len() >= 2 => (),
+        _ => return f64::NAN,
+    }
+    // counts must be cumulative, scrapes of separate buckets may be inconsistent
+    for i in 1..buckets.len() {
+        if buckets[i].1 < buckets[i - 1].1 {
+            buckets[i].1 = buckets[i - 1].1;
+        }
+    }
+    let observations = buckets[buckets.len() - 1].1;
+    if observations == 0.0 {
+        return f64::NAN;
+    }
+    let mut rank = q * observations;
+    let b = buckets
+        .iter()
+        .position(|(_, count)| *count >= rank)
+        .unwrap_or(buckets.len() - 1);
+    if b == buckets.len() - 1 {
+        return buckets[b - 1].0;
+    }
+    if b == 0 && buckets[0].0 <= 0.0 {
+        return buckets[0].0;
+    }
+    let (mut start, end, mut count) = (0.0, buckets[b].0, buckets[b].1);
+    if b > 0 {
+        start = buckets[b - 1].0;
+        count -= buckets[b - 1].1;
+        rank -= buckets[b - 1].1;
+    }
+    start + (end - start) * (rank / count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn series(values: &[(i64, f64)]) -> Vec<Point> {
+        values.iter().map(|(t, v)| Point::new(*t, *v)).collect()
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("250ms"), Ok(250));
+        assert_eq!(parse_duration("10s"), Ok(10_000));
+        assert_eq!(parse_duration("5m"), Ok(300_000));
+        assert_eq!(parse_duration("1w"), Ok(604_800_000));
+        assert!(parse_duration("5").is_err());
+        assert!(parse_duration("0s").is_err());
+        assert!(parse_duration("xs").is_err());
+    }
+
+    #[test]
+    fn rate_handles_counter_resets() {
+        let points = series(&[(0, 10.0), (1_000, 20.0), (2_000, 5.0), (4_000, 15.0)]);
+        assert_eq!(
+            rate(&points),
+            series(&[(1_000, 10.0), (2_000, 5.0), (4_000, 5.0)])
+        );
+    }
+
+    #[test]
+    fn derivative_and_difference() {
+        let points = series(&[(0, 10.0), (60_000, 40.0), (120_000, 10.0)]);
+        assert_eq!(
+            derivative(&points, 60_000),
+            series(&[(60_000, 30.0), (120_000, -30.0)])
+        );
+        assert_eq!(
+            non_negative_derivative(&points, 60_000),
+            series(&[(60_000, 30.0), (120_000, 10.0)])
+        );
+        assert_eq!(
+            difference(&points),
+            series(&[(60_000, 30.0), (120_000, -30.0)])
+        );
+        assert_eq!(non_negative_difference(&points), series(&[(60_000, 30.0)]));
+        assert_eq!(increase(&points), Some(40.0));
+        assert_eq!(increase(&points[..1]), None);
+    }
+
+    #[test]
+    fn moving_average_cumulative_sum_and_elapsed() {
+        let points = series(&[(0, 1.0), (1_000, 2.0), (3_000, 3.0), (6_000, 6.0)]);
+        assert_eq!(
+            moving_average(&points, 2),
+            series(&[(1_000, 1.5), (3_000, 2.5), (6_000, 4.5)])
+        );
+        assert_eq!(
+            cumulative_sum(&points),
+            series(&[(0, 1.0), (1_000, 3.0), (3_000, 6.0), (6_000, 12.0)])
+        );
+        assert_eq!(
+            elapsed(&points, 1_000),
+            series(&[(1_000, 1.0), (3_000, 2.0), (6_000, 3.0)])
+        );
+    }
+
+    #[test]
+    fn aggregates() {
+        let points = series(&[(0, 4.0), (1, 1.0), (2, 3.0), (3, 2.0), (4, 3.0), (5, 10.0)]);
+        assert_eq!(count(&points), Some(6.0));
+        assert_eq!(sum(&points), Some(23.0));
+        assert_eq!(first(&points), Some(4.0));
+        assert_eq!(last(&points), Some(10.0));
+        assert_eq!(median(&points), Some(3.0));
+        assert_eq!(mode(&points), Some(3.0));
+        assert_eq!(spread(&points), Some(9.0));
+        assert_eq!(percentile(&points, 50.0), Some(3.0));
+        assert_eq!(percentile(&points, 90.0), Some(4.0));
+        assert_eq!(percentile(&points, 100.0), Some(10.0));
+        assert_eq!(percentile(&points, 0.0), None);
+        assert!((stddev(&points).unwrap() - 3.188521).abs() < 1e-6);
+        assert_eq!(mean(&[]), None);
+        assert_eq!(stddev(&points[..1]), None);
+    }
+
+    #[test]
+    fn histogram_buckets() {
+        let points = series(&[(0, 0.0), (1, 1.0), (2, 5.0), (3, 9.0), (4, 10.0)]);
+        let buckets = histogram(&points, 2);
+        assert_eq!(
+            buckets,
+            vec![
+                Bucket {
+                    lower: 0.0,
+                    upper: 5.0,
+                    count: 2
+                },
+                Bucket {
+                    lower: 5.0,
+                    upper: 10.0,
+                    count: 3
+                },
+            ]
+        );
+        assert!(histogram(&[], 3).is_empty());
+    }
+
+    #[test]
+    fn bucket_quantiles() {
+        let buckets = [
+            (0.1, 50.0),
+            (0.5, 90.0),
+            (1.0, 100.0),
+            (f64::INFINITY, 100.0),
+        ];
+        assert_eq!(bucket_quantile(0.5, &buckets), 0.1);
+        assert!((bucket_quantile(0.7, &buckets) - 0.3).abs() < 1e-9);
+        assert_eq!(bucket_quantile(1.0, &buckets), 1.0);
+        assert!(bucket_quantile(0.5, &buckets[..3]).is_nan());
+        assert_eq!(bucket_quantile(2.0, &buckets), f64::INFINITY);
+    }
+}
@@ -0,0 +1,216 @@
+// Gorilla compression (Facebook's in-memory time series database)
+// Times are written as the difference between consecutive deltas, a single bit when points
+// are evenly spaced, values as the XOR with the previous one, a single bit when repeated and
+// the meaningful bits only otherwise. Both are bit streams written from the most significant bit.
+
+#[derive(Debug, Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    bits: usize,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u64, n: u32) {
+        for i in (0..n).rev() {
+            if self.bits.is_multiple_of(8) {
+                self.bytes.push(0);
+            }
+            if (value >> i) & 1 == 1 {
+                let last = self.bytes.len() - 1;
+                self.bytes[last] |= 1 << (7 - self.bits % 8);
+            }
+            self.bits += 1;
+        }
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    bits: usize,
+}
+
+impl BitReader<'_> {
+    fn read(&mut self, n: u32) -> Result<u64, String> {
+        let mut value = 0;
+        for _ in 0..n {
+            let byte = match self.bytes.get(self.bits / 8) {
+                Some(b) => b,
+                None => return Err(format!("Truncated block at bit {}", self.bits)),
+            };
+            value = (value << 1) | ((byte >> (7 - self.bits % 8)) & 1) as u64;
+            self.bits += 1;
+        }
+        Ok(value)
+    }
+}
+
+// Bits of the delta of delta ranges, after their control bits 10, 110 and 1110
+const DOD_RANGES: [(u64, u32, u32); 3] = [(0b10, 2, 7), (0b110, 3, 9), (0b1110, 4, 12)];
+
+pub fn encode_times(times: &[i64]) -> Vec<u8> {
+    let mut w = BitWriter::default();
+    let (mut previous, mut delta) = (0i64, 0i64);
+    for (i, time) in times.iter().enumerate() {
+        if i == 0 {
+            w.write(*time as u64, 64);
+            previous = *time;
+            continue;
+        }
+        let d = time.wrapping_sub(previous);
+        let dod = d.wrapping_sub(delta);
+        (previous, delta) = (*time, d);
+        if dod == 0 {
+            w.write(0, 1);
+            continue;
+        }
+        // dod in [-(2^(n-1) - 1), 2^(n-1)] is written as n bits
+        let range = DOD_RANGES.iter().find(|(_, _, n)| {
+            let half = 1i64 << (n - 1);
+            dod > -half && dod <= half
+        });
+        match range {
+            Some((control, len, n)) => {
+                w.write(*control, *len);
+                w.write((dod + (1i64 << (n - 1)) - 1) as u64, *n);
+            }
+            None => {
+                w.write(0b1111, 4);
+                w.write(dod as u64, 64);
+            }
+        }
+    }
+    w.bytes
+}
+
+pub fn decode_times(bytes: &[u8], count: usize) -> Result<Vec<i64>, String> {
+    let mut r = BitReader { bytes, bits: 0 };
+    let mut times = Vec::with_capacity(count);
+    let (mut previous, mut delta) = (0i64, 0i64);
+    for i in 0..count {
+        if i == 0 {
+            previous = r.read(64)? as i64;
+            times.push(previous);
+            continue;
+        }
+        let mut ones = 0;
+        while ones < 4 && r.read(1)? == 1 {
+            ones += 1;
+        }
+        let dod = match ones {
+            0 => 0,
+            4 => r.read(64)? as i64,
+            _ => {
+                let n = DOD_RANGES[ones - 1].2;
+                r.read(n)? as i64 - (1i64 << (n - 1)) + 1
+            }
+        };
+        delta = delta.wrapping_add(dod);
+        previous = previous.wrapping_add(delta);
+        times.push(previous);
+    }
+    Ok(times)
+}
+
+pub fn encode_values(values: &[f64]) -> Vec<u8> {
+    let mut w = BitWriter::default();
+    let mut previous = 0u64;
+    let mut window: Option<(u32, u32)> = None; // leading and trailing zeros of the last value written
+    for (i, value) in values.iter().enumerate() {
+        let bits = value.to_bits();
+        if i == 0 {
+            w.write(bits, 64);
+            previous = bits;
+            continue;
+        }
+        let xor = bits ^ previous;
+        previous = bits;
+        if xor == 0 {
+            w.write(0, 1);
+            continue;
+        }
+        let leading = xor.leading_zeros().min(31);
+        let trailing = xor.trailing_zeros();
+        match window {
+            // the meaningful bits fit in the previous window
+            Some((l, t)) if leading >= l && trailing >= t => {
+                w.write(0b10, 2);
+                w.write(xor >> t, 64 - l - t);
+            }
+            _ => {
+                let meaningful = 64 - leading - trailing;
+                w.write(0b11, 2);
+                w.write(leading as u64, 5);
+                w.write((meaningful - 1) as u64, 6);
+                w.write(xor >> trailing, meaningful);
+                window = Some((leading, trailing));
+            }
+        }
+    }
+    w.bytes
+}
+
+pub fn decode_values(bytes: &[u8], count: usize) -> Result<Vec<f64>, String> {
+    let mut r = BitReader { bytes, bits: 0 };
+    let mut values = Vec::with_capacity(count);
+    let mut previous = 0u64;
+    let mut window = (0, 0);
+    for i in 0..count {
+        if i == 0 {
+            previous = r.read(64)?;
+            values.push(f64::from_bits(previous));
+            continue;
+        }
+        if r.read(1)? == 1 {
+            if r.read(1)? == 1 {
+                let leading = r.read(5)? as u32;
+                let meaningful = r.read(6)? as u32 + 1;
+                window = (leading, 64 - leading - meaningful);
+            }
+            let (l, t) = window;
+            previous ^= r.read(64 - l - t)? << t;
+        }
+        values.push(f64::from_bits(previous));
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let times = vec![
+            1_600_000_000_000,
+            1_600_000_010_000,
+            1_600_000_020_000,
+            1_600_000_030_001,
+            1_600_000_029_000,
+            -5,
+            i64::MAX,
+            i64::MIN,
+        ];
+        assert_eq!(
+            decode_times(&encode_times(&times), times.len()).unwrap(),
+            times
+        );
+        let values = vec![12.5, 12.5, 12.75, -3.0, 0.0, f64::MAX, 1e-300, 12.5];
+        assert_eq!(
+            decode_values(&encode_values(&values), values.len()).unwrap(),
+            values
+        );
+
+        // evenly spaced points take a bit per time, repeated values a bit per value
+        let regular: Vec<i64> = (0..1000).map(|i| i * 10_000).collect();
+        assert_eq!(
+            encode_times(&regular).len(),
+            (64 + 68 + 998_usize).div_ceil(8)
+        );
+        assert_eq!(
+            encode_values(&[1.0; 1000]).len(),
+            (64 + 999_usize).div_ceil(8)
+        );
+        let encoded = encode_times(&regular);
+        assert!(decode_times(&encoded[..encoded.len() - 20], 1000).is_err());
+    }
+}
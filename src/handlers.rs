@@ -1,9 +1,15 @@
-use actix_web::{get, post, web, Error, HttpResponse, Result};
+use actix_web::{delete, get, post, put, route, web, Error, HttpRequest, HttpResponse, Result};
 use chrono::{DateTime, Utc};
 use log::{debug, info};
 use serde::Deserialize;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::explain::{Analyze, Explain, Trace};
+use crate::limits::RunningQuery;
+use crate::persistence::TimeseriesDiskPersistenceManager;
+use crate::query::Execution;
 
 #[derive(Deserialize, Clone)]
 struct TimeseriesInfo {
@@ -14,21 +20,326 @@ struct TimeseriesInfo {
 pub struct RangeQueryRequest {
     start: String,
     end: String,
+    limit: Option<usize>,   // page size, paginates the result
+    cursor: Option<String>, // continuation token of the previous page
+    step: Option<String>,   // resamples each series, such as 1m
+    fill: Option<String>,   // null (default), none, previous, linear or a number
+    stale: Option<String>,  // gaps longer aren't filled
+    raw: Option<bool>,      // reads the raw data instead of rollups
 }
 
 #[derive(Deserialize)]
 struct FormData {
-    q: String, // query string
+    q: String,            // query string
+    lang: Option<String>, // sql (default) or influxql
+    epoch: Option<String>,
+    timeout: Option<String>, // lowers the server query timeout, such as 5s
+    limit: Option<usize>,
+    cursor: Option<String>,
+    raw: Option<bool>, // reads the raw data instead of rollups
+}
+
+#[derive(Deserialize)]
+pub struct InfluxQLRequest {
+    q: String,
+    db: Option<String>, // accepted for InfluxDB 1.x clients, timeseries are global
+    epoch: Option<String>,
+    timeout: Option<String>,
+    raw: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct MetadataRequest {
+    #[serde(rename = "match")]
+    pattern: Option<String>, // regex over names
+    start: Option<String>,
+    end: Option<String>,
+}
+
+#[derive(Deserialize, Clone)]
+struct TagKeyInfo {
+    timeseries: String,
+    key: String,
+}
+
+// Runs a query charged to a budget under the server limits, listed by GET /queries while it runs.
+// Execution is synchronous, it goes to the blocking thread pool so the workers stay free to
+// list and kill it.
+async fn run_tracked<T, F>(
+    data: &web::Data<Arc<Mutex<TimeseriesDiskPersistenceManager>>>,
+    query: &str,
+    timeout: Option<&str>,
+    f: F,
+) -> Result<T, String>
+where
+    F: FnOnce(&mut TimeseriesDiskPersistenceManager) -> Result<T, String> + Send + 'static,
+    T: Send + 'static,
+{
+    let pm = data.lock().unwrap().clone();
+    let limits = pm.limits.with_timeout(timeout)?;
+    let (mut pm, running): (TimeseriesDiskPersistenceManager, RunningQuery) =
+        pm.track(query, limits);
+    let res = web::block(move || {
+        let res = f(&mut pm);
+        drop(running);
+        res
+    })
+    .await;
+    match res {
+        Ok(res) => res,
+        Err(e) => Err(format!("Query execution error: {}", e)),
+    }
+}
+
+// Runs a query through the result cache: a hit skips the execution, a miss runs it traced so
+// its entry knows the timeseries and time ranges it read. Queries without a key aren't cached.
+async fn run_cached<F>(
+    data: &web::Data<Arc<Mutex<TimeseriesDiskPersistenceManager>>>,
+    key: Option<String>,
+    query: &str,
+    timeout: Option<&str>,
+    f: F,
+) -> Result<serde_json::Value, String>
+where
+    F: FnOnce(&mut TimeseriesDiskPersistenceManager) -> Result<serde_json::Value, String>
+        + Send
+        + 'static,
+{
+    let cache = data.lock().unwrap().results.clone();
+    let key = match key {
+        Some(key) if cache.enabled() => key,
+        _ => return run_tracked(data, query, timeout, f).await,
+    };
+    if let Some(value) = cache.get(&key) {
+        return Ok(value);
+    }
+    let since = cache.generation();
+    let executed_at = Utc::now().timestamp_millis();
+    let trace = Arc::new(Trace::default());
+    let traced = trace.clone();
+    let value = run_tracked(data, query, timeout, move |pm| {
+        pm.trace = Some(traced);
+        f(pm)
+    })
+    .await?;
+    let reads = crate::cache::reads(&trace.scans(), executed_at);
+    cache.insert(&key, value.clone(), reads, since);
+    Ok(value)
+}
+
+// curl localhost:8086/cache
+#[get("/cache")]
+async fn cache_stats(
+    data: web::Data<Arc<Mutex<TimeseriesDiskPersistenceManager>>>,
+) -> Result<HttpResponse, Error> {
+    let stats = data.lock().unwrap().results.stats();
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(stats))
+}
+
+// curl localhost:8086/queries
+#[get("/queries")]
+async fn list_queries(
+    data: web::Data<Arc<Mutex<TimeseriesDiskPersistenceManager>>>,
+) -> Result<HttpResponse, Error> {
+    let queries = data.lock().unwrap().queries.list();
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(queries))
+}
+
+// curl -X DELETE localhost:8086/queries/1
+#[delete("/queries/{id}")]
+async fn kill_query(
+    id: web::Path<u64>,
+    data: web::Data<Arc<Mutex<TimeseriesDiskPersistenceManager>>>,
+) -> Result<HttpResponse, Error> {
+    let id = id.into_inner();
+    match data.lock().unwrap().queries.kill(id) {
+        Some(status) => Ok(HttpResponse::Ok()
+            .content_type("application/json")
+            .json(status)),
+        None => Ok(HttpResponse::NotFound()
+            .content_type("application/json")
+            .body(format!("Query not found: {}", id))),
+    }
 }
 
 #[get("/")]
 async fn list_timeseries(
     pm: web::Data<Arc<Mutex<crate::persistence::TimeseriesDiskPersistenceManager>>>,
 ) -> Result<HttpResponse, Error> {
-    let res = pm.lock().unwrap().clone().list_timeseries().unwrap();
-    return Ok(HttpResponse::Ok()
+    let mut res = pm.lock().unwrap().clone().list_timeseries().unwrap();
+    res.sort();
+    Ok(HttpResponse::Ok()
         .content_type("application/json")
-        .json(format!("{:?}", res)));
+        .json(res))
+}
+
+impl MetadataRequest {
+    fn pattern(&self) -> Result<Option<crate::query::Pattern>, String> {
+        match &self.pattern {
+            Some(p) => Ok(Some(crate::query::Pattern::new(p)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn filter(&self) -> Result<crate::metadata::Filter, String> {
+        let time = |t: &Option<String>| match t {
+            Some(t) => match t.parse::<DateTime<Utc>>() {
+                Ok(t) => Ok(Some(t.timestamp_millis())),
+                Err(e) => Err(format!("Invalid time {}: {}", t, e)),
+            },
+            None => Ok(None),
+        };
+        Ok(crate::metadata::Filter {
+            start: time(&self.start)?,
+            end: time(&self.end)?,
+            condition: None,
+        })
+    }
+}
+
+fn metadata_response<T: serde::Serialize>(res: Result<T, String>) -> HttpResponse {
+    match res {
+        Ok(body) => HttpResponse::Ok()
+            .content_type("application/json")
+            .json(body),
+        Err(e) => HttpResponse::BadRequest()
+            .content_type("application/json")
+            .body(format!("Metadata query error: {}", e)),
+    }
+}
+
+fn timeseries_not_found(
+    pm: &crate::persistence::TimeseriesDiskPersistenceManager,
+    timeseries: &str,
+) -> Option<HttpResponse> {
+    let known = pm.clone().list_timeseries().unwrap_or_default();
+    if known.iter().any(|t| t == timeseries) {
+        return None;
+    }
+    Some(
+        HttpResponse::NotFound()
+            .content_type("application/json")
+            .body(format!("Timeseries not found: {}", timeseries)),
+    )
+}
+
+// curl 'localhost:8086/measurements?match=^cpu&start=2021-11-30T00:00:00Z'
+#[get("/measurements")]
+async fn list_measurements(
+    web::Query(req): web::Query<MetadataRequest>,
+    data: web::Data<Arc<Mutex<crate::persistence::TimeseriesDiskPersistenceManager>>>,
+) -> Result<HttpResponse, Error> {
+    let res = run_tracked(&data, "SHOW MEASUREMENTS", None, move |pm| {
+        let pattern = req.pattern()?;
+        crate::metadata::measurements(pm, pattern.as_ref(), &req.filter()?)
+    })
+    .await;
+    Ok(metadata_response(res))
+}
+
+#[get("/measurements/{timeseries}/fields")]
+async fn list_field_keys(
+    web::Query(req): web::Query<MetadataRequest>,
+    ts: web::Path<TimeseriesInfo>,
+    data: web::Data<Arc<Mutex<crate::persistence::TimeseriesDiskPersistenceManager>>>,
+) -> Result<HttpResponse, Error> {
+    if let Some(res) = timeseries_not_found(&data.lock().unwrap(), &ts.timeseries) {
+        return Ok(res);
+    }
+    let timeseries = ts.timeseries.clone();
+    let query = format!("SHOW FIELD KEYS FROM {}", timeseries);
+    let res = run_tracked(&data, &query, None, move |pm| {
+        let pattern = req.pattern()?;
+        let fields = crate::metadata::field_keys(pm, &timeseries, &req.filter()?)?;
+        Ok(fields
+            .into_iter()
+            .filter(|f| pattern.as_ref().is_none_or(|p| p.is_match(f)))
+            .collect::<Vec<String>>())
+    })
+    .await;
+    Ok(metadata_response(res))
+}
+
+#[get("/measurements/{timeseries}/tags")]
+async fn list_tag_keys(
+    web::Query(req): web::Query<MetadataRequest>,
+    ts: web::Path<TimeseriesInfo>,
+    data: web::Data<Arc<Mutex<crate::persistence::TimeseriesDiskPersistenceManager>>>,
+) -> Result<HttpResponse, Error> {
+    if let Some(res) = timeseries_not_found(&data.lock().unwrap(), &ts.timeseries) {
+        return Ok(res);
+    }
+    let timeseries = ts.timeseries.clone();
+    let query = format!("SHOW TAG KEYS FROM {}", timeseries);
+    let res = run_tracked(&data, &query, None, move |pm| {
+        let pattern = req.pattern()?;
+        crate::metadata::tag_keys(pm, &timeseries, pattern.as_ref(), &req.filter()?)
+    })
+    .await;
+    Ok(metadata_response(res))
+}
+
+// curl 'localhost:8086/measurements/cpu/tags/host/values?match=^web'
+#[get("/measurements/{timeseries}/tags/{key}/values")]
+async fn list_tag_values(
+    web::Query(req): web::Query<MetadataRequest>,
+    info: web::Path<TagKeyInfo>,
+    data: web::Data<Arc<Mutex<crate::persistence::TimeseriesDiskPersistenceManager>>>,
+) -> Result<HttpResponse, Error> {
+    if let Some(res) = timeseries_not_found(&data.lock().unwrap(), &info.timeseries) {
+        return Ok(res);
+    }
+    let info = info.into_inner();
+    let query = format!(
+        "SHOW TAG VALUES FROM {} WITH KEY = {}",
+        info.timeseries, info.key
+    );
+    let res = run_tracked(&data, &query, None, move |pm| {
+        let pattern = req.pattern()?;
+        let key = info.key.clone();
+        let values = crate::metadata::tag_values(
+            pm,
+            &info.timeseries,
+            &move |k: &str| k == key,
+            &req.filter()?,
+        )?;
+        Ok(values
+            .into_iter()
+            .map(|(_, v)| v)
+            .filter(|v| pattern.as_ref().is_none_or(|p| p.is_match(v)))
+            .collect::<Vec<String>>())
+    })
+    .await;
+    Ok(metadata_response(res))
+}
+
+type Resampling = (i64, crate::query::Fill, Option<i64>);
+
+// step, fill and staleness of a resampled range, none without a step
+fn resample_request(info: &RangeQueryRequest) -> Result<Option<Resampling>, String> {
+    let step = match &info.step {
+        Some(step) => crate::functions::parse_duration(step)?,
+        None if info.fill.is_some() || info.stale.is_some() => {
+            return Err("fill and stale require a step".to_string())
+        }
+        None => return Ok(None),
+    };
+    if step <= 0 {
+        return Err(format!("Invalid step: {}", step));
+    }
+    let fill = match &info.fill {
+        Some(fill) => crate::query::Fill::parse(fill)?,
+        None => crate::query::Fill::Null,
+    };
+    let stale = match &info.stale {
+        Some(stale) => Some(crate::functions::parse_duration(stale)?),
+        None => None,
+    };
+    Ok(Some((step, fill, stale)))
 }
 
 #[get("/range/{timeseries}")]
@@ -38,60 +349,663 @@ async fn query_timeseries_range(
     data: web::Data<Arc<Mutex<crate::persistence::TimeseriesDiskPersistenceManager>>>,
 ) -> Result<HttpResponse, Error> {
     // sanitize query strings, check if the data type is really datetime
-    let st = info.start.parse::<DateTime<Utc>>().unwrap();
-    let en = info.end.parse::<DateTime<Utc>>().unwrap();
-    let mut pm = data.lock().unwrap().clone();
-    if !pm.clone().timeseries_exists(ts.timeseries.clone()) {
+    let (st, en) = match (
+        info.start.parse::<DateTime<Utc>>(),
+        info.end.parse::<DateTime<Utc>>(),
+    ) {
+        (Ok(st), Ok(en)) => (st, en),
+        (Err(e), _) | (_, Err(e)) => {
+            return Ok(HttpResponse::BadRequest()
+                .content_type("application/json")
+                .body(format!(
+                    "Invalid time range {} to {}: {}",
+                    info.start, info.end, e
+                )));
+        }
+    };
+    if !data
+        .lock()
+        .unwrap()
+        .clone()
+        .timeseries_exists(ts.timeseries.clone())
+    {
         return Ok(HttpResponse::NotFound()
             .content_type("application/json")
             .body(format!("Timeseries not found: {}", ts.timeseries.clone())));
     }
-    let measurement_range = pm.get_measurement_range(
-        ts.timeseries.clone(),
+    let timeseries = ts.timeseries.clone();
+    let query = format!("range {} {} {}", timeseries, info.start, info.end);
+    let page = crate::cursor::page_request(info.limit, info.cursor.as_deref());
+    let key = format!(
+        "range {} {} {} step={:?} fill={:?} stale={:?} limit={:?} cursor={:?} raw={:?}",
+        timeseries,
         st.timestamp_millis(),
         en.timestamp_millis(),
+        info.step,
+        info.fill,
+        info.stale,
+        info.limit,
+        info.cursor,
+        info.raw
     );
+    let resampling = resample_request(&info);
+    let raw = info.raw.unwrap_or(false);
+    let measurement_range = run_cached(&data, Some(key), &query, None, move |pm| {
+        pm.raw = raw;
+        let (st, en) = (st.timestamp_millis(), en.timestamp_millis());
+        if let Some((step, fill, stale)) = resampling? {
+            if page?.is_some() {
+                return Err("Pagination doesn't apply to resampled ranges".to_string());
+            }
+            let series = crate::query::resample(pm, &timeseries, st, en + 1, step, fill, stale)?;
+            pm.check_returned(crate::query::count_rows(&series))?;
+            return Ok(serde_json::json!(series));
+        }
+        if let Some((cursor, limit)) = page? {
+            let page = pm.get_measurement_page(timeseries, st, en, cursor.as_ref(), limit)?;
+            pm.check_returned(page.data.len())?;
+            return Ok(serde_json::json!(page));
+        }
+        let range = pm.get_measurement_range(timeseries, st, en)?;
+        pm.check_returned(range.len())?;
+        Ok(serde_json::json!(format!("{:?}", range)))
+    })
+    .await;
     match measurement_range {
-        Ok(ret) => {
-            return Ok(HttpResponse::Ok()
+        Ok(ret) => Ok(HttpResponse::Ok()
+            .content_type("application/json")
+            .json(ret)),
+        Err(e) => Ok(HttpResponse::BadRequest()
+            .content_type("application/json")
+            .body(format!("Query timeseries error: {}", e))),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct LastRequest {
+    group_by: Option<String>, // comma separated tags
+}
+
+// Latest point of every series, from the last value cache
+// curl 'localhost:8086/last/cpu?group_by=host'
+#[get("/last/{timeseries}")]
+async fn last_values(
+    web::Query(req): web::Query<LastRequest>,
+    ts: web::Path<TimeseriesInfo>,
+    data: web::Data<Arc<Mutex<crate::persistence::TimeseriesDiskPersistenceManager>>>,
+) -> Result<HttpResponse, Error> {
+    let last = data.lock().unwrap().last_measurements(&ts.timeseries);
+    match last {
+        Ok(mut points) => {
+            if let Some(group_by) = &req.group_by {
+                let tags: Vec<String> = group_by
+                    .split(',')
+                    .map(|t| t.trim().to_string())
+                    .filter(|t| !t.is_empty())
+                    .collect();
+                points = crate::lastvalue::group(points, &tags);
+            }
+            Ok(HttpResponse::Ok()
                 .content_type("application/json")
-                .json(format!("{:?}", ret)));
+                .json(points))
         }
-        Err(e) => {
-            return Ok(HttpResponse::BadRequest()
+        Err(e) => Ok(HttpResponse::NotFound()
+            .content_type("application/json")
+            .body(e)),
+    }
+}
+
+// Count, sum, min, max, mean, time range and quantiles of every series, kept up to date on
+// each write
+// curl localhost:8086/stats/cpu
+#[get("/stats/{timeseries}")]
+async fn series_stats(
+    ts: web::Path<TimeseriesInfo>,
+    data: web::Data<Arc<Mutex<crate::persistence::TimeseriesDiskPersistenceManager>>>,
+) -> Result<HttpResponse, Error> {
+    let pm = data.lock().unwrap().clone();
+    if !pm.storages.lock().unwrap().contains_key(&ts.timeseries) {
+        return Ok(HttpResponse::NotFound()
+            .content_type("application/json")
+            .body(format!("Timeseries not found: {}", ts.timeseries)));
+    }
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(pm.stats.get(&ts.timeseries)))
+}
+
+#[derive(Deserialize)]
+pub struct RetentionRequest {
+    keep: String, // 30d
+}
+
+// Policies of the timeseries and what the last expiry run removed
+// curl localhost:8086/retention
+#[get("/retention")]
+async fn list_retention(
+    data: web::Data<Arc<Mutex<TimeseriesDiskPersistenceManager>>>,
+) -> Result<HttpResponse, Error> {
+    let pm = data.lock().unwrap().clone();
+    let mut timeseries: Vec<String> = pm.storages.lock().unwrap().keys().cloned().collect();
+    timeseries.sort();
+    let policies: Vec<crate::retention::Policy> = timeseries
+        .iter()
+        .filter_map(|ts| pm.retention.policy(ts))
+        .collect();
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(serde_json::json!({
+            "policies": policies,
+            "last_run": pm.retention.last_run(),
+        })))
+}
+
+// curl -X PUT 'localhost:8086/retention/cpu?keep=30d'
+#[put("/retention/{timeseries}")]
+async fn set_retention(
+    web::Query(req): web::Query<RetentionRequest>,
+    ts: web::Path<TimeseriesInfo>,
+    data: web::Data<Arc<Mutex<TimeseriesDiskPersistenceManager>>>,
+) -> Result<HttpResponse, Error> {
+    let pm = data.lock().unwrap().clone();
+    let res = crate::functions::parse_duration(&req.keep).and_then(|keep| {
+        match pm.storages.lock().unwrap().get(&ts.timeseries) {
+            Some(storage) => pm.retention.set(&ts.timeseries, storage, Some(keep)),
+            None => Err(format!("Timeseries not found: {}", ts.timeseries)),
+        }
+    });
+    match res {
+        Ok(()) => Ok(HttpResponse::Ok()
+            .content_type("application/json")
+            .json(pm.retention.policy(&ts.timeseries))),
+        Err(e) => Ok(HttpResponse::BadRequest()
+            .content_type("application/json")
+            .body(format!("Retention error: {}", e))),
+    }
+}
+
+// Removes the policy set through the API, the configured one applies again
+// curl -X DELETE localhost:8086/retention/cpu
+#[delete("/retention/{timeseries}")]
+async fn delete_retention(
+    ts: web::Path<TimeseriesInfo>,
+    data: web::Data<Arc<Mutex<TimeseriesDiskPersistenceManager>>>,
+) -> Result<HttpResponse, Error> {
+    let pm = data.lock().unwrap().clone();
+    let res = match pm.storages.lock().unwrap().get(&ts.timeseries) {
+        Some(storage) => pm.retention.set(&ts.timeseries, storage, None),
+        None => Err(format!("Timeseries not found: {}", ts.timeseries)),
+    };
+    match res {
+        Ok(()) => Ok(HttpResponse::Ok()
+            .content_type("application/json")
+            .json(pm.retention.policy(&ts.timeseries))),
+        Err(e) => Ok(HttpResponse::NotFound()
+            .content_type("application/json")
+            .body(format!("Retention error: {}", e))),
+    }
+}
+
+// Runs the expiry now instead of waiting for the background task
+// curl -X POST localhost:8086/retention/expire
+#[post("/retention/expire")]
+async fn expire_retention(
+    data: web::Data<Arc<Mutex<TimeseriesDiskPersistenceManager>>>,
+) -> Result<HttpResponse, Error> {
+    let mut pm = data.lock().unwrap().clone();
+    let res = match web::block(move || crate::retention::expire(&mut pm)).await {
+        Ok(res) => res,
+        Err(e) => Err(format!("Retention error: {}", e)),
+    };
+    match res {
+        Ok(report) => Ok(HttpResponse::Ok()
+            .content_type("application/json")
+            .json(report)),
+        Err(e) => Ok(HttpResponse::InternalServerError()
+            .content_type("application/json")
+            .body(format!("Retention error: {}", e))),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RollupRequest {
+    name: String,       // cpu_1m
+    source: String,     // cpu
+    every: String,      // 1m
+    aggregates: String, // mean,max,min
+}
+
+#[derive(Deserialize)]
+struct RollupInfo {
+    name: String,
+}
+
+// Rollups, the end of their last completed window and the windows to compute again
+// curl localhost:8086/rollups
+#[get("/rollups")]
+async fn list_rollups(
+    data: web::Data<Arc<Mutex<TimeseriesDiskPersistenceManager>>>,
+) -> Result<HttpResponse, Error> {
+    let pm = data.lock().unwrap().clone();
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(pm.rollups.list()))
+}
+
+// curl -X POST localhost:8086/rollups -d name=cpu_1m -d source=cpu -d every=1m -d aggregates=mean,max,min
+#[post("/rollups")]
+async fn define_rollup(
+    form: web::Form<RollupRequest>,
+    data: web::Data<Arc<Mutex<TimeseriesDiskPersistenceManager>>>,
+) -> Result<HttpResponse, Error> {
+    let mut pm = data.lock().unwrap().clone();
+    let definition = crate::rollup::Definition {
+        name: form.name.clone(),
+        source: form.source.clone(),
+        every: form.every.clone(),
+        aggregates: form
+            .aggregates
+            .split(',')
+            .map(|a| a.trim().to_string())
+            .collect(),
+    };
+    match crate::rollup::define(&mut pm, definition) {
+        Ok(status) => Ok(HttpResponse::Ok()
+            .content_type("application/json")
+            .json(status)),
+        Err(e) => Ok(HttpResponse::BadRequest()
+            .content_type("application/json")
+            .body(format!("Rollup error: {}", e))),
+    }
+}
+
+// Stops computing a rollup, the points already computed are kept
+// curl -X DELETE localhost:8086/rollups/cpu_1m
+#[delete("/rollups/{name}")]
+async fn delete_rollup(
+    rollup: web::Path<RollupInfo>,
+    data: web::Data<Arc<Mutex<TimeseriesDiskPersistenceManager>>>,
+) -> Result<HttpResponse, Error> {
+    let pm = data.lock().unwrap().clone();
+    match crate::rollup::remove(&pm, &rollup.name) {
+        Ok(()) => Ok(HttpResponse::Ok()
+            .content_type("application/json")
+            .json(pm.rollups.list())),
+        Err(e) => Ok(HttpResponse::NotFound()
+            .content_type("application/json")
+            .body(format!("Rollup error: {}", e))),
+    }
+}
+
+// Computes the rollups now instead of waiting for the background task
+// curl -X POST localhost:8086/rollups/run
+#[post("/rollups/run")]
+async fn run_rollups(
+    data: web::Data<Arc<Mutex<TimeseriesDiskPersistenceManager>>>,
+) -> Result<HttpResponse, Error> {
+    let mut pm = data.lock().unwrap().clone();
+    let res = match web::block(move || crate::rollup::run(&mut pm)).await {
+        Ok(res) => res,
+        Err(e) => Err(format!("Rollup error: {}", e)),
+    };
+    match res {
+        Ok(report) => Ok(HttpResponse::Ok()
+            .content_type("application/json")
+            .json(report)),
+        Err(e) => Ok(HttpResponse::InternalServerError()
+            .content_type("application/json")
+            .body(format!("Rollup error: {}", e))),
+    }
+}
+
+// Time shards of a timeseries with their points, sealed once past their write window
+// curl localhost:8086/shards/cpu
+#[get("/shards/{timeseries}")]
+async fn list_shards(
+    ts: web::Path<TimeseriesInfo>,
+    data: web::Data<Arc<Mutex<TimeseriesDiskPersistenceManager>>>,
+) -> Result<HttpResponse, Error> {
+    let pm = data.lock().unwrap().clone();
+    let storage = match pm.storages.lock().unwrap().get(&ts.timeseries) {
+        Some(s) => s.clone(),
+        None => {
+            return Ok(HttpResponse::NotFound()
+                .content_type("application/json")
+                .body(format!("Timeseries not found: {}", ts.timeseries)));
+        }
+    };
+    match crate::shards::list(&storage) {
+        Ok(shards) => {
+            let shards: Vec<crate::shards::ShardInfo> = shards.iter().map(|s| s.info()).collect();
+            Ok(HttpResponse::Ok()
                 .content_type("application/json")
-                .body(format!("Query timeseries error: {}", e)));
+                .json(shards))
+        }
+        Err(e) => Ok(HttpResponse::InternalServerError()
+            .content_type("application/json")
+            .body(format!("Shard error: {}", e))),
+    }
+}
+
+// Seals the shards past their write window now instead of waiting for the background task
+// curl -X POST localhost:8086/shards/seal
+#[post("/shards/seal")]
+async fn seal_shards(
+    data: web::Data<Arc<Mutex<TimeseriesDiskPersistenceManager>>>,
+) -> Result<HttpResponse, Error> {
+    let pm = data.lock().unwrap().clone();
+    let res = match web::block(move || crate::shards::seal_all(&pm)).await {
+        Ok(res) => res,
+        Err(e) => Err(format!("Shard error: {}", e)),
+    };
+    match res {
+        Ok(sealed) => Ok(HttpResponse::Ok()
+            .content_type("application/json")
+            .json(sealed)),
+        Err(e) => Ok(HttpResponse::InternalServerError()
+            .content_type("application/json")
+            .body(format!("Shard error: {}", e))),
+    }
+}
+
+// SQL statements: federated when reading several timeseries, planned when using timeseries
+// functions, otherwise run by GlueSQL
+fn sql_query(
+    pm: &mut TimeseriesDiskPersistenceManager,
+    execution: &Execution,
+    page: Option<(Option<crate::cursor::Cursor>, usize)>,
+) -> Result<serde_json::Value, String> {
+    let series = match execution {
+        Execution::Federated(federation) => Some(crate::federation::execute(pm, federation)?),
+        Execution::Planned(plan) => Some(crate::query::execute(pm, plan)?),
+        Execution::Raw(_) => None,
+    };
+    if let Some(series) = series {
+        if page.is_some() {
+            return Err("Pagination applies to raw measurement queries".to_string());
         }
+        pm.check_returned(crate::query::count_rows(&series))?;
+        return Ok(serde_json::json!(series));
     }
+    let qs = match execution {
+        Execution::Raw(qs) => qs,
+        _ => return Err(format!("Unexpected execution: {:?}", execution)),
+    };
+    if let Some((cursor, limit)) = page {
+        let page = pm.query_measurement_page(qs.to_string(), cursor.as_ref(), limit)?;
+        pm.check_returned(page.data.len())?;
+        return Ok(serde_json::json!(page));
+    }
+    let rows = pm.query_measurements(qs.to_string())?;
+    pm.check_returned(rows.len())?;
+    Ok(serde_json::json!(format!("{:?}", rows)))
+}
+
+fn elapsed_ms(started: Instant) -> f64 {
+    started.elapsed().as_secs_f64() * 1000.0
 }
 
-// Consider this extremely insecure until proper SQL parsing and sanitization is implemented with read only storage.
+// EXPLAIN describes the plan and the reads of a query, EXPLAIN ANALYZE runs it as well and
+// reports the reads made, the rows scanned and returned and where the time went
+fn explain_query(
+    pm: &mut TimeseriesDiskPersistenceManager,
+    query: &str,
+    analyze: bool,
+    influxql: bool,
+) -> Result<Explain, String> {
+    enum Parsed {
+        Sql(Box<Execution>),
+        InfluxQL(Vec<crate::influxql::Statement>),
+    }
+    let started = Instant::now();
+    let parsed = match influxql {
+        true => Parsed::InfluxQL(crate::influxql::parse(query)?),
+        false => Parsed::Sql(Box::new(crate::query::plan_execution(query)?)),
+    };
+    let parse_ms = elapsed_ms(started);
+    let (plans, scans) = match &parsed {
+        Parsed::Sql(execution) => crate::explain::sql(pm, execution)?,
+        Parsed::InfluxQL(statements) => crate::explain::influxql(pm, statements)?,
+    };
+    if !analyze {
+        let mut explain = crate::explain::new(query, plans, scans);
+        for scan in explain.scans.iter_mut() {
+            crate::explain::pruning(pm, scan);
+        }
+        return Ok(explain);
+    }
+
+    let trace = Arc::new(Trace::default());
+    pm.trace = Some(trace.clone());
+    let started = Instant::now();
+    let body = match &parsed {
+        Parsed::Sql(execution) => sql_query(pm, execution, None)?,
+        Parsed::InfluxQL(statements) => {
+            let res = crate::influxql::execute_statements(pm, statements, None);
+            if let Some(e) = res.results.iter().find_map(|r| r.error.clone()) {
+                return Err(e);
+            }
+            serde_json::json!(res)
+        }
+    };
+    let execute_ms = elapsed_ms(started);
+    let started = Instant::now();
+    if let Err(e) = serde_json::to_vec(&body) {
+        return Err(format!("Error serializing the result: {}", e));
+    }
+    let serialize_ms = elapsed_ms(started);
+
+    let mut explain = crate::explain::new(query, plans, trace.scans());
+    for scan in explain.scans.iter_mut() {
+        crate::explain::pruning(pm, scan);
+    }
+    let status = pm.budget.as_ref().map(|b| b.status());
+    explain.analyze = Some(Analyze {
+        rows_scanned: status.as_ref().map_or(0, |s| s.rows_scanned),
+        bytes_scanned: status.as_ref().map_or(0, |s| s.bytes),
+        rows_returned: trace.rows_returned(),
+        parse_ms,
+        execute_ms,
+        serialize_ms,
+    });
+    Ok(explain)
+}
+
+// SQL queries are validated on the AST and run against read-only storage.
 // The timeseries is contained into the query and should be validated before going down the db sink
 #[post("/query")]
 async fn query_timeseries(
     form: web::Form<FormData>,
+    params: web::Query<HashMap<String, String>>,
     data: web::Data<Arc<Mutex<crate::persistence::TimeseriesDiskPersistenceManager>>>,
 ) -> Result<HttpResponse, Error> {
     // q -> query string
     let qs = form.q.clone();
     debug!("query string: {}", format!("{:?}", qs));
-    let mut pm = data.lock().unwrap().clone();
-    let pme = pm.query_measurements(qs.to_string());
-    match pme {
-        Ok(ret) => {
-            return Ok(HttpResponse::Ok()
-                .content_type("application/json")
-                .json(format!("{:?}", ret)));
+    let timeout = form
+        .timeout
+        .clone()
+        .or_else(|| params.get("timeout").cloned());
+    // InfluxDB 1.x clients always send the db parameter, SHOW statements share the InfluxQL executor
+    let explained = crate::explain::parse(&qs).map(|(analyze, q)| (analyze, q.to_string()));
+    let influxql = form.lang.as_deref() == Some("influxql")
+        || params.contains_key("db")
+        || crate::influxql::is_metadata_statement(
+            explained.as_ref().map_or(qs.as_str(), |(_, q)| q.as_str()),
+        );
+    let epoch = form.epoch.clone().or_else(|| params.get("epoch").cloned());
+    let raw = form.raw.unwrap_or(false) || params.get("raw").is_some_and(|r| r == "true");
+    let key = match explained {
+        Some(_) => None,
+        None => crate::cache::key(
+            &format!(
+                "query influxql={} epoch={:?} limit={:?} cursor={:?} raw={}",
+                influxql, epoch, form.limit, form.cursor, raw
+            ),
+            &qs,
+        ),
+    };
+    let page = crate::cursor::page_request(form.limit, form.cursor.as_deref());
+    let query = qs.clone();
+    let res = run_cached(&data, key, &qs, timeout.as_deref(), move |pm| {
+        pm.raw = raw;
+        let page = page?;
+        if let Some((analyze, q)) = explained {
+            return Ok(serde_json::json!(explain_query(pm, &q, analyze, influxql)?));
+        }
+        if influxql {
+            if page.is_some() {
+                return Err("Pagination applies to SQL queries".to_string());
+            }
+            let res = crate::influxql::execute(pm, &query, epoch.as_deref());
+            return Ok(serde_json::json!(res));
         }
+        sql_query(pm, &crate::query::plan_execution(&query)?, page)
+    })
+    .await;
+    match res {
+        Ok(body) => Ok(HttpResponse::Ok()
+            .content_type("application/json")
+            .json(body)),
         Err(e) => {
             info!("Error: Query timeseries error {}", e);
-            return Ok(HttpResponse::BadRequest()
+            Ok(HttpResponse::BadRequest()
+                .content_type("application/json")
+                .body(format!("Query timeseries error: {}", e)))
+        }
+    }
+}
+
+// InfluxDB 1.x compatible query endpoint
+// curl -G 'http://localhost:8086/query' --data-urlencode 'q=SELECT mean(value) FROM cpu GROUP BY time(1m)'
+#[get("/query")]
+async fn query_influxql(
+    web::Query(req): web::Query<InfluxQLRequest>,
+    data: web::Data<Arc<Mutex<crate::persistence::TimeseriesDiskPersistenceManager>>>,
+) -> Result<HttpResponse, Error> {
+    debug!("influxql query: {} db: {:?}", req.q, req.db);
+    let (query, epoch) = (req.q.clone(), req.epoch.clone());
+    let raw = req.raw.unwrap_or(false);
+    let key = match crate::explain::parse(&query) {
+        Some(_) => None,
+        None => crate::cache::key(&format!("influxql epoch={:?} raw={}", epoch, raw), &query),
+    };
+    let res = run_cached(&data, key, &req.q, req.timeout.as_deref(), move |pm| {
+        pm.raw = raw;
+        if let Some((analyze, q)) = crate::explain::parse(&query) {
+            return Ok(serde_json::json!(explain_query(pm, q, analyze, true)?));
+        }
+        let res = crate::influxql::execute(pm, &query, epoch.as_deref());
+        Ok(serde_json::json!(res))
+    })
+    .await;
+    match res {
+        Ok(res) => Ok(HttpResponse::Ok()
+            .content_type("application/json")
+            .json(res)),
+        Err(e) => Ok(HttpResponse::BadRequest()
+            .content_type("application/json")
+            .body(format!("Query timeseries error: {}", e))),
+    }
+}
+
+// Prometheus clients send parameters either in the query string or as a form body
+fn prometheus_params(req: &HttpRequest, body: &str) -> HashMap<String, String> {
+    let mut params: HashMap<String, String> = HashMap::new();
+    for qs in [req.query_string(), body] {
+        if let Ok(p) = web::Query::<HashMap<String, String>>::from_query(qs) {
+            params.extend(p.into_inner());
+        }
+    }
+    params
+}
+
+fn prometheus_response(res: Result<serde_json::Value, String>) -> HttpResponse {
+    match res {
+        Ok(body) => HttpResponse::Ok()
+            .content_type("application/json")
+            .json(body),
+        Err(e) => {
+            info!("Error: PromQL query error {}", e);
+            HttpResponse::BadRequest()
                 .content_type("application/json")
-                .body(format!("Query timeseries error: {}", e)));
+                .json(crate::promql::error(&e))
         }
     }
 }
 
+// curl 'http://localhost:8086/api/v1/query?query=sum by (host) (rate(cpu_usage[5m]))'
+#[route("/api/v1/query", method = "GET", method = "POST")]
+async fn prometheus_query(
+    req: HttpRequest,
+    body: String,
+    data: web::Data<Arc<Mutex<crate::persistence::TimeseriesDiskPersistenceManager>>>,
+) -> Result<HttpResponse, Error> {
+    let params = prometheus_params(&req, &body);
+    let q = match params.get("query") {
+        Some(q) => q.clone(),
+        None => {
+            return Ok(prometheus_response(Err(
+                "Missing query parameter".to_string()
+            )))
+        }
+    };
+    let time = match params.get("time") {
+        Some(t) => crate::promql::parse_time(t),
+        None => Ok(Utc::now().timestamp_millis()),
+    };
+    let query = q.clone();
+    let res = run_tracked(
+        &data,
+        &q,
+        params.get("timeout").map(|t| t.as_str()),
+        move |pm| {
+            let res = crate::promql::instant_query(pm, &query, time?)?;
+            pm.check_budget()?;
+            Ok(res)
+        },
+    )
+    .await;
+    Ok(prometheus_response(res))
+}
+
+// curl 'http://localhost:8086/api/v1/query_range?query=avg_over_time(cpu[5m])&start=1638316800&end=1638320400&step=60'
+#[route("/api/v1/query_range", method = "GET", method = "POST")]
+async fn prometheus_query_range(
+    req: HttpRequest,
+    body: String,
+    data: web::Data<Arc<Mutex<crate::persistence::TimeseriesDiskPersistenceManager>>>,
+) -> Result<HttpResponse, Error> {
+    let params = prometheus_params(&req, &body);
+    let parsed = (|| {
+        let param = |name: &str| match params.get(name) {
+            Some(v) => Ok(v.clone()),
+            None => Err(format!("Missing {} parameter", name)),
+        };
+        let start = crate::promql::parse_time(&param("start")?)?;
+        let end = crate::promql::parse_time(&param("end")?)?;
+        let step = crate::promql::parse_step(&param("step")?)?;
+        Ok((param("query")?, start, end, step))
+    })();
+    let (q, start, end, step) = match parsed {
+        Ok(p) => p,
+        Err(e) => return Ok(prometheus_response(Err(e))),
+    };
+    let query = q.clone();
+    let res = run_tracked(
+        &data,
+        &q,
+        params.get("timeout").map(|t| t.as_str()),
+        move |pm| {
+            let res = crate::promql::range_query(pm, &query, start, end, step)?;
+            pm.check_budget()?;
+            Ok(res)
+        },
+    )
+    .await;
+    Ok(prometheus_response(res))
+}
+
 /*
  * curl -i -XPOST 'http://localhost:8086/api/v2/write?bucket=db/rp&precision=ns' \
   --header 'Authorization: Token username:password' \
@@ -100,46 +1014,42 @@ async fn query_timeseries(
 #[post("/write")]
 async fn write_timeseries(
     req_body: String,
-    pm: web::Data<Arc<Mutex<crate::persistence::TimeseriesDiskPersistenceManager>>>,
+    ingest: web::Data<Arc<crate::ingest::Ingest>>,
 ) -> Result<HttpResponse, Error> {
     match crate::protocol::LineProtocol::parse(req_body.clone()) {
         Ok(b) => {
-            // persist
-            let mut htags: HashMap<String, String> = HashMap::new();
-            for key in b.tag_set.clone().keys() {
-                htags.insert(key.into(), b.tag_set.get(key).unwrap().into());
-            }
-            // One line for each measurement, represented b field_set
-            for field in b.field_set.clone() {
-                match pm.lock().unwrap().save_measurement(
-                    b.measurement_name.clone(),
-                    field.0.clone(),
-                    field.1.clone(),
-                    htags.clone(),
-                    true, // create db if it doesn't exists
-                ) {
-                    Ok(_) => info!(
-                        "Timeseries {} Measurement {} value {}",
-                        b.measurement_name.clone(),
-                        field.0.clone(),
-                        field.1.clone()
-                    ),
-                    Err(e) => {
-                        info!("Error writing measurement: {}", e);
-                        return Ok(HttpResponse::BadRequest()
-                            .content_type("application/json")
-                            .json(format!("Error writing measurement: {}", e)));
-                    }
-                };
-            }
-            return Ok(HttpResponse::Ok()
+            // queued, saved with the writes of the same batch
+            match ingest.write(&b, true).await {
+                // a line dropped over HTTP is refused as well, the client can retry it
+                Ok(crate::ingest::Outcome::Rejected) | Ok(crate::ingest::Outcome::Dropped) => {
+                    return Ok(HttpResponse::ServiceUnavailable()
+                        .content_type("application/json")
+                        .json("Ingest queue full".to_string()));
+                }
+                Ok(outcome) => debug!("Timeseries {}: {:?}", b.measurement_name, outcome),
+                Err(e) => {
+                    info!("Error writing measurement: {}", e);
+                    return Ok(HttpResponse::BadRequest()
+                        .content_type("application/json")
+                        .json(format!("Error writing measurement: {}", e)));
+                }
+            };
+            Ok(HttpResponse::Ok()
                 .content_type("application/json")
-                .json(format!("{:?}", b)));
-        }
-        Err(e) => {
-            return Ok(HttpResponse::BadRequest()
-                .content_type("application/json")
-                .json(format!("Error parsing protocol: {}", e)));
+                .json(format!("{:?}", b)))
         }
+        Err(e) => Ok(HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json(format!("Error parsing protocol: {}", e))),
     }
 }
+
+// Depth of the ingest queue and the writes it saved, dropped or refused
+#[get("/ingest")]
+async fn ingest_metrics(
+    ingest: web::Data<Arc<crate::ingest::Ingest>>,
+) -> Result<HttpResponse, Error> {
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(ingest.metrics()))
+}
@@ -0,0 +1,401 @@
+use crate::engine::{self, Point};
+use crate::persistence::{Measurement, TimeseriesDiskPersistenceManager};
+use crate::shards;
+use crate::wal::{self, Record, SyncPolicy, Wal};
+use gluesql::storages::SledStorage;
+use log::info;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Head block
+// The recent writes of each timeseries are held in memory, keyed as in the shards, and recent
+// reads merge them with the shards without going through sled. Every write is appended to the
+// write-ahead log (see wal.rs) first: the head is rebuilt from it on restart. The head is
+// flushed to the shards when it holds too many points, when its oldest write is too old and
+// before its shards are sealed. Rollups compute past windows again, they write to the shards.
+//   REFLUXDB_HEAD_MAX_POINTS=100000    (points held by the head of a timeseries)
+//   REFLUXDB_HEAD_MAX_AGE=15m
+
+pub const DEFAULT_MAX_POINTS: usize = 100_000;
+pub const DEFAULT_MAX_AGE: i64 = 900_000;
+pub const FLUSH_INTERVAL: i64 = 60_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Config {
+    pub max_points: usize,
+    pub max_age: i64, // milliseconds since the oldest write held
+    pub sync: SyncPolicy,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            max_points: DEFAULT_MAX_POINTS,
+            max_age: DEFAULT_MAX_AGE,
+            sync: SyncPolicy::default(),
+        }
+    }
+}
+
+pub fn config_from_env() -> Result<Config, String> {
+    let mut config = Config::default();
+    if let Ok(v) = std::env::var("REFLUXDB_HEAD_MAX_POINTS") {
+        config.max_points = match v.trim().parse() {
+            Ok(n) => n,
+            Err(_) => return Err(format!("Invalid head size: {}", v)),
+        };
+    }
+    if let Ok(v) = std::env::var("REFLUXDB_HEAD_MAX_AGE") {
+        config.max_age = crate::functions::parse_duration(&v)?;
+    }
+    config.sync = wal::policy_from_env()?;
+    Ok(config)
+}
+
+// The head of a timeseries
+#[derive(Debug)]
+pub struct TimeseriesHead {
+    points: Mutex<BTreeMap<Vec<u8>, Point>>,
+    wal: Mutex<Wal>,
+    flushing: Mutex<()>, // held by flushes and deletes, which change the shards
+}
+
+impl TimeseriesHead {
+    // Points from the key from (included) to the key to (excluded)
+    pub fn range(&self, from: Vec<u8>, to: Vec<u8>) -> VecDeque<(Vec<u8>, Point)> {
+        if from >= to {
+            return Default::default();
+        }
+        let points = self.points.lock().unwrap();
+        points
+            .range(from..to)
+            .map(|(k, p)| (k.clone(), *p))
+            .collect()
+    }
+
+    fn oldest(&self) -> Option<i64> {
+        let points = self.points.lock().unwrap();
+        points.values().map(|p| p.created_at).min()
+    }
+}
+
+// Deletes the points from start (included) to end (excluded)
+fn remove_range(points: &mut BTreeMap<Vec<u8>, Point>, start: Option<i64>, end: i64) -> usize {
+    let before = points.len();
+    points.retain(|key, _| match engine::decode_key(key) {
+        Ok((time, _)) => !(start.is_none_or(|s| time >= s) && time < end),
+        Err(_) => true,
+    });
+    before - points.len()
+}
+
+#[derive(Debug, Default)]
+pub struct Head {
+    config: Mutex<Config>,
+    heads: Mutex<HashMap<String, Arc<TimeseriesHead>>>,
+}
+
+impl Head {
+    pub fn configure(&self, config: Config) {
+        *self.config.lock().unwrap() = config;
+    }
+
+    pub fn config(&self) -> Config {
+        *self.config.lock().unwrap()
+    }
+
+    // Opens the log of the timeseries in its directory, with an empty head
+    pub fn open(&self, timeseries: &str, path: &str, storage: &SledStorage) -> Result<(), String> {
+        let wal = Wal::open(&Path::new(path).join("wal"), wal::applied(storage)?)?;
+        let head = TimeseriesHead {
+            points: Mutex::new(BTreeMap::new()),
+            wal: Mutex::new(wal),
+            flushing: Mutex::new(()),
+        };
+        self.heads
+            .lock()
+            .unwrap()
+            .insert(timeseries.to_string(), Arc::new(head));
+        Ok(())
+    }
+
+    // Replays the records of the log not applied to the shards, returns the points held
+    pub fn replay(&self, timeseries: &str, storage: &SledStorage) -> Result<usize, String> {
+        let head = self.head(timeseries)?;
+        let applied = wal::applied(storage)?;
+        let records: Vec<Record> = head
+            .wal
+            .lock()
+            .unwrap()
+            .records()?
+            .into_iter()
+            .filter(|(sequence, _)| applied.is_none_or(|a| *sequence > a))
+            .map(|(_, record)| record)
+            .collect();
+        let mut points = head.points.lock().unwrap();
+        for record in records.iter() {
+            match record {
+                Record::Point(key, point) => {
+                    points.insert(key.clone(), *point);
+                }
+                Record::Delete(start, end) => {
+                    remove_range(&mut points, *start, *end);
+                }
+            }
+        }
+        if !records.is_empty() {
+            info!(
+                "Replayed {} log records of {}: {} points in the head",
+                records.len(),
+                timeseries,
+                points.len()
+            );
+        }
+        Ok(points.len())
+    }
+
+    pub fn get(&self, timeseries: &str) -> Option<Arc<TimeseriesHead>> {
+        self.heads.lock().unwrap().get(timeseries).cloned()
+    }
+
+    fn head(&self, timeseries: &str) -> Result<Arc<TimeseriesHead>, String> {
+        match self.get(timeseries) {
+            Some(h) => Ok(h),
+            None => Err(format!("No head for {}", timeseries)),
+        }
+    }
+
+    // Logs the points, synced once when every write is, then holds them: the head is flushed
+    // once full
+    pub fn insert_all(
+        &self,
+        timeseries: &str,
+        storage: &SledStorage,
+        config: &shards::Config,
+        batch: &[Measurement],
+        created_at: i64,
+    ) -> Result<(), String> {
+        let head = self.head(timeseries)?;
+        let entries = batch
+            .iter()
+            .map(|m| engine::entry(storage, config, m, created_at))
+            .collect::<Result<Vec<(Vec<u8>, Point)>, String>>()?;
+        let sync = self.config().sync;
+        let policy = match sync {
+            SyncPolicy::Always => SyncPolicy::Batch(usize::MAX),
+            p => p,
+        };
+        let held = {
+            let mut wal = head.wal.lock().unwrap();
+            for (key, point) in entries.iter() {
+                wal.append(&Record::Point(key.clone(), *point), &policy)?;
+            }
+            if sync == SyncPolicy::Always {
+                wal.sync()?;
+            }
+            let mut points = head.points.lock().unwrap();
+            points.extend(entries);
+            points.len()
+        };
+        if held >= self.config().max_points {
+            self.flush(timeseries, storage, config)?;
+        }
+        Ok(())
+    }
+
+    /*
+    Saves the points of the head in the shards. The log starts a new segment as the points are
+    taken, the writes received meanwhile stay in the head and in the new segment. Once the points
+    are saved the last record taken is marked applied and the older segments are removed. Points
+    written to a shard sealed since reopen it, they were acknowledged.
+     */
+    pub fn flush(
+        &self,
+        timeseries: &str,
+        storage: &SledStorage,
+        config: &shards::Config,
+    ) -> Result<usize, String> {
+        let head = match self.get(timeseries) {
+            Some(h) => h,
+            None => return Ok(0),
+        };
+        let _flushing = head.flushing.lock().unwrap();
+        let (points, last, segment) = {
+            let mut wal = head.wal.lock().unwrap();
+            let points = head.points.lock().unwrap().clone();
+            if points.is_empty() {
+                return Ok(0);
+            }
+            (points, wal.last(), wal.rotate()?)
+        };
+        engine::save_logged(storage, config, points.iter())?;
+        wal::set_applied(storage, last)?;
+        if let Err(e) = storage.tree.flush() {
+            return Err(format!("Error flushing {}: {}", timeseries, e));
+        }
+        {
+            let mut held = head.points.lock().unwrap();
+            for key in points.keys() {
+                held.remove(key);
+            }
+        }
+        head.wal.lock().unwrap().remove_before(segment)?;
+        Ok(points.len())
+    }
+
+    // Deletes the points before the cutoff, from the head and the shards
+    pub fn expire(
+        &self,
+        timeseries: &str,
+        storage: &SledStorage,
+        cutoff: i64,
+    ) -> Result<usize, String> {
+        let head = match self.get(timeseries) {
+            Some(h) => h,
+            None => return engine::expire(storage, cutoff),
+        };
+        let _flushing = head.flushing.lock().unwrap();
+        let removed = {
+            let mut wal = head.wal.lock().unwrap();
+            wal.append(&Record::Delete(None, cutoff), &self.config().sync)?;
+            remove_range(&mut head.points.lock().unwrap(), None, cutoff)
+        };
+        Ok(removed + engine::expire(storage, cutoff)?)
+    }
+
+    // Syncs the records written since the last sync, for the interval policy
+    pub fn sync_all(&self) -> Result<(), String> {
+        let heads: Vec<Arc<TimeseriesHead>> =
+            self.heads.lock().unwrap().values().cloned().collect();
+        for head in heads {
+            head.wal.lock().unwrap().sync()?;
+        }
+        Ok(())
+    }
+}
+
+// Flushes the heads holding a write older than their maximum age
+pub fn flush_all(pm: &TimeseriesDiskPersistenceManager) -> Result<(), String> {
+    let storages: Vec<(String, SledStorage)> = pm
+        .storages
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    let now = chrono::Utc::now().timestamp_millis();
+    let max_age = pm.head.config().max_age;
+    for (timeseries, storage) in storages {
+        let oldest = match pm.head.get(&timeseries).and_then(|h| h.oldest()) {
+            Some(o) => o,
+            None => continue,
+        };
+        if oldest + max_age <= now {
+            let flushed = pm.head.flush(&timeseries, &storage, &pm.shards)?;
+            info!("Flushed {} points of the head of {}", flushed, timeseries);
+        }
+    }
+    Ok(())
+}
+
+// Syncs the logs at every interval of the policy, on the blocking thread pool
+pub async fn sync(pm: Arc<Mutex<TimeseriesDiskPersistenceManager>>, interval: i64) {
+    let mut ticks = actix_rt::time::interval(Duration::from_millis(interval as u64));
+    loop {
+        ticks.tick().await;
+        let head = pm.lock().unwrap().head.clone();
+        match actix_web::web::block(move || head.sync_all()).await {
+            Ok(Ok(_)) => (),
+            Ok(Err(e)) => info!("Log error: {}", e),
+            Err(e) => info!("Log error: {}", e),
+        }
+    }
+}
+
+// Flushes the heads at every interval, on the blocking thread pool
+pub async fn schedule(pm: Arc<Mutex<TimeseriesDiskPersistenceManager>>, interval: i64) {
+    let mut ticks = actix_rt::time::interval(Duration::from_millis(interval as u64));
+    loop {
+        ticks.tick().await;
+        let manager = pm.lock().unwrap().clone();
+        match actix_web::web::block(move || flush_all(&manager)).await {
+            Ok(Ok(_)) => (),
+            Ok(Err(e)) => info!("Head error: {}", e),
+            Err(e) => info!("Head error: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gluesql::sled;
+    use std::convert::TryFrom;
+    use uuid::Uuid;
+
+    #[test]
+    fn head_and_log() {
+        let dir = std::env::temp_dir().join(format!("refluxdb-head-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.to_str().unwrap();
+        let storage = SledStorage::try_from(sled::Config::default().temporary(true)).unwrap();
+        let config = shards::Config::default();
+        let head = Head::default();
+        head.open("cpu", path, &storage).unwrap();
+        for time in [1000, 2000, 3000] {
+            let m = Measurement {
+                key: time,
+                id: Uuid::new_v4(),
+                name: "usage".to_string(),
+                value: time as f64,
+                tags: HashMap::new(),
+            };
+            head.insert_all("cpu", &storage, &config, &[m], time)
+                .unwrap();
+        }
+        let held = |head: &Head| head.get("cpu").unwrap().range(vec![], vec![0xff; 33]).len();
+        let scanned = |head: &Head| {
+            engine::scan(&storage, head.get("cpu"), false, None)
+                .unwrap()
+                .count()
+        };
+        assert_eq!((held(&head), scanned(&head)), (3, 3));
+        assert_eq!(head.expire("cpu", &storage, 2000).unwrap(), 1);
+
+        // the log is replayed after a restart, deletes included
+        let restart = || {
+            let head = Head::default();
+            head.open("cpu", path, &storage).unwrap();
+            let replayed = head.replay("cpu", &storage).unwrap();
+            (head, replayed)
+        };
+        let (restarted, replayed) = restart();
+        assert_eq!(replayed, 2);
+        assert_eq!(restarted.flush("cpu", &storage, &config).unwrap(), 2);
+        assert_eq!((held(&restarted), scanned(&restarted)), (0, 2));
+        // the points flushed are counted once per shard
+        assert_eq!(shards::list(&storage).unwrap()[0].points, 2);
+        assert_eq!(std::fs::read_dir(dir.join("wal")).unwrap().count(), 1);
+        assert_eq!(restart().1, 0);
+
+        // records marked applied aren't replayed, when their segment outlived a crash
+        let m = Measurement {
+            key: 4000,
+            id: Uuid::new_v4(),
+            name: "usage".to_string(),
+            value: 4000.0,
+            tags: HashMap::new(),
+        };
+        let (restarted, _) = restart();
+        restarted
+            .insert_all("cpu", &storage, &config, &[m], 4000)
+            .unwrap();
+        assert_eq!(restart().1, 1);
+        let last = restarted.get("cpu").unwrap().wal.lock().unwrap().last();
+        wal::set_applied(&storage, last).unwrap();
+        assert_eq!(restart().1, 0);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,1471 @@
+use crate::calendar::{self, Period, Window};
+use crate::functions;
+use crate::metadata;
+use crate::persistence::TimeseriesDiskPersistenceManager;
+use crate::query::{
+    self, Comparison, Condition, Fill, Pattern, Projection, SelectPlan, Series, TagGrouping,
+};
+use chrono::{DateTime, Local, SecondsFormat, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+// InfluxQL front-end
+// Statements are parsed here and planned into query::SelectPlan, one per matching timeseries,
+// so they run over the same per timeseries storages as the SQL path.
+//   SELECT mean(usage) FROM /cpu.*/ WHERE host =~ /web.*/ AND time > now() - 1h
+//          GROUP BY time(5m), * fill(previous) LIMIT 100 SLIMIT 10
+//   SELECT sum(amount) FROM billing GROUP BY time(1mo) tz('Europe/Paris')
+//   SHOW MEASUREMENTS WITH MEASUREMENT =~ /cpu.*/
+//   SHOW TAG KEYS FROM cpu
+//   SHOW TAG VALUES FROM cpu WITH KEY = "host"
+//   SHOW FIELD KEYS FROM cpu
+// Tag conditions compare against strings, field conditions against numbers.
+// Integer time literals are nanoseconds since the epoch, as in InfluxDB.
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    QuotedIdent(String),
+    Str(String),
+    Integer(i64),
+    Number(f64),
+    Duration(i64),    // milliseconds
+    Calendar(Period), // months and years: 3mo, 1y
+    Regex(String),
+    Symbol(&'static str),
+    Eof,
+}
+
+const SYMBOLS: [&str; 18] = [
+    "=~", "!~", "!=", "<>", "<=", ">=", "::", "=", "<", ">", "+", "-", "*", ",", "(", ")", ";", ".",
+];
+
+fn tokenize(query: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = query.chars().collect();
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            let number: String = chars[start..i].iter().collect();
+            let unit_start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphabetic() || chars[i] == 'µ') {
+                i += 1;
+            }
+            let unit: String = chars[unit_start..i].iter().collect();
+            tokens.push(number_token(&number, &unit)?);
+        } else if c == '"' || c == '\'' || c == '/' {
+            let mut text = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => return Err(format!("Unterminated {} in: {}", c, query)),
+                    Some('\\') if chars.get(i + 1) == Some(&c) => {
+                        text.push(c);
+                        i += 2;
+                    }
+                    Some(ch) if *ch == c => {
+                        i += 1;
+                        break;
+                    }
+                    Some(ch) => {
+                        text.push(*ch);
+                        i += 1;
+                    }
+                }
+            }
+            tokens.push(match c {
+                '"' => Token::QuotedIdent(text),
+                '\'' => Token::Str(text),
+                _ => Token::Regex(text),
+            });
+        } else {
+            let rest: String = chars[i..chars.len().min(i + 2)].iter().collect();
+            match SYMBOLS.iter().find(|s| rest.starts_with(*s)) {
+                Some(s) => {
+                    tokens.push(Token::Symbol(s));
+                    i += s.len();
+                }
+                None => return Err(format!("Unexpected character '{}' in: {}", c, query)),
+            }
+        }
+    }
+    tokens.push(Token::Eof);
+    Ok(tokens)
+}
+
+fn number_token(number: &str, unit: &str) -> Result<Token, String> {
+    if unit.is_empty() {
+        if let Ok(i) = number.parse::<i64>() {
+            return Ok(Token::Integer(i));
+        }
+        return match number.parse::<f64>() {
+            Ok(n) => Ok(Token::Number(n)),
+            Err(_) => Err(format!("Invalid number: {}", number)),
+        };
+    }
+    let amount = match number.parse::<i64>() {
+        Ok(a) => a,
+        Err(_) => return Err(format!("Invalid duration: {}{}", number, unit)),
+    };
+    match unit {
+        "mo" | "y" => Ok(Token::Calendar(Period::parse(&format!(
+            "{}{}",
+            amount, unit
+        ))?)),
+        "ns" => Ok(Token::Duration(amount / 1_000_000)),
+        "u" | "µ" => Ok(Token::Duration(amount / 1_000)),
+        _ => Ok(Token::Duration(functions::parse_duration(&format!(
+            "{}{}",
+            amount, unit
+        ))?)),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BinaryOp {
+    And,
+    Or,
+    Compare(Comparison),
+    Match,
+    NotMatch,
+    Add,
+    Sub,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Ident(String),
+    Str(String),
+    Integer(i64),
+    Number(f64),
+    Duration(i64),
+    Regex(String),
+    Wildcard,
+    Call(String, Vec<Expr>),
+    Binary(Box<Expr>, BinaryOp, Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    pub expr: Expr,
+    pub alias: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    Name(String),
+    Regex(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Dimension {
+    Time(Period),
+    Tag(String),
+    All,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelectStatement {
+    pub fields: Vec<Field>,
+    pub sources: Vec<Source>,
+    pub condition: Option<Expr>,
+    pub dimensions: Vec<Dimension>,
+    pub fill: Option<Fill>,
+    pub stale: Option<i64>, // fill(linear, 10m): gaps longer aren't filled
+    pub tz: Option<Tz>,     // tz('Europe/Paris'): windows and times in the zone's local time
+    pub descending: bool,
+    pub limit: Option<usize>,
+    pub offset: usize,
+    pub slimit: Option<usize>,
+    pub soffset: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TagKeyFilter {
+    Eq(String),
+    In(Vec<String>),
+    Regex(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Statement {
+    Select(SelectStatement),
+    ShowMeasurements {
+        filter: Option<Source>,
+        condition: Option<Expr>,
+        limit: Option<usize>,
+        offset: usize,
+    },
+    ShowTagKeys {
+        sources: Vec<Source>,
+        key: Option<TagKeyFilter>,
+        condition: Option<Expr>,
+        limit: Option<usize>,
+        offset: usize,
+    },
+    ShowTagValues {
+        sources: Vec<Source>,
+        key: TagKeyFilter,
+        condition: Option<Expr>,
+        limit: Option<usize>,
+        offset: usize,
+    },
+    ShowFieldKeys {
+        sources: Vec<Source>,
+        condition: Option<Expr>,
+        limit: Option<usize>,
+        offset: usize,
+    },
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos.min(self.tokens.len() - 1)]
+    }
+
+    fn next(&mut self) -> Token {
+        let t = self.peek().clone();
+        if self.pos < self.tokens.len() {
+            self.pos += 1;
+        }
+        t
+    }
+
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Token::Ident(i) if i.eq_ignore_ascii_case(keyword))
+    }
+
+    fn keyword(&mut self, keyword: &str) -> bool {
+        if self.peek_keyword(keyword) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), String> {
+        if self.keyword(keyword) {
+            return Ok(());
+        }
+        Err(format!("Expected {}, found {:?}", keyword, self.peek()))
+    }
+
+    fn symbol(&mut self, symbol: &str) -> bool {
+        if *self.peek() == Token::Symbol(symbol_ref(symbol)) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect_symbol(&mut self, symbol: &str) -> Result<(), String> {
+        if self.symbol(symbol) {
+            return Ok(());
+        }
+        Err(format!("Expected {}, found {:?}", symbol, self.peek()))
+    }
+
+    fn identifier(&mut self) -> Result<String, String> {
+        match self.next() {
+            Token::Ident(i) | Token::QuotedIdent(i) => Ok(i),
+            t => Err(format!("Expected an identifier, found {:?}", t)),
+        }
+    }
+
+    fn unsigned(&mut self) -> Result<usize, String> {
+        match self.next() {
+            Token::Integer(i) if i >= 0 => Ok(i as usize),
+            t => Err(format!("Expected a positive integer, found {:?}", t)),
+        }
+    }
+
+    fn statement(&mut self) -> Result<Statement, String> {
+        if self.keyword("SELECT") {
+            return self.select().map(Statement::Select);
+        }
+        if self.keyword("SHOW") {
+            return self.show();
+        }
+        Err(format!("Unsupported statement: {:?}", self.peek()))
+    }
+
+    fn select(&mut self) -> Result<SelectStatement, String> {
+        let mut fields: Vec<Field> = Vec::new();
+        loop {
+            let expr = self.field_expr()?;
+            let alias = if self.keyword("AS") {
+                Some(self.identifier()?)
+            } else {
+                None
+            };
+            fields.push(Field { expr, alias });
+            if !self.symbol(",") {
+                break;
+            }
+        }
+        self.expect_keyword("FROM")?;
+        let mut stmt = SelectStatement {
+            fields,
+            sources: self.sources()?,
+            condition: None,
+            dimensions: vec![],
+            fill: None,
+            stale: None,
+            tz: None,
+            descending: false,
+            limit: None,
+            offset: 0,
+            slimit: None,
+            soffset: 0,
+        };
+        if self.keyword("WHERE") {
+            stmt.condition = Some(self.expr()?);
+        }
+        loop {
+            if self.keyword("GROUP") {
+                self.expect_keyword("BY")?;
+                stmt.dimensions = self.dimensions()?;
+            } else if self.keyword("FILL") {
+                let (fill, stale) = self.fill()?;
+                stmt.fill = Some(fill);
+                stmt.stale = stale;
+            } else if self.keyword("TZ") {
+                self.expect_symbol("(")?;
+                stmt.tz = match self.next() {
+                    Token::Str(name) => Some(calendar::timezone(&name)?),
+                    t => return Err(format!("Expected a time zone in tz(), found {:?}", t)),
+                };
+                self.expect_symbol(")")?;
+            } else if self.keyword("ORDER") {
+                self.expect_keyword("BY")?;
+                self.expect_keyword("time")?;
+                if self.keyword("DESC") {
+                    stmt.descending = true;
+                } else {
+                    self.keyword("ASC");
+                }
+            } else if self.keyword("LIMIT") {
+                stmt.limit = Some(self.unsigned()?);
+            } else if self.keyword("OFFSET") {
+                stmt.offset = self.unsigned()?;
+            } else if self.keyword("SLIMIT") {
+                stmt.slimit = Some(self.unsigned()?);
+            } else if self.keyword("SOFFSET") {
+                stmt.soffset = self.unsigned()?;
+            } else {
+                break;
+            }
+        }
+        Ok(stmt)
+    }
+
+    fn field_expr(&mut self) -> Result<Expr, String> {
+        let expr = match self.next() {
+            Token::Symbol("*") => Expr::Wildcard,
+            Token::Ident(name) if self.symbol("(") => {
+                let mut args: Vec<Expr> = Vec::new();
+                if !self.symbol(")") {
+                    loop {
+                        args.push(self.field_expr()?);
+                        if self.symbol(")") {
+                            break;
+                        }
+                        self.expect_symbol(",")?;
+                    }
+                }
+                Expr::Call(name.to_lowercase(), args)
+            }
+            Token::Ident(name) | Token::QuotedIdent(name) => Expr::Ident(name),
+            Token::Integer(i) => Expr::Integer(i),
+            Token::Number(n) => Expr::Number(n),
+            Token::Duration(d) => Expr::Duration(d),
+            Token::Str(s) => Expr::Str(s),
+            t => return Err(format!("Unexpected {:?} in field list", t)),
+        };
+        // type casts such as "usage"::field are accepted and ignored
+        if self.symbol("::") {
+            self.identifier()?;
+        }
+        Ok(expr)
+    }
+
+    fn sources(&mut self) -> Result<Vec<Source>, String> {
+        let mut sources: Vec<Source> = Vec::new();
+        loop {
+            match self.next() {
+                Token::Regex(r) => sources.push(Source::Regex(r)),
+                Token::Ident(mut name) | Token::QuotedIdent(mut name) => {
+                    // "db"."retention policy"."measurement": only the measurement is used
+                    while self.symbol(".") {
+                        name = self.identifier()?;
+                    }
+                    sources.push(Source::Name(name))
+                }
+                t => return Err(format!("Expected a measurement, found {:?}", t)),
+            }
+            if !self.symbol(",") {
+                break;
+            }
+        }
+        Ok(sources)
+    }
+
+    fn dimensions(&mut self) -> Result<Vec<Dimension>, String> {
+        let mut dimensions: Vec<Dimension> = Vec::new();
+        loop {
+            if self.symbol("*") {
+                dimensions.push(Dimension::All);
+            } else if self.peek_keyword("time")
+                && self.tokens.get(self.pos + 1) == Some(&Token::Symbol("("))
+            {
+                self.pos += 2;
+                let interval = match self.next() {
+                    Token::Duration(d) if d > 0 => Period::Fixed(d),
+                    Token::Calendar(p) => p,
+                    t => return Err(format!("Expected a duration in time(), found {:?}", t)),
+                };
+                self.expect_symbol(")")?;
+                dimensions.push(Dimension::Time(interval));
+            } else {
+                dimensions.push(Dimension::Tag(self.identifier()?));
+            }
+            if !self.symbol(",") {
+                break;
+            }
+        }
+        Ok(dimensions)
+    }
+
+    fn fill(&mut self) -> Result<(Fill, Option<i64>), String> {
+        self.expect_symbol("(")?;
+        let negative = self.symbol("-");
+        let sign = if negative { -1.0 } else { 1.0 };
+        let fill = match self.next() {
+            Token::Ident(i) if i.eq_ignore_ascii_case("null") => Fill::Null,
+            Token::Ident(i) if i.eq_ignore_ascii_case("none") => Fill::None,
+            Token::Ident(i) if i.eq_ignore_ascii_case("previous") => Fill::Previous,
+            Token::Ident(i) if i.eq_ignore_ascii_case("linear") => Fill::Linear,
+            Token::Integer(i) => Fill::Value(sign * i as f64),
+            Token::Number(n) => Fill::Value(sign * n),
+            t => return Err(format!("Invalid fill option {:?}", t)),
+        };
+        let mut stale = None;
+        if self.symbol(",") {
+            stale = match self.next() {
+                Token::Duration(d) if d > 0 => Some(d),
+                t => return Err(format!("Expected a staleness duration, found {:?}", t)),
+            };
+        }
+        self.expect_symbol(")")?;
+        Ok((fill, stale))
+    }
+
+    fn show(&mut self) -> Result<Statement, String> {
+        if self.keyword("MEASUREMENTS") {
+            let mut filter = None;
+            if self.keyword("WITH") {
+                self.expect_keyword("MEASUREMENT")?;
+                filter = Some(if self.symbol("=~") {
+                    match self.next() {
+                        Token::Regex(r) => Source::Regex(r),
+                        t => return Err(format!("Expected a regex, found {:?}", t)),
+                    }
+                } else {
+                    self.expect_symbol("=")?;
+                    Source::Name(self.identifier()?)
+                });
+            }
+            let condition = self.condition()?;
+            let (limit, offset) = self.limit_offset()?;
+            return Ok(Statement::ShowMeasurements {
+                filter,
+                condition,
+                limit,
+                offset,
+            });
+        }
+        let field_keys = self.keyword("FIELD");
+        if !field_keys {
+            self.expect_keyword("TAG")?;
+        }
+        let values = !field_keys && self.keyword("VALUES");
+        if !values {
+            self.expect_keyword("KEYS")?;
+        }
+        let sources = if self.keyword("FROM") {
+            self.sources()?
+        } else {
+            vec![]
+        };
+        if field_keys {
+            let condition = self.condition()?;
+            let (limit, offset) = self.limit_offset()?;
+            return Ok(Statement::ShowFieldKeys {
+                sources,
+                condition,
+                limit,
+                offset,
+            });
+        }
+        let key = self.tag_key_filter()?;
+        let condition = self.condition()?;
+        let (limit, offset) = self.limit_offset()?;
+        if values {
+            return match key {
+                Some(key) => Ok(Statement::ShowTagValues {
+                    sources,
+                    key,
+                    condition,
+                    limit,
+                    offset,
+                }),
+                None => Err("SHOW TAG VALUES requires WITH KEY".to_string()),
+            };
+        }
+        Ok(Statement::ShowTagKeys {
+            sources,
+            key,
+            condition,
+            limit,
+            offset,
+        })
+    }
+
+    // WITH KEY = "host", WITH KEY =~ /h.*/ or WITH KEY IN ("host", "region")
+    fn tag_key_filter(&mut self) -> Result<Option<TagKeyFilter>, String> {
+        if !self.keyword("WITH") {
+            return Ok(None);
+        }
+        self.expect_keyword("KEY")?;
+        if self.symbol("=~") {
+            return match self.next() {
+                Token::Regex(r) => Ok(Some(TagKeyFilter::Regex(r))),
+                t => Err(format!("Expected a regex, found {:?}", t)),
+            };
+        }
+        if self.keyword("IN") {
+            self.expect_symbol("(")?;
+            let mut keys: Vec<String> = vec![self.identifier()?];
+            while self.symbol(",") {
+                keys.push(self.identifier()?);
+            }
+            self.expect_symbol(")")?;
+            return Ok(Some(TagKeyFilter::In(keys)));
+        }
+        self.expect_symbol("=")?;
+        Ok(Some(TagKeyFilter::Eq(self.identifier()?)))
+    }
+
+    fn condition(&mut self) -> Result<Option<Expr>, String> {
+        if self.keyword("WHERE") {
+            return Ok(Some(self.expr()?));
+        }
+        Ok(None)
+    }
+
+    fn limit_offset(&mut self) -> Result<(Option<usize>, usize), String> {
+        let mut limit = None;
+        let mut offset = 0;
+        loop {
+            if self.keyword("LIMIT") {
+                limit = Some(self.unsigned()?);
+            } else if self.keyword("OFFSET") {
+                offset = self.unsigned()?;
+            } else {
+                return Ok((limit, offset));
+            }
+        }
+    }
+
+    // condition expressions: OR < AND < comparisons < + -
+    fn expr(&mut self) -> Result<Expr, String> {
+        let mut left = self.and_expr()?;
+        while self.keyword("OR") {
+            let right = self.and_expr()?;
+            left = Expr::Binary(Box::new(left), BinaryOp::Or, Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn and_expr(&mut self) -> Result<Expr, String> {
+        let mut left = self.comparison()?;
+        while self.keyword("AND") {
+            let right = self.comparison()?;
+            left = Expr::Binary(Box::new(left), BinaryOp::And, Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn comparison(&mut self) -> Result<Expr, String> {
+        let left = self.additive()?;
+        let op = match self.peek() {
+            Token::Symbol("=") => BinaryOp::Compare(Comparison::Eq),
+            Token::Symbol("!=") | Token::Symbol("<>") => BinaryOp::Compare(Comparison::NotEq),
+            Token::Symbol("<") => BinaryOp::Compare(Comparison::Lt),
+            Token::Symbol("<=") => BinaryOp::Compare(Comparison::LtEq),
+            Token::Symbol(">") => BinaryOp::Compare(Comparison::Gt),
+            Token::Symbol(">=") => BinaryOp::Compare(Comparison::GtEq),
+            Token::Symbol("=~") => BinaryOp::Match,
+            Token::Symbol("!~") => BinaryOp::NotMatch,
+            _ => return Ok(left),
+        };
+        self.pos += 1;
+        let right = self.additive()?;
+        Ok(Expr::Binary(Box::new(left), op, Box::new(right)))
+    }
+
+    fn additive(&mut self) -> Result<Expr, String> {
+        let mut left = self.primary()?;
+        loop {
+            let op = if self.symbol("+") {
+                BinaryOp::Add
+            } else if self.symbol("-") {
+                BinaryOp::Sub
+            } else {
+                return Ok(left);
+            };
+            let right = self.primary()?;
+            left = Expr::Binary(Box::new(left), op, Box::new(right));
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Token::Symbol("(") => {
+                let e = self.expr()?;
+                self.expect_symbol(")")?;
+                Ok(e)
+            }
+            Token::Symbol("-") => match self.next() {
+                Token::Integer(i) => Ok(Expr::Integer(-i)),
+                Token::Number(n) => Ok(Expr::Number(-n)),
+                Token::Duration(d) => Ok(Expr::Duration(-d)),
+                t => Err(format!("Unexpected {:?} after -", t)),
+            },
+            Token::Ident(name) if self.symbol("(") => {
+                self.expect_symbol(")")?;
+                Ok(Expr::Call(name.to_lowercase(), vec![]))
+            }
+            Token::Ident(name) | Token::QuotedIdent(name) => {
+                if self.symbol("::") {
+                    self.identifier()?;
+                }
+                Ok(Expr::Ident(name))
+            }
+            Token::Str(s) => Ok(Expr::Str(s)),
+            Token::Integer(i) => Ok(Expr::Integer(i)),
+            Token::Number(n) => Ok(Expr::Number(n)),
+            Token::Duration(d) => Ok(Expr::Duration(d)),
+            Token::Regex(r) => Ok(Expr::Regex(r)),
+            t => Err(format!("Unexpected {:?} in condition", t)),
+        }
+    }
+}
+
+fn symbol_ref(symbol: &str) -> &'static str {
+    SYMBOLS
+        .iter()
+        .find(|s| **s == symbol)
+        .cloned()
+        .unwrap_or("")
+}
+
+pub fn parse(query: &str) -> Result<Vec<Statement>, String> {
+    let mut parser = Parser {
+        tokens: tokenize(query)?,
+        pos: 0,
+    };
+    let mut statements: Vec<Statement> = Vec::new();
+    loop {
+        while parser.symbol(";") {}
+        if *parser.peek() == Token::Eof {
+            break;
+        }
+        statements.push(parser.statement()?);
+        if !parser.symbol(";") && *parser.peek() != Token::Eof {
+            return Err(format!("Unexpected {:?} after statement", parser.peek()));
+        }
+    }
+    if statements.is_empty() {
+        return Err("Empty query".to_string());
+    }
+    Ok(statements)
+}
+
+// Planning
+
+// Function calls are written back as SQL so they share the parsing of the SQL path
+fn sql_expr(expr: &Expr) -> Result<String, String> {
+    match expr {
+        Expr::Ident(i) => Ok(format!("\"{}\"", i)),
+        Expr::Wildcard => Ok("*".to_string()),
+        Expr::Integer(i) => Ok(i.to_string()),
+        Expr::Number(n) => Ok(n.to_string()),
+        Expr::Duration(d) => Ok(format!("'{}ms'", d)),
+        Expr::Str(s) => Ok(format!("'{}'", s.replace('\'', "''"))),
+        Expr::Call(name, args) => {
+            let args: Result<Vec<String>, String> = args.iter().map(sql_expr).collect();
+            Ok(format!("\"{}\"({})", name, args?.join(", ")))
+        }
+        e => Err(format!("Unsupported function argument {:?}", e)),
+    }
+}
+
+fn projection(field: &Field) -> Result<Option<Projection>, String> {
+    let mut p = match &field.expr {
+        Expr::Ident(name) if name == "time" => return Ok(None),
+        Expr::Ident(name) => Projection {
+            function: None,
+            aggregate: None,
+            selector: None,
+            field: Some(name.clone()),
+            alias: None,
+        },
+        Expr::Wildcard => Projection {
+            function: None,
+            aggregate: None,
+            selector: None,
+            field: None,
+            alias: None,
+        },
+        Expr::Call(name, _) => {
+            let sql = sql_expr(&field.expr)?;
+            match gluesql::parse_sql::parse_expr(&sql) {
+                Ok(gluesql::sqlparser::ast::Expr::Function(f)) => {
+                    match query::parse_function(&f)? {
+                        Some(p) => p,
+                        None => return Err(format!("Unsupported function: {}", name)),
+                    }
+                }
+                _ => return Err(format!("Unsupported function: {}", name)),
+            }
+        }
+        e => return Err(format!("Unsupported field {:?}", e)),
+    };
+    p.alias = field.alias.clone();
+    Ok(Some(p))
+}
+
+fn is_time(expr: &Expr) -> bool {
+    matches!(expr, Expr::Ident(i) if i.eq_ignore_ascii_case("time"))
+}
+
+// Evaluates now() - 1h, '2021-11-30T10:00:00Z', 1638316800000ms or nanosecond integers
+fn time_value(expr: &Expr, now: i64) -> Result<i64, String> {
+    match expr {
+        Expr::Call(name, args) if name == "now" && args.is_empty() => Ok(now),
+        Expr::Str(s) => match s.parse::<DateTime<Utc>>() {
+            Ok(t) => Ok(t.timestamp_millis()),
+            Err(e) => Err(format!("Invalid time {}: {}", s, e)),
+        },
+        Expr::Integer(ns) => Ok(ns / 1_000_000),
+        Expr::Duration(ms) => Ok(*ms),
+        Expr::Binary(l, BinaryOp::Add, r) => Ok(time_value(l, now)? + time_value(r, now)?),
+        Expr::Binary(l, BinaryOp::Sub, r) => Ok(time_value(l, now)? - time_value(r, now)?),
+        e => Err(format!("Invalid time expression {:?}", e)),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TimeRange {
+    pub start: Option<i64>, // inclusive
+    pub end: Option<i64>,   // exclusive
+}
+
+impl TimeRange {
+    fn restrict(&mut self, op: Comparison, time: i64) -> Result<(), String> {
+        let (start, end) = match op {
+            Comparison::Gt => (Some(time + 1), None),
+            Comparison::GtEq => (Some(time), None),
+            Comparison::Lt => (None, Some(time)),
+            Comparison::LtEq => (None, Some(time + 1)),
+            Comparison::Eq => (Some(time), Some(time + 1)),
+            Comparison::NotEq => return Err("time != is not supported".to_string()),
+        };
+        if let Some(s) = start {
+            self.start = Some(self.start.map_or(s, |c| c.max(s)));
+        }
+        if let Some(e) = end {
+            self.end = Some(self.end.map_or(e, |c| c.min(e)));
+        }
+        Ok(())
+    }
+}
+
+fn flip(op: Comparison) -> Comparison {
+    match op {
+        Comparison::Lt => Comparison::Gt,
+        Comparison::LtEq => Comparison::GtEq,
+        Comparison::Gt => Comparison::Lt,
+        Comparison::GtEq => Comparison::LtEq,
+        op => op,
+    }
+}
+
+// Splits a WHERE clause into the time range and the remaining row condition.
+// Time comparisons must be part of the top level AND.
+pub fn split_condition(
+    expr: &Expr,
+    now: i64,
+    range: &mut TimeRange,
+) -> Result<Option<Condition>, String> {
+    match expr {
+        Expr::Binary(l, BinaryOp::And, r) => {
+            let l = split_condition(l, now, range)?;
+            let r = split_condition(r, now, range)?;
+            Ok(match (l, r) {
+                (Some(l), Some(r)) => Some(Condition::And(Box::new(l), Box::new(r))),
+                (l, None) => l,
+                (None, r) => r,
+            })
+        }
+        Expr::Binary(l, BinaryOp::Compare(op), r) if is_time(l) => {
+            range.restrict(*op, time_value(r, now)?)?;
+            Ok(None)
+        }
+        Expr::Binary(l, BinaryOp::Compare(op), r) if is_time(r) => {
+            range.restrict(flip(*op), time_value(l, now)?)?;
+            Ok(None)
+        }
+        e => condition(e).map(Some),
+    }
+}
+
+fn condition(expr: &Expr) -> Result<Condition, String> {
+    match expr {
+        Expr::Binary(l, BinaryOp::And, r) => Ok(Condition::And(
+            Box::new(condition(l)?),
+            Box::new(condition(r)?),
+        )),
+        Expr::Binary(l, BinaryOp::Or, r) => Ok(Condition::Or(
+            Box::new(condition(l)?),
+            Box::new(condition(r)?),
+        )),
+        Expr::Binary(l, op, r) => {
+            let key = match l.as_ref() {
+                Expr::Ident(k) if is_time(l) => {
+                    return Err(format!("time conditions can't be used within OR: {}", k))
+                }
+                Expr::Ident(k) => k.clone(),
+                e => return Err(format!("Expected a tag or field name, found {:?}", e)),
+            };
+            match (op, r.as_ref()) {
+                (BinaryOp::Compare(op), Expr::Str(value)) => Ok(Condition::Tag {
+                    key,
+                    op: *op,
+                    value: value.clone(),
+                }),
+                (BinaryOp::Compare(op), Expr::Integer(value)) => Ok(Condition::Field {
+                    name: key,
+                    op: *op,
+                    value: *value as f64,
+                }),
+                (BinaryOp::Compare(op), Expr::Number(value)) => Ok(Condition::Field {
+                    name: key,
+                    op: *op,
+                    value: *value,
+                }),
+                (BinaryOp::Match, Expr::Regex(r)) | (BinaryOp::NotMatch, Expr::Regex(r)) => {
+                    Ok(Condition::TagMatch {
+                        key,
+                        pattern: Pattern::new(r)?,
+                        negated: *op == BinaryOp::NotMatch,
+                    })
+                }
+                _ => Err(format!("Unsupported condition {:?}", expr)),
+            }
+        }
+        e => Err(format!("Unsupported condition {:?}", e)),
+    }
+}
+
+pub fn resolve_sources(sources: &[Source], timeseries: &[String]) -> Result<Vec<String>, String> {
+    let mut names: Vec<String> = Vec::new();
+    for source in sources {
+        match source {
+            Source::Name(n) => {
+                if timeseries.contains(n) {
+                    names.push(n.clone())
+                }
+            }
+            Source::Regex(r) => {
+                let pattern = Pattern::new(r)?;
+                names.extend(timeseries.iter().filter(|t| pattern.is_match(t)).cloned());
+            }
+        }
+    }
+    names.sort();
+    names.dedup();
+    Ok(names)
+}
+
+// Plans a SELECT over each of the given timeseries
+pub fn plan_select(
+    stmt: &SelectStatement,
+    timeseries: &[String],
+    now: i64,
+) -> Result<Vec<SelectPlan>, String> {
+    let mut projections: Vec<Projection> = Vec::new();
+    for field in stmt.fields.iter() {
+        if let Some(p) = projection(field)? {
+            projections.push(p);
+        }
+    }
+    if projections.is_empty() {
+        return Err("At least one field is required".to_string());
+    }
+    let aggregated = projections.iter().filter(|p| p.is_aggregate()).count();
+    if aggregated > 0 && aggregated < projections.len() {
+        return Err("Aggregates can't be mixed with non aggregated fields".to_string());
+    }
+    if projections.len() > 1 && projections.iter().any(|p| p.selector.is_some()) {
+        return Err("top and bottom can't be combined with other fields".to_string());
+    }
+
+    let mut range = TimeRange::default();
+    let condition = match &stmt.condition {
+        Some(c) => split_condition(c, now, &mut range)?,
+        None => None,
+    };
+
+    let mut interval: Option<Period> = None;
+    let mut tags: Vec<String> = Vec::new();
+    let mut all = false;
+    for d in stmt.dimensions.iter() {
+        match d {
+            Dimension::Time(i) => interval = Some(*i),
+            Dimension::Tag(t) => tags.push(t.clone()),
+            Dimension::All => all = true,
+        }
+    }
+    if interval.is_some() && aggregated == 0 {
+        return Err("GROUP BY time() requires an aggregate".to_string());
+    }
+    let grouping = if all || (aggregated == 0 && tags.is_empty()) {
+        TagGrouping::Series
+    } else {
+        TagGrouping::Tags(tags)
+    };
+    let fill = match &stmt.fill {
+        Some(f) => f.clone(),
+        None => Fill::Null,
+    };
+
+    Ok(timeseries
+        .iter()
+        .map(|ts| {
+            let mut plan = SelectPlan::new(ts.clone(), projections.clone());
+            plan.condition = condition.clone();
+            plan.start = range.start;
+            plan.end = range.end;
+            plan.group_by_time = interval.map(|p| Window::new(p, stmt.tz));
+            plan.group_by_tags = grouping.clone();
+            plan.fill = fill.clone();
+            plan.stale = stmt.stale;
+            plan.descending = stmt.descending;
+            plan.limit = stmt.limit;
+            plan.offset = stmt.offset;
+            plan
+        })
+        .collect())
+}
+
+// Execution
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct StatementResult {
+    pub statement_id: usize,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub series: Vec<Series>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct QueryResponse {
+    pub results: Vec<StatementResult>,
+}
+
+fn page<T>(items: Vec<T>, limit: Option<usize>, offset: usize) -> Vec<T> {
+    let items = items.into_iter().skip(offset);
+    match limit {
+        Some(l) => items.take(l).collect(),
+        None => items.collect(),
+    }
+}
+
+fn single_column(name: &str, column: &str, values: Vec<String>) -> Series {
+    Series {
+        name: name.to_string(),
+        tags: BTreeMap::new(),
+        columns: vec![column.to_string()],
+        values: values
+            .into_iter()
+            .map(|v| vec![serde_json::json!(v)])
+            .collect(),
+        tier: None,
+    }
+}
+
+type KeyMatcher = Box<dyn Fn(&str) -> bool>;
+
+impl TagKeyFilter {
+    fn matcher(&self) -> Result<KeyMatcher, String> {
+        Ok(match self.clone() {
+            TagKeyFilter::Eq(e) => Box::new(move |k: &str| k == e),
+            TagKeyFilter::In(keys) => Box::new(move |k: &str| keys.iter().any(|e| e == k)),
+            TagKeyFilter::Regex(r) => {
+                let pattern = Pattern::new(&r)?;
+                Box::new(move |k: &str| pattern.is_match(k))
+            }
+        })
+    }
+}
+
+fn metadata_filter(condition: &Option<Expr>, now: i64) -> Result<metadata::Filter, String> {
+    let mut range = TimeRange::default();
+    let condition = match condition {
+        Some(c) => split_condition(c, now, &mut range)?,
+        None => None,
+    };
+    Ok(metadata::Filter {
+        start: range.start,
+        end: range.end,
+        condition,
+    })
+}
+
+// SHOW statements without FROM apply to every measurement
+fn show_sources(sources: &[Source], timeseries: &[String]) -> Result<Vec<String>, String> {
+    if sources.is_empty() {
+        return Ok(timeseries.to_vec());
+    }
+    resolve_sources(sources, timeseries)
+}
+
+fn execute_statement(
+    pm: &mut TimeseriesDiskPersistenceManager,
+    stmt: &Statement,
+) -> Result<Vec<Series>, String> {
+    let mut timeseries = pm.clone().list_timeseries()?;
+    timeseries.sort();
+    let now = Local::now().timestamp_millis();
+    match stmt {
+        Statement::Select(select) => {
+            let names = resolve_sources(&select.sources, &timeseries)?;
+            let mut series: Vec<Series> = Vec::new();
+            for plan in plan_select(select, &names, now)? {
+                series.extend(query::execute(pm, &plan)?);
+            }
+            Ok(page(series, select.slimit, select.soffset))
+        }
+        Statement::ShowMeasurements {
+            filter,
+            condition,
+            limit,
+            offset,
+        } => {
+            let pattern = match filter {
+                Some(Source::Regex(r)) => Some(Pattern::new(r)?),
+                Some(Source::Name(n)) => Some(Pattern::new(&format!("^{}$", regex::escape(n)))?),
+                None => None,
+            };
+            let names =
+                metadata::measurements(pm, pattern.as_ref(), &metadata_filter(condition, now)?)?;
+            if names.is_empty() {
+                return Ok(vec![]);
+            }
+            Ok(vec![single_column(
+                "measurements",
+                "name",
+                page(names, *limit, *offset),
+            )])
+        }
+        Statement::ShowTagKeys {
+            sources,
+            key,
+            condition,
+            limit,
+            offset,
+        } => {
+            let filter = metadata_filter(condition, now)?;
+            let matcher = match key {
+                Some(k) => Some(k.matcher()?),
+                None => None,
+            };
+            let mut series: Vec<Series> = Vec::new();
+            for ts in show_sources(sources, &timeseries)? {
+                let keys: Vec<String> = metadata::tag_keys(pm, &ts, None, &filter)?
+                    .into_iter()
+                    .filter(|k| matcher.as_ref().is_none_or(|m| m(k)))
+                    .collect();
+                let keys = page(keys, *limit, *offset);
+                if !keys.is_empty() {
+                    series.push(single_column(&ts, "tagKey", keys));
+                }
+            }
+            Ok(series)
+        }
+        Statement::ShowFieldKeys {
+            sources,
+            condition,
+            limit,
+            offset,
+        } => {
+            let filter = metadata_filter(condition, now)?;
+            let mut series: Vec<Series> = Vec::new();
+            for ts in show_sources(sources, &timeseries)? {
+                let keys = page(metadata::field_keys(pm, &ts, &filter)?, *limit, *offset);
+                if keys.is_empty() {
+                    continue;
+                }
+                series.push(Series {
+                    name: ts.clone(),
+                    tags: BTreeMap::new(),
+                    columns: vec!["fieldKey".to_string(), "fieldType".to_string()],
+                    values: keys
+                        .into_iter()
+                        .map(|k| vec![serde_json::json!(k), serde_json::json!("float")])
+                        .collect(),
+                    tier: None,
+                });
+            }
+            Ok(series)
+        }
+        Statement::ShowTagValues {
+            sources,
+            key,
+            condition,
+            limit,
+            offset,
+        } => {
+            let filter = metadata_filter(condition, now)?;
+            let matcher = key.matcher()?;
+            let mut series: Vec<Series> = Vec::new();
+            for ts in show_sources(sources, &timeseries)? {
+                let pairs = metadata::tag_values(pm, &ts, &*matcher, &filter)?;
+                let pairs = page(pairs, *limit, *offset);
+                if pairs.is_empty() {
+                    continue;
+                }
+                series.push(Series {
+                    name: ts.clone(),
+                    tags: BTreeMap::new(),
+                    columns: vec!["key".to_string(), "value".to_string()],
+                    values: pairs
+                        .into_iter()
+                        .map(|(k, v)| vec![serde_json::json!(k), serde_json::json!(v)])
+                        .collect(),
+                    tier: None,
+                });
+            }
+            Ok(series)
+        }
+    }
+}
+
+// True for statements the SQL endpoint hands over to this module
+pub fn is_metadata_statement(query: &str) -> bool {
+    query
+        .trim_start()
+        .get(..4)
+        .is_some_and(|s| s.eq_ignore_ascii_case("SHOW"))
+}
+
+// Converts the time column from unix milliseconds to the requested epoch
+// precision (ns, u, ms, s, m, h) or to RFC3339 strings when none is given, with the offset
+// of the statement's time zone
+pub fn format_times(
+    series: &mut [Series],
+    epoch: Option<&str>,
+    tz: Option<Tz>,
+) -> Result<(), String> {
+    let convert = |ms: i64| -> Result<serde_json::Value, String> {
+        match epoch {
+            None | Some("") | Some("rfc3339") => match tz {
+                Some(tz) => Ok(serde_json::json!(tz
+                    .timestamp_millis_opt(ms)
+                    .unwrap()
+                    .to_rfc3339_opts(SecondsFormat::Millis, true))),
+                None => Ok(serde_json::json!(query::timestamp_literal(ms))),
+            },
+            Some("ns") | Some("n") => Ok(serde_json::json!(ms * 1_000_000)),
+            Some("u") | Some("µ") => Ok(serde_json::json!(ms * 1_000)),
+            Some("ms") => Ok(serde_json::json!(ms)),
+            Some("s") => Ok(serde_json::json!(ms / 1_000)),
+            Some("m") => Ok(serde_json::json!(ms / 60_000)),
+            Some("h") => Ok(serde_json::json!(ms / 3_600_000)),
+            Some(e) => Err(format!("Invalid epoch: {}", e)),
+        }
+    };
+    for s in series.iter_mut() {
+        if s.columns.first().map(|c| c.as_str()) != Some("time") {
+            continue;
+        }
+        for row in s.values.iter_mut() {
+            if let Some(ms) = row.first().and_then(|t| t.as_i64()) {
+                row[0] = convert(ms)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn execute(
+    pm: &mut TimeseriesDiskPersistenceManager,
+    query: &str,
+    epoch: Option<&str>,
+) -> QueryResponse {
+    let statements = match parse(query) {
+        Ok(s) => s,
+        Err(e) => {
+            return QueryResponse {
+                results: vec![StatementResult {
+                    statement_id: 0,
+                    series: vec![],
+                    error: Some(format!("error parsing query: {}", e)),
+                }],
+            }
+        }
+    };
+    execute_statements(pm, &statements, epoch)
+}
+
+pub fn execute_statements(
+    pm: &mut TimeseriesDiskPersistenceManager,
+    statements: &[Statement],
+    epoch: Option<&str>,
+) -> QueryResponse {
+    let mut results: Vec<StatementResult> = Vec::new();
+    for (statement_id, stmt) in statements.iter().enumerate() {
+        let result = execute_statement(pm, stmt).and_then(|mut series| {
+            pm.check_returned(crate::query::count_rows(&series))?;
+            let tz = match stmt {
+                Statement::Select(select) => select.tz,
+                _ => None,
+            };
+            format_times(&mut series, epoch, tz)?;
+            Ok(series)
+        });
+        results.push(match result {
+            Ok(series) => StatementResult {
+                statement_id,
+                series,
+                error: None,
+            },
+            Err(e) => StatementResult {
+                statement_id,
+                series: vec![],
+                error: Some(e),
+            },
+        });
+    }
+    QueryResponse { results }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::Aggregate;
+
+    fn select(query: &str) -> SelectStatement {
+        match parse(query).unwrap().remove(0) {
+            Statement::Select(s) => s,
+            s => panic!("not a select: {:?}", s),
+        }
+    }
+
+    #[test]
+    fn parse_select() {
+        let s = select(
+            "SELECT mean(\"usage\") AS m FROM \"telegraf\".\"autogen\".\"cpu\", /mem.*/ WHERE host =~ /web.*/ AND time > now() - 1h GROUP BY time(5m), * fill(previous) ORDER BY time DESC LIMIT 10 OFFSET 2 SLIMIT 3 SOFFSET 1",
+        );
+        assert_eq!(
+            s.fields,
+            vec![Field {
+                expr: Expr::Call("mean".to_string(), vec![Expr::Ident("usage".to_string())]),
+                alias: Some("m".to_string()),
+            }]
+        );
+        assert_eq!(
+            s.sources,
+            vec![
+                Source::Name("cpu".to_string()),
+                Source::Regex("mem.*".to_string())
+            ]
+        );
+        assert_eq!(
+            s.dimensions,
+            vec![Dimension::Time(Period::Fixed(300_000)), Dimension::All]
+        );
+        assert_eq!(s.fill, Some(Fill::Previous));
+        assert!(s.descending);
+        assert_eq!(
+            (s.limit, s.offset, s.slimit, s.soffset),
+            (Some(10), 2, Some(3), 1)
+        );
+        let s = select("SELECT last(value) FROM cpu GROUP BY time(1m) fill(linear, 10m)");
+        assert_eq!((s.fill, s.stale), (Some(Fill::Linear), Some(600_000)));
+        let s = select("SELECT sum(value) FROM cpu GROUP BY time(3mo) tz('Europe/Paris')");
+        assert_eq!(s.dimensions, vec![Dimension::Time(Period::Months(3))]);
+        assert_eq!(s.tz, calendar::timezone("Europe/Paris").ok());
+        assert!(parse("SELECT FROM cpu").is_err());
+        assert!(parse("DROP MEASUREMENT cpu").is_err());
+    }
+
+    #[test]
+    fn parse_show() {
+        assert_eq!(
+            parse("SHOW MEASUREMENTS WITH MEASUREMENT =~ /cpu.*/ LIMIT 5; SHOW TAG VALUES FROM cpu WITH KEY IN (\"host\", region)").unwrap(),
+            vec![
+                Statement::ShowMeasurements {
+                    filter: Some(Source::Regex("cpu.*".to_string())),
+                    condition: None,
+                    limit: Some(5),
+                    offset: 0
+                },
+                Statement::ShowTagValues {
+                    sources: vec![Source::Name("cpu".to_string())],
+                    key: TagKeyFilter::In(vec!["host".to_string(), "region".to_string()]),
+                    condition: None,
+                    limit: None,
+                    offset: 0
+                },
+            ]
+        );
+        assert_eq!(
+            parse("SHOW FIELD KEYS").unwrap(),
+            vec![Statement::ShowFieldKeys {
+                sources: vec![],
+                condition: None,
+                limit: None,
+                offset: 0
+            }]
+        );
+        match parse(
+            "SHOW TAG KEYS FROM cpu WITH KEY =~ /h.*/ WHERE region = 'eu' AND time > now() - 1d",
+        )
+        .unwrap()
+        .remove(0)
+        {
+            Statement::ShowTagKeys { key, condition, .. } => {
+                assert_eq!(key, Some(TagKeyFilter::Regex("h.*".to_string())));
+                let filter = metadata_filter(&condition, 86_400_000).unwrap();
+                assert_eq!(filter.start, Some(1));
+                assert_eq!(
+                    filter.condition,
+                    Some(Condition::Tag {
+                        key: "region".to_string(),
+                        op: Comparison::Eq,
+                        value: "eu".to_string()
+                    })
+                );
+            }
+            s => panic!("not SHOW TAG KEYS: {:?}", s),
+        }
+        assert!(parse("SHOW TAG VALUES FROM cpu").is_err());
+        assert!(is_metadata_statement("  show measurements"));
+        assert!(!is_metadata_statement("SELECT * FROM cpu"));
+    }
+
+    #[test]
+    fn plan_conditions_and_ranges() {
+        let s = select(
+            "SELECT percentile(latency, 95) FROM http WHERE time >= '2021-11-30T10:00:00Z' AND time < '2021-11-30T11:00:00Z' AND (host = 'a' OR code > 499) GROUP BY time(1m), host",
+        );
+        let timeseries = vec!["http".to_string(), "cpu".to_string()];
+        let names = resolve_sources(&s.sources, &timeseries).unwrap();
+        let plans = plan_select(&s, &names, 0).unwrap();
+        assert_eq!(plans.len(), 1);
+        let p = &plans[0];
+        assert_eq!(p.start, Some(1638266400000));
+        assert_eq!(p.end, Some(1638270000000));
+        assert_eq!(p.group_by_time, Some(Window::fixed(60_000)));
+        assert_eq!(p.group_by_tags, TagGrouping::Tags(vec!["host".to_string()]));
+        assert_eq!(p.fill, Fill::Null);
+        assert_eq!(
+            p.projections[0].aggregate,
+            Some(Aggregate::Percentile(95.0))
+        );
+        assert_eq!(
+            p.condition,
+            Some(Condition::Or(
+                Box::new(Condition::Tag {
+                    key: "host".to_string(),
+                    op: Comparison::Eq,
+                    value: "a".to_string()
+                }),
+                Box::new(Condition::Field {
+                    name: "code".to_string(),
+                    op: Comparison::Gt,
+                    value: 499.0
+                }),
+            ))
+        );
+        assert_eq!(
+            p.base_query(),
+            "SELECT * FROM http WHERE time >= '2021-11-30T10:00:00.000Z' AND time < '2021-11-30T11:00:00.000Z'"
+        );
+
+        let s = select("SELECT usage FROM cpu WHERE time > now() - 1h");
+        let p = &plan_select(&s, &["cpu".to_string()], 7_200_000).unwrap()[0];
+        assert_eq!(p.start, Some(3_600_001));
+        assert_eq!(p.group_by_tags, TagGrouping::Series);
+
+        let s = select("SELECT usage FROM cpu WHERE host = 'a' OR time > now()");
+        assert!(plan_select(&s, &["cpu".to_string()], 0).is_err());
+        let s = select("SELECT usage FROM cpu GROUP BY time(1m)");
+        assert!(plan_select(&s, &["cpu".to_string()], 0).is_err());
+    }
+
+    #[test]
+    fn epoch_formatting() {
+        let mut series = vec![Series {
+            name: "cpu".to_string(),
+            tags: BTreeMap::new(),
+            columns: vec!["time".to_string(), "usage".to_string()],
+            values: vec![vec![serde_json::json!(1_500), serde_json::json!(1.0)]],
+            tier: None,
+        }];
+        format_times(&mut series, Some("ns"), None).unwrap();
+        assert_eq!(series[0].values[0][0], serde_json::json!(1_500_000_000i64));
+        let mut series = vec![Series {
+            values: vec![vec![serde_json::json!(1_500), serde_json::json!(1.0)]],
+            ..series[0].clone()
+        }];
+        format_times(&mut series, None, None).unwrap();
+        assert_eq!(
+            series[0].values[0][0],
+            serde_json::json!("1970-01-01T00:00:01.500Z")
+        );
+        let mut series = vec![Series {
+            values: vec![vec![serde_json::json!(1_500), serde_json::json!(1.0)]],
+            ..series[0].clone()
+        }];
+        let tz = calendar::timezone("Europe/Paris").ok();
+        format_times(&mut series, None, tz).unwrap();
+        assert_eq!(
+            series[0].values[0][0],
+            serde_json::json!("1970-01-01T01:00:01.500+01:00")
+        );
+    }
+}
@@ -0,0 +1,351 @@
+use crate::persistence::TimeseriesDiskPersistenceManager;
+use crate::protocol::LineProtocol;
+use futures::FutureExt;
+use log::{debug, info};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, oneshot};
+
+// Ingest queue
+// The listeners parse the lines and queue them, a writer task takes them in batches and saves
+// the fields of each timeseries together, its log synced once (see head.rs). HTTP writes wait
+// for their batch to be saved, an acknowledged write is in the log as before; UDP writes don't.
+// When the queue is full, writes wait for room, are dropped or refused with a 503:
+//   REFLUXDB_INGEST_QUEUE=10000            (lines queued)
+//   REFLUXDB_INGEST_BATCH=1000             (fields saved at once)
+//   REFLUXDB_INGEST_BACKPRESSURE=block|drop|reject
+
+pub const DEFAULT_QUEUE: usize = 10_000;
+pub const DEFAULT_BATCH: usize = 1_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backpressure {
+    Block,
+    Drop,
+    Reject,
+}
+
+impl Backpressure {
+    pub fn parse(backpressure: &str) -> Result<Self, String> {
+        match backpressure.trim() {
+            "block" => Ok(Backpressure::Block),
+            "drop" => Ok(Backpressure::Drop),
+            "reject" | "503" => Ok(Backpressure::Reject),
+            b => Err(format!(
+                "Invalid backpressure: {} (block, drop or reject)",
+                b
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Config {
+    pub queue: usize,
+    pub batch: usize,
+    pub backpressure: Backpressure,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            queue: DEFAULT_QUEUE,
+            batch: DEFAULT_BATCH,
+            backpressure: Backpressure::Block,
+        }
+    }
+}
+
+pub fn config_from_env() -> Result<Config, String> {
+    let mut config = Config::default();
+    let size = |name: &str, v: String| match v.trim().parse::<usize>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("Invalid {}: {}", name, v)),
+    };
+    if let Ok(v) = std::env::var("REFLUXDB_INGEST_QUEUE") {
+        config.queue = size("queue size", v)?;
+    }
+    if let Ok(v) = std::env::var("REFLUXDB_INGEST_BATCH") {
+        config.batch = size("batch size", v)?;
+    }
+    if let Ok(v) = std::env::var("REFLUXDB_INGEST_BACKPRESSURE") {
+        config.backpressure = Backpressure::parse(&v)?;
+    }
+    Ok(config)
+}
+
+// The fields of a line, with the channel of the writer waiting for them to be saved
+pub struct Write {
+    timeseries: String,
+    fields: Vec<(String, f64, HashMap<String, String>)>,
+    saved: Option<oneshot::Sender<Result<usize, String>>>,
+}
+
+impl Write {
+    fn new(line: &LineProtocol) -> Self {
+        let tags: HashMap<String, String> = line
+            .tag_set
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        Write {
+            timeseries: line.measurement_name.clone(),
+            fields: line
+                .field_set
+                .iter()
+                .map(|(name, value)| (name.clone(), *value, tags.clone()))
+                .collect(),
+            saved: None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Saved(usize), // fields saved
+    Queued,
+    Dropped,
+    Rejected,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Metrics {
+    pub depth: usize, // lines queued
+    pub capacity: usize,
+    pub max_depth: usize,
+    pub queued: usize,
+    pub saved: usize, // fields
+    pub batches: usize,
+    pub dropped: usize,
+    pub rejected: usize,
+    pub failed: usize, // fields
+    pub backpressure: String,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    depth: AtomicUsize,
+    max_depth: AtomicUsize,
+    queued: AtomicUsize,
+    saved: AtomicUsize,
+    batches: AtomicUsize,
+    dropped: AtomicUsize,
+    rejected: AtomicUsize,
+    failed: AtomicUsize,
+}
+
+#[derive(Debug)]
+pub struct Ingest {
+    config: Config,
+    sender: mpsc::Sender<Write>,
+    counters: Counters,
+}
+
+impl Ingest {
+    pub fn new(config: Config) -> (Self, mpsc::Receiver<Write>) {
+        let (sender, receiver) = mpsc::channel(config.queue);
+        let ingest = Ingest {
+            config,
+            sender,
+            counters: Counters::default(),
+        };
+        (ingest, receiver)
+    }
+
+    // Queues the fields of the line, waiting for them to be saved when asked to
+    pub async fn write(&self, line: &LineProtocol, wait: bool) -> Result<Outcome, String> {
+        let mut write = Write::new(line);
+        let (sender, saved) = oneshot::channel();
+        if wait {
+            write.saved = Some(sender);
+        }
+        // counted before the writer can take it
+        let depth = self.counters.depth.fetch_add(1, Ordering::Relaxed) + 1;
+        let queued = match self.config.backpressure {
+            Backpressure::Block => match self.sender.send(write).await {
+                Ok(_) => Ok(Outcome::Queued),
+                Err(_) => Err("Ingest queue closed".to_string()),
+            },
+            backpressure => match self.sender.try_send(write) {
+                Ok(_) => Ok(Outcome::Queued),
+                Err(mpsc::error::TrySendError::Full(_)) if backpressure == Backpressure::Drop => {
+                    self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+                    Ok(Outcome::Dropped)
+                }
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    self.counters.rejected.fetch_add(1, Ordering::Relaxed);
+                    Ok(Outcome::Rejected)
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => Err("Ingest queue closed".to_string()),
+            },
+        };
+        if queued != Ok(Outcome::Queued) {
+            self.counters.depth.fetch_sub(1, Ordering::Relaxed);
+            return queued;
+        }
+        self.counters.queued.fetch_add(1, Ordering::Relaxed);
+        self.counters.max_depth.fetch_max(depth, Ordering::Relaxed);
+        if !wait {
+            return Ok(Outcome::Queued);
+        }
+        match saved.await {
+            Ok(Ok(n)) => Ok(Outcome::Saved(n)),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err("Ingest writer stopped".to_string()),
+        }
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        let c = &self.counters;
+        Metrics {
+            depth: c.depth.load(Ordering::Relaxed),
+            capacity: self.config.queue,
+            max_depth: c.max_depth.load(Ordering::Relaxed),
+            queued: c.queued.load(Ordering::Relaxed),
+            saved: c.saved.load(Ordering::Relaxed),
+            batches: c.batches.load(Ordering::Relaxed),
+            dropped: c.dropped.load(Ordering::Relaxed),
+            rejected: c.rejected.load(Ordering::Relaxed),
+            failed: c.failed.load(Ordering::Relaxed),
+            backpressure: format!("{:?}", self.config.backpressure).to_lowercase(),
+        }
+    }
+}
+
+// Waits for a line, then takes those queued meanwhile up to the batch size
+pub async fn next_batch(
+    ingest: &Ingest,
+    receiver: &mut mpsc::Receiver<Write>,
+) -> Option<Vec<Write>> {
+    let first = receiver.recv().await?;
+    let mut fields = first.fields.len();
+    let mut batch = vec![first];
+    while fields < ingest.config.batch {
+        match receiver.recv().now_or_never() {
+            Some(Some(write)) => {
+                fields += write.fields.len();
+                batch.push(write);
+            }
+            _ => break,
+        }
+    }
+    ingest
+        .counters
+        .depth
+        .fetch_sub(batch.len(), Ordering::Relaxed);
+    Some(batch)
+}
+
+// Saves the fields of a batch timeseries by timeseries, then tells the writers waiting
+pub fn save(ingest: &Ingest, pm: &mut TimeseriesDiskPersistenceManager, batch: Vec<Write>) {
+    let mut grouped: BTreeMap<String, Vec<Write>> = BTreeMap::new();
+    for write in batch {
+        grouped
+            .entry(write.timeseries.clone())
+            .or_default()
+            .push(write);
+    }
+    for (timeseries, writes) in grouped {
+        let fields: Vec<(String, f64, HashMap<String, String>)> =
+            writes.iter().flat_map(|w| w.fields.clone()).collect();
+        let count = fields.len();
+        let saved = pm.save_batch(timeseries.clone(), fields);
+        ingest.counters.batches.fetch_add(1, Ordering::Relaxed);
+        match &saved {
+            Ok(_) => {
+                ingest.counters.saved.fetch_add(count, Ordering::Relaxed);
+                debug!("Timeseries {}: saved {} fields", timeseries, count);
+            }
+            Err(e) => {
+                ingest.counters.failed.fetch_add(count, Ordering::Relaxed);
+                info!("Error writing {} fields of {}: {}", count, timeseries, e);
+            }
+        }
+        for write in writes {
+            let fields = write.fields.len();
+            if let Some(sender) = write.saved {
+                let result = saved.as_ref().map(|_| fields).map_err(|e| e.clone());
+                let _ = sender.send(result);
+            }
+        }
+    }
+}
+
+// Saves the queued lines batch after batch, on the blocking thread pool
+pub async fn run(
+    ingest: Arc<Ingest>,
+    mut receiver: mpsc::Receiver<Write>,
+    pm: Arc<Mutex<TimeseriesDiskPersistenceManager>>,
+) {
+    while let Some(batch) = next_batch(&ingest, &mut receiver).await {
+        let mut manager = pm.lock().unwrap().clone();
+        let writer = ingest.clone();
+        if let Err(e) = actix_web::web::block(move || save(&writer, &mut manager, batch)).await {
+            info!("Ingest error: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    #[test]
+    fn queue_and_batches() {
+        let line = |value: &str| {
+            LineProtocol::parse(format!("cpu,host=a usage={},idle=1 1000", value)).unwrap()
+        };
+        let config = Config {
+            queue: 2,
+            batch: 3,
+            backpressure: Backpressure::Reject,
+        };
+        let (ingest, mut receiver) = Ingest::new(config);
+        for v in ["1", "2"] {
+            assert_eq!(block_on(ingest.write(&line(v), false)), Ok(Outcome::Queued));
+        }
+        assert_eq!(
+            block_on(ingest.write(&line("3"), false)),
+            Ok(Outcome::Rejected)
+        );
+        assert_eq!((ingest.metrics().depth, ingest.metrics().rejected), (2, 1));
+
+        let dir = std::env::temp_dir().join(format!("refluxdb-ingest-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut pm =
+            TimeseriesDiskPersistenceManager::new(dir.to_str().unwrap().to_string()).unwrap();
+        // the two lines fill a batch of 3 fields
+        let batch = block_on(next_batch(&ingest, &mut receiver)).unwrap();
+        assert_eq!(batch.len(), 2);
+        save(&ingest, &mut pm, batch);
+        let metrics = ingest.metrics();
+        assert_eq!(
+            (
+                metrics.depth,
+                metrics.max_depth,
+                metrics.saved,
+                metrics.batches
+            ),
+            (0, 2, 4, 1)
+        );
+        let rows = pm
+            .scan_measurements("cpu".to_string(), "SELECT * FROM cpu".to_string())
+            .unwrap();
+        assert_eq!(rows.len(), 4);
+
+        let (ingest, _receiver) = Ingest::new(Config {
+            queue: 1,
+            backpressure: Backpressure::Drop,
+            ..config
+        });
+        block_on(ingest.write(&line("1"), false)).unwrap();
+        assert_eq!(
+            block_on(ingest.write(&line("2"), false)),
+            Ok(Outcome::Dropped)
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
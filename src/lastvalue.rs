@@ -0,0 +1,230 @@
+use crate::persistence::Measurement;
+use crate::query::{self, SelectPlan, TagGrouping};
+use gluesql::storages::SledStorage;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+// Last value cache
+// The latest point of every series (field and tag set) of each timeseries, updated on every
+// write and persisted in a tree of the timeseries' sled database so it survives restarts.
+// It answers GET /last/{timeseries} and last() queries without scanning the table:
+//   SELECT last(value) FROM cpu GROUP BY host
+
+const TREE: &str = "last_values";
+
+#[derive(Debug, Default)]
+pub struct LastValues {
+    // timeseries -> series key -> latest point
+    values: Mutex<HashMap<String, BTreeMap<String, Measurement>>>,
+}
+
+// Field name and sorted tags, as in the line protocol: usage,host=a,region=eu
+pub fn series_key(m: &Measurement) -> String {
+    let tags: BTreeMap<&String, &String> = m.tags.iter().collect();
+    let mut key = m.name.clone();
+    for (k, v) in tags {
+        key.push_str(&format!(",{}={}", k, v));
+    }
+    key
+}
+
+impl LastValues {
+    pub fn load(&self, timeseries: &str, storage: &SledStorage) -> Result<(), String> {
+        let tree = match storage.tree.open_tree(TREE) {
+            Ok(t) => t,
+            Err(e) => {
+                return Err(format!(
+                    "Error opening last values of {}: {}",
+                    timeseries, e
+                ))
+            }
+        };
+        let mut series: BTreeMap<String, Measurement> = BTreeMap::new();
+        for entry in tree.iter() {
+            let (key, value) = match entry {
+                Ok(e) => e,
+                Err(e) => {
+                    return Err(format!(
+                        "Error reading last values of {}: {}",
+                        timeseries, e
+                    ))
+                }
+            };
+            match bincode::deserialize::<Measurement>(&value) {
+                Ok(m) => series.insert(String::from_utf8_lossy(&key).to_string(), m),
+                Err(e) => {
+                    return Err(format!(
+                        "Error decoding last value of {}: {}",
+                        timeseries, e
+                    ))
+                }
+            };
+        }
+        self.values
+            .lock()
+            .unwrap()
+            .insert(timeseries.to_string(), series);
+        Ok(())
+    }
+
+    // Keeps the point when it is the latest of its series
+    pub fn update(
+        &self,
+        timeseries: &str,
+        storage: &SledStorage,
+        m: &Measurement,
+    ) -> Result<(), String> {
+        let key = series_key(m);
+        let mut values = self.values.lock().unwrap();
+        let series = values.entry(timeseries.to_string()).or_default();
+        if series.get(&key).is_some_and(|last| last.key > m.key) {
+            return Ok(());
+        }
+        let encoded = match bincode::serialize(m) {
+            Ok(e) => e,
+            Err(e) => return Err(format!("Error encoding last value: {}", e)),
+        };
+        let persisted = storage
+            .tree
+            .open_tree(TREE)
+            .and_then(|tree| tree.insert(key.as_bytes(), encoded));
+        if let Err(e) = persisted {
+            return Err(format!("Error saving last value of {}: {}", timeseries, e));
+        }
+        series.insert(key, m.clone());
+        Ok(())
+    }
+
+    // Drops the series whose latest point is older than the cutoff
+    pub fn expire(
+        &self,
+        timeseries: &str,
+        storage: &SledStorage,
+        cutoff: i64,
+    ) -> Result<(), String> {
+        let mut values = self.values.lock().unwrap();
+        let series = match values.get_mut(timeseries) {
+            Some(s) => s,
+            None => return Ok(()),
+        };
+        let expired: Vec<String> = series
+            .iter()
+            .filter(|(_, m)| m.key < cutoff)
+            .map(|(k, _)| k.clone())
+            .collect();
+        for key in expired {
+            let removed = storage
+                .tree
+                .open_tree(TREE)
+                .and_then(|tree| tree.remove(key.as_bytes()));
+            if let Err(e) = removed {
+                return Err(format!(
+                    "Error expiring last value of {}: {}",
+                    timeseries, e
+                ));
+            }
+            series.remove(&key);
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, timeseries: &str) -> Vec<Measurement> {
+        match self.values.lock().unwrap().get(timeseries) {
+            Some(series) => series.values().cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+// A lone last() without time windows reads the cached points: the last point of a group is
+// the latest last point of its series. Any SQL condition or an end bound before the latest
+// points needs the table.
+pub fn answers(plan: &SelectPlan, points: &[Measurement]) -> bool {
+    plan.selection.is_none()
+        && plan.group_by_time.is_none()
+        && plan.projections.len() == 1
+        && plan.projections.iter().all(|p| {
+            p.function.is_none()
+                && p.selector.is_none()
+                && p.aggregate == Some(query::Aggregate::Last)
+        })
+        && plan
+            .end
+            .is_none_or(|end| points.iter().all(|m| m.key < end))
+}
+
+// The cached points within the plan's start and tag condition
+pub fn select(plan: &SelectPlan, points: Vec<Measurement>) -> Vec<Measurement> {
+    points
+        .into_iter()
+        .filter(|m| plan.start.is_none_or(|start| m.key >= start))
+        .filter(|m| plan.condition.as_ref().is_none_or(|c| c.matches(m)))
+        .collect()
+}
+
+// Latest point of each field per group of the listed tags, keeping only those tags
+pub fn group(points: Vec<Measurement>, tags: &[String]) -> Vec<Measurement> {
+    let grouping = TagGrouping::Tags(tags.to_vec());
+    let mut groups: BTreeMap<(String, BTreeMap<String, String>), Measurement> = BTreeMap::new();
+    for mut m in points {
+        let key = grouping.key(&query::tag_key(&m.tags));
+        m.tags = key.clone().into_iter().collect();
+        match groups.get(&(m.name.clone(), key.clone())) {
+            Some(last) if last.key > m.key => {}
+            _ => {
+                groups.insert((m.name.clone(), key), m);
+            }
+        }
+    }
+    groups.into_values().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use uuid::Uuid;
+
+    fn measurement(key: i64, host: &str, value: f64) -> Measurement {
+        let mut tags = HashMap::new();
+        tags.insert("host".to_string(), host.to_string());
+        tags.insert("dc".to_string(), "eu".to_string());
+        Measurement {
+            key,
+            id: Uuid::new_v4(),
+            name: "usage".to_string(),
+            value,
+            tags,
+        }
+    }
+
+    #[test]
+    fn cache_and_reload() {
+        let config = gluesql::sled::Config::default().temporary(true);
+        let storage = SledStorage::try_from(config).unwrap();
+        let cache = LastValues::default();
+        assert_eq!(series_key(&measurement(1, "a", 1.0)), "usage,dc=eu,host=a");
+
+        cache
+            .update("cpu", &storage, &measurement(2, "a", 2.0))
+            .unwrap();
+        cache
+            .update("cpu", &storage, &measurement(1, "a", 1.0))
+            .unwrap();
+        cache
+            .update("cpu", &storage, &measurement(1, "b", 3.0))
+            .unwrap();
+        let values: Vec<f64> = cache.get("cpu").iter().map(|m| m.value).collect();
+        assert_eq!(values, vec![2.0, 3.0]);
+
+        let reloaded = LastValues::default();
+        reloaded.load("cpu", &storage).unwrap();
+        assert_eq!(reloaded.get("cpu"), cache.get("cpu"));
+        assert!(reloaded.get("mem").is_empty());
+
+        let grouped = group(cache.get("cpu"), &["dc".to_string()]);
+        assert_eq!(grouped.len(), 1);
+        assert_eq!(grouped[0].value, 2.0);
+        assert_eq!(grouped[0].tags.len(), 1);
+    }
+}
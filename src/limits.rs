@@ -0,0 +1,323 @@
+use crate::functions;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Query limits
+// Every read goes through ReadOnlyStorage, which charges the rows it scans to the budget of
+// the running query: a wall-clock timeout, a maximum of rows scanned and a memory budget
+// counting the bytes of the rows read, an upper bound of what the executors hold. The engine
+// checks the timeout for every key it visits, the rows it filters out included. The rows
+// returned are checked once the result is built, as is the timeout for the work done after
+// the last row was read: GlueSQL planning or sorting isn't interrupted. Running queries are
+// registered so they can be listed and killed, a killed query fails on the next key it reads.
+// The limits are read from the environment, 0 disables one:
+//   REFLUXDB_QUERY_TIMEOUT=30s REFLUXDB_MAX_ROWS_SCANNED=10000000
+//   REFLUXDB_MAX_ROWS_RETURNED=1000000 REFLUXDB_QUERY_MEMORY=1GB
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryLimits {
+    pub timeout: Option<Duration>,
+    pub max_rows_scanned: Option<usize>,
+    pub max_rows_returned: Option<usize>,
+    pub max_memory: Option<usize>, // bytes
+}
+
+impl Default for QueryLimits {
+    fn default() -> Self {
+        QueryLimits {
+            timeout: Some(Duration::from_secs(30)),
+            max_rows_scanned: Some(10_000_000),
+            max_rows_returned: Some(1_000_000),
+            max_memory: Some(1 << 30),
+        }
+    }
+}
+
+fn parse_timeout(value: &str) -> Result<Option<Duration>, String> {
+    if value.trim() == "0" {
+        return Ok(None);
+    }
+    let ms = functions::parse_duration(value.trim())?;
+    Ok(Some(Duration::from_millis(ms as u64)))
+}
+
+fn parse_count(value: &str) -> Result<Option<usize>, String> {
+    match value.trim().parse::<usize>() {
+        Ok(0) => Ok(None),
+        Ok(n) => Ok(Some(n)),
+        Err(_) => Err(format!("Invalid number: {}", value)),
+    }
+}
+
+// 512MB, 1GB, 64KB or a number of bytes
+pub fn parse_bytes(value: &str) -> Result<Option<usize>, String> {
+    let value = value.trim().to_uppercase();
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => value.split_at(i),
+        None => (value.as_str(), ""),
+    };
+    let unit: usize = match unit {
+        "" | "B" => 1,
+        "KB" => 1 << 10,
+        "MB" => 1 << 20,
+        "GB" => 1 << 30,
+        _ => return Err(format!("Invalid size: {}", value)),
+    };
+    Ok(parse_count(number)?.map(|n| n * unit))
+}
+
+impl QueryLimits {
+    pub fn from_env() -> Result<Self, String> {
+        let mut limits = QueryLimits::default();
+        let var = |name: &str| std::env::var(name).ok();
+        if let Some(v) = var("REFLUXDB_QUERY_TIMEOUT") {
+            limits.timeout = parse_timeout(&v)?;
+        }
+        if let Some(v) = var("REFLUXDB_MAX_ROWS_SCANNED") {
+            limits.max_rows_scanned = parse_count(&v)?;
+        }
+        if let Some(v) = var("REFLUXDB_MAX_ROWS_RETURNED") {
+            limits.max_rows_returned = parse_count(&v)?;
+        }
+        if let Some(v) = var("REFLUXDB_QUERY_MEMORY") {
+            limits.max_memory = parse_bytes(&v)?;
+        }
+        Ok(limits)
+    }
+
+    // A request can lower the server timeout, not raise it
+    pub fn with_timeout(&self, timeout: Option<&str>) -> Result<Self, String> {
+        let requested = match timeout {
+            Some(t) => parse_timeout(t)?,
+            None => return Ok(self.clone()),
+        };
+        let mut limits = self.clone();
+        limits.timeout = match (self.timeout, requested) {
+            (Some(max), Some(t)) => Some(max.min(t)),
+            (max, t) => max.or(t),
+        };
+        Ok(limits)
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct QueryStatus {
+    pub id: u64,
+    pub query: String,
+    pub duration_ms: u64,
+    pub rows_scanned: usize,
+    pub bytes: usize,
+    pub killed: bool,
+}
+
+#[derive(Debug)]
+pub struct Budget {
+    pub id: u64,
+    pub query: String,
+    started: Instant,
+    limits: QueryLimits,
+    killed: AtomicBool,
+    rows_scanned: AtomicUsize,
+    bytes: AtomicUsize,
+}
+
+impl Budget {
+    pub fn new(id: u64, query: &str, limits: QueryLimits) -> Self {
+        Budget {
+            id,
+            query: query.to_string(),
+            started: Instant::now(),
+            limits,
+            killed: AtomicBool::new(false),
+            rows_scanned: AtomicUsize::new(0),
+            bytes: AtomicUsize::new(0),
+        }
+    }
+
+    // Fails once the query was killed or ran out of time
+    pub fn check(&self) -> Result<(), String> {
+        if self.killed.load(Ordering::Relaxed) {
+            return Err(format!("query {} was killed", self.id));
+        }
+        if let Some(timeout) = self.limits.timeout {
+            if self.started.elapsed() > timeout {
+                return Err(format!(
+                    "query {} timed out after {}ms",
+                    self.id,
+                    timeout.as_millis()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // Charges a scanned row of the given size
+    pub fn scan(&self, bytes: usize) -> Result<(), String> {
+        self.check()?;
+        let rows = self.rows_scanned.fetch_add(1, Ordering::Relaxed) + 1;
+        if let Some(max) = self.limits.max_rows_scanned {
+            if rows > max {
+                return Err(format!(
+                    "query {} exceeded the limit of {} rows scanned",
+                    self.id, max
+                ));
+            }
+        }
+        let total = self.bytes.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        if let Some(max) = self.limits.max_memory {
+            if total > max {
+                return Err(format!(
+                    "query {} exceeded the memory budget of {} bytes",
+                    self.id, max
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn returned(&self, rows: usize) -> Result<(), String> {
+        self.check()?;
+        match self.limits.max_rows_returned {
+            Some(max) if rows > max => Err(format!(
+                "query {} returned {} rows, over the limit of {}",
+                self.id, rows, max
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn kill(&self) {
+        self.killed.store(true, Ordering::Relaxed);
+    }
+
+    pub fn rows_scanned(&self) -> usize {
+        self.rows_scanned.load(Ordering::Relaxed)
+    }
+
+    pub fn status(&self) -> QueryStatus {
+        QueryStatus {
+            id: self.id,
+            query: self.query.clone(),
+            duration_ms: self.started.elapsed().as_millis() as u64,
+            rows_scanned: self.rows_scanned.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            killed: self.killed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct QueryRegistry {
+    next_id: AtomicU64,
+    running: Mutex<BTreeMap<u64, Arc<Budget>>>,
+}
+
+impl QueryRegistry {
+    pub fn register(
+        registry: &Arc<QueryRegistry>,
+        query: &str,
+        limits: QueryLimits,
+    ) -> RunningQuery {
+        let id = registry.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let budget = Arc::new(Budget::new(id, query, limits));
+        registry.running.lock().unwrap().insert(id, budget.clone());
+        RunningQuery {
+            registry: registry.clone(),
+            budget,
+        }
+    }
+
+    pub fn list(&self) -> Vec<QueryStatus> {
+        self.running
+            .lock()
+            .unwrap()
+            .values()
+            .map(|b| b.status())
+            .collect()
+    }
+
+    pub fn kill(&self, id: u64) -> Option<QueryStatus> {
+        let running = self.running.lock().unwrap();
+        let budget = running.get(&id)?;
+        budget.kill();
+        Some(budget.status())
+    }
+}
+
+// Unregisters the query when dropped, however the handler returns
+pub struct RunningQuery {
+    registry: Arc<QueryRegistry>,
+    pub budget: Arc<Budget>,
+}
+
+impl Drop for RunningQuery {
+    fn drop(&mut self) {
+        self.registry
+            .running
+            .lock()
+            .unwrap()
+            .remove(&self.budget.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_limits() {
+        assert_eq!(parse_bytes("512MB"), Ok(Some(512 << 20)));
+        assert_eq!(parse_bytes("2048"), Ok(Some(2048)));
+        assert_eq!(parse_bytes("0"), Ok(None));
+        assert!(parse_bytes("1TB").is_err());
+        assert_eq!(parse_timeout("1m"), Ok(Some(Duration::from_secs(60))));
+        assert_eq!(parse_timeout("0"), Ok(None));
+
+        let limits = QueryLimits::default();
+        assert_eq!(
+            limits.with_timeout(Some("5s")).unwrap().timeout,
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            limits.with_timeout(Some("1h")).unwrap().timeout,
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(limits.with_timeout(None), Ok(limits.clone()));
+    }
+
+    #[test]
+    fn budgets_and_registry() {
+        let limits = QueryLimits {
+            timeout: None,
+            max_rows_scanned: Some(2),
+            max_rows_returned: Some(1),
+            max_memory: Some(100),
+        };
+        let budget = Budget::new(1, "SELECT * FROM cpu", limits.clone());
+        assert!(budget.scan(10).is_ok());
+        assert!(budget.scan(10).is_ok());
+        assert!(budget.scan(10).unwrap_err().contains("rows scanned"));
+        assert!(Budget::new(2, "", limits.clone())
+            .scan(200)
+            .unwrap_err()
+            .contains("memory budget"));
+        assert!(budget.returned(1).is_ok());
+        assert!(budget.returned(2).is_err());
+
+        let registry = Arc::new(QueryRegistry::default());
+        let running = QueryRegistry::register(&registry, "SELECT * FROM cpu", limits);
+        let id = running.budget.id;
+        assert_eq!(registry.list().len(), 1);
+        assert!(registry.kill(id).unwrap().killed);
+        assert_eq!(
+            running.budget.check(),
+            Err(format!("query {} was killed", id))
+        );
+        drop(running);
+        assert!(registry.list().is_empty());
+        assert_eq!(registry.kill(id), None);
+    }
+}
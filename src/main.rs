@@ -4,11 +4,33 @@ use std::sync::{Arc, Mutex};
 
 // cargo run
 // echo "hi"| nc -u 127.0.0.1 8089
+mod blocks;
+mod cache;
+mod calendar;
+mod cursor;
+mod engine;
+mod explain;
+mod federation;
+mod functions;
+mod gorilla;
 mod handlers;
+mod head;
+mod influxql;
+mod ingest;
+mod lastvalue;
+mod limits;
+mod metadata;
 mod persistence;
+mod promql;
 mod protocol;
+mod query;
+mod retention;
+mod rollup;
+mod shards;
+mod stats;
 mod udpserver;
 mod utils;
+mod wal;
 
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
@@ -20,13 +42,64 @@ async fn main() -> std::io::Result<()> {
     let db_dir = "databases";
 
     let addr = "127.0.0.1:8089".to_string();
-    let pm = Arc::new(Mutex::new(
-        persistence::TimeseriesDiskPersistenceManager::new(db_dir.to_string()),
-    ));
+    let mut manager =
+        persistence::TimeseriesDiskPersistenceManager::new(db_dir.to_string()).unwrap();
+    manager.limits = limits::QueryLimits::from_env().unwrap();
+    info!("Query limits: {:?}", manager.limits);
+    manager.results = Arc::new(cache::ResultCache::new(cache::capacity_from_env().unwrap()));
+    manager
+        .retention
+        .configure(retention::policies_from_env().unwrap());
+    info!("Shards: {:?}", manager.shards);
+    info!("Head: {:?}", manager.head.config());
+    let expiry_interval = retention::interval_from_env().unwrap();
+    let rollup_interval = rollup::interval_from_env().unwrap();
+    for definition in rollup::definitions_from_env().unwrap() {
+        rollup::define(&mut manager, definition).unwrap();
+    }
+    let ingest_config = ingest::config_from_env().unwrap();
+    info!("Ingest: {:?}", ingest_config);
+    let pm = Arc::new(Mutex::new(manager));
     let data = web::Data::new(pm.clone());
+    let (ingest, queue) = ingest::Ingest::new(ingest_config);
+    let ingest = Arc::new(ingest);
+    let ingest_data = web::Data::new(ingest.clone());
+
+    let writer_pm = pm.clone();
+    let writer = ingest.clone();
+    let _writer = actix_rt::spawn(async move {
+        ingest::run(writer, queue, writer_pm).await;
+    });
+
+    let expiry_pm = pm.clone();
+    let _expiry = actix_rt::spawn(async move {
+        retention::run(expiry_pm, expiry_interval).await;
+    });
+
+    let rollup_pm = pm.clone();
+    let _rollups = actix_rt::spawn(async move {
+        rollup::schedule(rollup_pm, rollup_interval).await;
+    });
+
+    let shard_pm = pm.clone();
+    let _shards = actix_rt::spawn(async move {
+        shards::schedule(shard_pm, shards::SEAL_INTERVAL).await;
+    });
+
+    let head_pm = pm.clone();
+    let _head = actix_rt::spawn(async move {
+        head::schedule(head_pm, head::FLUSH_INTERVAL).await;
+    });
+
+    if let wal::SyncPolicy::Interval(interval) = pm.lock().unwrap().head.config().sync {
+        let sync_pm = pm.clone();
+        let _sync = actix_rt::spawn(async move {
+            head::sync(sync_pm, interval).await;
+        });
+    }
 
     let _task = actix_rt::spawn(async move {
-        let server = udpserver::UDPRefluxServer::new(addr, pm.clone());
+        let server = udpserver::UDPRefluxServer::new(addr, ingest);
         let mut srv = server.await;
         srv.run(false).await.unwrap(); // no echo back
     });
@@ -36,10 +109,34 @@ async fn main() -> std::io::Result<()> {
         App::new()
             .wrap(middleware::Logger::default())
             .app_data(data.clone())
+            .app_data(ingest_data.clone())
             .service(handlers::write_timeseries)
+            .service(handlers::ingest_metrics)
             .service(handlers::query_timeseries)
+            .service(handlers::query_influxql)
+            .service(handlers::prometheus_query)
+            .service(handlers::prometheus_query_range)
             .service(handlers::list_timeseries)
+            .service(handlers::list_measurements)
+            .service(handlers::list_field_keys)
+            .service(handlers::list_tag_keys)
+            .service(handlers::list_tag_values)
             .service(handlers::query_timeseries_range)
+            .service(handlers::last_values)
+            .service(handlers::series_stats)
+            .service(handlers::list_queries)
+            .service(handlers::cache_stats)
+            .service(handlers::list_retention)
+            .service(handlers::set_retention)
+            .service(handlers::delete_retention)
+            .service(handlers::expire_retention)
+            .service(handlers::list_rollups)
+            .service(handlers::define_rollup)
+            .service(handlers::delete_rollup)
+            .service(handlers::run_rollups)
+            .service(handlers::list_shards)
+            .service(handlers::seal_shards)
+            .service(handlers::kill_query)
     })
     .bind("127.0.0.1:8086")?
     .run()
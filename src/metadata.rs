@@ -0,0 +1,138 @@
+use crate::persistence::{Measurement, TimeseriesDiskPersistenceManager};
+use crate::query::{self, Condition, Pattern};
+
+// Schema exploration: measurements, field keys, tag keys and tag values.
+// Used by the SHOW statements and the /measurements endpoints. Everything is derived from
+// the stored points, so a time range or a tag condition restricts the schema to the
+// points that match it.
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Filter {
+    pub start: Option<i64>, // inclusive, unix ms
+    pub end: Option<i64>,   // exclusive, unix ms
+    pub condition: Option<Condition>,
+}
+
+impl Filter {
+    pub fn is_empty(&self) -> bool {
+        self.start.is_none() && self.end.is_none() && self.condition.is_none()
+    }
+
+    fn query(&self, timeseries: &str) -> String {
+        let mut bounds: Vec<String> = Vec::new();
+        if let Some(start) = self.start {
+            bounds.push(format!("time >= '{}'", query::timestamp_literal(start)));
+        }
+        if let Some(end) = self.end {
+            bounds.push(format!("time < '{}'", query::timestamp_literal(end)));
+        }
+        if bounds.is_empty() {
+            return format!("SELECT * FROM {}", timeseries);
+        }
+        format!(
+            "SELECT * FROM {} WHERE {}",
+            timeseries,
+            bounds.join(" AND ")
+        )
+    }
+}
+
+fn scan(
+    pm: &mut TimeseriesDiskPersistenceManager,
+    timeseries: &str,
+    filter: &Filter,
+) -> Result<Vec<Measurement>, String> {
+    let rows = pm.scan_measurements(timeseries.to_string(), filter.query(timeseries))?;
+    Ok(rows
+        .into_iter()
+        .filter(|m| filter.condition.as_ref().is_none_or(|c| c.matches(m)))
+        .collect())
+}
+
+fn sorted<T: Ord>(mut items: Vec<T>) -> Vec<T> {
+    items.sort();
+    items.dedup();
+    items
+}
+
+pub fn measurements(
+    pm: &mut TimeseriesDiskPersistenceManager,
+    pattern: Option<&Pattern>,
+    filter: &Filter,
+) -> Result<Vec<String>, String> {
+    let mut names: Vec<String> = Vec::new();
+    for ts in sorted(pm.clone().list_timeseries()?) {
+        if !pattern.is_none_or(|p| p.is_match(&ts)) {
+            continue;
+        }
+        if filter.is_empty() || !scan(pm, &ts, filter)?.is_empty() {
+            names.push(ts);
+        }
+    }
+    Ok(names)
+}
+
+pub fn field_keys(
+    pm: &mut TimeseriesDiskPersistenceManager,
+    timeseries: &str,
+    filter: &Filter,
+) -> Result<Vec<String>, String> {
+    let rows = scan(pm, timeseries, filter)?;
+    Ok(sorted(rows.into_iter().map(|m| m.name).collect()))
+}
+
+pub fn tag_keys(
+    pm: &mut TimeseriesDiskPersistenceManager,
+    timeseries: &str,
+    pattern: Option<&Pattern>,
+    filter: &Filter,
+) -> Result<Vec<String>, String> {
+    let rows = scan(pm, timeseries, filter)?;
+    Ok(sorted(
+        rows.into_iter()
+            .flat_map(|m| m.tags.into_keys())
+            .filter(|k| pattern.is_none_or(|p| p.is_match(k)))
+            .collect(),
+    ))
+}
+
+// (key, value) pairs for the tag keys accepted by keys
+pub fn tag_values(
+    pm: &mut TimeseriesDiskPersistenceManager,
+    timeseries: &str,
+    keys: &dyn Fn(&str) -> bool,
+    filter: &Filter,
+) -> Result<Vec<(String, String)>, String> {
+    let rows = scan(pm, timeseries, filter)?;
+    Ok(sorted(
+        rows.into_iter()
+            .flat_map(|m| m.tags.into_iter())
+            .filter(|(k, _)| keys(k))
+            .collect(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::Comparison;
+
+    #[test]
+    fn filter_queries() {
+        assert_eq!(Filter::default().query("cpu"), "SELECT * FROM cpu");
+        let filter = Filter {
+            start: Some(0),
+            end: None,
+            condition: Some(Condition::Tag {
+                key: "host".to_string(),
+                op: Comparison::Eq,
+                value: "a".to_string(),
+            }),
+        };
+        assert!(!filter.is_empty());
+        assert_eq!(
+            filter.query("cpu"),
+            "SELECT * FROM cpu WHERE time >= '1970-01-01T00:00:00.000Z'"
+        );
+    }
+}
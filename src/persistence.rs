@@ -1,11 +1,26 @@
 use chrono::Local;
+use futures::executor::block_on;
 use gluesql::executor::{EvaluateError, ExecuteError, FetchError};
 use gluesql::prelude::*;
+use gluesql::store::{StoreMut, Transaction};
 
+use crate::cache::ResultCache;
+use crate::cursor::{self, Cursor, Page};
+use crate::engine;
+use crate::explain::{Scan, Trace};
+use crate::head::Head;
+use crate::lastvalue::LastValues;
+use crate::limits::{Budget, QueryLimits, QueryRegistry, RunningQuery};
+use crate::retention::Retention;
+use crate::rollup::Rollups;
+use crate::shards;
+use crate::stats::{SeriesStats, TimeseriesStats};
 use crate::utils::db;
+use crate::utils::ReadOnlyStorage;
 use log::{debug, info};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::fs;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
@@ -31,6 +46,7 @@ use uuid::Uuid;
 //      tags -> key/value tag map
 
 // One Glue + Sled db per timeseries
+// Points are saved by the native engine (engine.rs), GlueSQL reads them as the table rows
 // Table structure
 // "CREATE TABLE <timeseries_name> (id UUID, time TIMESTAMP, created_at TIMESTAMP, name TEXT, value FLOAT, tags MAP);",
 // TODO: separated tag table: "CREATE TABLE <timeseries_name>_tags (id UUID, key TEXT, value TEXT);",
@@ -44,18 +60,79 @@ pub struct TimeseriesDiskPersistenceManager {
     pub timeseries_path: HashMap<String, String>,
     pub storages: Arc<Mutex<HashMap<String, gluesql::storages::SledStorage>>>,
     pub basepath: String,
+    pub limits: QueryLimits,
+    pub queries: Arc<QueryRegistry>,
+    pub budget: Option<Arc<Budget>>, // set on the clone running a tracked query
+    pub last_values: Arc<LastValues>,
+    pub trace: Option<Arc<Trace>>, // set on the clone running EXPLAIN ANALYZE or a cached query
+    pub raw: bool,                 // set on the clone of a query reading raw data over rollups
+    pub results: Arc<ResultCache>,
+    pub retention: Arc<Retention>,
+    pub rollups: Arc<Rollups>,
+    pub stats: Arc<SeriesStats>,
+    pub shards: crate::shards::Config,
+    pub head: Arc<Head>,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct Measurement {
     pub key: i64, // A timestamp
     pub id: Uuid, // Unique ID for each measurement
+    pub name: String,
     pub value: f64,
     // ts name -> ts db path
     pub tags: HashMap<String, String>,
 }
 
 impl TimeseriesDiskPersistenceManager {
+    // Registers a query: reads through the returned manager are charged to its budget
+    pub fn track(&self, query: &str, limits: QueryLimits) -> (Self, RunningQuery) {
+        let running = QueryRegistry::register(&self.queries, query, limits);
+        let mut pm = self.clone();
+        pm.budget = Some(running.budget.clone());
+        (pm, running)
+    }
+
+    // Fails once the tracked query was killed or ran out of time
+    pub fn check_budget(&self) -> Result<(), String> {
+        match &self.budget {
+            Some(budget) => budget.check(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn check_returned(&self, rows: usize) -> Result<(), String> {
+        if let Some(trace) = &self.trace {
+            trace.returned(rows);
+        }
+        match &self.budget {
+            Some(budget) => budget.returned(rows),
+            None => Ok(()),
+        }
+    }
+
+    // Rows read so far by the tracked query
+    pub fn rows_scanned(&self) -> usize {
+        self.budget.as_ref().map_or(0, |b| b.rows_scanned())
+    }
+
+    // Records a read for EXPLAIN ANALYZE, with the rows read since rows_scanned() was taken
+    pub fn record_scan(&self, timeseries: &str, query: &str, scanned_before: usize, rows: usize) {
+        if let Some(trace) = &self.trace {
+            let mut scan = Scan::new(timeseries, query);
+            scan.rows_read = Some(self.rows_scanned() - scanned_before);
+            scan.rows_matched = Some(rows);
+            trace.record(scan);
+        }
+    }
+
+    // Reads of the points of a timeseries and its head, charged to the budget of the query
+    pub fn read_only(&self, timeseries: &str, storage: SledStorage) -> ReadOnlyStorage {
+        ReadOnlyStorage::new(storage)
+            .with_budget(self.budget.clone())
+            .native(self.head.get(timeseries))
+    }
+
     pub fn list_timeseries(self) -> Result<Vec<String>, String> {
         let databases: Vec<String> = self
             .storages
@@ -68,26 +145,38 @@ impl TimeseriesDiskPersistenceManager {
     }
 
     pub fn timeseries_exists(self, ts_name: String) -> bool {
-        return self.timeseries_path.contains_key(&ts_name);
+        // storages are shared, timeseries_path only knows the ones loaded by this clone
+        return self.storages.lock().unwrap().contains_key(&ts_name);
     }
     pub fn check_database(
-        self,
+        mut self,
         timeseries_name: String,
         create_if_not_exists: bool,
     ) -> Result<gluesql::storages::SledStorage, String> {
-        let ss = self.storages.lock().unwrap();
-        match ss.get(&timeseries_name.clone()) {
-            Some(s) => Ok(s.clone()),
+        let storage = self
+            .storages
+            .lock()
+            .unwrap()
+            .get(&timeseries_name.clone())
+            .cloned();
+        match storage {
+            Some(s) => Ok(s),
             None => {
                 if create_if_not_exists {
                     let ts_path = format!("{}/{}", self.basepath, timeseries_name);
                     info!("Creating db {}", ts_path);
 
-                    fs::create_dir(ts_path.clone());
+                    let _ = fs::create_dir(ts_path.clone());
 
-                    return match self.load_or_create_database(ts_path) {
+                    match self.load_or_create_database(ts_path) {
                         Ok(d) => info!("db {} created and checked", d),
-                        Err(e) => info!("error creating db {}", e),
+                        Err(e) => return Err(format!("error creating db {}", e)),
+                    };
+                    // regex sources may match the new timeseries
+                    self.results.clear();
+                    return match self.storages.lock().unwrap().get(&timeseries_name) {
+                        Some(s) => Ok(s.clone()),
+                        None => Err("No storage found".to_string()),
                     };
                 };
                 Err(format!("No storage found"))
@@ -95,71 +184,151 @@ impl TimeseriesDiskPersistenceManager {
         }
     }
 
-    // TODO: implement tags
-    pub fn save_measurement(
+    // Saves a measurement at a given time, as rollups do for the windows they compute
+    pub fn save_measurement_at(
         &mut self,
         timeseries_name: String,
         name: String,
         value: f64,
         tags: HashMap<String, String>,
-        create_database: bool,
+        time: i64,
     ) -> Result<Measurement, String> {
-        match self.clone().check_database(timeseries_name.clone(), true) {
-            Ok(storage) => {
-                let mut db = Glue::new(storage.clone());
-                let uuid = Uuid::new_v4();
-                let now = Local::now();
-                let now_dt = now.to_rfc3339(); //timestamp_millis();
-                let tags_json = serde_json::to_string(&tags);
-                let query = format!(
-                    // "CREATE TABLE {} (id UUID, time TIMESTAMP, created_at TIMESTAMP, name TEXT, value FLOAT, tags MAP);",
-                    "INSERT INTO {} VALUES ('{}', '{}', '{}', '{}', {}, '{}')",
-                    timeseries_name,
-                    uuid, //.as_u128(),
-                    now_dt,
-                    now_dt,
-                    name,
-                    value,
-                    tags_json.unwrap()
-                );
-                match db.execute(&query) {
-                    Ok(result) => {
-                        debug!("{:?}", result);
-                        let ev = Measurement {
-                            key: now.clone().timestamp_millis(),
-                            id: uuid.clone(),
-                            value: value.clone(),
-                            tags: tags.clone(),
-                        };
-                        return Ok(ev);
-                    }
-                    Err(e) => {
-                        return Err(format!("Error saving measurement: {} {}", e, query.clone()))
-                    }
-                }
-            }
-            Err(e) => return Err(format!("Error checking database {}", e)),
+        let ev = Measurement {
+            key: time,
+            id: Uuid::new_v4(),
+            name,
+            value,
+            tags,
         };
+        self.save(&timeseries_name, vec![ev.clone()])?;
+        return Ok(ev);
     }
 
-    // consider this insecure by design. the timeseries name comes with the query string :grin:
-    pub fn query_measurements(&mut self, query: String) -> Result<Vec<Measurement>, String> {
-        if query.to_uppercase().contains("INSERT")
-            || query.to_uppercase().contains("DELETE")
-            || query.to_uppercase().contains("UPDATE")
-            || query.to_uppercase().contains("DROP")
-            || query.to_uppercase().contains("CREATE")
+    // Saves a batch of fields written to a timeseries now, its log synced once
+    pub fn save_batch(
+        &mut self,
+        timeseries_name: String,
+        fields: Vec<(String, f64, HashMap<String, String>)>,
+    ) -> Result<Vec<Measurement>, String> {
+        let now = Local::now().timestamp_millis();
+        let batch: Vec<Measurement> = fields
+            .into_iter()
+            .map(|(name, value, tags)| Measurement {
+                key: now,
+                id: Uuid::new_v4(),
+                name,
+                value,
+                tags,
+            })
+            .collect();
+        self.save(&timeseries_name, batch.clone())?;
+        Ok(batch)
+    }
+
+    fn save(&mut self, timeseries_name: &str, batch: Vec<Measurement>) -> Result<(), String> {
+        let storage = match self
+            .clone()
+            .check_database(timeseries_name.to_string(), true)
         {
-            return Err(format!("Invalid query {}", query));
+            Ok(s) => s,
+            Err(e) => return Err(format!("Error checking database {}", e)),
+        };
+        let now = Local::now().timestamp_millis();
+        // held until the statistics are updated, a rebuild doesn't see the points half written
+        let stats = self.stats.timeseries(timeseries_name);
+        let mut stats = stats.lock().unwrap();
+        // rollups write past windows again, straight to the shards
+        let saved = match self.rollups.is_rollup(timeseries_name) {
+            true => engine::insert_all(&storage, &self.shards, &batch, now),
+            false => self
+                .head
+                .insert_all(timeseries_name, &storage, &self.shards, &batch, now),
+        };
+        if let Err(e) = saved {
+            return Err(format!("Error saving measurement: {}", e));
         }
-        match db::query_statement_tablename(query.clone()) {
-            Ok(tablename) => {
-                return self._run_query(tablename.clone(), query.clone());
+        // the points are logged: a failure of what's derived from them is logged too but not
+        // returned, the writers told of it would write the points again
+        for ev in batch.iter() {
+            debug!("{:?}", ev);
+            if let Err(e) = self.derive(timeseries_name, &storage, &mut stats, ev) {
+                info!("Error updating {} after a write: {}", timeseries_name, e);
             }
-            Err(e) => {
-                return Err(format!("Validator error: {}", e));
+        }
+        if let Err(e) = stats.persist(timeseries_name, &storage) {
+            info!("Error saving the statistics of {}: {}", timeseries_name, e);
+        }
+        Ok(())
+    }
+
+    // Last values, statistics, cached results and rollup windows of a point saved
+    fn derive(
+        &mut self,
+        timeseries_name: &str,
+        storage: &SledStorage,
+        stats: &mut TimeseriesStats,
+        ev: &Measurement,
+    ) -> Result<(), String> {
+        self.results.invalidate(timeseries_name, ev.key);
+        self.last_values.update(timeseries_name, storage, ev)?;
+        stats.add(shards::for_time(storage, &self.shards, ev.key)?.start, ev);
+        crate::rollup::written(self, timeseries_name, ev.key)
+    }
+
+    // The query is validated on its AST: only SELECTs over known timeseries, run on a read-only handle
+    pub fn query_measurements(&mut self, query: String) -> Result<Vec<Measurement>, String> {
+        let tablename = self.validate_query(&query)?;
+        self._run_query(tablename, query)
+    }
+
+    // A page of a raw SELECT in (time, id) order, after the cursor when given
+    pub fn query_measurement_page(
+        &mut self,
+        query: String,
+        cursor: Option<&Cursor>,
+        limit: usize,
+    ) -> Result<Page, String> {
+        let tablename = self.validate_query(&query)?;
+        self.read_page(tablename, &query, cursor, limit)
+    }
+
+    // Reads the rows of the page queries only, the scan stops once the page is full
+    fn read_page(
+        &mut self,
+        timeseries_name: String,
+        query: &str,
+        cursor: Option<&Cursor>,
+        limit: usize,
+    ) -> Result<Page, String> {
+        let mut rows = Vec::new();
+        for q in cursor::page_queries(query, cursor, limit)? {
+            rows.extend(self.scan_measurements(timeseries_name.clone(), q)?);
+        }
+        Ok(cursor::paginate(rows, cursor, limit))
+    }
+
+    // Returns the timeseries a valid query reads
+    fn validate_query(&self, query: &str) -> Result<String, String> {
+        let tablenames = match db::query_statement_tablenames(query) {
+            Ok(t) => t,
+            Err(e) => return Err(format!("Validator error: {}", e)),
+        };
+        for tablename in tablenames.iter() {
+            if !self.storages.lock().unwrap().contains_key(tablename) {
+                return Err(format!(
+                    "Validator error: Timeseries not found: {}",
+                    tablename
+                ));
             }
         }
+        // each timeseries lives in its own storage
+        if tablenames.len() > 1 {
+            return Err(format!(
+                "Validator error: a query can only read one timeseries: {}",
+                tablenames.join(", ")
+            ));
+        }
+        Ok(tablenames[0].clone())
     }
     pub fn get_measurement_range(
         &mut self,
@@ -173,14 +342,16 @@ impl TimeseriesDiskPersistenceManager {
             .clone()
         {
             Ok(storage) => {
-                let mut db = Glue::new(storage.clone());
-                let query = format!(
-                    "SELECT key, id, created_at, name, value, tags from {} WHERE key >= {} AND key <= {}",
-                    timeseries_name, start_key, end_key
-                );
+                let mut db = Glue::new(self.read_only(&timeseries_name, storage));
+                let query = range_query(&timeseries_name, start_key, end_key);
+                let scanned = self.rows_scanned();
                 // fetch or create the db handler
                 match db.execute(&query) {
-                    Ok(payload) => return db::parse_select_payload(payload),
+                    Ok(payload) => {
+                        let rows = db::parse_select_payload(payload)?;
+                        self.record_scan(&timeseries_name, &query, scanned, rows.len());
+                        return Ok(rows);
+                    }
                     Err(e) => match e {
                         gluesql::result::Error::Fetch(FetchError::TableNotFound(a)) => {
                             return Err(format!("Table not found: {}", a));
@@ -195,9 +366,133 @@ impl TimeseriesDiskPersistenceManager {
         };
     }
 
+    // A page of the range in (time, id) order, after the cursor when given
+    pub fn get_measurement_page(
+        &mut self,
+        timeseries_name: String,
+        start_key: i64,
+        end_key: i64,
+        cursor: Option<&Cursor>,
+        limit: usize,
+    ) -> Result<Page, String> {
+        let query = range_query(&timeseries_name, start_key, end_key);
+        self.read_page(timeseries_name, &query, cursor, limit)
+    }
+
+    // Latest point of every series of the timeseries, from the last value cache
+    pub fn last_measurements(&self, timeseries_name: &str) -> Result<Vec<Measurement>, String> {
+        if !self.storages.lock().unwrap().contains_key(timeseries_name) {
+            return Err(format!("Timeseries not found: {}", timeseries_name));
+        }
+        Ok(self.last_values.get(timeseries_name))
+    }
+
+    // Runs a SELECT * query built by the query planners against a single timeseries
+    pub fn scan_measurements(
+        &mut self,
+        timeseries_name: String,
+        query: String,
+    ) -> Result<Vec<Measurement>, String> {
+        let storage = match self.storages.lock().unwrap().get(&timeseries_name) {
+            Some(s) => s.clone(),
+            None => return Err(format!("Timeseries not found: {}", timeseries_name)),
+        };
+        let mut db = Glue::new(self.read_only(&timeseries_name, storage));
+        let scanned = self.rows_scanned();
+        match db.execute(&query) {
+            Ok(payload) => {
+                let rows = db::parse_select_rows(payload)?;
+                self.record_scan(&timeseries_name, &query, scanned, rows.len());
+                Ok(rows)
+            }
+            Err(e) => Err(format!("query error: {:?}", e)),
+        }
+    }
+
+    // Runs a validated SELECT against a single timeseries, whatever its columns
+    pub fn select_rows(
+        &mut self,
+        timeseries_name: String,
+        query: String,
+    ) -> Result<db::LabeledRows, String> {
+        let storage = match self.storages.lock().unwrap().get(&timeseries_name) {
+            Some(s) => s.clone(),
+            None => return Err(format!("Timeseries not found: {}", timeseries_name)),
+        };
+        let mut db = Glue::new(self.read_only(&timeseries_name, storage));
+        let scanned = self.rows_scanned();
+        match db.execute(&query) {
+            Ok(payload) => {
+                let rows = db::parse_select_labels(payload)?;
+                self.record_scan(&timeseries_name, &query, scanned, rows.1.len());
+                Ok(rows)
+            }
+            Err(e) => Err(format!("query error: {:?}", e)),
+        }
+    }
+
+    /*
+    Federated execution: every (timeseries, query) source is run on its own storage and the
+    resulting rows are copied into a temporary storage, where the statement runs read-only.
+     */
+    pub fn query_federated(
+        &mut self,
+        query: String,
+        sources: Vec<(String, String)>,
+    ) -> Result<db::LabeledRows, String> {
+        let config = gluesql::sled::Config::default().temporary(true);
+        let federated = match SledStorage::try_from(config) {
+            Ok(s) => s,
+            Err(e) => return Err(format!("Error creating federated storage {}", e)),
+        };
+        let mut storages: Vec<(String, String, SledStorage)> = Vec::new();
+        for (ts_name, source_query) in sources {
+            match self.storages.lock().unwrap().get(&ts_name) {
+                Some(s) => storages.push((ts_name.clone(), source_query, s.clone())),
+                None => return Err(format!("Timeseries not found: {}", ts_name)),
+            };
+        }
+        for (ts_name, source_query, storage) in storages {
+            let scanned = self.rows_scanned();
+            let rows = match Glue::new(self.read_only(&ts_name, storage)).execute(&source_query) {
+                Ok(Payload::Select { rows, .. }) => rows,
+                Ok(p) => return Err(format!("Unexpected result: {:?}", p)),
+                Err(e) => return Err(format!("query error: {:?}", e)),
+            };
+            self.record_scan(&ts_name, &source_query, scanned, rows.len());
+            db::check_or_create_database(ts_name.clone(), federated.clone(), true)?;
+            let rows: Vec<gluesql::data::Row> = rows.into_iter().map(gluesql::data::Row).collect();
+            // writes go through a transaction, as GlueSQL does for an INSERT
+            let copied = block_on(async {
+                let (storage, _) = federated.clone().begin(true).await?;
+                let (storage, _) = storage.insert_data(&ts_name, rows).await?;
+                storage.commit().await
+            });
+            if let Err((_, e)) = copied {
+                return Err(format!("Error copying {}: {:?}", ts_name, e));
+            }
+        }
+        // the temporary storage holds table rows, not points
+        let federated = ReadOnlyStorage::new(federated).with_budget(self.budget.clone());
+        let mut db = Glue::new(federated);
+        let scanned = self.rows_scanned();
+        match db.execute(&query) {
+            Ok(payload) => {
+                let rows = db::parse_select_labels(payload)?;
+                self.record_scan("(temporary)", &query, scanned, rows.1.len());
+                Ok(rows)
+            }
+            Err(e) => Err(format!("query error: {:?}", e)),
+        }
+    }
+
     fn _run_query(&mut self, ts_name: String, query: String) -> Result<Vec<Measurement>, String> {
-        let storage = self.storages.lock().unwrap().get(&ts_name).unwrap().clone();
-        let mut db = Glue::new(storage.clone());
+        let storage = match self.storages.lock().unwrap().get(&ts_name) {
+            Some(s) => s.clone(),
+            None => return Err(format!("Timeseries not found: {}", ts_name)),
+        };
+        let mut db = Glue::new(self.read_only(&ts_name, storage));
+        let scanned = self.rows_scanned();
         match db.execute(&query) {
             Err(e) => match e {
                 gluesql::result::Error::Fetch(FetchError::TableNotFound(a)) => {
@@ -215,7 +510,11 @@ impl TimeseriesDiskPersistenceManager {
                     return Err(format!("query error: {:?}", e));
                 }
             },
-            Ok(payload) => db::parse_select_payload(payload),
+            Ok(payload) => {
+                let rows = db::parse_select_payload(payload)?;
+                self.record_scan(&ts_name, &query, scanned, rows.len());
+                Ok(rows)
+            }
         }
     }
 
@@ -226,10 +525,16 @@ impl TimeseriesDiskPersistenceManager {
             Ok(ss) => {
                 match db::check_or_create_database(ts_tablename.into(), ss.clone(), true) {
                     Ok(db) => {
+                        engine::migrate(ts_tablename, &ss, &self.shards)?;
+                        self.head.open(ts_tablename, &timeseries_name, &ss)?;
                         self.storages
                             .lock()
                             .unwrap()
                             .insert(ts_tablename.into(), ss.clone());
+                        self.load_last_values(ts_tablename, &ss)?;
+                        self.load_stats(ts_tablename, &ss)?;
+                        self.retention.load(ts_tablename, &ss)?;
+                        self.rollups.load(ts_tablename, &ss)?;
                         self.timeseries_path
                             .insert(ts_tablename.into(), timeseries_name.clone());
                         info!(
@@ -243,15 +548,80 @@ impl TimeseriesDiskPersistenceManager {
                 }
                 Ok(true)
             }
-            Err(e) => (return Err(format!("Error creating storage {}", e))),
+            Err(e) => Err(format!("Error creating storage {}", e)),
+        }
+    }
+
+    // Timeseries written before the cache existed have it rebuilt from the table
+    fn load_last_values(&mut self, ts_name: &str, storage: &SledStorage) -> Result<(), String> {
+        self.last_values.load(ts_name, storage)?;
+        if !self.last_values.get(ts_name).is_empty() {
+            return Ok(());
+        }
+        let query = format!("SELECT * FROM {}", ts_name);
+        for m in self.scan_measurements(ts_name.to_string(), query)? {
+            self.last_values.update(ts_name, storage, &m)?;
+        }
+        Ok(())
+    }
+
+    // Timeseries written before the statistics existed have them built from the table
+    fn load_stats(&mut self, ts_name: &str, storage: &SledStorage) -> Result<(), String> {
+        self.stats.load(ts_name, storage)?;
+        if !self.stats.is_empty(ts_name) {
+            return Ok(());
+        }
+        self.rebuild_stats(ts_name, None, None)
+    }
+
+    // Statistics can't forget points: after deleting the points of a range, the statistics of
+    // its shards are dropped and those of the shards left rebuilt from their points, the writes
+    // to the timeseries waiting meanwhile
+    pub fn rebuild_stats(
+        &mut self,
+        ts_name: &str,
+        start: Option<i64>,
+        end: Option<i64>,
+    ) -> Result<(), String> {
+        let storage = match self.storages.lock().unwrap().get(ts_name) {
+            Some(s) => s.clone(),
+            None => return Err(format!("Timeseries not found: {}", ts_name)),
+        };
+        let stats = self.stats.timeseries(ts_name);
+        let mut stats = stats.lock().unwrap();
+        let left = shards::overlapping(&storage, start, end)?;
+        // from the start of the first shard left, partly in the range
+        let from = match (start, left.first()) {
+            (Some(s), Some(shard)) => Some(s.min(shard.start)),
+            (start, _) => start,
+        };
+        stats.forget(ts_name, &storage, from, end)?;
+        for shard in left {
+            let query = format!(
+                "SELECT * FROM {} WHERE time >= '{}' AND time < '{}'",
+                ts_name,
+                crate::query::timestamp_literal(shard.start),
+                crate::query::timestamp_literal(shard.end)
+            );
+            for m in self.scan_measurements(ts_name.to_string(), query)? {
+                stats.add(shard.start, &m);
+            }
         }
+        stats.persist(ts_name, &storage)
     }
 
-    fn load_persistence(&mut self) {
+    fn load_persistence(&mut self) -> Result<(), String> {
         let dir = Path::new(&self.basepath);
         if dir.is_dir() {
-            for entry in fs::read_dir(dir).unwrap() {
-                let path = entry.unwrap().path();
+            let entries = match fs::read_dir(dir) {
+                Ok(e) => e,
+                Err(e) => return Err(format!("Error reading {}: {}", self.basepath, e)),
+            };
+            for entry in entries {
+                let path = match entry {
+                    Ok(e) => e.path(),
+                    Err(e) => return Err(format!("Error reading {}: {}", self.basepath, e)),
+                };
                 if path.is_dir() {
                     let timeseries_name = path.to_str().unwrap().to_string();
                     info!(
@@ -259,28 +629,73 @@ impl TimeseriesDiskPersistenceManager {
                         self.basepath,
                         timeseries_name.clone(),
                     );
-                    self.load_or_create_database(timeseries_name).unwrap();
+                    self.load_or_create_database(timeseries_name)?;
                 };
             }
         }
+        Ok(())
     }
 
-    pub fn setup(&mut self) {
+    pub fn setup(&mut self) -> Result<(), String> {
         if !Path::new(&self.basepath).exists() {
-            fs::create_dir_all(&self.basepath).unwrap();
-            return;
+            if let Err(e) = fs::create_dir_all(&self.basepath) {
+                return Err(format!("Error creating {}: {}", self.basepath, e));
+            }
+            return Ok(());
         }
 
-        self.load_persistence();
+        self.load_persistence()?;
+        self.replay()
     }
 
-    pub fn new(basepath: String) -> Self {
+    // Replays the writes logged but not applied to the shards before the restart in the heads
+    fn replay(&mut self) -> Result<(), String> {
+        let storages: Vec<(String, SledStorage)> = self
+            .storages
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        for (ts_name, storage) in storages {
+            self.head.replay(&ts_name, &storage)?;
+        }
+        Ok(())
+    }
+
+    // The shards and head configured from the environment before the databases are loaded and
+    // their logs replayed
+    pub fn new(basepath: String) -> Result<Self, String> {
+        let head = Head::default();
+        head.configure(crate::head::config_from_env()?);
         let mut s = Self {
             basepath: basepath.clone(),
             timeseries_path: HashMap::new(),
             storages: Arc::new(Mutex::new(HashMap::new())),
+            limits: QueryLimits::default(),
+            queries: Arc::new(QueryRegistry::default()),
+            budget: None,
+            last_values: Arc::new(LastValues::default()),
+            trace: None,
+            raw: false,
+            results: Arc::new(ResultCache::default()),
+            retention: Arc::new(Retention::default()),
+            rollups: Arc::new(Rollups::default()),
+            stats: Arc::new(SeriesStats::default()),
+            // loading the databases may split their points in shards
+            shards: crate::shards::config_from_env()?,
+            head: Arc::new(head),
         };
-        s.setup();
-        return s;
+        s.setup()?;
+        Ok(s)
     }
 }
+
+fn range_query(timeseries_name: &str, start_key: i64, end_key: i64) -> String {
+    format!(
+        "SELECT * FROM {} WHERE time >= '{}' AND time <= '{}'",
+        timeseries_name,
+        crate::query::timestamp_literal(start_key),
+        crate::query::timestamp_literal(end_key)
+    )
+}
@@ -0,0 +1,1137 @@
+use crate::functions::{self, Point};
+use crate::persistence::{Measurement, TimeseriesDiskPersistenceManager};
+use crate::query::{self, Comparison, Condition, Pattern};
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, HashMap};
+
+// PromQL subset for the Prometheus HTTP API (/api/v1/query and /api/v1/query_range)
+//   cpu_usage{host=~"web.*", region!="eu"}      instant vector, last sample within 5m
+//   http_requests_total[5m] offset 1h           range vector
+//   sum by (host) (rate(http_requests_total[5m]))
+//   avg_over_time(cpu_usage[10m]), histogram_quantile(0.99, sum by (le) (rate(latency_bucket[5m])))
+// Binary + - * / between scalars and vectors is supported, vector to vector matching
+// is one to one on all labels.
+// Metric names map to a timeseries and field: field value is named after the timeseries,
+// other fields are <timeseries>_<field>. Tags are the labels.
+
+// How far back an instant vector selector looks for the latest sample
+pub const LOOKBACK: i64 = 300_000;
+// Same resolution limit as Prometheus
+pub const MAX_POINTS: i64 = 11_000;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Number(f64),
+    Duration(i64), // milliseconds
+    Symbol(&'static str),
+    Eof,
+}
+
+const SYMBOLS: [&str; 16] = [
+    "=~", "!~", "!=", "==", "=", "{", "}", "(", ")", "[", "]", ",", "+", "-", "*", "/",
+];
+
+fn tokenize(query: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = query.chars().collect();
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_alphabetic() || c == '_' || c == ':' {
+            let start = i;
+            while i < chars.len()
+                && (chars[i].is_ascii_alphanumeric() || chars[i] == '_' || chars[i] == ':')
+            {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+        } else if c.is_ascii_digit() || c == '.' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            let number: String = chars[start..i].iter().collect();
+            if i < chars.len() && chars[i].is_ascii_alphabetic() {
+                // durations such as 5m or 1h30m
+                let mut duration = 0;
+                let mut amount = number;
+                loop {
+                    let unit_start = i;
+                    while i < chars.len() && chars[i].is_ascii_alphabetic() {
+                        i += 1;
+                    }
+                    let unit: String = chars[unit_start..i].iter().collect();
+                    duration += functions::parse_duration(&format!("{}{}", amount, unit))?;
+                    let amount_start = i;
+                    while i < chars.len() && chars[i].is_ascii_digit() {
+                        i += 1;
+                    }
+                    if i == amount_start {
+                        break;
+                    }
+                    amount = chars[amount_start..i].iter().collect();
+                }
+                tokens.push(Token::Duration(duration));
+            } else {
+                match number.parse::<f64>() {
+                    Ok(n) => tokens.push(Token::Number(n)),
+                    Err(_) => return Err(format!("Invalid number: {}", number)),
+                }
+            }
+        } else if c == '"' || c == '\'' || c == '`' {
+            let mut text = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => return Err(format!("Unterminated string in: {}", query)),
+                    Some('\\') if c != '`' => {
+                        match chars.get(i + 1) {
+                            Some('n') => text.push('\n'),
+                            Some('t') => text.push('\t'),
+                            Some(e) => text.push(*e),
+                            None => return Err(format!("Unterminated string in: {}", query)),
+                        }
+                        i += 2;
+                    }
+                    Some(ch) if *ch == c => {
+                        i += 1;
+                        break;
+                    }
+                    Some(ch) => {
+                        text.push(*ch);
+                        i += 1;
+                    }
+                }
+            }
+            tokens.push(Token::Str(text));
+        } else {
+            let rest: String = chars[i..chars.len().min(i + 2)].iter().collect();
+            match SYMBOLS.iter().find(|s| rest.starts_with(*s)) {
+                Some(s) => {
+                    tokens.push(Token::Symbol(s));
+                    i += s.len();
+                }
+                None => return Err(format!("Unexpected character '{}' in: {}", c, query)),
+            }
+        }
+    }
+    tokens.push(Token::Eof);
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MatchOp {
+    Eq,
+    NotEq,
+    Re,
+    NotRe,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Matcher {
+    pub name: String,
+    pub op: MatchOp,
+    pub value: String,
+}
+
+impl Matcher {
+    // Prometheus regexes are anchored, missing labels match as empty strings
+    fn condition(&self) -> Result<Condition, String> {
+        match self.op {
+            MatchOp::Eq | MatchOp::NotEq => Ok(Condition::Tag {
+                key: self.name.clone(),
+                op: if self.op == MatchOp::Eq {
+                    Comparison::Eq
+                } else {
+                    Comparison::NotEq
+                },
+                value: self.value.clone(),
+            }),
+            MatchOp::Re | MatchOp::NotRe => Ok(Condition::TagMatch {
+                key: self.name.clone(),
+                pattern: Pattern::new(&format!("^(?:{})$", self.value))?,
+                negated: self.op == MatchOp::NotRe,
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Selector {
+    pub name: String,
+    pub matchers: Vec<Matcher>,
+    pub range: Option<i64>,
+    pub offset: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AggregateOp {
+    Sum,
+    Avg,
+    Min,
+    Max,
+    Count,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Grouping {
+    By(Vec<String>),
+    Without(Vec<String>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArithOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    Selector(Selector),
+    Call(String, Vec<Expr>),
+    Aggregate {
+        op: AggregateOp,
+        grouping: Grouping,
+        expr: Box<Expr>,
+    },
+    Binary(Box<Expr>, ArithOp, Box<Expr>),
+}
+
+const FUNCTIONS: [&str; 8] = [
+    "rate",
+    "increase",
+    "avg_over_time",
+    "sum_over_time",
+    "min_over_time",
+    "max_over_time",
+    "count_over_time",
+    "histogram_quantile",
+];
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos.min(self.tokens.len() - 1)]
+    }
+
+    fn next(&mut self) -> Token {
+        let t = self.peek().clone();
+        if self.pos < self.tokens.len() {
+            self.pos += 1;
+        }
+        t
+    }
+
+    fn symbol(&mut self, symbol: &'static str) -> bool {
+        if *self.peek() == Token::Symbol(symbol) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect_symbol(&mut self, symbol: &'static str) -> Result<(), String> {
+        if self.symbol(symbol) {
+            return Ok(());
+        }
+        Err(format!("Expected {}, found {:?}", symbol, self.peek()))
+    }
+
+    fn keyword(&mut self, keyword: &str) -> bool {
+        if matches!(self.peek(), Token::Ident(i) if i.eq_ignore_ascii_case(keyword)) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn identifier(&mut self) -> Result<String, String> {
+        match self.next() {
+            Token::Ident(i) => Ok(i),
+            t => Err(format!("Expected a label name, found {:?}", t)),
+        }
+    }
+
+    fn duration(&mut self) -> Result<i64, String> {
+        match self.next() {
+            Token::Duration(d) => Ok(d),
+            t => Err(format!("Expected a duration, found {:?}", t)),
+        }
+    }
+
+    fn expr(&mut self) -> Result<Expr, String> {
+        let mut left = self.term()?;
+        loop {
+            let op = if self.symbol("+") {
+                ArithOp::Add
+            } else if self.symbol("-") {
+                ArithOp::Sub
+            } else {
+                return Ok(left);
+            };
+            let right = self.term()?;
+            left = Expr::Binary(Box::new(left), op, Box::new(right));
+        }
+    }
+
+    fn term(&mut self) -> Result<Expr, String> {
+        let mut left = self.unary()?;
+        loop {
+            let op = if self.symbol("*") {
+                ArithOp::Mul
+            } else if self.symbol("/") {
+                ArithOp::Div
+            } else {
+                return Ok(left);
+            };
+            let right = self.unary()?;
+            left = Expr::Binary(Box::new(left), op, Box::new(right));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.symbol("-") {
+            let e = self.unary()?;
+            return Ok(match e {
+                Expr::Number(n) => Expr::Number(-n),
+                e => Expr::Binary(Box::new(Expr::Number(-1.0)), ArithOp::Mul, Box::new(e)),
+            });
+        }
+        self.symbol("+");
+        let mut e = self.primary()?;
+        if self.symbol("[") {
+            let range = self.duration()?;
+            self.expect_symbol("]")?;
+            match &mut e {
+                Expr::Selector(s) => s.range = Some(range),
+                _ => return Err("Ranges are only supported on selectors".to_string()),
+            }
+        }
+        if self.keyword("offset") {
+            let offset = self.duration()?;
+            match &mut e {
+                Expr::Selector(s) => s.offset = offset,
+                _ => return Err("offset is only supported on selectors".to_string()),
+            }
+        }
+        Ok(e)
+    }
+
+    fn grouping(&mut self) -> Result<Option<Grouping>, String> {
+        let by = if self.keyword("by") {
+            true
+        } else if self.keyword("without") {
+            false
+        } else {
+            return Ok(None);
+        };
+        self.expect_symbol("(")?;
+        let mut labels: Vec<String> = Vec::new();
+        while !self.symbol(")") {
+            labels.push(self.identifier()?);
+            if !self.symbol(",") {
+                self.expect_symbol(")")?;
+                break;
+            }
+        }
+        Ok(Some(if by {
+            Grouping::By(labels)
+        } else {
+            Grouping::Without(labels)
+        }))
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Token::Number(n) => Ok(Expr::Number(n)),
+            Token::Symbol("(") => {
+                let e = self.expr()?;
+                self.expect_symbol(")")?;
+                Ok(e)
+            }
+            Token::Symbol("{") => self.selector(None),
+            Token::Ident(name) => {
+                let op = match name.to_lowercase().as_str() {
+                    "sum" => Some(AggregateOp::Sum),
+                    "avg" => Some(AggregateOp::Avg),
+                    "min" => Some(AggregateOp::Min),
+                    "max" => Some(AggregateOp::Max),
+                    "count" => Some(AggregateOp::Count),
+                    _ => None,
+                };
+                if let Some(op) = op {
+                    let before = self.grouping()?;
+                    self.expect_symbol("(")?;
+                    let expr = self.expr()?;
+                    self.expect_symbol(")")?;
+                    let after = self.grouping()?;
+                    return Ok(Expr::Aggregate {
+                        op,
+                        grouping: before.or(after).unwrap_or(Grouping::By(vec![])),
+                        expr: Box::new(expr),
+                    });
+                }
+                if self.symbol("(") {
+                    if !FUNCTIONS.contains(&name.as_str()) {
+                        return Err(format!("Unsupported function: {}", name));
+                    }
+                    let mut args: Vec<Expr> = Vec::new();
+                    while !self.symbol(")") {
+                        args.push(self.expr()?);
+                        if !self.symbol(",") {
+                            self.expect_symbol(")")?;
+                            break;
+                        }
+                    }
+                    return Ok(Expr::Call(name, args));
+                }
+                if self.symbol("{") {
+                    return self.selector(Some(name));
+                }
+                Ok(Expr::Selector(Selector {
+                    name,
+                    matchers: vec![],
+                    range: None,
+                    offset: 0,
+                }))
+            }
+            t => Err(format!("Unexpected {:?}", t)),
+        }
+    }
+
+    // the opening brace has been consumed
+    fn selector(&mut self, name: Option<String>) -> Result<Expr, String> {
+        let mut matchers: Vec<Matcher> = Vec::new();
+        while !self.symbol("}") {
+            let label = self.identifier()?;
+            let op = match self.next() {
+                Token::Symbol("=") => MatchOp::Eq,
+                Token::Symbol("!=") => MatchOp::NotEq,
+                Token::Symbol("=~") => MatchOp::Re,
+                Token::Symbol("!~") => MatchOp::NotRe,
+                t => return Err(format!("Expected a label matcher, found {:?}", t)),
+            };
+            let value = match self.next() {
+                Token::Str(s) => s,
+                t => return Err(format!("Expected a string, found {:?}", t)),
+            };
+            matchers.push(Matcher {
+                name: label,
+                op,
+                value,
+            });
+            if !self.symbol(",") {
+                self.expect_symbol("}")?;
+                break;
+            }
+        }
+        let mut name = name;
+        if name.is_none() {
+            if let Some(i) = matchers
+                .iter()
+                .position(|m| m.name == "__name__" && m.op == MatchOp::Eq)
+            {
+                name = Some(matchers.remove(i).value);
+            }
+        }
+        match name {
+            Some(name) => Ok(Expr::Selector(Selector {
+                name,
+                matchers,
+                range: None,
+                offset: 0,
+            })),
+            None => Err("A metric name is required".to_string()),
+        }
+    }
+}
+
+pub fn parse(query: &str) -> Result<Expr, String> {
+    let mut parser = Parser {
+        tokens: tokenize(query)?,
+        pos: 0,
+    };
+    let expr = parser.expr()?;
+    if *parser.peek() != Token::Eof {
+        return Err(format!("Unexpected {:?}", parser.peek()));
+    }
+    Ok(expr)
+}
+
+// Evaluation
+
+pub type Labels = BTreeMap<String, String>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub labels: Labels,
+    pub value: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RangeSeries {
+    pub labels: Labels,
+    pub points: Vec<Point>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Scalar(f64),
+    Vector(Vec<Sample>),
+    Matrix(Vec<RangeSeries>),
+}
+
+pub fn metric_name(timeseries: &str, field: &str) -> String {
+    if field == "value" {
+        return timeseries.to_string();
+    }
+    format!("{}_{}", timeseries, field)
+}
+
+fn selector_key(s: &Selector) -> String {
+    format!("{}{:?}", s.name, s.matchers)
+}
+
+fn selectors<'a>(expr: &'a Expr, found: &mut Vec<&'a Selector>) {
+    match expr {
+        Expr::Selector(s) => found.push(s),
+        Expr::Call(_, args) => args.iter().for_each(|a| selectors(a, found)),
+        Expr::Aggregate { expr, .. } => selectors(expr, found),
+        Expr::Binary(l, _, r) => {
+            selectors(l, found);
+            selectors(r, found);
+        }
+        Expr::Number(_) => (),
+    }
+}
+
+fn without_name(labels: &Labels) -> Labels {
+    let mut labels = labels.clone();
+    labels.remove("__name__");
+    labels
+}
+
+// Series of every selector are loaded once for the whole evaluation range
+pub struct Evaluator {
+    pub series: HashMap<String, Vec<RangeSeries>>,
+}
+
+impl Evaluator {
+    pub fn load(
+        pm: &mut TimeseriesDiskPersistenceManager,
+        expr: &Expr,
+        start: i64,
+        end: i64,
+    ) -> Result<Self, String> {
+        let timeseries = pm.clone().list_timeseries()?;
+        let mut found: Vec<&Selector> = Vec::new();
+        selectors(expr, &mut found);
+        let mut windows: HashMap<String, (i64, i64)> = HashMap::new();
+        for s in found.iter() {
+            let from = start
+                .checked_sub(s.offset)
+                .and_then(|t| t.checked_sub(s.range.unwrap_or(LOOKBACK)));
+            let to = end.checked_sub(s.offset).and_then(|t| t.checked_add(1));
+            let (from, to) = match (from, to) {
+                (Some(from), Some(to)) => (query::valid_time(from)?, query::valid_time(to)?),
+                _ => return Err("Time range out of bounds".to_string()),
+            };
+            let w = windows.entry(selector_key(s)).or_insert((from, to));
+            *w = (w.0.min(from), w.1.max(to));
+        }
+        let mut series: HashMap<String, Vec<RangeSeries>> = HashMap::new();
+        for s in found {
+            let key = selector_key(s);
+            if series.contains_key(&key) {
+                continue;
+            }
+            let (from, to) = windows[&key];
+            let mut condition: Option<Condition> = None;
+            for m in s.matchers.iter().filter(|m| m.name != "__name__") {
+                let c = m.condition()?;
+                condition = Some(match condition {
+                    Some(prev) => Condition::And(Box::new(prev), Box::new(c)),
+                    None => c,
+                });
+            }
+            let mut measurements: Vec<Measurement> = Vec::new();
+            for ts in timeseries
+                .iter()
+                .filter(|t| s.name == **t || s.name.starts_with(&format!("{}_", t)))
+            {
+                let q = format!(
+                    "SELECT * FROM {} WHERE time >= '{}' AND time < '{}'",
+                    ts,
+                    query::timestamp_literal(from),
+                    query::timestamp_literal(to)
+                );
+                measurements.extend(
+                    pm.scan_measurements(ts.clone(), q)?
+                        .into_iter()
+                        .filter(|m| metric_name(ts, &m.name) == s.name)
+                        .filter(|m| condition.as_ref().is_none_or(|c| c.matches(m))),
+                );
+            }
+            series.insert(key, group_measurements(&s.name, measurements));
+        }
+        Ok(Evaluator { series })
+    }
+
+    pub fn eval(&self, expr: &Expr, t: i64) -> Result<Value, String> {
+        match expr {
+            Expr::Number(n) => Ok(Value::Scalar(*n)),
+            Expr::Selector(s) => {
+                let at = t - s.offset;
+                let window = s.range.unwrap_or(LOOKBACK);
+                let mut vector: Vec<Sample> = Vec::new();
+                let mut matrix: Vec<RangeSeries> = Vec::new();
+                for rs in self.series.get(&selector_key(s)).into_iter().flatten() {
+                    let from = rs.points.partition_point(|p| p.time <= at - window);
+                    let to = rs.points.partition_point(|p| p.time <= at);
+                    let points = &rs.points[from..to];
+                    if points.is_empty() {
+                        continue;
+                    }
+                    match s.range {
+                        Some(_) => matrix.push(RangeSeries {
+                            labels: rs.labels.clone(),
+                            points: points.to_vec(),
+                        }),
+                        None => vector.push(Sample {
+                            labels: rs.labels.clone(),
+                            value: points[points.len() - 1].value,
+                        }),
+                    }
+                }
+                match s.range {
+                    Some(_) => Ok(Value::Matrix(matrix)),
+                    None => Ok(Value::Vector(vector)),
+                }
+            }
+            Expr::Call(name, args) if name == "histogram_quantile" => {
+                let (q, v) = match (args.first(), args.get(1), args.len()) {
+                    (Some(q), Some(v), 2) => (self.eval(q, t)?, self.eval(v, t)?),
+                    _ => return Err("histogram_quantile expects 2 arguments".to_string()),
+                };
+                match (q, v) {
+                    (Value::Scalar(q), Value::Vector(v)) => {
+                        Ok(Value::Vector(histogram_quantile(q, v)))
+                    }
+                    _ => {
+                        Err("histogram_quantile expects a scalar and an instant vector".to_string())
+                    }
+                }
+            }
+            Expr::Call(name, args) => {
+                let matrix = match args.as_slice() {
+                    [arg] => match self.eval(arg, t)? {
+                        Value::Matrix(m) => m,
+                        _ => return Err(format!("{} expects a range vector", name)),
+                    },
+                    _ => return Err(format!("{} expects 1 argument", name)),
+                };
+                let mut vector: Vec<Sample> = Vec::new();
+                for rs in matrix {
+                    let value = match name.as_str() {
+                        "rate" => match (rs.points.first(), rs.points.last()) {
+                            (Some(f), Some(l)) if l.time > f.time => {
+                                functions::increase(&rs.points)
+                                    .map(|i| i * 1000.0 / (l.time - f.time) as f64)
+                            }
+                            _ => None,
+                        },
+                        "increase" => functions::increase(&rs.points),
+                        "avg_over_time" => functions::mean(&rs.points),
+                        "sum_over_time" => functions::sum(&rs.points),
+                        "min_over_time" => functions::min(&rs.points),
+                        "max_over_time" => functions::max(&rs.points),
+                        "count_over_time" => functions::count(&rs.points),
+                        _ => return Err(format!("Unsupported function: {}", name)),
+                    };
+                    if let Some(value) = value {
+                        vector.push(Sample {
+                            labels: without_name(&rs.labels),
+                            value,
+                        });
+                    }
+                }
+                Ok(Value::Vector(vector))
+            }
+            Expr::Aggregate { op, grouping, expr } => {
+                let vector = match self.eval(expr, t)? {
+                    Value::Vector(v) => v,
+                    _ => return Err("Aggregations expect an instant vector".to_string()),
+                };
+                Ok(Value::Vector(aggregate(*op, grouping, vector)))
+            }
+            Expr::Binary(l, op, r) => binary(self.eval(l, t)?, *op, self.eval(r, t)?),
+        }
+    }
+}
+
+fn group_measurements(name: &str, measurements: Vec<Measurement>) -> Vec<RangeSeries> {
+    let mut grouped: BTreeMap<Vec<(String, String)>, Vec<Point>> = BTreeMap::new();
+    for m in measurements {
+        let mut tags: Vec<(String, String)> = m.tags.into_iter().collect();
+        tags.sort();
+        grouped
+            .entry(tags)
+            .or_default()
+            .push(Point::new(m.key, m.value));
+    }
+    grouped
+        .into_iter()
+        .map(|(tags, mut points)| {
+            points.sort_by_key(|p| p.time);
+            let mut labels: Labels = tags.into_iter().collect();
+            labels.insert("__name__".to_string(), name.to_string());
+            RangeSeries { labels, points }
+        })
+        .collect()
+}
+
+fn aggregate(op: AggregateOp, grouping: &Grouping, vector: Vec<Sample>) -> Vec<Sample> {
+    let mut groups: BTreeMap<Labels, Vec<Point>> = BTreeMap::new();
+    for s in vector {
+        let labels: Labels = match grouping {
+            Grouping::By(by) => s
+                .labels
+                .into_iter()
+                .filter(|(k, _)| by.contains(k))
+                .collect(),
+            Grouping::Without(without) => without_name(&s.labels)
+                .into_iter()
+                .filter(|(k, _)| !without.contains(k))
+                .collect(),
+        };
+        groups
+            .entry(labels)
+            .or_default()
+            .push(Point::new(0, s.value));
+    }
+    groups
+        .into_iter()
+        .filter_map(|(labels, points)| {
+            let value = match op {
+                AggregateOp::Sum => functions::sum(&points),
+                AggregateOp::Avg => functions::mean(&points),
+                AggregateOp::Min => functions::min(&points),
+                AggregateOp::Max => functions::max(&points),
+                AggregateOp::Count => functions::count(&points),
+            };
+            value.map(|value| Sample { labels, value })
+        })
+        .collect()
+}
+
+fn histogram_quantile(q: f64, vector: Vec<Sample>) -> Vec<Sample> {
+    let mut groups: BTreeMap<Labels, Vec<(f64, f64)>> = BTreeMap::new();
+    for s in vector {
+        let le = match s.labels.get("le").map(|le| le.parse::<f64>()) {
+            Some(Ok(le)) => le,
+            _ => continue,
+        };
+        let mut labels = without_name(&s.labels);
+        labels.remove("le");
+        groups.entry(labels).or_default().push((le, s.value));
+    }
+    groups
+        .into_iter()
+        .map(|(labels, buckets)| Sample {
+            labels,
+            value: functions::bucket_quantile(q, &buckets),
+        })
+        .collect()
+}
+
+fn arith(l: f64, op: ArithOp, r: f64) -> f64 {
+    match op {
+        ArithOp::Add => l + r,
+        ArithOp::Sub => l - r,
+        ArithOp::Mul => l * r,
+        ArithOp::Div => l / r,
+    }
+}
+
+fn binary(left: Value, op: ArithOp, right: Value) -> Result<Value, String> {
+    match (left, right) {
+        (Value::Scalar(l), Value::Scalar(r)) => Ok(Value::Scalar(arith(l, op, r))),
+        (Value::Vector(v), Value::Scalar(r)) => Ok(Value::Vector(
+            v.into_iter()
+                .map(|s| Sample {
+                    labels: without_name(&s.labels),
+                    value: arith(s.value, op, r),
+                })
+                .collect(),
+        )),
+        (Value::Scalar(l), Value::Vector(v)) => Ok(Value::Vector(
+            v.into_iter()
+                .map(|s| Sample {
+                    labels: without_name(&s.labels),
+                    value: arith(l, op, s.value),
+                })
+                .collect(),
+        )),
+        (Value::Vector(l), Value::Vector(r)) => {
+            let right: BTreeMap<Labels, f64> = r
+                .into_iter()
+                .map(|s| (without_name(&s.labels), s.value))
+                .collect();
+            Ok(Value::Vector(
+                l.into_iter()
+                    .filter_map(|s| {
+                        let labels = without_name(&s.labels);
+                        right.get(&labels).map(|r| Sample {
+                            value: arith(s.value, op, *r),
+                            labels,
+                        })
+                    })
+                    .collect(),
+            ))
+        }
+        _ => Err("Binary operations on range vectors are not supported".to_string()),
+    }
+}
+
+// Prometheus HTTP API
+
+// Unix seconds (with fraction) or RFC3339, in milliseconds
+pub fn parse_time(time: &str) -> Result<i64, String> {
+    if let Ok(seconds) = time.parse::<f64>() {
+        let ms = (seconds * 1000.0).round();
+        if !ms.is_finite() {
+            return Err(format!("Invalid time: {}", time));
+        }
+        // out of range values saturate, and are then refused
+        return query::valid_time(ms as i64).map_err(|_| format!("Time out of range: {}", time));
+    }
+    match time.parse::<DateTime<Utc>>() {
+        Ok(t) => Ok(t.timestamp_millis()),
+        Err(_) => Err(format!("Invalid time: {}", time)),
+    }
+}
+
+// Seconds or a duration such as 15s, in milliseconds
+pub fn parse_step(step: &str) -> Result<i64, String> {
+    let ms = match step.parse::<f64>() {
+        Ok(seconds) if (seconds * 1000.0).is_finite() => (seconds * 1000.0).round() as i64,
+        Ok(_) => return Err(format!("Invalid step: {}", step)),
+        Err(_) => functions::parse_duration(step)?,
+    };
+    if ms <= 0 {
+        return Err(format!("Step must be positive: {}", step));
+    }
+    Ok(ms)
+}
+
+fn format_value(v: f64) -> serde_json::Value {
+    let s = if v.is_nan() {
+        "NaN".to_string()
+    } else if v == f64::INFINITY {
+        "+Inf".to_string()
+    } else if v == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else {
+        v.to_string()
+    };
+    serde_json::json!(s)
+}
+
+fn format_time(t: i64) -> serde_json::Value {
+    serde_json::json!(t as f64 / 1000.0)
+}
+
+fn success(result_type: &str, result: serde_json::Value) -> serde_json::Value {
+    serde_json::json!({
+        "status": "success",
+        "data": {"resultType": result_type, "result": result},
+    })
+}
+
+pub fn error(error: &str) -> serde_json::Value {
+    serde_json::json!({"status": "error", "errorType": "bad_data", "error": error})
+}
+
+pub fn instant_query(
+    pm: &mut TimeseriesDiskPersistenceManager,
+    q: &str,
+    time: i64,
+) -> Result<serde_json::Value, String> {
+    let expr = parse(q)?;
+    let evaluator = Evaluator::load(pm, &expr, time, time)?;
+    Ok(match evaluator.eval(&expr, time)? {
+        Value::Scalar(v) => success("scalar", serde_json::json!([format_time(time), format_value(v)])),
+        Value::Vector(v) => success(
+            "vector",
+            v.into_iter()
+                .map(|s| {
+                    serde_json::json!({"metric": s.labels, "value": [format_time(time), format_value(s.value)]})
+                })
+                .collect(),
+        ),
+        Value::Matrix(m) => success(
+            "matrix",
+            m.into_iter()
+                .map(|rs| {
+                    let values: Vec<serde_json::Value> = rs
+                        .points
+                        .iter()
+                        .map(|p| serde_json::json!([format_time(p.time), format_value(p.value)]))
+                        .collect();
+                    serde_json::json!({"metric": rs.labels, "values": values})
+                })
+                .collect(),
+        ),
+    })
+}
+
+pub fn range_query(
+    pm: &mut TimeseriesDiskPersistenceManager,
+    q: &str,
+    start: i64,
+    end: i64,
+    step: i64,
+) -> Result<serde_json::Value, String> {
+    if end < start {
+        return Err("end timestamp must not be before start time".to_string());
+    }
+    if (end - start) / step > MAX_POINTS {
+        return Err(format!(
+            "exceeded maximum resolution of {} points per timeseries",
+            MAX_POINTS
+        ));
+    }
+    let expr = parse(q)?;
+    let evaluator = Evaluator::load(pm, &expr, start, end)?;
+    let mut series: BTreeMap<Labels, Vec<serde_json::Value>> = BTreeMap::new();
+    let mut t = start;
+    while t <= end {
+        let samples = match evaluator.eval(&expr, t)? {
+            Value::Scalar(v) => vec![Sample {
+                labels: Labels::new(),
+                value: v,
+            }],
+            Value::Vector(v) => v,
+            Value::Matrix(_) => {
+                return Err("Range vectors can't be used in range queries".to_string())
+            }
+        };
+        for s in samples {
+            series
+                .entry(s.labels)
+                .or_default()
+                .push(serde_json::json!([format_time(t), format_value(s.value)]));
+        }
+        t = match t.checked_add(step) {
+            Some(next) => next,
+            None => break,
+        };
+    }
+    Ok(success(
+        "matrix",
+        series
+            .into_iter()
+            .map(|(labels, values)| serde_json::json!({"metric": labels, "values": values}))
+            .collect(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(pairs: &[(&str, &str)]) -> Labels {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn range_series(name: &str, host: &str, points: &[(i64, f64)]) -> RangeSeries {
+        RangeSeries {
+            labels: labels(&[("__name__", name), ("host", host)]),
+            points: points.iter().map(|(t, v)| Point::new(*t, *v)).collect(),
+        }
+    }
+
+    #[test]
+    fn parse_expressions() {
+        assert_eq!(
+            parse("sum by (host) (rate(http_requests_total{code=~\"5..\", method!=\"GET\"}[5m] offset 1h))").unwrap(),
+            Expr::Aggregate {
+                op: AggregateOp::Sum,
+                grouping: Grouping::By(vec!["host".to_string()]),
+                expr: Box::new(Expr::Call(
+                    "rate".to_string(),
+                    vec![Expr::Selector(Selector {
+                        name: "http_requests_total".to_string(),
+                        matchers: vec![
+                            Matcher {
+                                name: "code".to_string(),
+                                op: MatchOp::Re,
+                                value: "5..".to_string()
+                            },
+                            Matcher {
+                                name: "method".to_string(),
+                                op: MatchOp::NotEq,
+                                value: "GET".to_string()
+                            },
+                        ],
+                        range: Some(300_000),
+                        offset: 3_600_000,
+                    })]
+                )),
+            }
+        );
+        assert_eq!(
+            parse("{__name__=\"cpu\"}[1h30m]").unwrap(),
+            Expr::Selector(Selector {
+                name: "cpu".to_string(),
+                matchers: vec![],
+                range: Some(5_400_000),
+                offset: 0
+            })
+        );
+        assert_eq!(
+            parse("1 + 2 * 3").unwrap(),
+            Expr::Binary(
+                Box::new(Expr::Number(1.0)),
+                ArithOp::Add,
+                Box::new(Expr::Binary(
+                    Box::new(Expr::Number(2.0)),
+                    ArithOp::Mul,
+                    Box::new(Expr::Number(3.0))
+                ))
+            )
+        );
+        assert!(parse("predict_linear(cpu[5m], 60)").is_err());
+        assert!(parse("{host=\"a\"}").is_err());
+        assert!(parse("rate(cpu)[5m]").is_err());
+    }
+
+    #[test]
+    fn evaluate_vectors() {
+        let cpu = Selector {
+            name: "cpu".to_string(),
+            matchers: vec![],
+            range: None,
+            offset: 0,
+        };
+        let mut series = HashMap::new();
+        series.insert(
+            selector_key(&cpu),
+            vec![
+                range_series("cpu", "a", &[(0, 0.0), (30_000, 30.0), (60_000, 90.0)]),
+                range_series("cpu", "b", &[(0, 10.0), (30_000, 5.0), (60_000, 35.0)]),
+            ],
+        );
+        let evaluator = Evaluator { series };
+
+        let instant = evaluator.eval(&parse("cpu").unwrap(), 60_000).unwrap();
+        assert_eq!(
+            instant,
+            Value::Vector(vec![
+                Sample {
+                    labels: labels(&[("__name__", "cpu"), ("host", "a")]),
+                    value: 90.0
+                },
+                Sample {
+                    labels: labels(&[("__name__", "cpu"), ("host", "b")]),
+                    value: 35.0
+                },
+            ])
+        );
+        // nothing within the lookback window
+        assert_eq!(
+            evaluator.eval(&parse("cpu").unwrap(), 1_000_000).unwrap(),
+            Value::Vector(vec![])
+        );
+        // b resets: 5 + 30 over 60s
+        assert_eq!(
+            evaluator
+                .eval(&parse("sum(rate(cpu[5m]))").unwrap(), 60_000)
+                .unwrap(),
+            Value::Vector(vec![Sample {
+                labels: Labels::new(),
+                value: 1.5 + 35.0 / 60.0
+            }])
+        );
+        // ranges are open on the left: the sample at 0 is excluded
+        assert_eq!(
+            evaluator
+                .eval(&parse("avg_over_time(cpu[1m]) * 2").unwrap(), 60_000)
+                .unwrap(),
+            Value::Vector(vec![
+                Sample {
+                    labels: labels(&[("host", "a")]),
+                    value: 120.0
+                },
+                Sample {
+                    labels: labels(&[("host", "b")]),
+                    value: 40.0
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn evaluate_histogram_quantile() {
+        let bucket = Selector {
+            name: "latency_bucket".to_string(),
+            matchers: vec![],
+            range: None,
+            offset: 0,
+        };
+        let mut series = HashMap::new();
+        series.insert(
+            selector_key(&bucket),
+            ["0.1", "0.5", "+Inf"]
+                .iter()
+                .zip([50.0, 100.0, 100.0].iter())
+                .map(|(le, count)| RangeSeries {
+                    labels: labels(&[("__name__", "latency_bucket"), ("le", le)]),
+                    points: vec![Point::new(0, *count)],
+                })
+                .collect(),
+        );
+        let evaluator = Evaluator { series };
+        match evaluator
+            .eval(
+                &parse("histogram_quantile(0.75, latency_bucket)").unwrap(),
+                0,
+            )
+            .unwrap()
+        {
+            Value::Vector(v) => {
+                assert_eq!(v.len(), 1);
+                assert!(v[0].labels.is_empty());
+                assert!((v[0].value - 0.3).abs() < 1e-9);
+            }
+            v => panic!("not a vector: {:?}", v),
+        }
+    }
+
+    #[test]
+    fn api_parameters() {
+        assert_eq!(parse_time("1638316800.5"), Ok(1638316800500));
+        assert_eq!(parse_time("2021-12-01T00:00:00Z"), Ok(1638316800000));
+        assert!(parse_time("1e13").is_err());
+        assert!(parse_time("1e300").is_err());
+        assert_eq!(parse_step("15s"), Ok(15_000));
+        assert_eq!(parse_step("0.5"), Ok(500));
+        assert!(parse_step("0").is_err());
+        assert_eq!(format_value(f64::INFINITY), serde_json::json!("+Inf"));
+        assert_eq!(format_value(2.0), serde_json::json!("2"));
+    }
+}
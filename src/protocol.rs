@@ -37,10 +37,22 @@ impl LineProtocol {
         }
     }
 
-    pub fn field(&mut self, key: String, value: String) {
+    // field values are stored as floats, anything else is refused
+    pub fn field(&mut self, key: String, value: String) -> Result<(), String> {
         if key.len() > 0 && value.len() > 0 {
-            self.field_set.insert(key, value.parse().unwrap());
+            match value.parse() {
+                Ok(v) => {
+                    self.field_set.insert(key, v);
+                }
+                Err(e) => {
+                    return Err(format!(
+                        "Error: invalid field value {} for {}: {}",
+                        value, key, e
+                    ))
+                }
+            }
         }
+        Ok(())
     }
 
     pub fn serialize(self: Self) -> Result<String, String> {
@@ -117,7 +129,7 @@ impl LineProtocol {
                 });
                 for fk in fkeys.iter() {
                     match fk.split_once("=") {
-                        Some((k, v)) => proto.field(k.to_string(), v.to_string()),
+                        Some((k, v)) => proto.field(k.to_string(), v.to_string())?,
                         None => (),
                     }
                 }
@@ -152,8 +164,8 @@ mod tests {
 
     #[test]
     fn single_tag() {
-        let tst = "mySingleTagMeasurement,tag1=value1 fieldKey1=\"fieldValue\" 1556813561098000000"
-            .to_string();
+        let tst =
+            "mySingleTagMeasurement,tag1=value1 fieldKey1=0.5 1556813561098000000".to_string();
         let res = crate::protocol::LineProtocol::parse(tst.clone()).unwrap();
         let out = res.serialize().unwrap();
 
@@ -161,7 +173,9 @@ mod tests {
     }
     #[test]
     fn multiple_tags() {
-        let tst = "myMultipleTagMeasurement,tag1=value1,tag2=value2 fieldKey=\"fieldValue\" 1556813561098000000".to_string();
+        let tst =
+            "myMultipleTagMeasurement,tag1=value1,tag2=value2 fieldKey=0.5 1556813561098000000"
+                .to_string();
         let res = crate::protocol::LineProtocol::parse(tst.clone()).unwrap();
         let out = res.serialize().unwrap();
 
@@ -170,7 +184,7 @@ mod tests {
 
     #[test]
     fn single_fieldvalue() {
-        let tst = "mySingleFieldKey fieldKey=\"fieldValue\" 1556813561098000000".to_string();
+        let tst = "mySingleFieldKey fieldKey=42 1556813561098000000".to_string();
         let res = crate::protocol::LineProtocol::parse(tst.clone()).unwrap();
         let out = res.serialize().unwrap();
 
@@ -179,12 +193,18 @@ mod tests {
 
     #[test]
     fn multiple_fieldvalues() {
-        let tst =
-            "myMultipleFieldKey fieldKey1=\"fieldValue\",fieldKey2=\"oi\" 1556813561098000000"
-                .to_string();
+        let tst = "myMultipleFieldKey fieldKey1=0.5,fieldKey2=-3 1556813561098000000".to_string();
         let res = crate::protocol::LineProtocol::parse(tst.clone()).unwrap();
         let out = res.serialize().unwrap();
 
         assert_eq!(tst.clone(), out);
     }
+
+    #[test]
+    fn non_numeric_fieldvalue() {
+        let tst = "myStringFieldKey fieldKey=\"fieldValue\" 1556813561098000000".to_string();
+        let res = crate::protocol::LineProtocol::parse(tst);
+
+        assert!(res.unwrap_err().contains("invalid field value"));
+    }
 }
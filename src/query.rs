@@ -0,0 +1,1569 @@
+use crate::calendar::{self, Period, Window};
+use crate::functions::{self, Point};
+use crate::persistence::{Measurement, TimeseriesDiskPersistenceManager};
+use chrono_tz::Tz;
+use gluesql::sqlparser::ast::{
+    Expr, Function, FunctionArg, SelectItem, SetExpr, Statement, TableFactor, Value as SqlValue,
+};
+use gluesql::sqlparser::dialect::GenericDialect;
+use gluesql::sqlparser::tokenizer::{Token, Tokenizer};
+use regex::Regex;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+// Extended query support
+// GlueSQL evaluates generic SQL over rows, but timeseries functions need the rows of each
+// series ordered by time. Queries using them are planned here: the raw rows are fetched
+// through GlueSQL with the original WHERE clause, split per series (tag set) and evaluated.
+//   SELECT rate(value) FROM cpu WHERE name = 'usage'
+//   SELECT moving_average(usage, 5), non_negative_derivative(bytes, '1m') FROM net
+//   SELECT percentile(latency, 99), histogram(latency, 10) FROM http GROUP BY time('5m'), host
+//   SELECT non_negative_derivative(max(bytes), '1s') FROM net GROUP BY time('1m')
+//   SELECT top(mean(usage), host, 10) FROM cpu WHERE time > '2021-11-30T10:00:00Z'
+// The function argument is a field name, `value` applies the function to every field.
+// Aggregates merge every series into one unless tags are listed in GROUP BY, and are
+// computed per time window when GROUP BY time('<duration>') is present.
+// top(field, tag, k) and bottom(field, tag, k) rank the values of a tag by their highest (or
+// lowest) point, or by an aggregate such as top(mean(field), tag, k), and keep the first k.
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Series {
+    pub name: String,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, String>,
+    pub columns: Vec<String>,
+    pub values: Vec<Vec<serde_json::Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tier: Option<String>, // rollup read instead of the raw data
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SeriesFunction {
+    Rate,
+    Derivative(i64),
+    NonNegativeDerivative(i64),
+    Difference,
+    NonNegativeDifference,
+    MovingAverage(usize),
+    CumulativeSum,
+    Elapsed(i64),
+}
+
+impl SeriesFunction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SeriesFunction::Rate => "rate",
+            SeriesFunction::Derivative(_) => "derivative",
+            SeriesFunction::NonNegativeDerivative(_) => "non_negative_derivative",
+            SeriesFunction::Difference => "difference",
+            SeriesFunction::NonNegativeDifference => "non_negative_difference",
+            SeriesFunction::MovingAverage(_) => "moving_average",
+            SeriesFunction::CumulativeSum => "cumulative_sum",
+            SeriesFunction::Elapsed(_) => "elapsed",
+        }
+    }
+
+    pub fn apply(&self, points: &[Point]) -> Vec<Point> {
+        match self {
+            SeriesFunction::Rate => functions::rate(points),
+            SeriesFunction::Derivative(unit) => functions::derivative(points, *unit),
+            SeriesFunction::NonNegativeDerivative(unit) => {
+                functions::non_negative_derivative(points, *unit)
+            }
+            SeriesFunction::Difference => functions::difference(points),
+            SeriesFunction::NonNegativeDifference => functions::non_negative_difference(points),
+            SeriesFunction::MovingAverage(n) => functions::moving_average(points, *n),
+            SeriesFunction::CumulativeSum => functions::cumulative_sum(points),
+            SeriesFunction::Elapsed(unit) => functions::elapsed(points, *unit),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Aggregate {
+    Count,
+    Sum,
+    Mean,
+    Min,
+    Max,
+    First,
+    Last,
+    Percentile(f64),
+    Median,
+    Stddev,
+    Spread,
+    Mode,
+    Histogram(usize),
+}
+
+impl Aggregate {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aggregate::Count => "count",
+            Aggregate::Sum => "sum",
+            Aggregate::Mean => "mean",
+            Aggregate::Min => "min",
+            Aggregate::Max => "max",
+            Aggregate::First => "first",
+            Aggregate::Last => "last",
+            Aggregate::Percentile(_) => "percentile",
+            Aggregate::Median => "median",
+            Aggregate::Stddev => "stddev",
+            Aggregate::Spread => "spread",
+            Aggregate::Mode => "mode",
+            Aggregate::Histogram(_) => "histogram",
+        }
+    }
+
+    // Aggregates GlueSQL can evaluate by itself when no time window is involved
+    pub fn is_builtin(&self) -> bool {
+        matches!(
+            self,
+            Aggregate::Count | Aggregate::Sum | Aggregate::Mean | Aggregate::Min | Aggregate::Max
+        )
+    }
+
+    pub fn scalar(&self, points: &[Point]) -> Option<f64> {
+        match self {
+            Aggregate::Count => functions::count(points),
+            Aggregate::Sum => functions::sum(points),
+            Aggregate::Mean => functions::mean(points),
+            Aggregate::Min => functions::min(points),
+            Aggregate::Max => functions::max(points),
+            Aggregate::First => functions::first(points),
+            Aggregate::Last => functions::last(points),
+            Aggregate::Percentile(p) => functions::percentile(points, *p),
+            Aggregate::Median => functions::median(points),
+            Aggregate::Stddev => functions::stddev(points),
+            Aggregate::Spread => functions::spread(points),
+            Aggregate::Mode => functions::mode(points),
+            Aggregate::Histogram(_) => None,
+        }
+    }
+
+    pub fn evaluate(&self, points: &[Point]) -> serde_json::Value {
+        match self {
+            Aggregate::Histogram(buckets) => {
+                serde_json::json!(functions::histogram(points, *buckets))
+            }
+            _ => match self.scalar(points) {
+                Some(v) => serde_json::json!(v),
+                None => serde_json::Value::Null,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Selector {
+    pub top: bool, // top or bottom
+    pub tag: String,
+    pub k: usize,
+}
+
+impl Selector {
+    pub fn name(&self) -> &'static str {
+        if self.top {
+            "top"
+        } else {
+            "bottom"
+        }
+    }
+
+    // Highest (or lowest) point, the earliest one on ties
+    pub fn select(&self, points: &[Point]) -> Option<Point> {
+        let mut selected: Option<Point> = None;
+        for p in points {
+            selected = match selected {
+                Some(s)
+                    if (self.top && s.value >= p.value) || (!self.top && s.value <= p.value) =>
+                {
+                    Some(s)
+                }
+                _ => Some(*p),
+            };
+        }
+        selected
+    }
+
+    // Orders candidates (tag value, point) by rank and keeps the first k
+    pub fn rank(&self, mut candidates: Vec<(String, Point)>) -> Vec<(String, Point)> {
+        candidates.sort_by(|a, b| {
+            let order =
+                a.1.value
+                    .partial_cmp(&b.1.value)
+                    .unwrap_or(std::cmp::Ordering::Equal);
+            let order = if self.top { order.reverse() } else { order };
+            order.then_with(|| a.0.cmp(&b.0))
+        });
+        candidates.truncate(self.k);
+        candidates
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Projection {
+    pub function: Option<SeriesFunction>,
+    pub aggregate: Option<Aggregate>,
+    pub selector: Option<Selector>,
+    pub field: Option<String>, // None: every field of the timeseries
+    pub alias: Option<String>,
+}
+
+impl Projection {
+    pub fn label(&self, field: &str) -> String {
+        if let Some(alias) = &self.alias {
+            return alias.clone();
+        }
+        let inner = match &self.aggregate {
+            Some(a) => format!("{}({})", a.name(), field),
+            None => field.to_string(),
+        };
+        let inner = match &self.selector {
+            Some(s) => format!("{}({})", s.name(), inner),
+            None => inner,
+        };
+        match &self.function {
+            Some(f) => format!("{}({})", f.name(), inner),
+            None => inner,
+        }
+    }
+
+    // Aggregates and selectors reduce many series into one
+    pub fn is_aggregate(&self) -> bool {
+        self.aggregate.is_some() || self.selector.is_some()
+    }
+
+    pub fn is_builtin(&self) -> bool {
+        self.function.is_none()
+            && self.selector.is_none()
+            && self.aggregate.as_ref().is_some_and(|a| a.is_builtin())
+    }
+
+    // The point picked by a first() or last() projection
+    pub fn selected_point(&self, points: &[Point]) -> Option<Point> {
+        if self.function.is_some() || self.selector.is_some() {
+            return None;
+        }
+        match self.aggregate {
+            Some(Aggregate::First) => points.first().cloned(),
+            Some(Aggregate::Last) => points.last().cloned(),
+            _ => None,
+        }
+    }
+
+    // Evaluates the projection over the time ordered points of one field
+    pub fn evaluate(
+        &self,
+        points: &[Point],
+        interval: Option<Window>,
+    ) -> Vec<(i64, serde_json::Value)> {
+        let json = |points: Vec<Point>| {
+            points
+                .into_iter()
+                .map(|p| (p.time, serde_json::json!(p.value)))
+                .collect()
+        };
+        match (&self.aggregate, &self.function) {
+            (Some(aggregate), Some(function)) => {
+                let aggregated: Vec<Point> = windows(points, interval)
+                    .into_iter()
+                    .filter_map(|(t, w)| aggregate.scalar(w).map(|v| Point::new(t, v)))
+                    .collect();
+                json(function.apply(&aggregated))
+            }
+            (Some(aggregate), None) => windows(points, interval)
+                .into_iter()
+                .map(|(t, w)| (t, aggregate.evaluate(w)))
+                .collect(),
+            (None, Some(function)) => json(function.apply(points)),
+            (None, None) => json(points.to_vec()),
+        }
+    }
+}
+
+pub fn window_start(time: i64, interval: i64) -> i64 {
+    time - time.rem_euclid(interval)
+}
+
+// Splits time ordered points into consecutive windows, a single window without interval
+pub fn windows(points: &[Point], interval: Option<Window>) -> Vec<(i64, &[Point])> {
+    let mut result: Vec<(i64, &[Point])> = Vec::new();
+    if points.is_empty() {
+        return result;
+    }
+    let interval = match interval {
+        Some(i) => i,
+        None => return vec![(points[0].time, points)],
+    };
+    let mut start = 0;
+    for i in 1..=points.len() {
+        if i == points.len() || interval.start(points[i].time) != interval.start(points[start].time)
+        {
+            result.push((interval.start(points[start].time), &points[start..i]));
+            start = i;
+        }
+    }
+    result
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TagGrouping {
+    Series,            // every tag set is a group of its own
+    Tags(Vec<String>), // groups by the listed tags, merging the others
+}
+
+impl TagGrouping {
+    pub fn key(&self, tags: &BTreeMap<String, String>) -> BTreeMap<String, String> {
+        match self {
+            TagGrouping::Series => tags.clone(),
+            TagGrouping::Tags(keys) => keys
+                .iter()
+                .map(|k| (k.clone(), tags.get(k).cloned().unwrap_or_default()))
+                .collect(),
+        }
+    }
+}
+
+// Regex with equality, so conditions and plans can be compared
+#[derive(Debug, Clone)]
+pub struct Pattern(pub Regex);
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Pattern) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Result<Self, String> {
+        match Regex::new(pattern) {
+            Ok(r) => Ok(Pattern(r)),
+            Err(e) => Err(format!("Invalid regex /{}/: {}", pattern, e)),
+        }
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        self.0.is_match(text)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Eq,
+    NotEq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+}
+
+impl Comparison {
+    pub fn compare<T: PartialOrd>(&self, left: &T, right: &T) -> bool {
+        match self {
+            Comparison::Eq => left == right,
+            Comparison::NotEq => left != right,
+            Comparison::Lt => left < right,
+            Comparison::LtEq => left <= right,
+            Comparison::Gt => left > right,
+            Comparison::GtEq => left >= right,
+        }
+    }
+}
+
+// Row filters GlueSQL can't evaluate: tags live in a MAP column
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+    // a missing tag compares as an empty string
+    Tag {
+        key: String,
+        op: Comparison,
+        value: String,
+    },
+    TagMatch {
+        key: String,
+        pattern: Pattern,
+        negated: bool,
+    },
+    // field conditions only match points of that field
+    Field {
+        name: String,
+        op: Comparison,
+        value: f64,
+    },
+}
+
+impl Condition {
+    pub fn matches(&self, m: &Measurement) -> bool {
+        match self {
+            Condition::And(l, r) => l.matches(m) && r.matches(m),
+            Condition::Or(l, r) => l.matches(m) || r.matches(m),
+            Condition::Tag { key, op, value } => {
+                let tag = m.tags.get(key).cloned().unwrap_or_default();
+                op.compare(&tag, value)
+            }
+            Condition::TagMatch {
+                key,
+                pattern,
+                negated,
+            } => {
+                let tag = m.tags.get(key).cloned().unwrap_or_default();
+                pattern.is_match(&tag) != *negated
+            }
+            Condition::Field { name, op, value } => m.name == *name && op.compare(&m.value, value),
+        }
+    }
+}
+
+// How windows without data are reported when grouping by time
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fill {
+    None,
+    Null,
+    Previous,
+    Linear,
+    Value(f64),
+}
+
+impl Fill {
+    // null, none, previous, linear or a number
+    pub fn parse(fill: &str) -> Result<Fill, String> {
+        match fill.trim().to_lowercase().as_str() {
+            "null" => Ok(Fill::Null),
+            "none" => Ok(Fill::None),
+            "previous" => Ok(Fill::Previous),
+            "linear" => Ok(Fill::Linear),
+            f => match f.parse::<f64>() {
+                Ok(v) => Ok(Fill::Value(v)),
+                Err(_) => Err(format!("Invalid fill option: {}", fill)),
+            },
+        }
+    }
+}
+
+// Upper bound of empty windows generated by fill
+const MAX_FILL_WINDOWS: i64 = 100_000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelectPlan {
+    pub timeseries: String,
+    pub projections: Vec<Projection>,
+    pub selection: Option<String>,
+    pub condition: Option<Condition>,
+    pub start: Option<i64>, // inclusive, unix milliseconds
+    pub end: Option<i64>,   // exclusive, unix milliseconds
+    pub group_by_time: Option<Window>,
+    pub group_by_tags: TagGrouping,
+    pub fill: Fill,
+    pub stale: Option<i64>, // previous and linear don't fill across longer gaps, milliseconds
+    pub descending: bool,
+    pub limit: Option<usize>,
+    pub offset: usize,
+}
+
+impl SelectPlan {
+    pub fn new(timeseries: String, projections: Vec<Projection>) -> Self {
+        SelectPlan {
+            timeseries,
+            projections,
+            selection: None,
+            condition: None,
+            start: None,
+            end: None,
+            group_by_time: None,
+            group_by_tags: TagGrouping::Series,
+            fill: Fill::None,
+            stale: None,
+            descending: false,
+            limit: None,
+            offset: 0,
+        }
+    }
+
+    // Query that fetches the raw rows the projections are evaluated over
+    pub fn base_query(&self) -> String {
+        let mut filters: Vec<String> = Vec::new();
+        if let Some(selection) = &self.selection {
+            filters.push(selection.clone());
+        }
+        if let Some(start) = self.start {
+            filters.push(format!("time >= '{}'", timestamp_literal(start)));
+        }
+        if let Some(end) = self.end {
+            filters.push(format!("time < '{}'", timestamp_literal(end)));
+        }
+        if filters.is_empty() {
+            return format!("SELECT * FROM {}", self.timeseries);
+        }
+        format!(
+            "SELECT * FROM {} WHERE {}",
+            self.timeseries,
+            filters.join(" AND ")
+        )
+    }
+
+    pub fn is_aggregate(&self) -> bool {
+        self.projections.iter().any(|p| p.is_aggregate())
+    }
+
+    // Applies ordering, OFFSET and LIMIT to the rows of a series
+    fn page(&self, mut values: Vec<Vec<serde_json::Value>>) -> Vec<Vec<serde_json::Value>> {
+        if self.descending {
+            values.reverse();
+        }
+        let mut values: Vec<Vec<serde_json::Value>> =
+            values.into_iter().skip(self.offset).collect();
+        if let Some(limit) = self.limit {
+            values.truncate(limit);
+        }
+        values
+    }
+}
+
+pub fn count_rows(series: &[Series]) -> usize {
+    series.iter().map(|s| s.values.len()).sum()
+}
+
+// Times chrono can represent, in milliseconds (timestamp_literal panics on the others)
+pub fn valid_time(time: i64) -> Result<i64, String> {
+    use chrono::{TimeZone, Utc};
+    match Utc.timestamp_millis_opt(time).single() {
+        Some(_) => Ok(time),
+        None => Err(format!("Time out of range: {}", time)),
+    }
+}
+
+pub fn timestamp_literal(time: i64) -> String {
+    use chrono::{SecondsFormat, TimeZone, Utc};
+    Utc.timestamp_millis_opt(time)
+        .unwrap()
+        .to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+pub fn function_name(function: &Function) -> String {
+    match function.name.0.last() {
+        Some(ident) => ident.value.to_lowercase(),
+        None => String::new(),
+    }
+}
+
+// TOP is a keyword for sqlparser (SELECT TOP n), quote it when used as a function: top(...)
+pub fn quote_selectors(query: &str) -> String {
+    let tokens = match Tokenizer::new(&GenericDialect {}, query).tokenize() {
+        Ok(t) => t,
+        Err(_) => return query.to_string(),
+    };
+    let mut quoted = String::new();
+    for (i, token) in tokens.iter().enumerate() {
+        match (token, tokens.get(i + 1)) {
+            (Token::Word(w), Some(Token::LParen))
+                if w.quote_style.is_none() && w.value.to_lowercase() == "top" =>
+            {
+                quoted += &format!("\"{}\"", w.value)
+            }
+            _ => quoted += &token.to_string(),
+        }
+    }
+    quoted
+}
+
+pub fn function_args(function: &Function) -> Vec<&Expr> {
+    function
+        .args
+        .iter()
+        .map(|arg| match arg {
+            FunctionArg::Named { name: _, arg } => arg,
+            FunctionArg::Unnamed(arg) => arg,
+        })
+        .collect()
+}
+
+fn field_arg(function: &Function, args: &[&Expr]) -> Result<Option<String>, String> {
+    match args.first() {
+        Some(Expr::Identifier(ident)) if ident.value == "value" => Ok(None),
+        Some(Expr::Identifier(ident)) => Ok(Some(ident.value.clone())),
+        Some(Expr::Wildcard) => Ok(None),
+        _ => Err(format!(
+            "Expected a field name as first argument: {}",
+            function
+        )),
+    }
+}
+
+fn duration_arg(function: &Function, args: &[&Expr], default: i64) -> Result<i64, String> {
+    match args.get(1) {
+        None => Ok(default),
+        Some(Expr::Value(SqlValue::SingleQuotedString(d))) => functions::parse_duration(d),
+        _ => Err(format!("Expected a duration such as '1s': {}", function)),
+    }
+}
+
+fn integer_arg(function: &Function, args: &[&Expr]) -> Result<usize, String> {
+    match args.get(1) {
+        Some(Expr::Value(SqlValue::Number(n, _))) => match n.to_string().parse::<usize>() {
+            Ok(n) if n > 0 => Ok(n),
+            _ => Err(format!("Expected a positive integer: {}", function)),
+        },
+        _ => Err(format!("Expected a positive integer: {}", function)),
+    }
+}
+
+fn percentile_arg(function: &Function, args: &[&Expr]) -> Result<f64, String> {
+    match args.get(1) {
+        Some(Expr::Value(SqlValue::Number(n, _))) => match n.to_string().parse::<f64>() {
+            Ok(p) if (0.0..=100.0).contains(&p) => Ok(p),
+            _ => Err(format!(
+                "Expected a percentile between 0 and 100: {}",
+                function
+            )),
+        },
+        _ => Err(format!(
+            "Expected a percentile between 0 and 100: {}",
+            function
+        )),
+    }
+}
+
+pub fn parse_aggregate(function: &Function) -> Result<Option<Aggregate>, String> {
+    let name = function_name(function);
+    let args = function_args(function);
+    let aggregate = match name.as_str() {
+        "count" => Aggregate::Count,
+        "sum" => Aggregate::Sum,
+        "mean" | "avg" => Aggregate::Mean,
+        "min" => Aggregate::Min,
+        "max" => Aggregate::Max,
+        "first" => Aggregate::First,
+        "last" => Aggregate::Last,
+        "percentile" => Aggregate::Percentile(percentile_arg(function, &args)?),
+        "median" => Aggregate::Median,
+        "stddev" => Aggregate::Stddev,
+        "spread" => Aggregate::Spread,
+        "mode" => Aggregate::Mode,
+        "histogram" => Aggregate::Histogram(integer_arg(function, &args)?),
+        _ => return Ok(None),
+    };
+    Ok(Some(aggregate))
+}
+
+// Returns None for functions GlueSQL is expected to handle
+pub fn parse_function(function: &Function) -> Result<Option<Projection>, String> {
+    let name = function_name(function);
+    let args = function_args(function);
+    if let Some(aggregate) = parse_aggregate(function)? {
+        return Ok(Some(Projection {
+            function: None,
+            aggregate: Some(aggregate),
+            selector: None,
+            field: field_arg(function, &args)?,
+            alias: None,
+        }));
+    }
+    if name == "top" || name == "bottom" {
+        return parse_selector(function, name == "top").map(Some);
+    }
+    let sf = match name.as_str() {
+        "rate" => SeriesFunction::Rate,
+        "derivative" => SeriesFunction::Derivative(duration_arg(function, &args, 1_000)?),
+        "non_negative_derivative" => {
+            SeriesFunction::NonNegativeDerivative(duration_arg(function, &args, 1_000)?)
+        }
+        "difference" => SeriesFunction::Difference,
+        "non_negative_difference" => SeriesFunction::NonNegativeDifference,
+        "moving_average" => SeriesFunction::MovingAverage(integer_arg(function, &args)?),
+        "cumulative_sum" => SeriesFunction::CumulativeSum,
+        "elapsed" => SeriesFunction::Elapsed(duration_arg(function, &args, 1)?),
+        _ => return Ok(None),
+    };
+    // transforms can be applied over the result of an aggregate: rate(max(bytes))
+    if let Some(Expr::Function(inner)) = args.first() {
+        return match parse_aggregate(inner)? {
+            Some(Aggregate::Histogram(_)) | None => {
+                Err(format!("Expected an aggregate as argument: {}", function))
+            }
+            Some(aggregate) => Ok(Some(Projection {
+                function: Some(sf),
+                aggregate: Some(aggregate),
+                selector: None,
+                field: field_arg(inner, &function_args(inner))?,
+                alias: None,
+            })),
+        };
+    }
+    Ok(Some(Projection {
+        function: Some(sf),
+        aggregate: None,
+        selector: None,
+        field: field_arg(function, &args)?,
+        alias: None,
+    }))
+}
+
+// top(field, tag, k), bottom(field, tag, k) or ranked by an aggregate: top(mean(field), tag, k)
+fn parse_selector(function: &Function, top: bool) -> Result<Projection, String> {
+    let args = function_args(function);
+    if args.len() != 3 {
+        return Err(format!("Expected (field, tag, k) arguments: {}", function));
+    }
+    let tag = match args[1] {
+        Expr::Identifier(ident) => ident.value.clone(),
+        _ => return Err(format!("Expected a tag as second argument: {}", function)),
+    };
+    let k = match args[2] {
+        Expr::Value(SqlValue::Number(n, _)) => match n.to_string().parse::<usize>() {
+            Ok(k) if k > 0 => k,
+            _ => return Err(format!("Expected a positive integer: {}", function)),
+        },
+        _ => return Err(format!("Expected a positive integer: {}", function)),
+    };
+    let (aggregate, field) = match args[0] {
+        Expr::Function(inner) => match parse_aggregate(inner)? {
+            Some(Aggregate::Histogram(_)) | None => {
+                return Err(format!("Expected an aggregate as argument: {}", function))
+            }
+            Some(aggregate) => (Some(aggregate), field_arg(inner, &function_args(inner))?),
+        },
+        _ => (None, field_arg(function, &args)?),
+    };
+    Ok(Projection {
+        function: None,
+        aggregate,
+        selector: Some(Selector { top, tag, k }),
+        field,
+        alias: None,
+    })
+}
+
+const COLUMNS: [&str; 6] = ["id", "time", "created_at", "name", "value", "tags"];
+
+// time window, tags to group by and how empty windows are filled
+#[derive(Debug, Default)]
+struct GroupBy {
+    interval: Option<Period>,
+    tz: Option<Tz>,
+    tags: Vec<String>,
+    fill: Option<Fill>,
+    stale: Option<i64>,
+}
+
+// fill(linear), fill(previous, '10m'), fill(0): the option and a staleness limit
+fn parse_fill(function: &Function) -> Result<(Fill, Option<i64>), String> {
+    let args = function_args(function);
+    let fill = match args.first() {
+        Some(Expr::Identifier(ident)) => Fill::parse(&ident.value)?,
+        Some(e @ Expr::Value(SqlValue::Number(..))) | Some(e @ Expr::UnaryOp { .. }) => {
+            Fill::parse(&e.to_string().replace(' ', ""))? // -1 is displayed as - 1
+        }
+        _ => {
+            return Err(format!(
+                "Expected a fill option such as fill(linear): {}",
+                function
+            ))
+        }
+    };
+    let stale = match args.get(1) {
+        Some(Expr::Value(SqlValue::SingleQuotedString(d))) => Some(functions::parse_duration(d)?),
+        None => None,
+        _ => {
+            return Err(format!(
+                "Expected a staleness such as fill(linear, '10m'): {}",
+                function
+            ))
+        }
+    };
+    Ok((fill, stale))
+}
+
+// GROUP BY time('1m'), host, fill(previous): returns the window, the tags to group by and
+// the fill, None when the clause only refers to table columns and is left to GlueSQL
+fn parse_group_by(group_by: &[Expr]) -> Result<Option<GroupBy>, String> {
+    let mut interval: Option<Period> = None;
+    let mut tz: Option<Tz> = None;
+    let mut tags: Vec<String> = Vec::new();
+    let mut fill: Option<(Fill, Option<i64>)> = None;
+    let mut extended = false;
+    for expr in group_by {
+        match expr {
+            Expr::Function(f) if function_name(f) == "fill" => {
+                fill = Some(parse_fill(f)?);
+                extended = true;
+            }
+            Expr::Function(f) if function_name(f) == "time" => {
+                interval = match function_args(f).first() {
+                    Some(Expr::Value(SqlValue::SingleQuotedString(d))) => Some(Period::parse(d)?),
+                    _ => return Err(format!("Expected a duration such as time('1m'): {}", f)),
+                };
+                extended = true;
+            }
+            Expr::Function(f) if function_name(f) == "tz" => {
+                tz = match function_args(f).first() {
+                    Some(Expr::Value(SqlValue::SingleQuotedString(name))) => {
+                        Some(calendar::timezone(name)?)
+                    }
+                    _ => {
+                        return Err(format!(
+                            "Expected a time zone such as tz('Europe/Paris'): {}",
+                            f
+                        ))
+                    }
+                };
+                extended = true;
+            }
+            Expr::Identifier(ident) if COLUMNS.contains(&ident.value.as_str()) => (),
+            Expr::Identifier(ident) => {
+                tags.push(ident.value.clone());
+                extended = true;
+            }
+            _ => return Err(format!("Unsupported GROUP BY expression: {}", expr)),
+        }
+    }
+    if !extended {
+        return Ok(None);
+    }
+    if fill.is_some() && interval.is_none() {
+        return Err("fill() requires GROUP BY time()".to_string());
+    }
+    if tz.is_some() && interval.is_none() {
+        return Err("tz() requires GROUP BY time()".to_string());
+    }
+    let (fill, stale) = match fill {
+        Some((fill, stale)) => (Some(fill), stale),
+        None => (None, None),
+    };
+    Ok(Some(GroupBy {
+        interval,
+        tz,
+        tags,
+        fill,
+        stale,
+    }))
+}
+
+// Plans a query if it uses timeseries functions, plain SQL returns Ok(None)
+pub fn plan(query: String) -> Result<Option<SelectPlan>, String> {
+    let statements = match gluesql::parse_sql::parse(&quote_selectors(&query)) {
+        Ok(s) => s,
+        Err(e) => return Err(format!("Improper query: {}", e)),
+    };
+    let q = match statements.first() {
+        Some(Statement::Query(q)) => q,
+        _ => return Ok(None),
+    };
+    let select = match &q.body {
+        SetExpr::Select(select) => select,
+        _ => return Ok(None),
+    };
+
+    let mut projections: Vec<Projection> = Vec::new();
+    let mut plain = false;
+    for item in select.projection.iter() {
+        match item {
+            SelectItem::UnnamedExpr(Expr::Function(f)) => match parse_function(f)? {
+                Some(p) => projections.push(p),
+                None => plain = true,
+            },
+            SelectItem::ExprWithAlias {
+                expr: Expr::Function(f),
+                alias,
+            } => match parse_function(f)? {
+                Some(mut p) => {
+                    p.alias = Some(alias.value.clone());
+                    projections.push(p)
+                }
+                None => plain = true,
+            },
+            SelectItem::UnnamedExpr(Expr::Identifier(ident)) if ident.value == "time" => (),
+            _ => plain = true,
+        }
+    }
+    let group_by = parse_group_by(&select.group_by)?;
+    if group_by.is_none() && projections.iter().all(|p| p.is_builtin()) {
+        return Ok(None);
+    }
+    if plain || projections.is_empty() {
+        return Err(format!(
+            "Timeseries functions can't be mixed with other columns: {}",
+            query
+        ));
+    }
+    let aggregated = projections.iter().filter(|p| p.is_aggregate()).count();
+    if aggregated > 0 && aggregated < projections.len() {
+        return Err(format!(
+            "Aggregates can't be mixed with non aggregated functions: {}",
+            query
+        ));
+    }
+    if projections.len() > 1 && projections.iter().any(|p| p.selector.is_some()) {
+        return Err(format!(
+            "top and bottom can't be combined with other functions: {}",
+            query
+        ));
+    }
+    let group_by = group_by.unwrap_or_default();
+    let group_by_tags = match group_by.tags {
+        tags if !tags.is_empty() => TagGrouping::Tags(tags),
+        _ if aggregated > 0 => TagGrouping::Tags(vec![]),
+        _ => TagGrouping::Series,
+    };
+
+    let timeseries = match select.from.first().map(|f| &f.relation) {
+        Some(TableFactor::Table { name, .. }) => name.to_string(),
+        _ => return Err("No table found".to_string()),
+    };
+    let limit = match &q.limit {
+        Some(Expr::Value(SqlValue::Number(n, _))) => match n.to_string().parse::<usize>() {
+            Ok(l) => Some(l),
+            Err(_) => return Err(format!("Invalid limit: {}", n)),
+        },
+        Some(e) => return Err(format!("Invalid limit: {}", e)),
+        None => None,
+    };
+
+    let mut plan = SelectPlan::new(timeseries, projections);
+    plan.selection = select.selection.as_ref().map(|s| s.to_string());
+    let tz = group_by.tz;
+    plan.group_by_time = group_by.interval.map(|p| Window::new(p, tz));
+    plan.group_by_tags = group_by_tags;
+    if let Some(fill) = group_by.fill {
+        plan.fill = fill;
+    }
+    plan.stale = group_by.stale;
+    plan.limit = limit;
+    Ok(Some(plan))
+}
+
+pub fn tag_key(tags: &std::collections::HashMap<String, String>) -> BTreeMap<String, String> {
+    tags.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+}
+
+// Splits measurements by tag set and field name, each series ordered by time
+pub fn split_series(
+    measurements: Vec<Measurement>,
+) -> BTreeMap<BTreeMap<String, String>, BTreeMap<String, Vec<Point>>> {
+    let mut series: BTreeMap<BTreeMap<String, String>, BTreeMap<String, Vec<Point>>> =
+        BTreeMap::new();
+    for m in measurements {
+        series
+            .entry(tag_key(&m.tags))
+            .or_default()
+            .entry(m.name.clone())
+            .or_default()
+            .push(Point::new(m.key, m.value));
+    }
+    for fields in series.values_mut() {
+        for points in fields.values_mut() {
+            points.sort_by_key(|p| p.time);
+        }
+    }
+    series
+}
+
+// Merges series into the groups selected by the plan
+pub fn group_series(
+    grouping: &TagGrouping,
+    series: BTreeMap<BTreeMap<String, String>, BTreeMap<String, Vec<Point>>>,
+) -> BTreeMap<BTreeMap<String, String>, BTreeMap<String, Vec<Point>>> {
+    if *grouping == TagGrouping::Series {
+        return series;
+    }
+    let mut groups: BTreeMap<BTreeMap<String, String>, BTreeMap<String, Vec<Point>>> =
+        BTreeMap::new();
+    for (tags, fields) in series {
+        let group = groups.entry(grouping.key(&tags)).or_default();
+        for (field, points) in fields {
+            group.entry(field).or_default().extend(points);
+        }
+    }
+    for fields in groups.values_mut() {
+        for points in fields.values_mut() {
+            points.sort_by_key(|p| p.time);
+        }
+    }
+    groups
+}
+
+type SeriesByTag = BTreeMap<String, Vec<Point>>;
+
+// Ranks the values of the selector tag within each group and time window
+pub fn evaluate_selector(
+    plan: &SelectPlan,
+    projection: &Projection,
+    selector: &Selector,
+    measurements: Vec<Measurement>,
+) -> Vec<Series> {
+    // group -> field -> tag value -> points
+    let mut groups: BTreeMap<BTreeMap<String, String>, BTreeMap<String, SeriesByTag>> =
+        BTreeMap::new();
+    for (tags, fields) in split_series(measurements) {
+        let tag_value = match tags.get(&selector.tag) {
+            Some(v) => v.clone(),
+            None => continue,
+        };
+        let group = groups.entry(plan.group_by_tags.key(&tags)).or_default();
+        for (field, points) in fields {
+            group
+                .entry(field)
+                .or_default()
+                .entry(tag_value.clone())
+                .or_default()
+                .extend(points);
+        }
+    }
+
+    let mut result: Vec<Series> = Vec::new();
+    for (tags, fields) in groups {
+        for (field, by_tag) in fields {
+            if let Some(f) = &projection.field {
+                if *f != field {
+                    continue;
+                }
+            }
+            let mut candidates: BTreeMap<i64, Vec<(String, Point)>> = BTreeMap::new();
+            for (tag_value, mut points) in by_tag {
+                points.sort_by_key(|p| p.time);
+                for (start, window) in windows(&points, plan.group_by_time) {
+                    let selected = match &projection.aggregate {
+                        Some(a) => a.scalar(window).map(|v| Point::new(start, v)),
+                        None => selector.select(window),
+                    };
+                    if let Some(p) = selected {
+                        candidates
+                            .entry(start)
+                            .or_default()
+                            .push((tag_value.clone(), p));
+                    }
+                }
+            }
+            let mut values: Vec<Vec<serde_json::Value>> = Vec::new();
+            for c in candidates.into_values() {
+                for (tag_value, p) in selector.rank(c) {
+                    values.push(vec![
+                        serde_json::json!(p.time),
+                        serde_json::json!(tag_value),
+                        serde_json::json!(p.value),
+                    ]);
+                }
+            }
+            result.push(Series {
+                name: plan.timeseries.clone(),
+                tags: tags.clone(),
+                columns: vec![
+                    "time".to_string(),
+                    selector.tag.clone(),
+                    projection.label(&field),
+                ],
+                values: plan.page(values),
+                tier: None,
+            });
+        }
+    }
+    result
+}
+
+// Evaluates the projections over each group, aligning the results by time
+pub fn evaluate(plan: &SelectPlan, measurements: Vec<Measurement>) -> Result<Vec<Series>, String> {
+    if let Some(projection) = plan.projections.first() {
+        if let Some(selector) = &projection.selector {
+            return Ok(evaluate_selector(plan, projection, selector, measurements));
+        }
+    }
+    let mut result: Vec<Series> = Vec::new();
+    // a lone first() or last() without time windows is reported at the time of its point
+    let lone_selection = plan.projections.len() == 1 && plan.group_by_time.is_none();
+    let groups = group_series(&plan.group_by_tags, split_series(measurements));
+    for (tags, fields) in groups {
+        let mut columns: Vec<String> = vec!["time".to_string()];
+        let mut outputs: Vec<Vec<(i64, serde_json::Value)>> = Vec::new();
+        for projection in plan.projections.iter() {
+            for (field, points) in fields.iter() {
+                if let Some(f) = &projection.field {
+                    if f != field {
+                        continue;
+                    }
+                }
+                columns.push(projection.label(field));
+                outputs.push(match projection.selected_point(points) {
+                    Some(p) if lone_selection => vec![(p.time, serde_json::json!(p.value))],
+                    _ => projection.evaluate(points, plan.group_by_time),
+                });
+            }
+        }
+        if outputs.is_empty() {
+            continue;
+        }
+
+        let mut rows: BTreeMap<i64, Vec<serde_json::Value>> = BTreeMap::new();
+        for (i, points) in outputs.iter().enumerate() {
+            for (time, value) in points {
+                let row = rows
+                    .entry(*time)
+                    .or_insert_with(|| vec![serde_json::Value::Null; outputs.len()]);
+                row[i] = value.clone();
+            }
+        }
+        if let Some(window) = plan.group_by_time {
+            if plan.is_aggregate() {
+                fill_windows(plan, window, outputs.len(), &mut rows)?;
+            }
+        }
+        let values: Vec<Vec<serde_json::Value>> = rows
+            .into_iter()
+            .map(|(time, row)| {
+                let mut v = vec![serde_json::json!(time)];
+                v.extend(row);
+                v
+            })
+            .collect();
+        result.push(Series {
+            name: plan.timeseries.clone(),
+            tags,
+            columns,
+            values: plan.page(values),
+            tier: None,
+        });
+    }
+    Ok(result)
+}
+
+// Adds the windows without data within the queried range and fills them
+pub fn fill_windows(
+    plan: &SelectPlan,
+    window: Window,
+    columns: usize,
+    rows: &mut BTreeMap<i64, Vec<serde_json::Value>>,
+) -> Result<(), String> {
+    if plan.fill == Fill::None {
+        rows.retain(|_, row| row.iter().any(|v| !v.is_null()));
+        return Ok(());
+    }
+    let first = match plan.start {
+        Some(start) => Some(window.start(start)),
+        None => rows.keys().next().cloned(),
+    };
+    let last = match plan.end {
+        Some(end) => Some(window.start(end - 1)),
+        None => rows.keys().next_back().cloned(),
+    };
+    if let (Some(first), Some(last)) = (first, last) {
+        let mut starts: Vec<i64> = Vec::new();
+        let mut t = first;
+        while t <= last {
+            if starts.len() as i64 >= MAX_FILL_WINDOWS {
+                return Err(format!(
+                    "fill would generate more than {} windows, narrow the time range or widen the windows",
+                    MAX_FILL_WINDOWS
+                ));
+            }
+            starts.push(t);
+            t = window.next(t);
+        }
+        for t in starts {
+            rows.entry(t)
+                .or_insert_with(|| vec![serde_json::Value::Null; columns]);
+        }
+    }
+
+    for i in 0..columns {
+        let known: Vec<(i64, f64)> = rows
+            .iter()
+            .filter_map(|(t, row)| row[i].as_f64().map(|v| (*t, v)))
+            .collect();
+        // rows and known points both in time order, a single pass over them
+        let mut next = 0; // first known point after the row
+        for (t, row) in rows.iter_mut() {
+            while next < known.len() && known[next].0 <= *t {
+                next += 1;
+            }
+            if !row[i].is_null() {
+                continue;
+            }
+            let before = known[..next].last();
+            let after = known.get(next);
+            let fresh = |gap: i64| plan.stale.is_none_or(|stale| gap <= stale);
+            row[i] = match (&plan.fill, before, after) {
+                (Fill::Value(v), _, _) => serde_json::json!(v),
+                (Fill::Previous, Some((t0, v)), _) if fresh(t - t0) => serde_json::json!(v),
+                (Fill::Linear, Some((t0, v0)), Some((t1, v1))) if fresh(t1 - t0) => {
+                    serde_json::json!(v0 + (v1 - v0) * (t - t0) as f64 / (t1 - t0) as f64)
+                }
+                _ => serde_json::Value::Null,
+            };
+        }
+    }
+    Ok(())
+}
+
+// How a SQL statement runs: federated when reading several timeseries, planned when using
+// timeseries functions, otherwise by GlueSQL
+#[derive(Debug, Clone, PartialEq)]
+pub enum Execution {
+    Federated(crate::federation::Federation),
+    Planned(SelectPlan),
+    Raw(String),
+}
+
+pub fn plan_execution(query: &str) -> Result<Execution, String> {
+    // UNION and joins read several timeseries, each one stored on its own
+    if let Some(federation) = crate::federation::plan(query)? {
+        return Ok(Execution::Federated(federation));
+    }
+    match plan(query.to_string())? {
+        Some(plan) => Ok(Execution::Planned(plan)),
+        None => Ok(Execution::Raw(query.to_string())),
+    }
+}
+
+// Resamples every series of the range to a fixed step: the last point of each step window,
+// windows without one are filled. Columns are named after the fields.
+pub fn resample(
+    pm: &mut TimeseriesDiskPersistenceManager,
+    timeseries: &str,
+    start: i64,
+    end: i64, // exclusive
+    step: i64,
+    fill: Fill,
+    stale: Option<i64>,
+) -> Result<Vec<Series>, String> {
+    let last = Projection {
+        function: None,
+        aggregate: Some(Aggregate::Last),
+        selector: None,
+        field: None,
+        alias: None,
+    };
+    let mut plan = SelectPlan::new(timeseries.to_string(), vec![last]);
+    plan.start = Some(start);
+    plan.end = Some(end);
+    plan.group_by_time = Some(Window::fixed(step));
+    plan.fill = fill;
+    plan.stale = stale;
+    let mut series = execute(pm, &plan)?;
+    for s in series.iter_mut() {
+        for column in s.columns.iter_mut().skip(1) {
+            if let Some(field) = column
+                .strip_prefix("last(")
+                .and_then(|c| c.strip_suffix(')'))
+            {
+                *column = field.to_string();
+            }
+        }
+    }
+    Ok(series)
+}
+
+pub fn execute(
+    pm: &mut TimeseriesDiskPersistenceManager,
+    plan: &SelectPlan,
+) -> Result<Vec<Series>, String> {
+    // the base query carries the user's WHERE clause, which may hold subqueries
+    let query = plan.base_query();
+    if crate::utils::query_statement_tablenames(&query)? != vec![plan.timeseries.clone()] {
+        return Err(format!(
+            "Subqueries into other timeseries are not supported: {}",
+            query
+        ));
+    }
+    if let Some(series) = crate::rollup::execute(pm, plan)? {
+        return Ok(series);
+    }
+    let last = pm.last_measurements(&plan.timeseries)?;
+    if crate::lastvalue::answers(plan, &last) {
+        let scanned = pm.rows_scanned();
+        pm.record_scan(
+            &plan.timeseries,
+            crate::explain::LAST_VALUES,
+            scanned,
+            last.len(),
+        );
+        let measurements = crate::lastvalue::select(plan, last);
+        return evaluate(plan, measurements);
+    }
+    let mut measurements = pm.scan_measurements(plan.timeseries.clone(), query)?;
+    if let Some(condition) = &plan.condition {
+        measurements.retain(|m| condition.matches(m));
+    }
+    evaluate(plan, measurements)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use uuid::Uuid;
+
+    fn measurement(key: i64, name: &str, value: f64, host: &str) -> Measurement {
+        let mut tags = HashMap::new();
+        tags.insert("host".to_string(), host.to_string());
+        Measurement {
+            key,
+            id: Uuid::new_v4(),
+            name: name.to_string(),
+            value,
+            tags,
+        }
+    }
+
+    #[test]
+    fn plain_sql_is_not_planned() {
+        assert_eq!(plan("SELECT * FROM cpu".to_string()), Ok(None));
+        assert_eq!(plan("SELECT count(*) FROM cpu".to_string()), Ok(None));
+        assert_eq!(
+            plan("SELECT name, max(value) FROM cpu GROUP BY name".to_string()),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn plan_aggregates() {
+        let p = plan(
+            "SELECT percentile(latency, 99), histogram(latency, 4) FROM http GROUP BY time('5m'), host"
+                .to_string(),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(p.group_by_time, Some(Window::fixed(300_000)));
+        assert_eq!(p.group_by_tags, TagGrouping::Tags(vec!["host".to_string()]));
+        assert_eq!(
+            p.projections[0].aggregate,
+            Some(Aggregate::Percentile(99.0))
+        );
+        assert_eq!(p.projections[1].aggregate, Some(Aggregate::Histogram(4)));
+
+        let p = plan("SELECT rate(max(bytes)) FROM net GROUP BY time('1m')".to_string())
+            .unwrap()
+            .unwrap();
+        assert_eq!(p.projections[0].label("bytes"), "rate(max(bytes))");
+        assert_eq!(p.group_by_tags, TagGrouping::Tags(vec![]));
+
+        assert!(plan("SELECT median(value), rate(value) FROM cpu".to_string()).is_err());
+        assert!(plan("SELECT percentile(value, 101) FROM cpu".to_string()).is_err());
+        assert!(plan("SELECT rate(histogram(value, 2)) FROM cpu".to_string()).is_err());
+    }
+
+    #[test]
+    fn evaluate_windows_and_groups() {
+        let p = plan("SELECT max(usage), median(usage) FROM cpu GROUP BY time('1s')".to_string())
+            .unwrap()
+            .unwrap();
+        let measurements = vec![
+            measurement(100, "usage", 1.0, "a"),
+            measurement(900, "usage", 4.0, "b"),
+            measurement(500, "usage", 3.0, "b"),
+            measurement(1_500, "usage", 7.0, "a"),
+        ];
+        let result = evaluate(&p, measurements.clone()).unwrap();
+        assert_eq!(result.len(), 1);
+        assert_eq!(
+            result[0].columns,
+            vec!["time", "max(usage)", "median(usage)"]
+        );
+        assert_eq!(
+            result[0].values,
+            vec![
+                vec![
+                    serde_json::json!(0),
+                    serde_json::json!(4.0),
+                    serde_json::json!(3.0)
+                ],
+                vec![
+                    serde_json::json!(1_000),
+                    serde_json::json!(7.0),
+                    serde_json::json!(7.0)
+                ],
+            ]
+        );
+
+        let p = plan("SELECT spread(usage) FROM cpu GROUP BY host".to_string())
+            .unwrap()
+            .unwrap();
+        let result = evaluate(&p, measurements).unwrap();
+        assert_eq!(result.len(), 2);
+        assert_eq!(
+            result[0].values,
+            vec![vec![serde_json::json!(100), serde_json::json!(6.0)]]
+        );
+        assert_eq!(
+            result[1].values,
+            vec![vec![serde_json::json!(500), serde_json::json!(1.0)]]
+        );
+    }
+
+    #[test]
+    fn fill_gaps() {
+        let p =
+            plan("SELECT last(usage) FROM cpu GROUP BY time('1s'), fill(linear, '2s')".to_string())
+                .unwrap()
+                .unwrap();
+        assert_eq!((p.fill.clone(), p.stale), (Fill::Linear, Some(2_000)));
+        let measurements = vec![
+            measurement(0, "usage", 1.0, "a"),
+            measurement(2_000, "usage", 3.0, "a"),
+            measurement(6_000, "usage", 7.0, "a"),
+        ];
+        let values = |p: &SelectPlan| -> Vec<serde_json::Value> {
+            evaluate(p, measurements.clone()).unwrap()[0]
+                .values
+                .iter()
+                .map(|row| row[1].clone())
+                .collect()
+        };
+        // the 4s gap between 2s and 6s is over the staleness limit
+        let null = serde_json::Value::Null;
+        assert_eq!(
+            values(&p),
+            vec![
+                serde_json::json!(1.0),
+                serde_json::json!(2.0),
+                serde_json::json!(3.0),
+                null.clone(),
+                null.clone(),
+                null.clone(),
+                serde_json::json!(7.0)
+            ]
+        );
+        let p = plan(
+            "SELECT last(usage) FROM cpu GROUP BY time('1s'), fill(previous, '1s')".to_string(),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            values(&p)[1..5],
+            [
+                serde_json::json!(1.0),
+                serde_json::json!(3.0),
+                serde_json::json!(3.0),
+                null
+            ]
+        );
+        let p = plan("SELECT last(usage) FROM cpu GROUP BY time('1s'), fill(-1)".to_string())
+            .unwrap()
+            .unwrap();
+        assert_eq!(p.fill, Fill::Value(-1.0));
+        assert!(plan("SELECT last(usage) FROM cpu GROUP BY host, fill(null)".to_string()).is_err());
+        assert!(Fill::parse("nearest").is_err());
+        // too many empty windows is an error, not a shorter result
+        let mut p = plan("SELECT last(usage) FROM cpu GROUP BY time('1ms'), fill(0)".to_string())
+            .unwrap()
+            .unwrap();
+        p.end = Some(MAX_FILL_WINDOWS + 1);
+        assert!(evaluate(&p, measurements.clone()).is_err());
+    }
+
+    #[test]
+    fn plan_functions() {
+        let p = plan(
+            "SELECT time, rate(value), moving_average(usage, 3), elapsed(usage, '1s') FROM cpu WHERE name = 'usage' LIMIT 10"
+                .to_string(),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(p.timeseries, "cpu");
+        assert_eq!(p.limit, Some(10));
+        assert_eq!(
+            p.projections,
+            vec![
+                Projection {
+                    function: Some(SeriesFunction::Rate),
+                    aggregate: None,
+                    selector: None,
+                    field: None,
+                    alias: None
+                },
+                Projection {
+                    function: Some(SeriesFunction::MovingAverage(3)),
+                    aggregate: None,
+                    selector: None,
+                    field: Some("usage".to_string()),
+                    alias: None
+                },
+                Projection {
+                    function: Some(SeriesFunction::Elapsed(1_000)),
+                    aggregate: None,
+                    selector: None,
+                    field: Some("usage".to_string()),
+                    alias: None
+                },
+            ]
+        );
+        assert_eq!(p.group_by_tags, TagGrouping::Series);
+        assert_eq!(p.base_query(), "SELECT * FROM cpu WHERE name = 'usage'");
+        assert!(plan("SELECT rate(value), name FROM cpu".to_string()).is_err());
+        assert!(plan("SELECT moving_average(value) FROM cpu".to_string()).is_err());
+    }
+
+    #[test]
+    fn evaluate_per_series() {
+        let p = plan("SELECT difference(usage) FROM cpu".to_string())
+            .unwrap()
+            .unwrap();
+        let measurements = vec![
+            measurement(2_000, "usage", 5.0, "a"),
+            measurement(1_000, "usage", 1.0, "a"),
+            measurement(1_000, "usage", 10.0, "b"),
+            measurement(2_000, "usage", 30.0, "b"),
+            measurement(2_000, "idle", 30.0, "b"),
+        ];
+        let result = evaluate(&p, measurements).unwrap();
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[0].tags.get("host"), Some(&"a".to_string()));
+        assert_eq!(result[0].columns, vec!["time", "difference(usage)"]);
+        assert_eq!(
+            result[0].values,
+            vec![vec![serde_json::json!(2_000), serde_json::json!(4.0)]]
+        );
+        assert_eq!(
+            result[1].values,
+            vec![vec![serde_json::json!(2_000), serde_json::json!(20.0)]]
+        );
+    }
+
+    #[test]
+    fn top_and_bottom() {
+        let measurements = vec![
+            measurement(100, "usage", 10.0, "a"),
+            measurement(200, "usage", 50.0, "a"),
+            measurement(100, "usage", 30.0, "b"),
+            measurement(200, "usage", 35.0, "b"),
+            measurement(100, "usage", 5.0, "c"),
+        ];
+        let p = plan("SELECT top(usage, host, 2) FROM cpu".to_string())
+            .unwrap()
+            .unwrap();
+        let result = evaluate(&p, measurements.clone()).unwrap();
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].columns, vec!["time", "host", "top(usage)"]);
+        assert_eq!(
+            result[0].values,
+            vec![
+                vec![
+                    serde_json::json!(200),
+                    serde_json::json!("a"),
+                    serde_json::json!(50.0)
+                ],
+                vec![
+                    serde_json::json!(200),
+                    serde_json::json!("b"),
+                    serde_json::json!(35.0)
+                ],
+            ]
+        );
+
+        let p = plan("SELECT bottom(mean(usage), host, 1) FROM cpu".to_string())
+            .unwrap()
+            .unwrap();
+        let result = evaluate(&p, measurements).unwrap();
+        assert_eq!(
+            result[0].values,
+            vec![vec![
+                serde_json::json!(100),
+                serde_json::json!("c"),
+                serde_json::json!(5.0)
+            ]]
+        );
+
+        assert!(plan("SELECT top(usage, host) FROM cpu".to_string()).is_err());
+        assert!(plan("SELECT top(usage, host, 2), max(usage) FROM cpu".to_string()).is_err());
+    }
+}
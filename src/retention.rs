@@ -0,0 +1,301 @@
+use crate::persistence::TimeseriesDiskPersistenceManager;
+use gluesql::storages::SledStorage;
+use log::{debug, info};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Retention policies
+// A timeseries keeps its points for a duration, set through the API and saved in a tree of
+// its sled database, or from the configuration for the timeseries without one:
+//   REFLUXDB_RETENTION=cpu=30d,mem=7d,*=90d    (* applies to every other timeseries)
+//   REFLUXDB_RETENTION_INTERVAL=1h
+// A background task deletes the expired points at every interval and reports what it
+// removed: the expired shards are dropped whole, the points of the one holding the cutoff
+// deleted. A timeseries failing to expire is reported with its error, the others still expire.
+// The files aren't shrunk: sled reuses the space freed for the points written next, the size
+// on disk reported before and after a run usually stays the same.
+
+const TREE: &str = "retention";
+const KEY: &str = "keep";
+
+pub const DEFAULT_INTERVAL: i64 = 3_600_000;
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Policies {
+    pub default: Option<i64>, // milliseconds
+    pub timeseries: HashMap<String, i64>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Policy {
+    pub timeseries: String,
+    pub keep_ms: i64,
+    pub source: String, // api or config
+}
+
+// What an expiry run removed from a timeseries
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Expiry {
+    pub timeseries: String,
+    pub cutoff: String,
+    pub removed: usize,
+    pub bytes_before: u64,
+    pub bytes_after: u64, // not freed, reused by sled for the next writes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct Retention {
+    configured: Mutex<Policies>,
+    stored: Mutex<HashMap<String, i64>>,
+    last_run: Mutex<Vec<Expiry>>,
+}
+
+// cpu=30d,mem=7d,*=90d
+pub fn parse_policies(policies: &str) -> Result<Policies, String> {
+    let mut parsed = Policies::default();
+    for policy in policies
+        .split(',')
+        .map(|p| p.trim())
+        .filter(|p| !p.is_empty())
+    {
+        let (timeseries, keep) = match policy.split_once('=') {
+            Some((t, k)) => (t.trim(), crate::functions::parse_duration(k)?),
+            None => return Err(format!("Expected timeseries=duration: {}", policy)),
+        };
+        match timeseries {
+            "*" => parsed.default = Some(keep),
+            t => {
+                parsed.timeseries.insert(t.to_string(), keep);
+            }
+        }
+    }
+    Ok(parsed)
+}
+
+pub fn policies_from_env() -> Result<Policies, String> {
+    match std::env::var("REFLUXDB_RETENTION") {
+        Ok(v) => parse_policies(&v),
+        Err(_) => Ok(Policies::default()),
+    }
+}
+
+pub fn interval_from_env() -> Result<i64, String> {
+    match std::env::var("REFLUXDB_RETENTION_INTERVAL") {
+        Ok(v) => crate::functions::parse_duration(&v),
+        Err(_) => Ok(DEFAULT_INTERVAL),
+    }
+}
+
+fn disk_usage(path: &Path) -> u64 {
+    match fs::read_dir(path) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .map(|e| match e.metadata() {
+                Ok(m) if m.is_dir() => disk_usage(&e.path()),
+                Ok(m) => m.len(),
+                Err(_) => 0,
+            })
+            .sum(),
+        Err(_) => 0,
+    }
+}
+
+impl Retention {
+    pub fn configure(&self, policies: Policies) {
+        *self.configured.lock().unwrap() = policies;
+    }
+
+    pub fn load(&self, timeseries: &str, storage: &SledStorage) -> Result<(), String> {
+        let stored = storage.tree.open_tree(TREE).and_then(|tree| tree.get(KEY));
+        match stored {
+            Ok(Some(keep)) => match bincode::deserialize::<i64>(&keep) {
+                Ok(keep) => {
+                    self.stored
+                        .lock()
+                        .unwrap()
+                        .insert(timeseries.to_string(), keep);
+                    Ok(())
+                }
+                Err(e) => Err(format!("Error decoding retention of {}: {}", timeseries, e)),
+            },
+            Ok(None) => Ok(()),
+            Err(e) => Err(format!("Error reading retention of {}: {}", timeseries, e)),
+        }
+    }
+
+    // Saves the policy of a timeseries, none removes it
+    pub fn set(
+        &self,
+        timeseries: &str,
+        storage: &SledStorage,
+        keep: Option<i64>,
+    ) -> Result<(), String> {
+        let saved = match keep {
+            Some(keep) => match bincode::serialize(&keep) {
+                Ok(encoded) => storage
+                    .tree
+                    .open_tree(TREE)
+                    .and_then(|tree| tree.insert(KEY, encoded))
+                    .map(|_| ()),
+                Err(e) => return Err(format!("Error encoding retention: {}", e)),
+            },
+            None => storage
+                .tree
+                .open_tree(TREE)
+                .and_then(|tree| tree.remove(KEY))
+                .map(|_| ()),
+        };
+        if let Err(e) = saved {
+            return Err(format!("Error saving retention of {}: {}", timeseries, e));
+        }
+        let mut stored = self.stored.lock().unwrap();
+        match keep {
+            Some(keep) => stored.insert(timeseries.to_string(), keep),
+            None => stored.remove(timeseries),
+        };
+        Ok(())
+    }
+
+    // The policy set through the API, otherwise the configured one
+    pub fn policy(&self, timeseries: &str) -> Option<Policy> {
+        let policy = |keep: i64, source: &str| Policy {
+            timeseries: timeseries.to_string(),
+            keep_ms: keep,
+            source: source.to_string(),
+        };
+        if let Some(keep) = self.stored.lock().unwrap().get(timeseries) {
+            return Some(policy(*keep, "api"));
+        }
+        let configured = self.configured.lock().unwrap();
+        configured
+            .timeseries
+            .get(timeseries)
+            .or(configured.default.as_ref())
+            .map(|keep| policy(*keep, "config"))
+    }
+
+    pub fn last_run(&self) -> Vec<Expiry> {
+        self.last_run.lock().unwrap().clone()
+    }
+}
+
+// Deletes the points older than the policy of each timeseries
+pub fn expire(pm: &mut TimeseriesDiskPersistenceManager) -> Result<Vec<Expiry>, String> {
+    let storages: BTreeMap<String, SledStorage> = pm
+        .storages
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    let now = chrono::Utc::now().timestamp_millis();
+    let mut report: Vec<Expiry> = Vec::new();
+    for (timeseries, storage) in storages {
+        let policy = match pm.retention.policy(&timeseries) {
+            Some(p) => p,
+            None => continue,
+        };
+        let cutoff = now - policy.keep_ms;
+        let path = Path::new(&pm.basepath).join(&timeseries);
+        let bytes_before = disk_usage(&path);
+        let (removed, error) = match expire_timeseries(pm, &timeseries, &storage, cutoff) {
+            Ok(n) => (n, None),
+            Err(e) => (0, Some(format!("Error expiring {}: {}", timeseries, e))),
+        };
+        let expiry = Expiry {
+            timeseries: timeseries.clone(),
+            cutoff: crate::query::timestamp_literal(cutoff),
+            removed,
+            bytes_before,
+            bytes_after: disk_usage(&path),
+            error,
+        };
+        match (removed, &expiry.error) {
+            (0, None) => debug!("Retention: {:?}", expiry),
+            _ => info!("Retention: {:?}", expiry),
+        }
+        report.push(expiry);
+    }
+    *pm.retention.last_run.lock().unwrap() = report.clone();
+    Ok(report)
+}
+
+// Deletes the points of the timeseries before the cutoff, with what's derived from them
+fn expire_timeseries(
+    pm: &mut TimeseriesDiskPersistenceManager,
+    timeseries: &str,
+    storage: &SledStorage,
+    cutoff: i64,
+) -> Result<usize, String> {
+    let removed = pm.head.expire(timeseries, storage, cutoff)?;
+    if removed > 0 {
+        pm.last_values.expire(timeseries, storage, cutoff)?;
+        pm.rebuild_stats(timeseries, None, Some(cutoff))?;
+        pm.results
+            .invalidate_range(timeseries, None, Some(cutoff - 1));
+        if let Err(e) = storage.tree.flush() {
+            return Err(format!("Error flushing {}: {}", timeseries, e));
+        }
+    }
+    Ok(removed)
+}
+
+// Expires the points at every interval, on the blocking thread pool
+pub async fn run(pm: Arc<Mutex<TimeseriesDiskPersistenceManager>>, interval: i64) {
+    let mut ticks = actix_rt::time::interval(Duration::from_millis(interval as u64));
+    loop {
+        ticks.tick().await;
+        let mut manager = pm.lock().unwrap().clone();
+        match actix_web::web::block(move || expire(&mut manager)).await {
+            Ok(Ok(_)) => (),
+            Ok(Err(e)) => info!("Retention error: {}", e),
+            Err(e) => info!("Retention error: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn expire_points() {
+        let policies = parse_policies("cpu=30d, *=1h").unwrap();
+        assert_eq!(policies.timeseries.get("cpu"), Some(&2_592_000_000));
+        assert_eq!(policies.default, Some(3_600_000));
+        assert!(parse_policies("cpu").is_err());
+
+        let dir = std::env::temp_dir().join(format!("refluxdb-retention-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut pm =
+            TimeseriesDiskPersistenceManager::new(dir.to_str().unwrap().to_string()).unwrap();
+        pm.save_batch(
+            "cpu".to_string(),
+            vec![("usage".to_string(), 1.0, HashMap::new())],
+        )
+        .unwrap();
+        pm.retention.configure(policies);
+        assert_eq!(pm.retention.policy("cpu").unwrap().source, "config");
+        assert_eq!(expire(&mut pm).unwrap()[0].removed, 0);
+
+        let storage = pm.storages.lock().unwrap().get("cpu").cloned().unwrap();
+        pm.retention.set("cpu", &storage, Some(1)).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        assert_eq!(expire(&mut pm).unwrap()[0].removed, 1);
+        assert!(pm.last_values.get("cpu").is_empty());
+        assert!(pm.stats.get("cpu").is_empty());
+
+        // the policy is saved with the timeseries
+        let reloaded = Retention::default();
+        reloaded.load("cpu", &storage).unwrap();
+        assert_eq!(reloaded.policy("cpu").unwrap().keep_ms, 1);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
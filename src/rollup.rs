@@ -0,0 +1,966 @@
+use crate::calendar::{Period, Window};
+use crate::functions::{self, Point};
+use crate::persistence::{Measurement, TimeseriesDiskPersistenceManager};
+use crate::query::{self, Aggregate, Condition, Projection, SelectPlan, Series, TagGrouping};
+use gluesql::storages::SledStorage;
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Continuous rollups
+// A rollup downsamples a source timeseries into its own timeseries, one point per window,
+// series (tag set) and field aggregate, named <field>_<aggregate>:
+//   cpu_1m=cpu:1m:mean,max,min          usage -> usage_mean, usage_max, usage_min per minute
+//   cpu_1h=cpu_1m:1h:mean,max,min       usage_mean -> usage_mean per hour, ...
+// A rollup of a rollup aggregates the fields of the same aggregate again: the max of the
+// maxima, the sum of the counts and the mean of the means, weighted by <field>_count when the
+// source keeps it. Windows are computed once complete, on a schedule, and the end of the last
+// completed window is saved with the rollup's definition in a tree of its timeseries. A
+// point written into a completed window marks it for recomputation on the next run. Windows are
+// read one at a time, the ones without points skipped.
+//   REFLUXDB_ROLLUPS="cpu_1m=cpu:1m:mean,max,min;cpu_1h=cpu_1m:1h:mean,max,min"
+//   REFLUXDB_ROLLUP_INTERVAL=10s
+
+const TREE: &str = "rollup";
+const KEY: &str = "state";
+
+pub const DEFAULT_INTERVAL: i64 = 10_000;
+
+const AGGREGATES: [Aggregate; 7] = [
+    Aggregate::Count,
+    Aggregate::Sum,
+    Aggregate::Mean,
+    Aggregate::Min,
+    Aggregate::Max,
+    Aggregate::First,
+    Aggregate::Last,
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Definition {
+    pub name: String,   // timeseries written
+    pub source: String, // timeseries read
+    pub every: String,  // window: 1m, 1h, 1d, 1mo
+    pub aggregates: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct State {
+    definition: Definition,
+    completed: Option<i64>,    // end of the last completed window
+    dirty: BTreeMap<i64, u64>, // completed windows written since, with their count of marks
+    #[serde(skip)]
+    computing: Option<i64>, // end of the windows being completed by a run
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Status {
+    #[serde(flatten)]
+    pub definition: Definition,
+    pub completed: Option<String>,
+    pub dirty: Vec<String>,
+}
+
+// What a run computed for a rollup
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Computed {
+    pub rollup: String,
+    pub windows: usize,
+    pub recomputed: usize, // windows computed again for late data
+    pub points: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct Rollups {
+    states: Mutex<BTreeMap<String, State>>,
+    running: Mutex<()>,
+}
+
+// (window start, field, value, tags)
+type Row = (i64, String, f64, HashMap<String, String>);
+
+// window start and group -> field -> points and their weight
+type Windows = BTreeMap<(i64, BTreeMap<String, String>), BTreeMap<String, Vec<(Point, f64)>>>;
+
+fn parse_aggregate(name: &str) -> Result<Aggregate, String> {
+    match AGGREGATES.iter().find(|a| a.name() == name.trim()) {
+        Some(a) => Ok(a.clone()),
+        None => Err(format!(
+            "Unsupported rollup aggregate: {}, expected count, sum, mean, min, max, first or last",
+            name
+        )),
+    }
+}
+
+impl Definition {
+    // cpu_1m=cpu:1m:mean,max,min
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let invalid = || format!("Expected name=source:every:aggregates: {}", spec);
+        let (name, rest) = spec.split_once('=').ok_or_else(invalid)?;
+        let parts: Vec<&str> = rest.splitn(3, ':').collect();
+        if parts.len() != 3 {
+            return Err(invalid());
+        }
+        let definition = Definition {
+            name: name.trim().to_string(),
+            source: parts[0].trim().to_string(),
+            every: parts[1].trim().to_string(),
+            aggregates: parts[2].split(',').map(|a| a.trim().to_string()).collect(),
+        };
+        definition.validate()?;
+        Ok(definition)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        for name in [&self.name, &self.source] {
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(format!("Invalid timeseries name: {}", name));
+            }
+        }
+        if self.name == self.source {
+            return Err(format!(
+                "A rollup can't read its own timeseries: {}",
+                self.name
+            ));
+        }
+        self.window()?;
+        if self.aggregates.is_empty() {
+            return Err(format!("A rollup needs aggregates: {}", self.name));
+        }
+        self.parsed_aggregates()?;
+        Ok(())
+    }
+
+    pub fn window(&self) -> Result<Window, String> {
+        Ok(Window::new(Period::parse(&self.every)?, None))
+    }
+
+    fn parsed_aggregates(&self) -> Result<Vec<Aggregate>, String> {
+        self.aggregates.iter().map(|a| parse_aggregate(a)).collect()
+    }
+}
+
+pub fn definitions_from_env() -> Result<Vec<Definition>, String> {
+    match std::env::var("REFLUXDB_ROLLUPS") {
+        Ok(v) => v
+            .split(';')
+            .filter(|d| !d.trim().is_empty())
+            .map(Definition::parse)
+            .collect(),
+        Err(_) => Ok(vec![]),
+    }
+}
+
+pub fn interval_from_env() -> Result<i64, String> {
+    match std::env::var("REFLUXDB_ROLLUP_INTERVAL") {
+        Ok(v) => functions::parse_duration(&v),
+        Err(_) => Ok(DEFAULT_INTERVAL),
+    }
+}
+
+fn status(state: &State) -> Status {
+    Status {
+        definition: state.definition.clone(),
+        completed: state.completed.map(query::timestamp_literal),
+        dirty: state
+            .dirty
+            .keys()
+            .map(|w| query::timestamp_literal(*w))
+            .collect(),
+    }
+}
+
+fn save_state(
+    pm: &TimeseriesDiskPersistenceManager,
+    name: &str,
+    state: Option<&State>,
+) -> Result<(), String> {
+    let storage = match pm.storages.lock().unwrap().get(name) {
+        Some(s) => s.clone(),
+        None => return Err(format!("Timeseries not found: {}", name)),
+    };
+    let saved = match state {
+        Some(state) => match bincode::serialize(state) {
+            Ok(encoded) => storage
+                .tree
+                .open_tree(TREE)
+                .and_then(|tree| tree.insert(KEY, encoded))
+                .map(|_| ()),
+            Err(e) => return Err(format!("Error encoding rollup {}: {}", name, e)),
+        },
+        None => storage
+            .tree
+            .open_tree(TREE)
+            .and_then(|tree| tree.remove(KEY))
+            .map(|_| ()),
+    };
+    match saved {
+        Ok(()) => Ok(()),
+        Err(e) => Err(format!("Error saving rollup {}: {}", name, e)),
+    }
+}
+
+impl Rollups {
+    pub fn load(&self, timeseries: &str, storage: &SledStorage) -> Result<(), String> {
+        let stored = storage.tree.open_tree(TREE).and_then(|tree| tree.get(KEY));
+        match stored {
+            Ok(Some(state)) => match bincode::deserialize::<State>(&state) {
+                Ok(state) => {
+                    self.states
+                        .lock()
+                        .unwrap()
+                        .insert(timeseries.to_string(), state);
+                    Ok(())
+                }
+                Err(e) => Err(format!("Error decoding rollup {}: {}", timeseries, e)),
+            },
+            Ok(None) => Ok(()),
+            Err(e) => Err(format!("Error reading rollup {}: {}", timeseries, e)),
+        }
+    }
+
+    pub fn list(&self) -> Vec<Status> {
+        self.states.lock().unwrap().values().map(status).collect()
+    }
+
+    pub fn is_rollup(&self, timeseries: &str) -> bool {
+        self.states.lock().unwrap().contains_key(timeseries)
+    }
+}
+
+// Creates or replaces a rollup, a new definition computes every window again
+pub fn define(
+    pm: &mut TimeseriesDiskPersistenceManager,
+    definition: Definition,
+) -> Result<Status, String> {
+    definition.validate()?;
+    {
+        let states = pm.rollups.states.lock().unwrap();
+        let mut source = definition.source.clone();
+        while let Some(state) = states.get(&source) {
+            if state.definition.source == definition.name {
+                return Err(format!(
+                    "Rollup cycle: {} reads {}",
+                    source, definition.name
+                ));
+            }
+            source = state.definition.source.clone();
+        }
+        if let Some(state) = states.get(&definition.name) {
+            if state.definition == definition {
+                return Ok(status(state));
+            }
+        }
+    }
+    pm.clone().check_database(definition.name.clone(), true)?;
+    let state = State {
+        definition: definition.clone(),
+        completed: None,
+        dirty: BTreeMap::new(),
+        computing: None,
+    };
+    save_state(pm, &definition.name, Some(&state))?;
+    let status = status(&state);
+    pm.rollups
+        .states
+        .lock()
+        .unwrap()
+        .insert(definition.name, state);
+    Ok(status)
+}
+
+// Stops computing a rollup, its timeseries is kept
+pub fn remove(pm: &TimeseriesDiskPersistenceManager, name: &str) -> Result<(), String> {
+    if !pm.rollups.is_rollup(name) {
+        return Err(format!("Rollup not found: {}", name));
+    }
+    save_state(pm, name, None)?;
+    pm.rollups.states.lock().unwrap().remove(name);
+    Ok(())
+}
+
+// Marks the completed windows of the rollups reading the timeseries written, and the ones
+// being completed as the run may have read them already
+pub fn written(
+    pm: &TimeseriesDiskPersistenceManager,
+    timeseries: &str,
+    time: i64,
+) -> Result<(), String> {
+    let mut states = pm.rollups.states.lock().unwrap();
+    for (name, state) in states.iter_mut() {
+        let completed = state.completed.max(state.computing);
+        if state.definition.source != timeseries || completed.is_none_or(|c| time >= c) {
+            continue;
+        }
+        let marks = state
+            .dirty
+            .entry(state.definition.window()?.start(time))
+            .or_insert(0);
+        *marks += 1;
+        if *marks == 1 {
+            save_state(pm, name, Some(state))?;
+        }
+    }
+    Ok(())
+}
+
+// Aggregate of the aggregates of a rollup source, the means weighted by their counts
+fn reaggregate(aggregate: &Aggregate, points: &[(Point, f64)]) -> Option<f64> {
+    let values: Vec<Point> = points.iter().map(|(p, _)| *p).collect();
+    match aggregate {
+        Aggregate::Count => functions::sum(&values),
+        Aggregate::Mean => {
+            let total: f64 = points.iter().map(|(p, w)| p.value * w).sum();
+            let n: f64 = points.iter().map(|(_, w)| w).sum();
+            match n > 0.0 {
+                true => Some(total / n),
+                false => None,
+            }
+        }
+        aggregate => aggregate.scalar(&values),
+    }
+}
+
+// One point per window, group and aggregate named <field>_<aggregate>, from raw rows or from
+// the rows of a rollup
+fn aggregate(
+    window: &Window,
+    aggregates: &[Aggregate],
+    from_rollup: bool,
+    grouping: &TagGrouping,
+    rows: Vec<Measurement>,
+) -> Vec<Row> {
+    // the count of each rollup point weighs its mean
+    let mut counts: HashMap<(i64, BTreeMap<String, String>, String), f64> = HashMap::new();
+    if from_rollup {
+        for m in rows.iter() {
+            if let Some(base) = m.name.strip_suffix("_count") {
+                counts.insert((m.key, query::tag_key(&m.tags), base.to_string()), m.value);
+            }
+        }
+    }
+    let mut groups: Windows = BTreeMap::new();
+    for m in rows {
+        let series = query::tag_key(&m.tags);
+        let weight = match m.name.strip_suffix("_mean") {
+            Some(base) => counts
+                .get(&(m.key, series.clone(), base.to_string()))
+                .cloned()
+                .unwrap_or(1.0),
+            None => 1.0,
+        };
+        groups
+            .entry((window.start(m.key), grouping.key(&series)))
+            .or_default()
+            .entry(m.name)
+            .or_default()
+            .push((Point::new(m.key, m.value), weight));
+    }
+    let mut result: Vec<Row> = Vec::new();
+    for ((time, tags), mut fields) in groups {
+        for points in fields.values_mut() {
+            points.sort_by_key(|(p, _)| p.time);
+        }
+        let tags: HashMap<String, String> = tags.into_iter().collect();
+        for (field, points) in fields.iter() {
+            for aggregate in aggregates.iter() {
+                let (name, value) = match from_rollup {
+                    false => {
+                        let values: Vec<Point> = points.iter().map(|(p, _)| *p).collect();
+                        (
+                            format!("{}_{}", field, aggregate.name()),
+                            aggregate.scalar(&values),
+                        )
+                    }
+                    true => match field.ends_with(&format!("_{}", aggregate.name())) {
+                        true => (field.clone(), reaggregate(aggregate, points)),
+                        false => continue,
+                    },
+                };
+                if let Some(value) = value {
+                    result.push((time, name, value, tags.clone()));
+                }
+            }
+        }
+    }
+    result
+}
+
+fn time_range(start: Option<i64>, end: i64) -> String {
+    match start {
+        Some(start) => format!(
+            "time >= '{}' AND time < '{}'",
+            query::timestamp_literal(start),
+            query::timestamp_literal(end)
+        ),
+        None => format!("time < '{}'", query::timestamp_literal(end)),
+    }
+}
+
+// Time of the first point of the source within the range
+fn first_point(
+    pm: &mut TimeseriesDiskPersistenceManager,
+    source: &str,
+    start: Option<i64>,
+    end: i64,
+) -> Result<Option<i64>, String> {
+    let query = format!(
+        "SELECT * FROM {} WHERE {} LIMIT 1",
+        source,
+        time_range(start, end)
+    );
+    Ok(pm
+        .scan_measurements(source.to_string(), query)?
+        .first()
+        .map(|m| m.key))
+}
+
+// Computes the windows of a rollup completed since the last run and the ones written since
+fn compute(
+    pm: &mut TimeseriesDiskPersistenceManager,
+    name: &str,
+) -> Result<Option<Computed>, String> {
+    let state = match pm.rollups.states.lock().unwrap().get(name) {
+        Some(s) => s.clone(),
+        None => return Ok(None),
+    };
+    let definition = &state.definition;
+    let storage = match pm.storages.lock().unwrap().get(name) {
+        Some(s) => s.clone(),
+        None => return Err(format!("Timeseries not found: {}", name)),
+    };
+    if !pm.storages.lock().unwrap().contains_key(&definition.source) {
+        return Ok(None);
+    }
+    let window = definition.window()?;
+    let mut end = window.start(chrono::Utc::now().timestamp_millis());
+    // a rollup of a rollup waits for the windows of its source
+    let from_rollup = pm.rollups.is_rollup(&definition.source);
+    if from_rollup {
+        let states = pm.rollups.states.lock().unwrap();
+        let source = states.get(&definition.source).and_then(|s| s.completed);
+        end = match source {
+            Some(source) => end.min(window.start(source)),
+            None => state.completed.unwrap_or(i64::MIN),
+        };
+    }
+    let dirty = state.dirty.clone();
+    let mut ranges: Vec<(Option<i64>, i64)> =
+        dirty.keys().map(|w| (Some(*w), window.next(*w))).collect();
+    let completes = state.completed.map_or(end > i64::MIN, |c| c < end);
+    if completes {
+        ranges.push((state.completed, end));
+        if let Some(current) = pm.rollups.states.lock().unwrap().get_mut(name) {
+            current.computing = Some(end);
+        }
+    }
+    let mut computed = Computed {
+        rollup: name.to_string(),
+        windows: 0,
+        recomputed: dirty.len(),
+        points: 0,
+    };
+    let mut deleted: Vec<(Option<i64>, i64)> = Vec::new();
+    let computing = (|| {
+        for (start, stop) in ranges {
+            // the points computed before for these windows are replaced
+            match crate::engine::delete(&storage, start, stop) {
+                Ok(0) => (),
+                Ok(_) => deleted.push((start, stop)),
+                Err(e) => return Err(format!("Error clearing rollup {}: {}", name, e)),
+            }
+            pm.results.invalidate_range(name, start, Some(stop - 1));
+            let mut from = start;
+            // the window of the first point left is read next
+            while let Some(first) = first_point(pm, &definition.source, from, stop)? {
+                let w = window.start(first).max(from.unwrap_or(i64::MIN));
+                let next = window.next(window.start(first)).min(stop);
+                let query = format!(
+                    "SELECT * FROM {} WHERE {}",
+                    definition.source,
+                    time_range(Some(w), next)
+                );
+                let rows = pm.scan_measurements(definition.source.clone(), query)?;
+                let points = aggregate(
+                    &window,
+                    &definition.parsed_aggregates()?,
+                    from_rollup,
+                    &TagGrouping::Series,
+                    rows,
+                );
+                computed.windows += points.iter().map(|p| p.0).collect::<BTreeSet<i64>>().len();
+                computed.points += points.len();
+                for (time, field, value, tags) in points {
+                    pm.save_measurement_at(name.to_string(), field, value, tags, time)?;
+                }
+                from = Some(next);
+            }
+        }
+        Ok(())
+    })();
+    if computing.is_err() {
+        if let Some(current) = pm.rollups.states.lock().unwrap().get_mut(name) {
+            current.computing = None;
+        }
+    }
+    computing?;
+    for (start, stop) in deleted {
+        pm.rebuild_stats(name, start, Some(stop))?;
+    }
+
+    // windows written while computing stay marked
+    let mut states = pm.rollups.states.lock().unwrap();
+    if let Some(current) = states.get_mut(name) {
+        current.computing = None;
+        if current.definition != state.definition {
+            return Ok(None);
+        }
+        for (w, marks) in dirty.iter() {
+            if current.dirty.get(w) == Some(marks) {
+                current.dirty.remove(w);
+            }
+        }
+        if completes {
+            current.completed = Some(current.completed.map_or(end, |c| c.max(end)));
+        }
+        save_state(pm, name, Some(current))?;
+    }
+    Ok(Some(computed))
+}
+
+// Computes every rollup, the ones reading a rollup after it
+pub fn run(pm: &mut TimeseriesDiskPersistenceManager) -> Result<Vec<Computed>, String> {
+    let rollups = pm.rollups.clone();
+    let _running = rollups.running.lock().unwrap();
+    let mut names: Vec<(usize, String)> = {
+        let states = rollups.states.lock().unwrap();
+        states
+            .keys()
+            .map(|name| {
+                let mut depth = 0;
+                let mut source = &states[name].definition.source;
+                while let Some(state) = states.get(source) {
+                    depth += 1;
+                    source = &state.definition.source;
+                    if depth > states.len() {
+                        break;
+                    }
+                }
+                (depth, name.clone())
+            })
+            .collect()
+    };
+    names.sort();
+    let mut report: Vec<Computed> = Vec::new();
+    for (_, name) in names {
+        if let Some(computed) = compute(pm, &name)? {
+            match computed.points {
+                0 => debug!("Rollup: {:?}", computed),
+                _ => info!("Rollup: {:?}", computed),
+            }
+            report.push(computed);
+        }
+    }
+    Ok(report)
+}
+
+// Computes the rollups at every interval, on the blocking thread pool
+pub async fn schedule(pm: Arc<Mutex<TimeseriesDiskPersistenceManager>>, interval: i64) {
+    let mut ticks = actix_rt::time::interval(Duration::from_millis(interval as u64));
+    loop {
+        ticks.tick().await;
+        let mut manager = pm.lock().unwrap().clone();
+        match actix_web::web::block(move || run(&mut manager)).await {
+            Ok(Ok(_)) => (),
+            Ok(Err(e)) => info!("Rollup error: {}", e),
+            Err(e) => info!("Rollup error: {}", e),
+        }
+    }
+}
+
+// Automatic resolution
+// A query grouping by time with count, sum, mean, min, max, first or last reads the coarsest
+// rollup of its timeseries keeping these aggregates whose windows divide its own. Whole query
+// windows within the completed windows of the tier are read from it, the partial windows
+// around them and the ones not computed yet from the raw data. Queries filtering on values
+// or overlapping windows to recompute read the raw data, as do the ones forcing it.
+
+// Query windows checked to be unions of tier windows
+const ALIGNMENT_CHECKS: usize = 10_000;
+
+// Where a planned query reads its points
+#[derive(Debug, Clone, PartialEq)]
+pub struct TierPlan {
+    pub tier: String,
+    pub aggregates: Vec<Aggregate>,
+    pub rollup: SelectPlan,   // whole windows, from the tier
+    pub raw: Vec<SelectPlan>, // the windows before and after, from the source
+}
+
+fn on_tags(condition: &Condition) -> bool {
+    match condition {
+        Condition::And(l, r) | Condition::Or(l, r) => on_tags(l) && on_tags(r),
+        Condition::Tag { .. } | Condition::TagMatch { .. } => true,
+        Condition::Field { .. } => false,
+    }
+}
+
+fn duration(window: &Window) -> i64 {
+    let start = window.start(0);
+    window.next(start) - start
+}
+
+// Whether the query windows from the time on are made of whole tier windows
+fn aligned(tier: &Window, window: &Window, from: i64, to: i64) -> bool {
+    let mut boundary = window.start(from);
+    for _ in 0..ALIGNMENT_CHECKS {
+        if tier.start(boundary) != boundary {
+            return false;
+        }
+        boundary = window.next(boundary);
+        if boundary > to {
+            break;
+        }
+    }
+    true
+}
+
+// The coarsest rollup tier able to answer a planned query
+pub fn plan(pm: &TimeseriesDiskPersistenceManager, plan: &SelectPlan) -> Option<TierPlan> {
+    let window = plan.group_by_time?;
+    let mut aggregates: Vec<Aggregate> = Vec::new();
+    for projection in plan.projections.iter() {
+        match (&projection.aggregate, &projection.selector) {
+            (Some(a), None) if AGGREGATES.contains(a) => {
+                if !aggregates.contains(a) {
+                    aggregates.push(a.clone());
+                }
+            }
+            _ => return None,
+        }
+    }
+    let query = plan.base_query();
+    if !crate::explain::only_time_bounds(&query) || !plan.condition.as_ref().is_none_or(on_tags) {
+        return None;
+    }
+    let (start, end) = crate::explain::time_bounds(&query);
+    // the first whole window
+    let first = start.map(|s| match window.start(s) == s {
+        true => s,
+        false => window.next(window.start(s)),
+    });
+
+    let states = pm.rollups.states.lock().unwrap();
+    let mut best: Option<(i64, TierPlan)> = None;
+    for (name, state) in states.iter() {
+        // the rollups down to the raw data
+        let mut chain = vec![state];
+        let mut source = &state.definition.source;
+        while let Some(s) = states.get(source) {
+            chain.push(s);
+            source = &s.definition.source;
+            if chain.len() > states.len() {
+                break;
+            }
+        }
+        let kept = |a: &Aggregate| {
+            chain
+                .iter()
+                .all(|s| s.definition.aggregates.iter().any(|n| n == a.name()))
+        };
+        if *source != plan.timeseries || !aggregates.iter().all(kept) {
+            continue;
+        }
+        let (tier, completed) = match (state.definition.window(), state.completed) {
+            (Ok(tier), Some(completed)) => (tier, completed),
+            _ => continue,
+        };
+        // the end of the last whole window computed
+        let mut last = window.start(completed);
+        if let Some(end) = end {
+            last = last.min(window.start(end));
+        }
+        if first.is_some_and(|f| f >= last) || best.as_ref().is_some_and(|b| b.0 >= duration(&tier))
+        {
+            continue;
+        }
+        let from = first.unwrap_or_else(|| window.start(last - 366 * 86_400_000));
+        let dirty = chain.iter().any(|s| {
+            s.dirty
+                .keys()
+                .any(|w| first.is_none_or(|f| *w >= f) && *w < last)
+        });
+        if dirty || !aligned(&tier, &window, from, last) {
+            continue;
+        }
+
+        let mut rollup = SelectPlan::new(name.clone(), vec![]);
+        rollup.start = first;
+        rollup.end = Some(last);
+        let mut raw: Vec<SelectPlan> = Vec::new();
+        if let (Some(start), Some(first)) = (start, first) {
+            if start < first {
+                let mut before = plan.clone();
+                before.end = Some(first);
+                raw.push(before);
+            }
+        }
+        if end.is_none_or(|e| e >= last) {
+            let mut after = plan.clone();
+            after.start = Some(last);
+            raw.push(after);
+        }
+        let tier_plan = TierPlan {
+            tier: name.clone(),
+            aggregates: aggregates.clone(),
+            rollup,
+            raw,
+        };
+        best = Some((duration(&tier), tier_plan));
+    }
+    best.map(|(_, tier_plan)| tier_plan)
+}
+
+// Runs a planned query over a rollup tier, None when it reads the raw data
+pub fn execute(
+    pm: &mut TimeseriesDiskPersistenceManager,
+    plan: &SelectPlan,
+) -> Result<Option<Vec<Series>>, String> {
+    let (tier, window) = match (pm.raw, self::plan(pm, plan), plan.group_by_time) {
+        (false, Some(tier), Some(window)) => (tier, window),
+        _ => return Ok(None),
+    };
+    // each window of each group is reduced to a point per aggregate
+    let mut points: Vec<Row> = Vec::new();
+    let reads = std::iter::once((true, &tier.rollup)).chain(tier.raw.iter().map(|p| (false, p)));
+    for (from_rollup, read) in reads {
+        let mut rows = pm.scan_measurements(read.timeseries.clone(), read.base_query())?;
+        if let Some(condition) = &plan.condition {
+            rows.retain(|m| condition.matches(m));
+        }
+        points.extend(aggregate(
+            &window,
+            &tier.aggregates,
+            from_rollup,
+            &plan.group_by_tags,
+            rows,
+        ));
+    }
+    let measurements: Vec<Measurement> = points
+        .into_iter()
+        .map(|(key, name, value, tags)| Measurement {
+            key,
+            id: uuid::Uuid::nil(),
+            name,
+            value,
+            tags,
+        })
+        .collect();
+
+    // the projections pick the point of their aggregate in each window
+    let mut reduced = plan.clone();
+    reduced.projections = Vec::new();
+    for projection in plan.projections.iter() {
+        let suffix = match &projection.aggregate {
+            Some(a) => format!("_{}", a.name()),
+            None => continue,
+        };
+        let fields: BTreeSet<String> = match &projection.field {
+            Some(f) => BTreeSet::from([f.clone()]),
+            None => measurements
+                .iter()
+                .filter_map(|m| m.name.strip_suffix(&suffix).map(|f| f.to_string()))
+                .collect(),
+        };
+        for field in fields {
+            reduced.projections.push(Projection {
+                function: projection.function.clone(),
+                aggregate: Some(Aggregate::Last),
+                selector: None,
+                field: Some(format!("{}{}", field, suffix)),
+                alias: Some(projection.label(&field)),
+            });
+        }
+    }
+    let mut series = query::evaluate(&reduced, measurements)?;
+    for s in series.iter_mut() {
+        s.tier = Some(tier.tier.clone());
+    }
+    Ok(Some(series))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn measurement(key: i64, name: &str, value: f64) -> Measurement {
+        let mut tags = HashMap::new();
+        tags.insert("host".to_string(), "a".to_string());
+        Measurement {
+            key,
+            id: uuid::Uuid::new_v4(),
+            name: name.to_string(),
+            value,
+            tags,
+        }
+    }
+
+    fn rollup(definition: &Definition, from_rollup: bool, rows: Vec<Measurement>) -> Vec<Row> {
+        aggregate(
+            &definition.window().unwrap(),
+            &definition.parsed_aggregates().unwrap(),
+            from_rollup,
+            &TagGrouping::Series,
+            rows,
+        )
+    }
+
+    #[test]
+    fn downsample() {
+        let minutes = Definition::parse("cpu_1m=cpu:1m:mean,max,count").unwrap();
+        assert!(Definition::parse("cpu_1m=cpu:1m:p99").is_err());
+        assert!(Definition::parse("cpu=cpu:1m:mean").is_err());
+        let rows = vec![
+            measurement(1_000, "usage", 1.0),
+            measurement(2_000, "usage", 3.0),
+            measurement(61_000, "usage", 5.0),
+        ];
+        let points = rollup(&minutes, false, rows);
+        let values: Vec<(i64, &str, f64)> = points
+            .iter()
+            .map(|(t, f, v, _)| (*t, f.as_str(), *v))
+            .collect();
+        assert_eq!(
+            values,
+            vec![
+                (0, "usage_mean", 2.0),
+                (0, "usage_max", 3.0),
+                (0, "usage_count", 2.0),
+                (60_000, "usage_mean", 5.0),
+                (60_000, "usage_max", 5.0),
+                (60_000, "usage_count", 1.0),
+            ]
+        );
+
+        // the mean of the means is weighted by the counts
+        let hours = Definition::parse("cpu_1h=cpu_1m:1h:mean,max").unwrap();
+        let rows: Vec<Measurement> = points
+            .into_iter()
+            .map(|(t, f, v, _)| measurement(t, &f, v))
+            .collect();
+        let points = rollup(&hours, true, rows);
+        let values: Vec<(&str, f64)> = points.iter().map(|(_, f, v, _)| (f.as_str(), *v)).collect();
+        assert_eq!(values, vec![("usage_max", 5.0), ("usage_mean", 3.0)]);
+    }
+
+    #[test]
+    fn recompute_late_windows() {
+        let dir = std::env::temp_dir().join(format!("refluxdb-rollup-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut pm =
+            TimeseriesDiskPersistenceManager::new(dir.to_str().unwrap().to_string()).unwrap();
+        let save = |pm: &mut TimeseriesDiskPersistenceManager, time: i64, value: f64| {
+            pm.save_measurement_at(
+                "cpu".to_string(),
+                "usage".to_string(),
+                value,
+                HashMap::new(),
+                time,
+            )
+            .unwrap();
+        };
+        save(&mut pm, 60_000, 1.0);
+        save(&mut pm, 90_000, 3.0);
+        // windows are read one at a time, the empty ones in between skipped
+        save(&mut pm, 600_000, 5.0);
+        define(
+            &mut pm,
+            Definition::parse("cpu_1m=cpu:1m:mean,max").unwrap(),
+        )
+        .unwrap();
+        let computed = run(&mut pm).unwrap();
+        assert_eq!((computed[0].windows, computed[0].points), (2, 4));
+        assert!(pm.rollups.list()[0].completed.is_some());
+
+        // late data marks its window, computed again on the next run
+        save(&mut pm, 100_000, 8.0);
+        assert_eq!(pm.rollups.list()[0].dirty, vec!["1970-01-01T00:01:00.000Z"]);
+        let computed = run(&mut pm).unwrap();
+        assert_eq!(computed[0].recomputed, 1);
+        let query =
+            "SELECT * FROM cpu_1m WHERE time < '1970-01-01T00:02:00Z' AND name = 'usage_mean'"
+                .to_string();
+        let rows = pm.scan_measurements("cpu_1m".to_string(), query).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].value, 4.0);
+        assert!(pm.rollups.list()[0].dirty.is_empty());
+
+        // the definition and progress are saved with the rollup
+        let storage = pm.storages.lock().unwrap().get("cpu_1m").cloned().unwrap();
+        let reloaded = Rollups::default();
+        reloaded.load("cpu_1m", &storage).unwrap();
+        assert_eq!(reloaded.list(), pm.rollups.list());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn pick_tiers() {
+        let dir = std::env::temp_dir().join(format!("refluxdb-tiers-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut pm =
+            TimeseriesDiskPersistenceManager::new(dir.to_str().unwrap().to_string()).unwrap();
+        for i in 0..36 {
+            let mut tags = HashMap::new();
+            tags.insert("host".to_string(), format!("{}", i % 2));
+            let (name, value) = ("usage".to_string(), (i * i % 7) as f64);
+            pm.save_measurement_at("cpu".to_string(), name, value, tags, i * 300_000)
+                .unwrap();
+        }
+        for definition in [
+            "cpu_1m=cpu:1m:mean,max,count",
+            "cpu_1h=cpu_1m:1h:mean,max,count",
+        ] {
+            define(&mut pm, Definition::parse(definition).unwrap()).unwrap();
+        }
+        run(&mut pm).unwrap();
+
+        let answers = |pm: &mut TimeseriesDiskPersistenceManager, query: &str| {
+            let plan = query::plan(query.to_string()).unwrap().unwrap();
+            let tier = plan_tier(pm, &plan);
+            pm.raw = true;
+            let raw = query::execute(pm, &plan).unwrap();
+            pm.raw = false;
+            let series = query::execute(pm, &plan).unwrap();
+            assert_eq!(series[0].values, raw[0].values);
+            assert_eq!(series[0].columns, raw[0].columns);
+            tier
+        };
+        let hourly = "SELECT mean(usage), count(usage), max(usage) FROM cpu WHERE time >= '1970-01-01T00:30:00Z' AND time < '1970-01-01T03:00:00Z' GROUP BY time('1h')";
+        assert_eq!(answers(&mut pm, hourly), Some("cpu_1h".to_string()));
+        let half_hourly = "SELECT mean(usage) FROM cpu GROUP BY time('30m'), host";
+        assert_eq!(answers(&mut pm, half_hourly), Some("cpu_1m".to_string()));
+        let spread = "SELECT spread(usage) FROM cpu GROUP BY time('1h')";
+        assert_eq!(answers(&mut pm, spread), None);
+
+        // late data is read raw until its windows are computed again
+        pm.save_measurement_at(
+            "cpu".to_string(),
+            "usage".to_string(),
+            9.0,
+            HashMap::new(),
+            4_200_000,
+        )
+        .unwrap();
+        assert_eq!(answers(&mut pm, hourly), None);
+        run(&mut pm).unwrap();
+        assert_eq!(answers(&mut pm, hourly), Some("cpu_1h".to_string()));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    fn plan_tier(pm: &TimeseriesDiskPersistenceManager, select: &SelectPlan) -> Option<String> {
+        plan(pm, select).map(|t| t.tier)
+    }
+}
@@ -0,0 +1,406 @@
+use crate::persistence::TimeseriesDiskPersistenceManager;
+use gluesql::sled::Tree;
+use gluesql::storages::SledStorage;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Time shards
+// The points of a timeseries are split into shards of an hour, a day or a week, each one a tree
+// of its sled database listed in the shards tree with its time range and points. Reads open the
+// shards overlapping their time range, retention drops the expired shards whole and a shard past
+// its write window (its end plus a grace period) is sealed: it takes no more points and its points
+// are compressed in blocks (see blocks.rs). The shards of rollups aren't sealed, they write
+// windows once complete and compute them again.
+//   REFLUXDB_SHARD_DURATION=hour|day|week    (default day, for the shards created from then on)
+//   REFLUXDB_SHARD_GRACE=1h
+
+const TREE: &str = "shards";
+
+pub const DEFAULT_GRACE: i64 = 3_600_000;
+pub const SEAL_INTERVAL: i64 = 60_000;
+
+const HOUR: i64 = 3_600_000;
+const DAY: i64 = 24 * HOUR;
+const WEEK: i64 = 7 * DAY;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShardDuration {
+    Hour,
+    Day,
+    Week,
+}
+
+impl ShardDuration {
+    pub fn parse(duration: &str) -> Result<Self, String> {
+        match duration.trim() {
+            "hour" | "1h" => Ok(ShardDuration::Hour),
+            "day" | "1d" => Ok(ShardDuration::Day),
+            "week" | "1w" | "7d" => Ok(ShardDuration::Week),
+            d => Err(format!("Invalid shard duration: {} (hour, day or week)", d)),
+        }
+    }
+
+    // Start of the shard holding the time, weeks starting on monday
+    fn start(&self, time: i64) -> i64 {
+        match self {
+            ShardDuration::Hour => time.div_euclid(HOUR) * HOUR,
+            ShardDuration::Day => time.div_euclid(DAY) * DAY,
+            ShardDuration::Week => (time + 3 * DAY).div_euclid(WEEK) * WEEK - 3 * DAY,
+        }
+    }
+
+    fn millis(&self) -> i64 {
+        match self {
+            ShardDuration::Hour => HOUR,
+            ShardDuration::Day => DAY,
+            ShardDuration::Week => WEEK,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Config {
+    pub duration: ShardDuration,
+    pub grace: i64, // milliseconds after the end of a shard before it's sealed
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            duration: ShardDuration::Day,
+            grace: DEFAULT_GRACE,
+        }
+    }
+}
+
+pub fn config_from_env() -> Result<Config, String> {
+    let mut config = Config::default();
+    if let Ok(v) = std::env::var("REFLUXDB_SHARD_DURATION") {
+        config.duration = ShardDuration::parse(&v)?;
+    }
+    if let Ok(v) = std::env::var("REFLUXDB_SHARD_GRACE") {
+        config.grace = crate::functions::parse_duration(&v)?;
+    }
+    Ok(config)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Shard {
+    pub start: i64,
+    pub end: i64, // excluded
+    pub points: u64,
+    pub sealed: bool,
+    pub compressed: bool, // its points are in blocks
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ShardInfo {
+    pub start: String,
+    pub end: String,
+    pub points: u64,
+    pub sealed: bool,
+    pub compressed: bool,
+}
+
+impl Shard {
+    pub fn open(&self, storage: &SledStorage) -> Result<Tree, String> {
+        match storage.tree.open_tree(format!("points/{}", self.start)) {
+            Ok(t) => Ok(t),
+            Err(e) => Err(format!("Error opening shard {}: {}", self.start, e)),
+        }
+    }
+
+    pub fn blocks(&self, storage: &SledStorage) -> Result<Tree, String> {
+        match storage.tree.open_tree(format!("blocks/{}", self.start)) {
+            Ok(t) => Ok(t),
+            Err(e) => Err(format!(
+                "Error opening blocks of shard {}: {}",
+                self.start, e
+            )),
+        }
+    }
+
+    pub fn info(&self) -> ShardInfo {
+        ShardInfo {
+            start: crate::query::timestamp_literal(self.start),
+            end: crate::query::timestamp_literal(self.end),
+            points: self.points,
+            sealed: self.sealed,
+            compressed: self.compressed,
+        }
+    }
+}
+
+// Shards are listed by start, big endian with the sign flipped as the point keys
+fn key(start: i64) -> [u8; 8] {
+    ((start as u64) ^ (1 << 63)).to_be_bytes()
+}
+
+fn open(storage: &SledStorage) -> Result<Tree, String> {
+    match storage.tree.open_tree(TREE) {
+        Ok(t) => Ok(t),
+        Err(e) => Err(format!("Error opening shards: {}", e)),
+    }
+}
+
+fn decode(value: &[u8]) -> Result<Shard, String> {
+    match bincode::deserialize(value) {
+        Ok(s) => Ok(s),
+        Err(e) => Err(format!("Error decoding shard: {}", e)),
+    }
+}
+
+// Shards overlapping the range from start (included) to end (excluded), in time order
+pub fn overlapping(
+    storage: &SledStorage,
+    start: Option<i64>,
+    end: Option<i64>,
+) -> Result<Vec<Shard>, String> {
+    let mut shards = Vec::new();
+    for entry in open(storage)?.iter() {
+        let shard = match entry {
+            Ok((_, value)) => decode(&value)?,
+            Err(e) => return Err(format!("Error reading shards: {}", e)),
+        };
+        if start.is_some_and(|s| shard.end <= s) {
+            continue;
+        }
+        if end.is_some_and(|e| shard.start >= e) {
+            break;
+        }
+        shards.push(shard);
+    }
+    Ok(shards)
+}
+
+pub fn list(storage: &SledStorage) -> Result<Vec<Shard>, String> {
+    overlapping(storage, None, None)
+}
+
+// The shard holding the time, created with the configured duration when there's none yet
+pub fn for_time(storage: &SledStorage, config: &Config, time: i64) -> Result<Shard, String> {
+    let shards = open(storage)?;
+    let previous = match shards.range(..=key(time)).next_back() {
+        Some(Ok((_, value))) => Some(decode(&value)?),
+        Some(Err(e)) => return Err(format!("Error reading shards: {}", e)),
+        None => None,
+    };
+    if let Some(shard) = previous.as_ref().filter(|s| time < s.end) {
+        return Ok(shard.clone());
+    }
+    // the shards created with another duration are kept, the new one fits between them
+    let mut shard = Shard {
+        start: config.duration.start(time),
+        end: config.duration.start(time) + config.duration.millis(),
+        points: 0,
+        sealed: false,
+        compressed: false,
+    };
+    if let Some(p) = previous {
+        shard.start = shard.start.max(p.end);
+    }
+    match shards.range(key(time)..).next() {
+        Some(Ok((_, value))) => shard.end = shard.end.min(decode(&value)?.start),
+        Some(Err(e)) => return Err(format!("Error reading shards: {}", e)),
+        None => {}
+    }
+    let encoded = match bincode::serialize(&shard) {
+        Ok(e) => e,
+        Err(e) => return Err(format!("Error encoding shard: {}", e)),
+    };
+    // a concurrent write may have created it first
+    match shards.compare_and_swap(key(shard.start), None as Option<&[u8]>, Some(encoded)) {
+        Ok(Ok(())) => Ok(shard),
+        Ok(Err(current)) => match current.current {
+            Some(existing) => decode(&existing),
+            None => Err(format!("Error creating shard {}", shard.start)),
+        },
+        Err(e) => Err(format!("Error creating shard {}: {}", shard.start, e)),
+    }
+}
+
+fn update(storage: &SledStorage, shard: &Shard, change: impl Fn(&mut Shard)) -> Result<(), String> {
+    let updated = open(storage)?.update_and_fetch(key(shard.start), |value| {
+        let value = value?;
+        // an undecodable shard is left as is rather than removed
+        let mut shard = match decode(value) {
+            Ok(s) => s,
+            Err(_) => return Some(value.to_vec()),
+        };
+        change(&mut shard);
+        bincode::serialize(&shard)
+            .ok()
+            .or_else(|| Some(value.to_vec()))
+    });
+    match updated {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Error updating shard {}: {}", shard.start, e)),
+    }
+}
+
+// Points added to (or removed from) a shard
+pub fn count(storage: &SledStorage, shard: &Shard, points: i64) -> Result<(), String> {
+    update(storage, shard, |s| {
+        s.points = (s.points as i64 + points).max(0) as u64
+    })
+}
+
+fn drop_tree(storage: &SledStorage, shard: &Shard, tree: &str) -> Result<(), String> {
+    match storage
+        .tree
+        .drop_tree(format!("{}/{}", tree, shard.start).as_bytes())
+    {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Error dropping shard {}: {}", shard.start, e)),
+    }
+}
+
+// Marks the shard compressed once its blocks are saved, then drops its points
+pub fn compressed(storage: &SledStorage, shard: &Shard) -> Result<(), String> {
+    update(storage, shard, |s| s.compressed = true)?;
+    drop_tree(storage, shard, "points")
+}
+
+// Marks the shard open again once its blocks are back in points, then drops its blocks
+pub fn reopened(storage: &SledStorage, shard: &Shard) -> Result<Shard, String> {
+    update(storage, shard, |s| {
+        s.sealed = false;
+        s.compressed = false;
+    })?;
+    drop_tree(storage, shard, "blocks")?;
+    Ok(Shard {
+        sealed: false,
+        compressed: false,
+        ..shard.clone()
+    })
+}
+
+// Drops the trees of the shard at once, whatever its points
+pub fn drop(storage: &SledStorage, shard: &Shard) -> Result<(), String> {
+    drop_tree(storage, shard, "points")?;
+    drop_tree(storage, shard, "blocks")?;
+    match open(storage)?.remove(key(shard.start)) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Error removing shard {}: {}", shard.start, e)),
+    }
+}
+
+// Seals the shards past their write window, flushed to disk first, and compresses them. Those
+// sealed but not compressed yet (interrupted) are compressed again.
+pub fn seal(storage: &SledStorage, config: &Config, now: i64) -> Result<Vec<Shard>, String> {
+    let mut expired: Vec<Shard> = list(storage)?
+        .into_iter()
+        .filter(|s| !s.compressed && s.end + config.grace <= now)
+        .collect();
+    if expired.is_empty() {
+        return Ok(expired);
+    }
+    if let Err(e) = storage.tree.flush() {
+        return Err(format!("Error flushing shards: {}", e));
+    }
+    for shard in expired.iter_mut() {
+        update(storage, shard, |s| s.sealed = true)?;
+        crate::engine::compress(storage, shard)?;
+        shard.sealed = true;
+        shard.compressed = true;
+    }
+    Ok(expired)
+}
+
+// Seals the shards of every timeseries past their write window
+pub fn seal_all(
+    pm: &TimeseriesDiskPersistenceManager,
+) -> Result<BTreeMap<String, Vec<ShardInfo>>, String> {
+    let storages: BTreeMap<String, SledStorage> = pm
+        .storages
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    let now = chrono::Utc::now().timestamp_millis();
+    let mut sealed = BTreeMap::new();
+    for (timeseries, storage) in storages {
+        // rollups write their windows once complete and compute them again
+        if pm.rollups.is_rollup(&timeseries) {
+            continue;
+        }
+        // the points held by the head are saved first
+        let due = list(&storage)?
+            .iter()
+            .any(|s| !s.compressed && s.end + pm.shards.grace <= now);
+        if due {
+            pm.head.flush(&timeseries, &storage, &pm.shards)?;
+        }
+        let shards = seal(&storage, &pm.shards, now)?;
+        if !shards.is_empty() {
+            info!(
+                "Sealed and compressed {} shards of {}",
+                shards.len(),
+                timeseries
+            );
+            sealed.insert(timeseries, shards.iter().map(|s| s.info()).collect());
+        }
+    }
+    Ok(sealed)
+}
+
+// Seals the shards at every interval, on the blocking thread pool
+pub async fn schedule(pm: Arc<Mutex<TimeseriesDiskPersistenceManager>>, interval: i64) {
+    let mut ticks = actix_rt::time::interval(Duration::from_millis(interval as u64));
+    loop {
+        ticks.tick().await;
+        let manager = pm.lock().unwrap().clone();
+        match actix_web::web::block(move || seal_all(&manager)).await {
+            Ok(Ok(_)) => (),
+            Ok(Err(e)) => info!("Shard error: {}", e),
+            Err(e) => info!("Shard error: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn shard_ranges() {
+        let storage =
+            SledStorage::try_from(gluesql::sled::Config::default().temporary(true)).unwrap();
+        let day = Config::default();
+        let hour = Config {
+            duration: ShardDuration::Hour,
+            grace: 0,
+        };
+        assert_eq!(ShardDuration::Week.start(0), -3 * DAY); // monday 1969-12-29
+        assert_eq!(for_time(&storage, &day, -1).unwrap().start, -DAY);
+
+        let first = for_time(&storage, &day, DAY + 5).unwrap();
+        assert_eq!((first.start, first.end), (DAY, 2 * DAY));
+        assert_eq!(for_time(&storage, &hour, 2 * DAY - 1).unwrap(), first);
+        // hourly shards created later fit between the daily ones
+        let next = for_time(&storage, &hour, 2 * DAY + 1).unwrap();
+        assert_eq!((next.start, next.end), (2 * DAY, 2 * DAY + HOUR));
+
+        assert_eq!(
+            overlapping(&storage, Some(DAY), Some(2 * DAY)).unwrap(),
+            vec![first.clone()]
+        );
+        assert_eq!(list(&storage).unwrap().len(), 3);
+        count(&storage, &first, 2).unwrap();
+        assert_eq!(list(&storage).unwrap()[1].points, 2);
+
+        let sealed = seal(&storage, &hour, 2 * DAY + HOUR).unwrap();
+        assert_eq!(sealed.len(), 3);
+        assert!(list(&storage)
+            .unwrap()
+            .iter()
+            .all(|s| s.sealed && s.compressed));
+        drop(&storage, &first).unwrap();
+        assert_eq!(list(&storage).unwrap().len(), 2);
+    }
+}
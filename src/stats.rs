@@ -0,0 +1,434 @@
+use crate::lastvalue::series_key;
+use crate::persistence::Measurement;
+use gluesql::storages::SledStorage;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+
+// Series statistics
+// Count, sum, min, max, mean, first and last timestamps and approximate quantiles of every
+// series (field and tag set) of each timeseries, updated on every write and persisted in a
+// tree of the timeseries' sled database once per batch. GET /stats/{timeseries} reads them
+// without scanning.
+// Quantiles come from a sketch of logarithmic buckets: each one holds the values within 1% of
+// its own, so a quantile is off by 1% of its value at most, whatever the number of points.
+// They are kept per shard: deleting points (retention, recomputed rollups) drops the statistics
+// of the shards dropped and rebuilds those of the shards left from their points. A running
+// total per series is updated with them, what GET /stats reads, merged again from the shards
+// only after a rebuild.
+
+const TREE: &str = "series_stats";
+
+// Relative accuracy of the quantiles and the buckets kept before merging the lowest ones
+const ACCURACY: f64 = 0.01;
+const MAX_BUCKETS: usize = 2048;
+
+const QUANTILES: [f64; 5] = [0.5, 0.75, 0.9, 0.95, 0.99];
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct Sketch {
+    positive: BTreeMap<i32, u64>, // bucket index -> points
+    negative: BTreeMap<i32, u64>, // by magnitude
+    zero: u64,
+}
+
+fn gamma() -> f64 {
+    (1.0 + ACCURACY) / (1.0 - ACCURACY)
+}
+
+fn bucket(value: f64) -> i32 {
+    (value.ln() / gamma().ln()).ceil() as i32
+}
+
+// The value a bucket stands for, within the accuracy of the values it holds
+fn bucket_value(index: i32) -> f64 {
+    2.0 * gamma().powi(index) / (gamma() + 1.0)
+}
+
+// The buckets of the smallest magnitudes are merged, keeping the high quantiles accurate
+fn collapse(buckets: &mut BTreeMap<i32, u64>) {
+    while buckets.len() > MAX_BUCKETS {
+        if let Some((_, count)) = buckets.pop_first() {
+            if let Some(mut next) = buckets.first_entry() {
+                *next.get_mut() += count;
+            }
+        }
+    }
+}
+
+impl Sketch {
+    pub fn add(&mut self, value: f64) {
+        if value.is_nan() {
+            return;
+        }
+        let buckets = match value {
+            v if v > f64::MIN_POSITIVE => &mut self.positive,
+            v if v < -f64::MIN_POSITIVE => &mut self.negative,
+            _ => {
+                self.zero += 1;
+                return;
+            }
+        };
+        *buckets.entry(bucket(value.abs())).or_default() += 1;
+        collapse(buckets);
+    }
+
+    pub fn merge(&mut self, other: &Sketch) {
+        for (buckets, others) in [
+            (&mut self.positive, &other.positive),
+            (&mut self.negative, &other.negative),
+        ] {
+            for (index, n) in others {
+                *buckets.entry(*index).or_default() += n;
+            }
+            collapse(buckets);
+        }
+        self.zero += other.zero;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.positive.values().sum::<u64>() + self.negative.values().sum::<u64>() + self.zero
+    }
+
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = (q.clamp(0.0, 1.0) * (count - 1) as f64).floor() as u64;
+        let mut seen = 0;
+        for (index, n) in self.negative.iter().rev() {
+            seen += n;
+            if seen > rank {
+                return Some(-bucket_value(*index));
+            }
+        }
+        seen += self.zero;
+        if seen > rank {
+            return Some(0.0);
+        }
+        for (index, n) in self.positive.iter() {
+            seen += n;
+            if seen > rank {
+                return Some(bucket_value(*index));
+            }
+        }
+        None
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Stats {
+    field: String,
+    tags: BTreeMap<String, String>,
+    count: u64,
+    sum: f64,
+    min: f64,
+    max: f64,
+    first: i64,
+    last: i64,
+    sketch: Sketch,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Summary {
+    pub field: String,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, String>,
+    pub count: u64,
+    pub sum: f64,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub first: String,
+    pub last: String,
+    pub quantiles: BTreeMap<String, f64>, // p50, p75, p90, p95, p99
+}
+
+impl Stats {
+    fn new(m: &Measurement) -> Self {
+        Stats {
+            field: m.name.clone(),
+            tags: crate::query::tag_key(&m.tags),
+            count: 0,
+            sum: 0.0,
+            min: m.value,
+            max: m.value,
+            first: m.key,
+            last: m.key,
+            sketch: Sketch::default(),
+        }
+    }
+
+    fn add(&mut self, m: &Measurement) {
+        self.count += 1;
+        self.sum += m.value;
+        self.min = self.min.min(m.value);
+        self.max = self.max.max(m.value);
+        self.first = self.first.min(m.key);
+        self.last = self.last.max(m.key);
+        self.sketch.add(m.value);
+    }
+
+    fn merge(&mut self, other: &Stats) {
+        self.count += other.count;
+        self.sum += other.sum;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.first = self.first.min(other.first);
+        self.last = self.last.max(other.last);
+        self.sketch.merge(&other.sketch);
+    }
+
+    fn summary(&self) -> Summary {
+        let quantiles = QUANTILES
+            .iter()
+            .filter_map(|q| {
+                // sketch values are clamped to the exact bounds
+                let value = self.sketch.quantile(*q)?.clamp(self.min, self.max);
+                Some((format!("p{}", (q * 100.0).round()), value))
+            })
+            .collect();
+        Summary {
+            field: self.field.clone(),
+            tags: self.tags.clone(),
+            count: self.count,
+            sum: self.sum,
+            min: self.min,
+            max: self.max,
+            mean: self.sum / self.count as f64,
+            first: crate::query::timestamp_literal(self.first),
+            last: crate::query::timestamp_literal(self.last),
+            quantiles,
+        }
+    }
+}
+
+// Statistics of the series of a timeseries, per shard: the shards dropped take theirs along and
+// those losing points have theirs rebuilt from their own points only
+#[derive(Debug, Default)]
+pub struct TimeseriesStats {
+    shards: BTreeMap<(i64, String), Stats>, // (shard start, series key) -> statistics
+    totals: BTreeMap<String, Stats>,        // series key -> statistics of all its shards
+    dirty: BTreeSet<(i64, String)>,         // changed since they were saved
+}
+
+// Big endian with the sign flipped, the keys of a shard together and sorted as their starts
+fn key(shard: i64, series: &str) -> Vec<u8> {
+    let mut key = ((shard as u64) ^ (1 << 63)).to_be_bytes().to_vec();
+    key.extend_from_slice(series.as_bytes());
+    key
+}
+
+fn decode_key(key: &[u8]) -> Result<(i64, String), String> {
+    if key.len() < 8 {
+        return Err(format!("Invalid stats key of {} bytes", key.len()));
+    }
+    let mut start = [0u8; 8];
+    start.copy_from_slice(&key[..8]);
+    let shard = (u64::from_be_bytes(start) ^ (1 << 63)) as i64;
+    Ok((shard, String::from_utf8_lossy(&key[8..]).to_string()))
+}
+
+impl TimeseriesStats {
+    // Adds the point to the statistics of its series in its shard, saved by persist()
+    pub fn add(&mut self, shard: i64, m: &Measurement) {
+        let key = (shard, series_key(m));
+        self.shards
+            .entry(key.clone())
+            .or_insert_with(|| Stats::new(m))
+            .add(m);
+        self.totals
+            .entry(key.1.clone())
+            .or_insert_with(|| Stats::new(m))
+            .add(m);
+        self.dirty.insert(key);
+    }
+
+    // Merges the totals of the series again from their shards
+    fn total(&mut self) {
+        self.totals.clear();
+        for ((_, series), s) in self.shards.iter() {
+            match self.totals.get_mut(series) {
+                Some(total) => total.merge(s),
+                None => {
+                    self.totals.insert(series.clone(), s.clone());
+                }
+            }
+        }
+    }
+
+    // Saves the statistics changed since the last call, once per batch of points
+    pub fn persist(&mut self, timeseries: &str, storage: &SledStorage) -> Result<(), String> {
+        let tree = match storage.tree.open_tree(TREE) {
+            Ok(t) => t,
+            Err(e) => return Err(format!("Error opening stats of {}: {}", timeseries, e)),
+        };
+        for (shard, series) in std::mem::take(&mut self.dirty) {
+            let stats = match self.shards.get(&(shard, series.clone())) {
+                Some(s) => s,
+                None => continue,
+            };
+            let encoded = match bincode::serialize(stats) {
+                Ok(e) => e,
+                Err(e) => return Err(format!("Error encoding stats: {}", e)),
+            };
+            if let Err(e) = tree.insert(key(shard, &series), encoded) {
+                return Err(format!("Error saving stats of {}: {}", timeseries, e));
+            }
+        }
+        Ok(())
+    }
+
+    // Drops the statistics of the shards starting from start (included) to end (excluded)
+    pub fn forget(
+        &mut self,
+        timeseries: &str,
+        storage: &SledStorage,
+        start: Option<i64>,
+        end: Option<i64>,
+    ) -> Result<(), String> {
+        let within = |shard: i64| start.is_none_or(|s| shard >= s) && end.is_none_or(|e| shard < e);
+        self.shards.retain(|(shard, _), _| !within(*shard));
+        self.dirty.retain(|(shard, _)| !within(*shard));
+        self.total();
+        let tree = match storage.tree.open_tree(TREE) {
+            Ok(t) => t,
+            Err(e) => return Err(format!("Error opening stats of {}: {}", timeseries, e)),
+        };
+        let from = key(start.unwrap_or(i64::MIN), "");
+        for entry in tree.range(from..) {
+            let k = match entry {
+                Ok((k, _)) => k,
+                Err(e) => return Err(format!("Error reading stats of {}: {}", timeseries, e)),
+            };
+            if !within(decode_key(&k)?.0) {
+                break;
+            }
+            if let Err(e) = tree.remove(k) {
+                return Err(format!("Error clearing stats of {}: {}", timeseries, e));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SeriesStats {
+    // timeseries -> statistics, locked by the writes of the timeseries and the rebuilds
+    stats: Mutex<HashMap<String, Arc<Mutex<TimeseriesStats>>>>,
+}
+
+impl SeriesStats {
+    pub fn load(&self, timeseries: &str, storage: &SledStorage) -> Result<(), String> {
+        let tree = match storage.tree.open_tree(TREE) {
+            Ok(t) => t,
+            Err(e) => return Err(format!("Error opening stats of {}: {}", timeseries, e)),
+        };
+        let mut loaded = TimeseriesStats::default();
+        for entry in tree.iter() {
+            let (key, value) = match entry {
+                Ok(e) => e,
+                Err(e) => return Err(format!("Error reading stats of {}: {}", timeseries, e)),
+            };
+            match bincode::deserialize::<Stats>(&value) {
+                Ok(s) => loaded.shards.insert(decode_key(&key)?, s),
+                Err(e) => return Err(format!("Error decoding stats of {}: {}", timeseries, e)),
+            };
+        }
+        loaded.total();
+        self.stats
+            .lock()
+            .unwrap()
+            .insert(timeseries.to_string(), Arc::new(Mutex::new(loaded)));
+        Ok(())
+    }
+
+    // The statistics of a timeseries, held while its points are written or read again
+    pub fn timeseries(&self, timeseries: &str) -> Arc<Mutex<TimeseriesStats>> {
+        self.stats
+            .lock()
+            .unwrap()
+            .entry(timeseries.to_string())
+            .or_default()
+            .clone()
+    }
+
+    pub fn is_empty(&self, timeseries: &str) -> bool {
+        self.timeseries(timeseries)
+            .lock()
+            .unwrap()
+            .shards
+            .is_empty()
+    }
+
+    // The statistics of each series, from their running totals
+    pub fn get(&self, timeseries: &str) -> Vec<Summary> {
+        let stats = self.timeseries(timeseries);
+        let stats = stats.lock().unwrap();
+        stats.totals.values().map(|s| s.summary()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn series_statistics() {
+        let dir = std::env::temp_dir().join(format!("refluxdb-stats-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let storage = SledStorage::new(dir.to_str().unwrap()).unwrap();
+        let stats = SeriesStats::default();
+        let http = stats.timeseries("http");
+        for i in 1..=1000 {
+            let m = Measurement {
+                key: i,
+                id: uuid::Uuid::new_v4(),
+                name: "latency".to_string(),
+                value: i as f64,
+                tags: HashMap::new(),
+            };
+            // shards of 500ms, merged in the summary
+            http.lock().unwrap().add(i / 500 * 500, &m);
+        }
+        http.lock().unwrap().persist("http", &storage).unwrap();
+        let summary = &stats.get("http")[0];
+        assert_eq!(
+            (summary.count, summary.min, summary.max),
+            (1000, 1.0, 1000.0)
+        );
+        assert_eq!(summary.mean, 500.5);
+        assert_eq!(summary.first, "1970-01-01T00:00:00.001Z");
+        for (q, exact) in [("p50", 500.0), ("p90", 900.0), ("p99", 990.0)] {
+            let estimate = summary.quantiles[q];
+            assert!(
+                (estimate - exact).abs() <= exact * ACCURACY,
+                "{} {}",
+                q,
+                estimate
+            );
+        }
+
+        // the statistics are saved with the timeseries
+        let reloaded = SeriesStats::default();
+        reloaded.load("http", &storage).unwrap();
+        assert_eq!(reloaded.get("http"), stats.get("http"));
+        // a shard forgotten takes its points out
+        let reloaded = reloaded.timeseries("http");
+        let mut reloaded = reloaded.lock().unwrap();
+        reloaded.forget("http", &storage, None, Some(500)).unwrap();
+        assert_eq!(reloaded.shards.len(), 2);
+        assert_eq!(reloaded.totals["latency"].count, 501);
+        let forgotten = SeriesStats::default();
+        forgotten.load("http", &storage).unwrap();
+        assert_eq!(forgotten.get("http")[0].count, 501);
+        let mut sketch = Sketch::default();
+        for v in [-2.0, 0.0, 3.0] {
+            sketch.add(v);
+        }
+        assert_eq!(sketch.quantile(0.0).map(|v| v.round()), Some(-2.0));
+        assert_eq!(sketch.quantile(0.5), Some(0.0));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
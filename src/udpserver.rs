@@ -1,15 +1,14 @@
 use actix_rt::net::UdpSocket;
 use log::{debug, info};
-use std::collections::HashMap;
 use std::io;
 use std::net::SocketAddr;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
 pub struct UDPRefluxServer {
     pub socket: UdpSocket,
     buf: Vec<u8>,
     to_send: Option<(usize, SocketAddr)>,
-    pm: Arc<Mutex<crate::persistence::TimeseriesDiskPersistenceManager>>,
+    ingest: Arc<crate::ingest::Ingest>,
 }
 
 impl UDPRefluxServer {
@@ -25,28 +24,16 @@ impl UDPRefluxServer {
                     String::from_utf8_lossy(&self.buf[..size - 1]).to_string(),
                 ) {
                     Ok(b) => {
-                        let mut htags: HashMap<String, String> = HashMap::new();
-                        for key in b.tag_set.clone().keys() {
-                            htags.insert(key.into(), b.tag_set.get(key).unwrap().into());
-                        }
-                        // One line for each measurement, represented b field_set
-                        for field in b.field_set.clone() {
-                            match self.pm.lock().unwrap().save_measurement(
+                        // queued, the writer saves it with the next batch
+                        match self.ingest.write(&b, false).await {
+                            Ok(outcome) => info!(
+                                "Timeseries {} fields {:?}: {:?}",
                                 b.measurement_name.clone(),
-                                field.0.clone(),
-                                field.1.clone(),
-                                htags.clone(),
-                                true, // create the database if it doesn't exists
-                            ) {
-                                Ok(_) => info!(
-                                    "Timeseries {} Measurement {} value {}",
-                                    b.measurement_name.clone(),
-                                    field.0.clone(),
-                                    field.1.clone()
-                                ),
-                                Err(e) => info!("Error writing measurement: {}", e),
-                            };
-                        }
+                                b.field_set.clone(),
+                                outcome
+                            ),
+                            Err(e) => info!("Error writing measurement: {}", e),
+                        };
                         //echo back the line
                         let bs = b.serialize().clone();
                         if echo {
@@ -89,10 +76,7 @@ impl UDPRefluxServer {
             self.to_send = Some(self.socket.recv_from(&mut self.buf).await?);
         }
     }
-    pub async fn new(
-        addr: String,
-        pm: Arc<Mutex<crate::persistence::TimeseriesDiskPersistenceManager>>,
-    ) -> Self {
+    pub async fn new(addr: String, ingest: Arc<crate::ingest::Ingest>) -> Self {
         let socket = UdpSocket::bind(&addr).await.unwrap();
         info!("Listening on UDP: {}", socket.local_addr().unwrap());
 
@@ -100,7 +84,7 @@ impl UDPRefluxServer {
             socket,
             buf: vec![0; 1024],
             to_send: None,
-            pm: pm,
+            ingest,
         };
         return s;
     }
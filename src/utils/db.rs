@@ -8,13 +8,19 @@ use uuid::Uuid;
 pub fn parse_select_payload(
     payload: Payload,
 ) -> Result<Vec<crate::persistence::Measurement>, String> {
+    let ev = parse_select_rows(payload)?;
+    if ev.is_empty() {
+        return Err("No data found for query".to_string());
+    };
+    Ok(ev)
+}
+
+// Same as parse_select_payload, an empty resultset is not an error
+pub fn parse_select_rows(payload: Payload) -> Result<Vec<crate::persistence::Measurement>, String> {
     let rows = match payload {
         Payload::Select { labels: _, rows } => rows,
         _ => return Err(format!("Unexpected result: {:?}", payload)),
     };
-    if rows.len() == 0 {
-        return Err(format!("No data found for query"));
-    };
     let mut ev: Vec<crate::persistence::Measurement> = Vec::new();
     for row in rows {
         match parse_select_resultset_row(&row) {
@@ -27,6 +33,41 @@ pub fn parse_select_payload(
     Ok(ev)
 }
 
+// Column labels and rows of any SELECT, for results that aren't measurements (joins, unions)
+pub type LabeledRows = (Vec<String>, Vec<Vec<serde_json::Value>>);
+
+pub fn parse_select_labels(payload: Payload) -> Result<LabeledRows, String> {
+    match payload {
+        Payload::Select { labels, rows } => Ok((
+            labels,
+            rows.iter()
+                .map(|row| row.iter().map(json_value).collect())
+                .collect(),
+        )),
+        _ => Err(format!("Unexpected result: {:?}", payload)),
+    }
+}
+
+// Timestamps are reported as unix milliseconds, like the time column of the planned queries
+pub fn json_value(value: &gluesql::data::Value) -> serde_json::Value {
+    match value {
+        Value::Bool(b) => serde_json::json!(b),
+        Value::I64(i) => serde_json::json!(i),
+        Value::F64(f) => serde_json::json!(f),
+        Value::Str(s) => serde_json::json!(s),
+        Value::Timestamp(t) => serde_json::json!(t.and_utc().timestamp_millis()),
+        Value::Date(d) => serde_json::json!(d.to_string()),
+        Value::Time(t) => serde_json::json!(t.to_string()),
+        Value::Interval(i) => serde_json::json!(format!("{:?}", i)),
+        Value::Uuid(u) => serde_json::json!(Uuid::from_u128(*u).to_string()),
+        Value::Map(m) => {
+            serde_json::Value::Object(m.iter().map(|(k, v)| (k.clone(), json_value(v))).collect())
+        }
+        Value::List(l) => serde_json::Value::Array(l.iter().map(json_value).collect()),
+        Value::Null => serde_json::Value::Null,
+    }
+}
+
 pub fn parse_select_resultset_row(
     row: &std::vec::Vec<gluesql::data::Value>,
 ) -> Result<crate::persistence::Measurement, String> {
@@ -39,19 +80,31 @@ pub fn parse_select_resultset_row(
         Value::Timestamp(key) => key,
         val => return Err(format!("Unexpected timestamp value: {:?}", val)),
     };
+    let name = match &row[3] {
+        Value::Str(name) => name,
+        val => return Err(format!("Unexpected name value: {:?}", val)),
+    };
     let value = match &row[4] {
         Value::F64(value) => value,
         val => return Err(format!("Unexpected value: {:?}", val)),
     };
-    let _ = match &row[5] {
+    let tags = match &row[5] {
         Value::Map(tags) => tags,
         val => return Err(format!("Unexpected tag value: {:?}", val)),
     };
+    let mut tt: HashMap<String, String> = HashMap::new();
+    for (k, v) in tags.iter() {
+        match v {
+            Value::Str(s) => tt.insert(k.clone(), s.clone()),
+            val => tt.insert(k.clone(), format!("{:?}", val)),
+        };
+    }
     Ok(crate::persistence::Measurement {
-        key: key.timestamp_millis().clone(),
+        key: key.and_utc().timestamp_millis(),
         id: Uuid::from_u128(id.clone()),
+        name: name.clone(),
         value: value.clone(),
-        tags: HashMap::new(), //tt,
+        tags: tt,
     })
 }
 /*
@@ -107,26 +160,274 @@ pub fn check_or_create_database(
     };
 }
 
-pub fn query_statement_tablename(query: String) -> Result<String, String> {
-    match gluesql::parse_sql::parse(&query) {
-        Ok(t) => match &t[0] {
-            gluesql::sqlparser::ast::Statement::Query(tt) => match &tt.body {
-                gluesql::sqlparser::ast::SetExpr::Select(ss) => match &ss.from[0].relation {
-                    gluesql::sqlparser::ast::TableFactor::Table {
-                        name,
-                        alias: _,
-                        args: _,
-                        with_hints: _,
-                    } => {
-                        let tablename = &name.0[0].value;
-                        Ok(tablename.clone())
-                    }
-                    _ => return Err(format!("No table found")),
-                },
-                _ => return Err(format!("Invalid SELECT statement: {}", tt.body)),
-            },
-            _ => return Err(format!("Unknown query: {}", t[0])),
-        },
+/*
+Validates a user query on the parsed AST: a single SELECT (set operations and subqueries
+included), nothing that writes. Returns every table the query reads, subqueries included,
+so they can be checked against the known timeseries.
+ */
+pub fn query_statement_tablenames(query: &str) -> Result<Vec<String>, String> {
+    use gluesql::sqlparser::ast::Statement;
+    let statements = match gluesql::parse_sql::parse(query) {
+        Ok(s) => s,
         Err(e) => return Err(format!("Improper query: {}", e)),
+    };
+    if statements.len() != 1 {
+        return Err("Only one statement is allowed".to_string());
+    }
+    let mut tables: Vec<String> = Vec::new();
+    match &statements[0] {
+        Statement::Query(q) => query_tables(q, &mut tables)?,
+        s => return Err(format!("Only SELECT queries are allowed: {}", s)),
+    }
+    tables.sort();
+    tables.dedup();
+    if tables.is_empty() {
+        return Err("No table found".to_string());
+    }
+    Ok(tables)
+}
+
+fn query_tables(
+    query: &gluesql::sqlparser::ast::Query,
+    tables: &mut Vec<String>,
+) -> Result<(), String> {
+    if query.with.is_some() {
+        return Err("WITH is not supported".to_string());
+    }
+    set_expr_tables(&query.body, tables)?;
+    for o in query.order_by.iter() {
+        expr_tables(&o.expr, tables)?;
+    }
+    if let Some(l) = &query.limit {
+        expr_tables(l, tables)?;
+    }
+    if let Some(o) = &query.offset {
+        expr_tables(&o.value, tables)?;
+    }
+    Ok(())
+}
+
+fn set_expr_tables(
+    body: &gluesql::sqlparser::ast::SetExpr,
+    tables: &mut Vec<String>,
+) -> Result<(), String> {
+    use gluesql::sqlparser::ast::{SelectItem, SetExpr};
+    match body {
+        SetExpr::Select(select) => {
+            for from in select.from.iter() {
+                table_factor_tables(&from.relation, tables)?;
+                for join in from.joins.iter() {
+                    table_factor_tables(&join.relation, tables)?;
+                    join_tables(&join.join_operator, tables)?;
+                }
+            }
+            for item in select.projection.iter() {
+                match item {
+                    SelectItem::UnnamedExpr(e) | SelectItem::ExprWithAlias { expr: e, .. } => {
+                        expr_tables(e, tables)?
+                    }
+                    SelectItem::QualifiedWildcard(_) | SelectItem::Wildcard => (),
+                }
+            }
+            for e in select
+                .selection
+                .iter()
+                .chain(select.group_by.iter())
+                .chain(select.having.iter())
+            {
+                expr_tables(e, tables)?;
+            }
+            Ok(())
+        }
+        SetExpr::Query(q) => query_tables(q, tables),
+        SetExpr::SetOperation { left, right, .. } => {
+            set_expr_tables(left, tables)?;
+            set_expr_tables(right, tables)
+        }
+        SetExpr::Values(values) => {
+            for e in values.0.iter().flatten() {
+                expr_tables(e, tables)?;
+            }
+            Ok(())
+        }
+        SetExpr::Insert(s) => Err(format!("Only SELECT queries are allowed: {}", s)),
+    }
+}
+
+fn table_factor_tables(
+    relation: &gluesql::sqlparser::ast::TableFactor,
+    tables: &mut Vec<String>,
+) -> Result<(), String> {
+    use gluesql::sqlparser::ast::TableFactor;
+    match relation {
+        TableFactor::Table { name, args, .. } => {
+            if !args.is_empty() {
+                return Err(format!("Table functions are not supported: {}", name));
+            }
+            match name.0.last() {
+                Some(ident) => tables.push(ident.value.clone()),
+                None => return Err("No table found".to_string()),
+            }
+            Ok(())
+        }
+        TableFactor::Derived { subquery, .. } => query_tables(subquery, tables),
+        TableFactor::NestedJoin(t) => {
+            table_factor_tables(&t.relation, tables)?;
+            for join in t.joins.iter() {
+                table_factor_tables(&join.relation, tables)?;
+                join_tables(&join.join_operator, tables)?;
+            }
+            Ok(())
+        }
+        TableFactor::TableFunction { .. } => Err("Table functions are not supported".to_string()),
+    }
+}
+
+fn join_tables(
+    operator: &gluesql::sqlparser::ast::JoinOperator,
+    tables: &mut Vec<String>,
+) -> Result<(), String> {
+    use gluesql::sqlparser::ast::{JoinConstraint, JoinOperator};
+    match operator {
+        JoinOperator::Inner(JoinConstraint::On(e))
+        | JoinOperator::LeftOuter(JoinConstraint::On(e))
+        | JoinOperator::RightOuter(JoinConstraint::On(e))
+        | JoinOperator::FullOuter(JoinConstraint::On(e)) => expr_tables(e, tables),
+        _ => Ok(()),
+    }
+}
+
+// Subqueries can hide anywhere in an expression
+fn expr_tables(
+    expr: &gluesql::sqlparser::ast::Expr,
+    tables: &mut Vec<String>,
+) -> Result<(), String> {
+    use gluesql::sqlparser::ast::{Expr, FunctionArg};
+    match expr {
+        Expr::Identifier(_)
+        | Expr::Wildcard
+        | Expr::QualifiedWildcard(_)
+        | Expr::CompoundIdentifier(_)
+        | Expr::Value(_)
+        | Expr::TypedString { .. } => Ok(()),
+        Expr::IsNull(e)
+        | Expr::IsNotNull(e)
+        | Expr::UnaryOp { expr: e, .. }
+        | Expr::Cast { expr: e, .. }
+        | Expr::TryCast { expr: e, .. }
+        | Expr::Extract { expr: e, .. }
+        | Expr::Collate { expr: e, .. }
+        | Expr::Nested(e)
+        | Expr::MapAccess { column: e, .. } => expr_tables(e, tables),
+        Expr::InList { expr, list, .. } => {
+            expr_tables(expr, tables)?;
+            for e in list.iter() {
+                expr_tables(e, tables)?;
+            }
+            Ok(())
+        }
+        Expr::InSubquery { expr, subquery, .. } => {
+            expr_tables(expr, tables)?;
+            query_tables(subquery, tables)
+        }
+        Expr::Between {
+            expr, low, high, ..
+        } => {
+            expr_tables(expr, tables)?;
+            expr_tables(low, tables)?;
+            expr_tables(high, tables)
+        }
+        Expr::BinaryOp { left, right, .. } => {
+            expr_tables(left, tables)?;
+            expr_tables(right, tables)
+        }
+        Expr::Substring {
+            expr,
+            substring_from,
+            substring_for,
+        } => {
+            expr_tables(expr, tables)?;
+            for e in substring_from.iter().chain(substring_for.iter()) {
+                expr_tables(e, tables)?;
+            }
+            Ok(())
+        }
+        Expr::Trim { expr, trim_where } => {
+            expr_tables(expr, tables)?;
+            match trim_where {
+                Some((_, e)) => expr_tables(e, tables),
+                None => Ok(()),
+            }
+        }
+        Expr::Function(f) => {
+            for arg in f.args.iter() {
+                match arg {
+                    FunctionArg::Named { arg, .. } | FunctionArg::Unnamed(arg) => {
+                        expr_tables(arg, tables)?
+                    }
+                }
+            }
+            Ok(())
+        }
+        Expr::Case {
+            operand,
+            conditions,
+            results,
+            else_result,
+        } => {
+            for e in operand.iter().chain(else_result.iter()) {
+                expr_tables(e, tables)?;
+            }
+            for e in conditions.iter().chain(results.iter()) {
+                expr_tables(e, tables)?;
+            }
+            Ok(())
+        }
+        Expr::Exists(q) | Expr::Subquery(q) => query_tables(q, tables),
+        Expr::ListAgg(l) => {
+            expr_tables(&l.expr, tables)?;
+            match &l.separator {
+                Some(e) => expr_tables(e, tables),
+                None => Ok(()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_statements_on_the_ast() {
+        assert_eq!(
+            query_statement_tablenames("SELECT * FROM cpu WHERE tags = 'update'"),
+            Ok(vec!["cpu".to_string()])
+        );
+        assert_eq!(
+            query_statement_tablenames(
+                "SELECT * FROM cpu WHERE value > (SELECT AVG(value) FROM mem) UNION SELECT * FROM disk"
+            ),
+            Ok(vec![
+                "cpu".to_string(),
+                "disk".to_string(),
+                "mem".to_string()
+            ])
+        );
+        assert_eq!(
+            query_statement_tablenames(
+                "SELECT * FROM (SELECT * FROM cpu) AS c JOIN net ON c.id IN (SELECT id FROM swap)"
+            ),
+            Ok(vec![
+                "cpu".to_string(),
+                "net".to_string(),
+                "swap".to_string()
+            ])
+        );
+        assert!(query_statement_tablenames("INSERT INTO cpu VALUES (1)").is_err());
+        assert!(query_statement_tablenames("DELETE FROM cpu").is_err());
+        assert!(query_statement_tablenames("DROP TABLE cpu").is_err());
+        assert!(query_statement_tablenames("SELECT * FROM cpu; DROP TABLE cpu").is_err());
+        assert!(query_statement_tablenames("SELECT 1").is_err());
     }
 }
@@ -1,3 +1,7 @@
 pub mod db;
+pub mod readonly;
 
+#[allow(unused_imports)]
 pub use db::*;
+#[allow(unused_imports)]
+pub use readonly::*;
@@ -0,0 +1,286 @@
+// MutResult is GlueSQL's storage interface
+#![allow(clippy::result_large_err)]
+
+use crate::engine;
+use crate::head::TimeseriesHead;
+use crate::limits::Budget;
+use async_trait::async_trait;
+use gluesql::ast::{ColumnDef, Expr, IndexOperator, OrderByExpr};
+use gluesql::data::{Row, Schema, SchemaIndex, SchemaIndexOrd, Value};
+use gluesql::result::{Error, MutResult, Result};
+use gluesql::sled::IVec;
+use gluesql::storages::SledStorage;
+use gluesql::store::{
+    AlterTable, GStore, GStoreMut, Index, IndexMut, RowIter, Store, StoreMut, Transaction,
+};
+use std::sync::Arc;
+
+/*
+Storage handle for user queries: reads and transactions go to the sled storage,
+every write, schema or index change fails, whatever statement got through validation.
+With a budget, every row read is charged to the running query (see limits.rs).
+On a timeseries storage, the rows are the points of the native engine (see engine.rs) and the
+table has a time index, the conditions on the time GlueSQL plans with it are ranges of keys.
+ */
+#[derive(Debug, Clone)]
+pub struct ReadOnlyStorage {
+    storage: SledStorage,
+    budget: Option<Arc<Budget>>,
+    native: bool,
+    head: Option<Arc<TimeseriesHead>>,
+}
+
+impl ReadOnlyStorage {
+    pub fn new(storage: SledStorage) -> Self {
+        ReadOnlyStorage {
+            storage,
+            budget: None,
+            native: false,
+            head: None,
+        }
+    }
+
+    pub fn with_budget(mut self, budget: Option<Arc<Budget>>) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    // Reads the points of the native engine instead of the table rows, with those of the head
+    pub fn native(mut self, head: Option<Arc<TimeseriesHead>>) -> Self {
+        self.native = true;
+        self.head = head;
+        self
+    }
+
+    fn points(
+        &self,
+        descending: bool,
+        condition: Option<(IndexOperator, Value)>,
+    ) -> Result<RowIter<IVec>> {
+        let rows = engine::scan(&self.storage, self.head.clone(), descending, condition)
+            .map_err(Error::StorageMsg)?
+            .with_budget(self.budget.clone());
+        self.charge(Box::new(rows.map(|row| row.map_err(Error::StorageMsg))))
+    }
+
+    fn charge(&self, rows: RowIter<IVec>) -> Result<RowIter<IVec>> {
+        let budget = match &self.budget {
+            Some(b) => b.clone(),
+            None => return Ok(rows),
+        };
+        budget.check().map_err(Error::StorageMsg)?;
+        Ok(Box::new(rows.map(move |row| {
+            let (key, row) = row?;
+            budget.scan(row_size(&row)).map_err(Error::StorageMsg)?;
+            Ok((key, row))
+        })))
+    }
+}
+
+// Approximate bytes held by a row once read
+fn value_size(value: &Value) -> usize {
+    std::mem::size_of::<Value>()
+        + match value {
+            Value::Str(s) => s.len(),
+            Value::Map(m) => m.iter().map(|(k, v)| k.len() + value_size(v)).sum(),
+            Value::List(l) => l.iter().map(value_size).sum(),
+            _ => 0,
+        }
+}
+
+fn row_size(row: &Row) -> usize {
+    row.0.iter().map(value_size).sum()
+}
+
+fn read_only<T>(storage: ReadOnlyStorage) -> MutResult<ReadOnlyStorage, T> {
+    Err((storage, Error::StorageMsg("read-only storage".to_string())))
+}
+
+// moves the inner storage through a transaction call
+fn wrap<T>(res: MutResult<SledStorage, T>, from: ReadOnlyStorage) -> MutResult<ReadOnlyStorage, T> {
+    match res {
+        Ok((storage, v)) => Ok((ReadOnlyStorage { storage, ..from }, v)),
+        Err((storage, e)) => Err((ReadOnlyStorage { storage, ..from }, e)),
+    }
+}
+
+#[async_trait(?Send)]
+impl Store<IVec> for ReadOnlyStorage {
+    async fn fetch_schema(&self, table_name: &str) -> Result<Option<Schema>> {
+        let mut schema = self.storage.fetch_schema(table_name).await?;
+        if let (true, Some(s)) = (self.native, schema.as_mut()) {
+            s.indexes.push(SchemaIndex {
+                name: engine::TIME_INDEX.to_string(),
+                expr: Expr::Identifier("time".to_string()),
+                order: SchemaIndexOrd::Both,
+            });
+        }
+        Ok(schema)
+    }
+
+    async fn scan_data(&self, table_name: &str) -> Result<RowIter<IVec>> {
+        if self.native {
+            return self.points(false, None);
+        }
+        self.charge(self.storage.scan_data(table_name).await?)
+    }
+}
+
+#[async_trait(?Send)]
+impl Index<IVec> for ReadOnlyStorage {
+    async fn scan_indexed_data(
+        &self,
+        table_name: &str,
+        index_name: &str,
+        asc: Option<bool>,
+        cmp_value: Option<(&IndexOperator, Value)>,
+    ) -> Result<RowIter<IVec>> {
+        if self.native {
+            let condition = cmp_value.map(|(op, value)| (op.clone(), value));
+            return self.points(asc == Some(false), condition);
+        }
+        let rows = self
+            .storage
+            .scan_indexed_data(table_name, index_name, asc, cmp_value)
+            .await?;
+        self.charge(rows)
+    }
+}
+
+#[async_trait(?Send)]
+impl Transaction for ReadOnlyStorage {
+    async fn begin(self, autocommit: bool) -> MutResult<Self, bool> {
+        let storage = self.storage.clone();
+        wrap(storage.begin(autocommit).await, self)
+    }
+
+    async fn rollback(self) -> MutResult<Self, ()> {
+        let storage = self.storage.clone();
+        wrap(storage.rollback().await, self)
+    }
+
+    async fn commit(self) -> MutResult<Self, ()> {
+        let storage = self.storage.clone();
+        wrap(storage.commit().await, self)
+    }
+}
+
+#[async_trait(?Send)]
+impl StoreMut<IVec> for ReadOnlyStorage {
+    async fn insert_schema(self, _schema: &Schema) -> MutResult<Self, ()> {
+        read_only(self)
+    }
+
+    async fn delete_schema(self, _table_name: &str) -> MutResult<Self, ()> {
+        read_only(self)
+    }
+
+    async fn insert_data(self, _table_name: &str, _rows: Vec<Row>) -> MutResult<Self, ()> {
+        read_only(self)
+    }
+
+    async fn update_data(self, _table_name: &str, _rows: Vec<(IVec, Row)>) -> MutResult<Self, ()> {
+        read_only(self)
+    }
+
+    async fn delete_data(self, _table_name: &str, _keys: Vec<IVec>) -> MutResult<Self, ()> {
+        read_only(self)
+    }
+}
+
+#[async_trait(?Send)]
+impl IndexMut<IVec> for ReadOnlyStorage {
+    async fn create_index(
+        self,
+        _table_name: &str,
+        _index_name: &str,
+        _column: &OrderByExpr,
+    ) -> MutResult<Self, ()> {
+        read_only(self)
+    }
+
+    async fn drop_index(self, _table_name: &str, _index_name: &str) -> MutResult<Self, ()> {
+        read_only(self)
+    }
+}
+
+#[async_trait(?Send)]
+impl AlterTable for ReadOnlyStorage {
+    async fn rename_schema(self, _table_name: &str, _new_table_name: &str) -> MutResult<Self, ()> {
+        read_only(self)
+    }
+
+    async fn rename_column(
+        self,
+        _table_name: &str,
+        _old_column_name: &str,
+        _new_column_name: &str,
+    ) -> MutResult<Self, ()> {
+        read_only(self)
+    }
+
+    async fn add_column(self, _table_name: &str, _column_def: &ColumnDef) -> MutResult<Self, ()> {
+        read_only(self)
+    }
+
+    async fn drop_column(
+        self,
+        _table_name: &str,
+        _column_name: &str,
+        _if_exists: bool,
+    ) -> MutResult<Self, ()> {
+        read_only(self)
+    }
+}
+
+impl GStore<IVec> for ReadOnlyStorage {}
+impl GStoreMut<IVec> for ReadOnlyStorage {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gluesql::prelude::Glue;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn rejects_writes() {
+        let storage =
+            SledStorage::try_from(gluesql::sled::Config::default().temporary(true)).unwrap();
+        let mut db = Glue::new(storage.clone());
+        db.execute("CREATE TABLE cpu (value FLOAT)").unwrap();
+        db.execute("INSERT INTO cpu VALUES (1.0)").unwrap();
+
+        let mut ro = Glue::new(ReadOnlyStorage::new(storage));
+        assert!(ro.execute("SELECT * FROM cpu").is_ok());
+        assert!(ro.execute("INSERT INTO cpu VALUES (2.0)").is_err());
+        assert!(ro.execute("DELETE FROM cpu").is_err());
+        assert!(ro.execute("DROP TABLE cpu").is_err());
+        assert!(ro.execute("CREATE TABLE mem (value FLOAT)").is_err());
+        match ro.execute("SELECT * FROM cpu") {
+            Ok(gluesql::prelude::Payload::Select { rows, .. }) => assert_eq!(rows.len(), 1),
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn charges_budget() {
+        let storage =
+            SledStorage::try_from(gluesql::sled::Config::default().temporary(true)).unwrap();
+        let mut db = Glue::new(storage.clone());
+        db.execute("CREATE TABLE cpu (value FLOAT)").unwrap();
+        db.execute("INSERT INTO cpu VALUES (1.0), (2.0), (3.0)")
+            .unwrap();
+
+        let limits = crate::limits::QueryLimits {
+            timeout: None,
+            max_rows_scanned: Some(2),
+            max_rows_returned: None,
+            max_memory: None,
+        };
+        let budget = Arc::new(Budget::new(1, "SELECT * FROM cpu", limits));
+        let mut ro = Glue::new(ReadOnlyStorage::new(storage).with_budget(Some(budget.clone())));
+        assert!(ro.execute("SELECT * FROM cpu LIMIT 1").is_ok());
+        assert!(ro.execute("SELECT * FROM cpu").is_err());
+        assert!(budget.status().rows_scanned >= 3);
+    }
+}
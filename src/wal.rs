@@ -0,0 +1,367 @@
+use crate::engine::Point;
+use gluesql::storages::SledStorage;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+// Write-ahead log
+// The writes are appended to the log of their timeseries before they're acknowledged, and the
+// records not applied yet are replayed into the head on restart. The log is a sequence of segment
+// files, wal/<n>.log in the timeseries' directory: when the head is flushed it starts a new
+// segment, saves the sequence number of the last record applied in the wal tree of the
+// timeseries and the older segments are removed.
+// A record is its length and CRC-32 (4 bytes each, little endian) then its sequence number and
+// content as bincode: a record torn by a crash fails its checksum, it and the rest of its
+// segment are ignored. The records are synced to disk depending on the policy:
+//   REFLUXDB_WAL_SYNC=always         (before each write is acknowledged, the default)
+//   REFLUXDB_WAL_SYNC=batch:100      (every 100 records, a crash may lose up to 99 writes)
+//   REFLUXDB_WAL_SYNC=interval:1s    (every second, a crash may lose a second of writes)
+
+const MAGIC: &[u8; 8] = b"RFLXWAL1"; // every segment starts with it
+const TREE: &str = "wal";
+const APPLIED: &str = "applied";
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum SyncPolicy {
+    #[default]
+    Always,
+    Batch(usize),
+    Interval(i64),
+}
+
+impl SyncPolicy {
+    pub fn parse(policy: &str) -> Result<Self, String> {
+        let policy = policy.trim();
+        let (name, arg) = match policy.split_once(':') {
+            Some((n, a)) => (n.trim(), Some(a.trim())),
+            None => (policy, None),
+        };
+        match (name, arg) {
+            ("always", None) => Ok(SyncPolicy::Always),
+            ("batch", Some(n)) => match n.parse::<usize>() {
+                Ok(n) if n > 0 => Ok(SyncPolicy::Batch(n)),
+                _ => Err(format!("Invalid batch size: {}", n)),
+            },
+            ("interval", Some(d)) => Ok(SyncPolicy::Interval(crate::functions::parse_duration(d)?)),
+            _ => Err(format!(
+                "Invalid sync policy: {} (always, batch:<records> or interval:<duration>)",
+                policy
+            )),
+        }
+    }
+}
+
+pub fn policy_from_env() -> Result<SyncPolicy, String> {
+    match std::env::var("REFLUXDB_WAL_SYNC") {
+        Ok(v) => SyncPolicy::parse(&v),
+        Err(_) => Ok(SyncPolicy::default()),
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Record {
+    Point(Vec<u8>, Point),    // key and value of a point
+    Delete(Option<i64>, i64), // points from start (included) to end (excluded)
+}
+
+#[derive(Debug)]
+pub struct Wal {
+    dir: PathBuf,
+    segment: u64,
+    file: File,
+    next: u64,       // sequence number of the next record
+    unsynced: usize, // records written since the last sync
+}
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0xedb8_8320,
+                _ => crc >> 1,
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0, |crc, b| {
+        CRC_TABLE[((crc ^ *b as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+fn segment_path(dir: &Path, segment: u64) -> PathBuf {
+    dir.join(format!("{}.log", segment))
+}
+
+// Segments of the log, in order
+fn segments(dir: &Path) -> Result<Vec<u64>, String> {
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(e) => return Err(format!("Error reading log {}: {}", dir.display(), e)),
+    };
+    let mut segments: Vec<u64> = entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let name = e.file_name().to_string_lossy().to_string();
+            name.strip_suffix(".log")?.parse().ok()
+        })
+        .collect();
+    segments.sort();
+    Ok(segments)
+}
+
+// Records of a segment with their sequence numbers, a segment cut in its header (by a crash as
+// it was created) has none and one without the header is refused
+fn read_segment(path: &Path) -> Result<Vec<(u64, Record)>, String> {
+    let mut bytes = Vec::new();
+    if let Err(e) = File::open(path).and_then(|mut f| f.read_to_end(&mut bytes)) {
+        return Err(format!("Error reading log {}: {}", path.display(), e));
+    }
+    if !bytes.starts_with(MAGIC) {
+        if MAGIC.starts_with(&bytes) {
+            return Ok(Vec::new());
+        }
+        return Err(format!("Log {} isn't a segment of the log", path.display()));
+    }
+    let header = 8;
+    let mut records = Vec::new();
+    let mut offset = MAGIC.len();
+    while offset < bytes.len() {
+        let mut len = [0; 4];
+        let complete = offset + header <= bytes.len() && {
+            len.copy_from_slice(&bytes[offset..offset + 4]);
+            offset + header + u32::from_le_bytes(len) as usize <= bytes.len()
+        };
+        if !complete {
+            info!(
+                "Ignored a torn record at byte {} of {}",
+                offset,
+                path.display()
+            );
+            break;
+        }
+        let end = offset + header + u32::from_le_bytes(len) as usize;
+        let payload = &bytes[offset + header..end];
+        let mut crc = [0; 4];
+        crc.copy_from_slice(&bytes[offset + 4..offset + 8]);
+        if u32::from_le_bytes(crc) != crc32(payload) {
+            info!(
+                "Ignored a torn record at byte {} of {} and the rest of the segment",
+                offset,
+                path.display()
+            );
+            break;
+        }
+        match bincode::deserialize::<(u64, Record)>(payload) {
+            Ok(r) => records.push(r),
+            Err(e) => return Err(format!("Error decoding log {}: {}", path.display(), e)),
+        }
+        offset = end;
+    }
+    Ok(records)
+}
+
+impl Wal {
+    // Opens the log in the directory, the records are written to a new segment
+    pub fn open(dir: &Path, applied: Option<u64>) -> Result<Wal, String> {
+        if let Err(e) = fs::create_dir_all(dir) {
+            return Err(format!("Error creating log {}: {}", dir.display(), e));
+        }
+        let segments = segments(dir)?;
+        let mut last = applied.unwrap_or(0);
+        for segment in segments.iter() {
+            if let Some((sequence, _)) = read_segment(&segment_path(dir, *segment))?.last() {
+                last = last.max(*sequence);
+            }
+        }
+        let segment = segments.last().map_or(0, |s| s + 1);
+        Ok(Wal {
+            dir: dir.to_path_buf(),
+            segment,
+            file: Wal::create(dir, segment)?,
+            next: last + 1,
+            unsynced: 0,
+        })
+    }
+
+    fn create(dir: &Path, segment: u64) -> Result<File, String> {
+        let path = segment_path(dir, segment);
+        let created = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut f| f.write_all(MAGIC).and_then(|_| f.sync_data()).map(|_| f));
+        match created {
+            Ok(f) => Ok(f),
+            Err(e) => Err(format!("Error creating log {}: {}", path.display(), e)),
+        }
+    }
+
+    // Records of the segments written before this one was opened, to replay
+    pub fn records(&self) -> Result<Vec<(u64, Record)>, String> {
+        let mut records = Vec::new();
+        for segment in segments(&self.dir)?
+            .into_iter()
+            .filter(|s| *s < self.segment)
+        {
+            records.extend(read_segment(&segment_path(&self.dir, segment))?);
+        }
+        Ok(records)
+    }
+
+    // Appends the record and syncs the log as the policy asks, returns its sequence number
+    pub fn append(&mut self, record: &Record, policy: &SyncPolicy) -> Result<u64, String> {
+        let sequence = self.next;
+        let payload = match bincode::serialize(&(sequence, record)) {
+            Ok(e) => e,
+            Err(e) => return Err(format!("Error encoding log record: {}", e)),
+        };
+        let mut bytes = (payload.len() as u32).to_le_bytes().to_vec();
+        bytes.extend(crc32(&payload).to_le_bytes());
+        bytes.extend(payload);
+        if let Err(e) = self.file.write_all(&bytes) {
+            return Err(format!("Error writing log {}: {}", self.dir.display(), e));
+        }
+        self.next += 1;
+        self.unsynced += 1;
+        match policy {
+            SyncPolicy::Always => self.sync()?,
+            SyncPolicy::Batch(n) if self.unsynced >= *n => self.sync()?,
+            _ => {}
+        }
+        Ok(sequence)
+    }
+
+    pub fn sync(&mut self) -> Result<(), String> {
+        if self.unsynced == 0 {
+            return Ok(());
+        }
+        match self.file.sync_data() {
+            Ok(_) => {
+                self.unsynced = 0;
+                Ok(())
+            }
+            Err(e) => Err(format!("Error syncing log {}: {}", self.dir.display(), e)),
+        }
+    }
+
+    // Sequence number of the last record written
+    pub fn last(&self) -> u64 {
+        self.next - 1
+    }
+
+    // Starts a new segment, returns it: the segments before it can be removed once applied
+    pub fn rotate(&mut self) -> Result<u64, String> {
+        self.sync()?;
+        self.file = Wal::create(&self.dir, self.segment + 1)?;
+        self.segment += 1;
+        Ok(self.segment)
+    }
+
+    pub fn remove_before(&self, segment: u64) -> Result<(), String> {
+        for s in segments(&self.dir)?.into_iter().filter(|s| *s < segment) {
+            let path = segment_path(&self.dir, s);
+            if let Err(e) = fs::remove_file(&path) {
+                return Err(format!("Error removing log {}: {}", path.display(), e));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Sequence number of the last record applied to the shards of the timeseries
+pub fn applied(storage: &SledStorage) -> Result<Option<u64>, String> {
+    let stored = storage.tree.open_tree(TREE).and_then(|t| t.get(APPLIED));
+    match stored {
+        Ok(Some(v)) if v.len() == 8 => {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&v);
+            Ok(Some(u64::from_be_bytes(bytes)))
+        }
+        Ok(_) => Ok(None),
+        Err(e) => Err(format!("Error reading applied log records: {}", e)),
+    }
+}
+
+pub fn set_applied(storage: &SledStorage, sequence: u64) -> Result<(), String> {
+    let saved = storage
+        .tree
+        .open_tree(TREE)
+        .and_then(|t| t.insert(APPLIED, &sequence.to_be_bytes()));
+    match saved {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Error saving applied log records: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn torn_records() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(SyncPolicy::parse("batch:10"), Ok(SyncPolicy::Batch(10)));
+        assert_eq!(
+            SyncPolicy::parse("interval:1s"),
+            Ok(SyncPolicy::Interval(1000))
+        );
+        assert!(SyncPolicy::parse("batch").is_err());
+
+        let dir = std::env::temp_dir().join(format!("refluxdb-wal-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut wal = Wal::open(&dir, None).unwrap();
+        for end in 1..=3 {
+            let record = Record::Delete(None, end);
+            assert_eq!(
+                wal.append(&record, &SyncPolicy::Batch(2)).unwrap(),
+                end as u64
+            );
+        }
+        wal.sync().unwrap();
+        // the last record cut short, as by a crash while writing it
+        let path = segment_path(&dir, 0);
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 3]).unwrap();
+        let reopened = Wal::open(&dir, None).unwrap();
+        assert_eq!(reopened.next, 3);
+        let sequences: Vec<u64> = reopened.records().unwrap().iter().map(|r| r.0).collect();
+        assert_eq!(sequences, vec![1, 2]);
+
+        // a record changed on disk fails its checksum
+        let mut bytes = fs::read(&path).unwrap();
+        let record = (bytes.len() + 3 - MAGIC.len()) / 3;
+        bytes[MAGIC.len() + 2 * record - 1] ^= 1;
+        fs::write(&path, &bytes).unwrap();
+        assert_eq!(
+            Wal::open(&dir, Some(7)).unwrap().records().unwrap().len(),
+            1
+        );
+        assert_eq!(Wal::open(&dir, Some(7)).unwrap().next, 8);
+
+        // a segment cut in its header has no records, one without it is refused
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(segment_path(&dir, 0), &MAGIC[..3]).unwrap();
+        assert!(Wal::open(&dir, None).unwrap().records().unwrap().is_empty());
+        let record = bincode::serialize(&Record::Delete(Some(1), 2)).unwrap();
+        let mut unknown = (record.len() as u32).to_le_bytes().to_vec();
+        unknown.extend(record);
+        fs::write(segment_path(&dir, 0), &unknown).unwrap();
+        assert!(Wal::open(&dir, None).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}